@@ -1,4 +1,35 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::compile_protos("proto/transcode.proto")?;
+
+    // Expose the git commit to the `/version` endpoint; builds outside a git checkout report "unknown"
+    let git_commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=GIT_COMMIT={}", git_commit);
+
+    // HEAD only changes on checkout; a commit changes the branch ref HEAD points to, which is a
+    // file of its own until `git gc` packs it into `packed-refs`
+    let git_dir = Path::new("../.git");
+    println!("cargo:rerun-if-changed={}", git_dir.join("HEAD").display());
+    if let Ok(head) = fs::read_to_string(git_dir.join("HEAD")) {
+        if let Some(head_ref) = head.trim().strip_prefix("ref: ") {
+            for path in [git_dir.join(head_ref), git_dir.join("packed-refs")] {
+                // A path that doesn't exist would rerun the build script on every build
+                if path.exists() {
+                    println!("cargo:rerun-if-changed={}", path.display());
+                }
+            }
+        }
+    }
+    println!("cargo:rerun-if-changed=proto/transcode.proto");
+
     Ok(())
 }
@@ -1,13 +1,22 @@
 use jsonwebtoken::{decode, DecodingKey, Validation, Algorithm};
 use serde::{Deserialize, Serialize};
-use warp::reject::custom;
 use warp::{Filter, Rejection};
-use dotenv::var;
+use crate::config::var;
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Claims {
-    sub: String,
-    exp: usize,
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Claims {
+    pub sub: String,
+    pub exp: usize,
+    // Space-separated scopes, e.g. "admin"
+    #[serde(default)]
+    pub scope: String,
+}
+
+impl Claims {
+    /// Returns whether the token carries the "admin" scope.
+    pub fn is_admin(&self) -> bool {
+        self.scope.split_whitespace().any(|scope| scope == "admin")
+    }
 }
 
 #[derive(Debug)]
@@ -15,11 +24,9 @@ struct InvalidToken;
 
 /// Creates a Warp filter for JWT authentication.
 /// 
-/// This function extracts the `Authorization` header from the incoming request,
-/// verifies the JWT token, and ensures it matches the expected token stored in
-/// the `FABSTIR_TRANSCODER_JWT` environment variable. It also decodes and validates
-/// the token using the secret key stored in the `FABSTIR_TRANSCODER_SECRET_KEY`
-/// environment variable.
+/// This function extracts the `Authorization` header from the incoming request
+/// and decodes and validates the JWT token using the secret keys returned by
+/// `secret_keys`.
 ///
 /// # Returns
 /// 
@@ -28,29 +35,94 @@ struct InvalidToken;
 impl warp::reject::Reject for InvalidToken {}
 
 pub fn with_auth() -> impl Filter<Extract = (), Error = Rejection> + Clone {
-    warp::header::<String>("authorization")
-        .and_then(|token: String| async move {
-            let token = token.trim_start_matches("Bearer ");
-            let env_token = match var("FABSTIR_TRANSCODER_JWT") {
-                Ok(val) => val,
-                Err(_) => return Err(warp::reject::custom(InvalidToken)),
-            };
-
-            if token != env_token {
-                return Err(warp::reject::custom(InvalidToken));
-            }
+    with_claims().map(|_claims: Claims| ()).untuple_one()
+}
 
-            let key = match var("FABSTIR_TRANSCODER_SECRET_KEY") {
-                Ok(val) => val,
-                Err(_) => return Err(warp::reject::custom(InvalidToken)),
-            };
+/// Creates a Warp filter for JWT authentication that, like `with_auth`, rejects requests without a
+/// valid token, and passes the decoded claims on to the handler, e.g. to identify the subject
+/// submitting a task.
+///
+pub fn with_claims() -> impl Filter<Extract = (Claims,), Error = Rejection> + Clone {
+    warp::header::<String>("authorization")
+        .and_then(|token: String| async move { verify_token(&token) })
+}
 
-            let validation = Validation::new(Algorithm::HS256);
+/// Creates a Warp filter for admin endpoints. The token must carry the "admin" scope, so a token
+/// used to submit tasks cannot be used for operator actions.
+///
+pub fn with_admin() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::<String>("authorization")
+        .and_then(|token: String| async move {
+            let claims = verify_token(&token)?;
 
-            match decode::<Claims>(token, &DecodingKey::from_secret(key.as_ref()), &validation) {
-                Ok(_) => Ok::<_, Rejection>(()), // Ensure the return type matches the expected type
-                Err(_) => Err(warp::reject::custom(InvalidToken)),
+            if claims.is_admin() {
+                Ok::<_, Rejection>(())
+            } else {
+                Err(warp::reject::custom(InvalidToken))
             }
         })
         .untuple_one() // Flatten the nested tuple
+}
+
+/// Returns the secret keys tokens may be signed with, primary key first. During a key rotation
+/// `FABSTIR_TRANSCODER_SECRET_KEYS` holds the new key followed by the previous ones, comma-separated;
+/// when it is not set the single `FABSTIR_TRANSCODER_SECRET_KEY` is used.
+pub fn secret_keys() -> Vec<String> {
+    let keys: Vec<String> = var("FABSTIR_TRANSCODER_SECRET_KEYS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|key| key.trim().to_string())
+        .filter(|key| !key.is_empty())
+        .collect();
+
+    if !keys.is_empty() {
+        return keys;
+    }
+    var("FABSTIR_TRANSCODER_SECRET_KEY").into_iter().collect()
+}
+
+/// Decodes and validates a bearer token with any of the `secret_keys`, so tokens signed with a
+/// previous key are still accepted while a rotation is in progress.
+fn verify_token(token: &str) -> Result<Claims, Rejection> {
+    decode_claims(token, &secret_keys()).ok_or_else(|| warp::reject::custom(InvalidToken))
+}
+
+/// Decodes and validates a token, with or without its `Bearer ` prefix, with the first of `keys`
+/// that validates it.
+///
+/// # Returns
+/// The token's claims, or `None` if no key validates it.
+///
+pub fn decode_claims(token: &str, keys: &[String]) -> Option<Claims> {
+    let token = token.trim_start_matches("Bearer ");
+    let validation = Validation::new(Algorithm::HS256);
+
+    keys.iter()
+        .find_map(|key| decode::<Claims>(token, &DecodingKey::from_secret(key.as_ref()), &validation).ok())
+        .map(|token_data| token_data.claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    fn token(key: &str) -> String {
+        let claims = Claims {
+            sub: "user".to_string(),
+            exp: 10000000000,
+            scope: String::new(),
+        };
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(key.as_ref())).unwrap()
+    }
+
+    #[test]
+    fn decode_claims_accepts_a_token_signed_with_any_key() {
+        let keys = vec!["new-key".to_string(), "old-key".to_string()];
+
+        let claims = decode_claims(&format!("Bearer {}", token("old-key")), &keys).unwrap();
+        assert_eq!(claims.sub, "user");
+        assert!(decode_claims(&token("new-key"), &keys).is_some());
+        assert!(decode_claims(&token("other-key"), &keys).is_none());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,250 @@
+use crate::transcode_video::DEFAULT_DEST;
+use chrono::Utc;
+use crate::config::var;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub cid: String,
+    pub created_at: i64,
+    // The rendition as the task that encoded it returned it, absent from entries written before
+    // renditions were cached
+    #[serde(default)]
+    pub rendition: Option<Value>,
+}
+
+// Rendition fields that describe the encode of the task that ran it, or must only ever be given to
+// that task, and so are not returned from the cache
+const UNCACHED_RENDITION_FIELDS: [&str; 3] = ["hls_key", "encode_secs", "encode_speed"];
+
+// HashMap<hash of (source_cid, format settings), cached transcode result>
+static TRANSCODE_CACHE: Lazy<Mutex<HashMap<String, CacheEntry>>> =
+    Lazy::new(|| Mutex::new(load_cache()));
+
+static TRANSCODE_CACHE_MAX_ENTRIES: Lazy<usize> = Lazy::new(|| {
+    var("TRANSCODE_CACHE_MAX_ENTRIES")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(10000)
+});
+
+static TRANSCODE_CACHE_TTL_SECS: Lazy<i64> = Lazy::new(|| {
+    var("TRANSCODE_CACHE_TTL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(604800) // default to 7 days
+});
+
+/// Loads the transcode cache from the file given by `TRANSCODE_CACHE_FILE`. If the variable is not
+/// set the cache is kept in memory only and is lost on restart.
+///
+fn load_cache() -> HashMap<String, CacheEntry> {
+    let cache_file = match var("TRANSCODE_CACHE_FILE") {
+        Ok(path) => path,
+        Err(_) => return HashMap::new(),
+    };
+
+    match fs::read_to_string(&cache_file) {
+        Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+            eprintln!("Failed to parse transcode cache file {}: {}", cache_file, e);
+            HashMap::new()
+        }),
+        Err(_) => HashMap::new(),
+    }
+}
+
+fn save_cache(cache: &HashMap<String, CacheEntry>) {
+    let cache_file = match var("TRANSCODE_CACHE_FILE") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+
+    match serde_json::to_string(cache) {
+        Ok(contents) => {
+            if let Err(e) = fs::write(&cache_file, contents) {
+                eprintln!("Failed to write transcode cache file {}: {}", cache_file, e);
+            }
+        }
+        Err(e) => eprintln!("Error serializing transcode cache: {:?}", e),
+    }
+}
+
+/// Computes the cache key for a rendition as the blake3 hash of the source CID, the format
+/// settings and whether the output is encrypted. A format without a `dest` is hashed with the
+/// `DEFAULT_DEST` it is uploaded to, so changing the default never returns a CID from the other
+/// storage network. `serde_json` serializes object keys in sorted order, so identical settings
+/// always produce the same key regardless of their order in the request.
+///
+/// # Arguments
+/// * `source_cid` - The CID of the source video.
+/// * `video_format` - The format settings the source is transcoded with.
+/// * `is_encrypted` - Whether the transcoded output is encrypted.
+///
+pub fn cache_key(source_cid: &str, video_format: &Value, is_encrypted: bool) -> String {
+    let mut hasher = blake3::Hasher::new();
+    let mut video_format = video_format.clone();
+    if let Some(settings) = video_format.as_object_mut() {
+        settings
+            .entry("dest")
+            .or_insert_with(|| Value::String(DEFAULT_DEST.clone()));
+    }
+
+    hasher.update(source_cid.as_bytes());
+    hasher.update(video_format.to_string().as_bytes());
+    hasher.update(&[is_encrypted as u8]);
+    hasher.finalize().to_hex().to_string()
+}
+
+fn get_entry(key: &str) -> Option<CacheEntry> {
+    let mut cache = TRANSCODE_CACHE.lock().unwrap();
+
+    let entry = cache.get(key)?.clone();
+    if *TRANSCODE_CACHE_TTL_SECS > 0
+        && Utc::now().timestamp() - entry.created_at > *TRANSCODE_CACHE_TTL_SECS
+    {
+        cache.remove(key);
+        save_cache(&cache);
+        return None;
+    }
+
+    Some(entry)
+}
+
+/// Returns the CID of a previous transcode with the same cache key, or `None` if there is no entry
+/// or the entry is older than `TRANSCODE_CACHE_TTL_SECS`.
+///
+/// # Arguments
+/// * `key` - The cache key computed by `cache_key`.
+///
+pub fn get_cached_cid(key: &str) -> Option<String> {
+    get_entry(key).map(|entry| entry.cid)
+}
+
+/// Returns the rendition of a previous transcode with the same cache key, with every field it was
+/// returned with such as `cid`, `hls_key_cid`, `scenes` and `perceptual_hashes`, or `None` as for
+/// `get_cached_cid`. An entry written before renditions were cached has only its `cid`.
+///
+/// # Arguments
+/// * `key` - The cache key computed by `cache_key`.
+///
+pub fn get_cached_rendition(key: &str) -> Option<Value> {
+    let entry = get_entry(key)?;
+    Some(entry.rendition.unwrap_or_else(|| json!({ "cid": entry.cid })))
+}
+
+/// Stores a completed rendition under the given cache key, without the fields in
+/// `UNCACHED_RENDITION_FIELDS`. When the cache holds more than `TRANSCODE_CACHE_MAX_ENTRIES`
+/// entries the oldest ones are evicted.
+///
+/// # Arguments
+/// * `key` - The cache key computed by `cache_key`.
+/// * `rendition` - The rendition as returned to the task, with the CID of the transcoded output.
+///
+pub fn insert_cached_rendition(key: &str, rendition: &Value) {
+    let cid = match rendition["cid"].as_str() {
+        Some(cid) => cid.to_string(),
+        None => return,
+    };
+    let mut rendition = rendition.clone();
+    if let Some(fields) = rendition.as_object_mut() {
+        for field in UNCACHED_RENDITION_FIELDS {
+            fields.remove(field);
+        }
+    }
+
+    let mut cache = TRANSCODE_CACHE.lock().unwrap();
+
+    cache.insert(
+        key.to_string(),
+        CacheEntry {
+            cid,
+            created_at: Utc::now().timestamp(),
+            rendition: Some(rendition),
+        },
+    );
+
+    while cache.len() > *TRANSCODE_CACHE_MAX_ENTRIES {
+        let oldest_key = cache
+            .iter()
+            .min_by_key(|(_, entry)| entry.created_at)
+            .map(|(key, _)| key.clone());
+
+        match oldest_key {
+            Some(oldest_key) => {
+                cache.remove(&oldest_key);
+            }
+            None => break,
+        }
+    }
+
+    save_cache(&cache);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coalesce;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn cached_renditions_keep_their_results_but_not_the_hls_key() {
+        let key = cache_key("cached_renditions_source", &json!({"id": 1}), false);
+        let rendition = json!({
+            "id": 1,
+            "cid": "s5://output",
+            "hls_key_cid": "s5://key",
+            "hls_key": "00112233445566778899aabbccddeeff",
+            "scenes": [{"cid": "s5://scene", "start": 0.0, "end": 4.0}],
+            "encode_secs": 12.5,
+        });
+        insert_cached_rendition(&key, &rendition);
+
+        let cached = get_cached_rendition(&key).unwrap();
+        assert_eq!(cached["cid"], "s5://output");
+        assert_eq!(cached["hls_key_cid"], "s5://key");
+        assert_eq!(cached["scenes"], rendition["scenes"]);
+        assert!(cached.get("hls_key").is_none());
+        assert!(cached.get("encode_secs").is_none());
+        assert_eq!(get_cached_cid(&key).as_deref(), Some("s5://output"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn identical_concurrent_renditions_are_encoded_once() {
+        let encodes = Arc::new(AtomicUsize::new(0));
+        // The same settings in a different order, as two tasks could send them
+        let settings = [
+            json!({"id": 1, "vcodec": "libx264", "crf": 23}),
+            json!({"crf": 23, "vcodec": "libx264", "id": 1}),
+        ];
+
+        let tasks = settings.map(|video_format| {
+            let encodes = encodes.clone();
+            tokio::spawn(async move {
+                let key = cache_key("identical_concurrent_source", &video_format, false);
+                let _rendition = coalesce::begin(&format!("rendition_{}", key)).await;
+                if let Some(rendition) = get_cached_rendition(&key) {
+                    return rendition;
+                }
+
+                encodes.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let rendition = json!({"cid": "s5://encoded", "perceptual_hashes": ["ab"]});
+                insert_cached_rendition(&key, &rendition);
+                rendition
+            })
+        });
+
+        for task in tasks {
+            let rendition = task.await.unwrap();
+            assert_eq!(rendition["perceptual_hashes"], json!(["ab"]));
+        }
+        assert_eq!(encodes.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,219 @@
+use crate::config::var;
+use crate::s5::check_download_url;
+use crate::utils::post_serialized_json_with_retry;
+use once_cell::sync::Lazy;
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+use reqwest::header::{HeaderName, HeaderValue};
+use serde_json::Value;
+use std::collections::HashMap;
+
+// Header carrying the signature of a callback's body
+pub const CALLBACK_SIGNATURE_HEADER: &str = "x-transcode-signature";
+
+// Key callbacks are signed with, `None` to send them unsigned
+static CALLBACK_SIGNING_SECRET: Lazy<Option<String>> =
+    Lazy::new(|| var("CALLBACK_SIGNING_SECRET").ok());
+
+// Most headers a task's `callback_headers` may set
+pub const MAX_CALLBACK_HEADERS: usize = 20;
+
+// Longest value accepted for a callback header
+const MAX_CALLBACK_HEADER_VALUE_LEN: usize = 4096;
+
+// Headers describing the connection or body framing, which are set by the HTTP client, and the
+// signature header
+const RESERVED_CALLBACK_HEADERS: [&str; 7] = [
+    "host",
+    "content-length",
+    "transfer-encoding",
+    "connection",
+    "upgrade",
+    "te",
+    CALLBACK_SIGNATURE_HEADER,
+];
+
+/// Checks a task's completion callback: the URL must pass the same host checks as a download URL,
+/// so a callback cannot reach internal services, and each header must be a valid HTTP header that
+/// is not one of `RESERVED_CALLBACK_HEADERS`.
+///
+/// # Arguments
+/// * `callback_url` - The task's `callback_url`, empty for no callback.
+/// * `callback_headers` - The task's `callback_headers`.
+///
+/// # Returns
+/// The headers as (name, value) pairs sorted by name, or an error message.
+///
+pub fn validate_callback(
+    callback_url: &str,
+    callback_headers: &HashMap<String, String>,
+) -> Result<Vec<(String, String)>, String> {
+    if callback_url.is_empty() {
+        if !callback_headers.is_empty() {
+            return Err("callback_headers needs a callback_url".to_string());
+        }
+        return Ok(Vec::new());
+    }
+    check_download_url(callback_url).map_err(|e| format!("Invalid callback_url: {}", e))?;
+
+    if callback_headers.len() > MAX_CALLBACK_HEADERS {
+        return Err(format!(
+            "callback_headers sets {} headers, more than {}",
+            callback_headers.len(),
+            MAX_CALLBACK_HEADERS
+        ));
+    }
+
+    let mut headers = Vec::new();
+    for (name, value) in callback_headers {
+        let header_name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| {
+            format!(
+                "callback_headers name {:?} is not a valid header name",
+                name
+            )
+        })?;
+        if RESERVED_CALLBACK_HEADERS.contains(&header_name.as_str()) {
+            return Err(format!(
+                "callback_headers cannot set {}, which is set by the transcoder",
+                name
+            ));
+        }
+        if value.len() > MAX_CALLBACK_HEADER_VALUE_LEN || HeaderValue::from_str(value).is_err() {
+            return Err(format!(
+                "callback_headers value of {} must be printable ASCII of at most {} characters",
+                name, MAX_CALLBACK_HEADER_VALUE_LEN
+            ));
+        }
+        headers.push((header_name.as_str().to_string(), value.clone()));
+    }
+
+    headers.sort();
+    if let Some(pair) = headers.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+        return Err(format!(
+            "callback_headers sets {} more than once",
+            pair[0].0
+        ));
+    }
+    Ok(headers)
+}
+
+/// Signs a callback body as `sha256=` followed by the hex HMAC-SHA256 of the body with `secret`.
+pub fn sign_callback(body: &[u8], secret: &str) -> Result<String, String> {
+    let sign = || -> Result<Vec<u8>, openssl::error::ErrorStack> {
+        let key = PKey::hmac(secret.as_bytes())?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        signer.update(body)?;
+        signer.sign_to_vec()
+    };
+    let signature = sign().map_err(|e| format!("Failed to sign callback: {}", e))?;
+    Ok(format!("sha256={}", hex::encode(signature)))
+}
+
+// A callback's (name, value) headers
+type CallbackHeaders = Vec<(String, String)>;
+
+/// Serializes a callback body and adds its signature to the headers sent with it, if there is a
+/// `secret` to sign it with.
+///
+/// # Returns
+/// The serialized body and the headers to send, or an error message.
+///
+fn signed_callback(
+    body: &Value,
+    headers: &[(String, String)],
+    secret: Option<&str>,
+) -> Result<(Vec<u8>, CallbackHeaders), String> {
+    let body = serde_json::to_vec(body).map_err(|e| format!("Error serializing callback: {}", e))?;
+    let mut headers = headers.to_vec();
+    if let Some(secret) = secret {
+        headers.push((
+            CALLBACK_SIGNATURE_HEADER.to_string(),
+            sign_callback(&body, secret)?,
+        ));
+    }
+    Ok((body, headers))
+}
+
+/// POSTs a callback to a task's `callback_url` with its `callback_headers`, signed with
+/// `CALLBACK_SIGNING_SECRET` if it is set. The URL is checked again when sending, and the request
+/// connects to the checked address, so a host that now resolves to an internal address is not
+/// reached.
+///
+/// # Arguments
+/// * `callback_url` - The task's `callback_url`.
+/// * `body` - The callback's JSON body.
+/// * `callback_headers` - The headers returned by `validate_callback`.
+/// * `retries` - How many times to retry a failed POST.
+///
+pub async fn send_callback(
+    callback_url: &str,
+    body: &Value,
+    callback_headers: &[(String, String)],
+    retries: u32,
+) -> Result<(), String> {
+    let (body, headers) =
+        signed_callback(body, callback_headers, CALLBACK_SIGNING_SECRET.as_deref())?;
+    post_serialized_json_with_retry(callback_url, body, &headers, retries, true).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn sign_callback_is_the_hmac_sha256_of_the_body() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign_callback(b"what do ya want for nothing?", "Jefe").unwrap(),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn validate_callback_rejects_the_signature_header() {
+        let headers = HashMap::from([(
+            "X-Transcode-Signature".to_string(),
+            "sha256=forged".to_string(),
+        )]);
+        assert!(validate_callback("https://example.com/hook", &headers).is_err());
+    }
+
+    #[tokio::test]
+    async fn callbacks_are_sent_with_their_headers_and_signature() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let receiver = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            // Read until the end of the body, whose length is given by the client
+            while !request.ends_with(b"}") {
+                let read = stream.read(&mut buf).unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..read]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                .unwrap();
+            String::from_utf8(request).unwrap().to_lowercase()
+        });
+
+        let body = json!({"event": "task_finished", "task_id": "task"});
+        let callback_headers = vec![("x-api-key".to_string(), "secret".to_string())];
+        let (body, headers) = signed_callback(&body, &callback_headers, Some("key")).unwrap();
+        let signature = sign_callback(&body, "key").unwrap();
+        // Not checked, as the receiver is on the loopback address
+        post_serialized_json_with_retry(&url, body, &headers, 0, false)
+            .await
+            .unwrap();
+
+        let request = receiver.join().unwrap();
+        assert!(request.contains("\r\nx-api-key: secret\r\n"));
+        assert!(request.contains("\r\ncontent-type: application/json\r\n"));
+        assert!(request.contains(&format!("\r\nx-transcode-signature: {}\r\n", signature)));
+    }
+}
@@ -0,0 +1,151 @@
+use crate::transcode_video::FFMPEG_PATH;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::process::Command;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FfmpegCaps {
+    // First line of `ffmpeg -version`, e.g. "ffmpeg version 6.1.1 Copyright ..."
+    pub version_line: String,
+    // Version number alone, e.g. "6.1.1" or "n6.1-dev"
+    pub version: String,
+    // Features ffmpeg was configured with `--enable-...`, e.g. "gpl", "libx264", "nvenc"
+    pub enabled: Vec<String>,
+    // Names of every encoder listed by `ffmpeg -encoders`
+    pub encoders: Vec<String>,
+}
+
+/// Capabilities of the installed ffmpeg, detected on first use, or `None` if ffmpeg could not be
+/// run. The server forces detection at startup.
+pub static FFMPEG_CAPS: Lazy<Option<FfmpegCaps>> = Lazy::new(probe_ffmpeg_capabilities);
+
+// Values of codec options that select no encoder
+const NON_ENCODER_CODECS: [&str; 2] = ["copy", "none"];
+
+/// Runs ffmpeg with `args` and returns its standard output, or `None` if it could not be run.
+pub fn run_ffmpeg_query(args: &[&str]) -> Option<String> {
+    let output = Command::new(FFMPEG_PATH.as_str())
+        .args(args)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Parses the version number from `ffmpeg -version` output, whose first line reads
+/// "ffmpeg version <version> Copyright ...".
+///
+/// # Arguments
+/// * `version_output` - The output of `ffmpeg -version`.
+///
+pub fn parse_version(version_output: &str) -> Option<String> {
+    let mut words = version_output.lines().next()?.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some("ffmpeg"), Some("version"), Some(version)) => Some(version.to_string()),
+        _ => None,
+    }
+}
+
+/// Parses the features enabled with `--enable-...` from `ffmpeg -buildconf` output, which lists
+/// one configure flag per line. Also accepts the single "configuration:" line of `-version`.
+///
+/// # Arguments
+/// * `buildconf_output` - The output of `ffmpeg -buildconf`.
+///
+pub fn parse_buildconf(buildconf_output: &str) -> Vec<String> {
+    let mut enabled: Vec<String> = buildconf_output
+        .split_whitespace()
+        .filter_map(|flag| flag.strip_prefix("--enable-"))
+        .map(|feature| feature.to_string())
+        .collect();
+    enabled.sort();
+    enabled.dedup();
+    enabled
+}
+
+/// Parses the encoder names from `ffmpeg -hide_banner -encoders` output. Each encoder line is a
+/// flags column followed by the name, e.g. " V....D libx264  libx264 H.264 ...", and the legend
+/// above the list is separated from it by a " ------" line.
+///
+/// # Arguments
+/// * `encoders_output` - The output of `ffmpeg -hide_banner -encoders`.
+///
+pub fn parse_encoders(encoders_output: &str) -> Vec<String> {
+    encoders_output
+        .lines()
+        .skip_while(|line| !line.trim_start().starts_with("---"))
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(|encoder| encoder.to_string())
+        .collect()
+}
+
+/// Detects the version, enabled features and encoders of the installed ffmpeg.
+///
+/// # Returns
+/// The detected capabilities, or `None` if `ffmpeg -version` could not be run.
+///
+pub fn probe_ffmpeg_capabilities() -> Option<FfmpegCaps> {
+    let version_output = run_ffmpeg_query(&["-version"])?;
+    let buildconf_output = run_ffmpeg_query(&["-hide_banner", "-buildconf"]).unwrap_or_default();
+    let encoders_output = run_ffmpeg_query(&["-hide_banner", "-encoders"]).unwrap_or_default();
+
+    Some(FfmpegCaps {
+        version_line: version_output
+            .lines()
+            .next()
+            .map(|line| line.trim().to_string())
+            .unwrap_or_default(),
+        version: parse_version(&version_output).unwrap_or_default(),
+        enabled: parse_buildconf(&buildconf_output),
+        encoders: parse_encoders(&encoders_output),
+    })
+}
+
+/// Checks that the installed ffmpeg was configured with a feature such as "libvmaf", for options
+/// that need a filter or library rather than an encoder. Passes if ffmpeg's capabilities could not
+/// be detected.
+///
+/// # Arguments
+/// * `feature` - The feature name as given to `--enable-`.
+///
+pub fn check_feature(feature: &str) -> Result<(), String> {
+    match FFMPEG_CAPS.as_ref() {
+        Some(caps)
+            if !caps.enabled.is_empty() && !caps.enabled.iter().any(|name| name == feature) =>
+        {
+            Err(format!(
+                "The installed ffmpeg {} was not built with {}",
+                caps.version, feature
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks that the installed ffmpeg has an encoder, so formats asking for a missing codec are
+/// rejected up front instead of failing at encode time. Passes if ffmpeg's capabilities could not be
+/// detected, leaving the error to the encode itself.
+///
+/// # Arguments
+/// * `encoder` - The encoder name given as a format's `vcodec`, `c_a` or `acodec`.
+///
+pub fn check_encoder(encoder: &str) -> Result<(), String> {
+    if encoder.is_empty() || NON_ENCODER_CODECS.contains(&encoder) {
+        return Ok(());
+    }
+
+    match FFMPEG_CAPS.as_ref() {
+        Some(caps)
+            if !caps.encoders.is_empty() && !caps.encoders.iter().any(|name| name == encoder) =>
+        {
+            Err(format!(
+                "Encoder {} is not available in the installed ffmpeg {}",
+                encoder, caps.version
+            ))
+        }
+        _ => Ok(()),
+    }
+}
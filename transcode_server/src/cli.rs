@@ -0,0 +1,287 @@
+/*
+ * cli.rs
+ *
+ * Command line tool that transcodes a local video file to the formats in a
+ * media formats JSON file using the same pipeline as the transcode server,
+ * without standing up the gRPC/REST servers. Outputs are written locally and
+ * are only uploaded to storage when `--upload` is given.
+ *
+ * Local outputs are renamed to human-readable names such as
+ * `{basename}_1080p_h264.mp4`; the template can be changed with `--name-template`
+ * or the `OUTPUT_FILENAME_TEMPLATE` environment variable. If two formats resolve
+ * to the same name, `NAMING_COLLISION_STRATEGY` decides whether the later ones get
+ * a `_2`, `_3`, ... suffix ("suffix", the default) or the run is rejected before
+ * anything is transcoded ("reject").
+ *
+ * Usage:
+ *   transcode-cli <input_file> <media_formats_file> [--output-dir <dir>] [--name-template <template>]
+ *                 [--gpu] [--upload] [--encrypt]
+ */
+
+// tonic::Status is the error type throughout, large as it is
+#![allow(clippy::result_large_err)]
+
+// The modules are shared with the server, which uses more of each of them than the CLI does
+#[allow(dead_code)]
+mod capabilities;
+#[allow(dead_code)]
+mod config;
+#[allow(dead_code)]
+mod encrypt_file;
+#[allow(dead_code)]
+mod encrypted_cid;
+#[allow(dead_code)]
+mod error_code;
+#[allow(dead_code)]
+mod probe;
+#[allow(dead_code)]
+mod s5;
+#[allow(dead_code)]
+mod sample_aes;
+#[allow(dead_code)]
+mod shared;
+#[allow(dead_code)]
+mod throttle;
+#[allow(dead_code)]
+mod transcode_video;
+#[allow(dead_code)]
+mod utils;
+
+use dotenv::dotenv;
+use serde_json::Value;
+use std::fs::read_to_string;
+use std::path::Path;
+use std::process::exit;
+use transcode_video::{
+    friendly_file_name, get_video_format_from_str, naming_collision_strategy,
+    resolve_name_collisions, transcode_video, transcode_video_local, DEFAULT_FILENAME_TEMPLATE,
+};
+
+struct CliArgs {
+    input_file: String,
+    media_formats_file: String,
+    output_dir: Option<String>,
+    name_template: Option<String>,
+    is_gpu: bool,
+    upload: bool,
+    is_encrypted: bool,
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: transcode-cli <input_file> <media_formats_file> [--output-dir <dir>] [--name-template <template>] [--gpu] [--upload] [--encrypt]"
+    );
+    exit(2);
+}
+
+fn parse_args() -> CliArgs {
+    let mut positional = Vec::new();
+    let mut output_dir = None;
+    let mut name_template = None;
+    let mut is_gpu = false;
+    let mut upload = false;
+    let mut is_encrypted = false;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output-dir" => output_dir = Some(args.next().unwrap_or_else(|| usage())),
+            "--name-template" => name_template = Some(args.next().unwrap_or_else(|| usage())),
+            "--gpu" => is_gpu = true,
+            "--upload" => upload = true,
+            "--encrypt" => is_encrypted = true,
+            "-h" | "--help" => usage(),
+            _ => positional.push(arg),
+        }
+    }
+
+    if positional.len() != 2 {
+        usage();
+    }
+
+    CliArgs {
+        input_file: positional[0].clone(),
+        media_formats_file: positional[1].clone(),
+        output_dir,
+        name_template,
+        is_gpu,
+        upload,
+        is_encrypted,
+    }
+}
+
+/// Resolves the friendly filename of every format, detecting formats that would overwrite each
+/// other's output. Collisions are suffixed or rejected according to `NAMING_COLLISION_STRATEGY`,
+/// and each one is reported.
+///
+/// # Returns
+/// The friendly filename of each format, `None` for formats that are invalid and will fail to
+/// transcode, or an error message if a collision is rejected.
+///
+fn resolve_friendly_names(
+    input_file: &str,
+    media_formats: &[Value],
+    name_template: &str,
+) -> Result<Vec<Option<String>>, String> {
+    let strategy = naming_collision_strategy()?;
+
+    let basename = Path::new(input_file)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let friendly_names = media_formats
+        .iter()
+        .map(|video_format| {
+            get_video_format_from_str(&video_format.to_string())
+                .ok()
+                .map(|format| friendly_file_name(name_template, &basename, &format))
+        })
+        .collect();
+
+    resolve_name_collisions(friendly_names, strategy)
+}
+
+/// Renames a local output to its friendly filename in the same directory.
+///
+/// # Returns
+/// The new path of the output, or an error message if it could not be renamed.
+///
+fn rename_to_friendly_name(output_path: &str, friendly_name: &str) -> Result<String, String> {
+    let friendly_path = Path::new(output_path).with_file_name(friendly_name);
+
+    std::fs::rename(output_path, &friendly_path)
+        .map_err(|e| format!("Failed to rename {}: {}", output_path, e))?;
+
+    Ok(friendly_path.to_string_lossy().to_string())
+}
+
+// Settings are loaded, and PATH_TO_TRANSCODED_FILE set, before the runtime starts any threads
+fn main() {
+    dotenv().ok();
+    match config::load_config_file() {
+        Ok(unknown_keys) => {
+            for key in unknown_keys {
+                eprintln!("Unknown setting {} in CONFIG_FILE", key);
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+
+    let args = parse_args();
+
+    // The pipeline writes outputs to PATH_TO_TRANSCODED_FILE, which is a directory prefix
+    if let Some(mut output_dir) = args.output_dir.clone() {
+        if !output_dir.ends_with('/') {
+            output_dir.push('/');
+        }
+        std::env::set_var("PATH_TO_TRANSCODED_FILE", output_dir);
+    }
+
+    run(args);
+}
+
+#[tokio::main]
+async fn run(args: CliArgs) {
+    let media_formats_json = read_to_string(&args.media_formats_file).unwrap_or_else(|e| {
+        eprintln!(
+            "Failed to read media formats file {}: {}",
+            args.media_formats_file, e
+        );
+        exit(1);
+    });
+    let media_formats_vec: Vec<Value> =
+        serde_json::from_str(&media_formats_json).unwrap_or_else(|e| {
+            eprintln!(
+                "Failed to parse media formats file {}: {}",
+                args.media_formats_file, e
+            );
+            exit(1);
+        });
+
+    let name_template = args
+        .name_template
+        .clone()
+        .or_else(|| config::var("OUTPUT_FILENAME_TEMPLATE").ok())
+        .unwrap_or_else(|| DEFAULT_FILENAME_TEMPLATE.to_string());
+
+    // Names are only needed for local outputs, but are resolved before any transcoding so that a
+    // rejected collision doesn't leave a partial run behind
+    let friendly_names = if args.upload {
+        Vec::new()
+    } else {
+        match resolve_friendly_names(&args.input_file, &media_formats_vec, &name_template) {
+            Ok(friendly_names) => friendly_names,
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
+    };
+
+    let task_id = "cli".to_string();
+    let mut failed = false;
+
+    for (index, video_format) in media_formats_vec.iter().enumerate() {
+        let video_format_str = video_format.to_string();
+
+        if args.upload {
+            match transcode_video(
+                task_id.clone(),
+                index,
+                &args.input_file,
+                &video_format_str,
+                args.is_encrypted,
+                args.is_gpu,
+                false,
+            )
+            .await
+            {
+                Ok(response) => {
+                    let response = response.into_inner();
+                    println!(
+                        "format {}: {} cid: {}",
+                        index, response.message, response.cid
+                    );
+                    failed |= response.status_code != 200;
+                }
+                Err(e) => {
+                    eprintln!("format {}: {}", index, e.message());
+                    failed = true;
+                }
+            }
+        } else {
+            match transcode_video_local(
+                task_id.clone(),
+                index,
+                &args.input_file,
+                &video_format_str,
+                args.is_gpu,
+            ) {
+                Ok(output_path) => match friendly_names.get(index).cloned().flatten() {
+                    Some(friendly_name) => {
+                        match rename_to_friendly_name(&output_path, &friendly_name) {
+                            Ok(friendly_path) => println!("format {}: {}", index, friendly_path),
+                            Err(e) => {
+                                eprintln!("format {}: {}", index, e);
+                                println!("format {}: {}", index, output_path);
+                            }
+                        }
+                    }
+                    None => println!("format {}: {}", index, output_path),
+                },
+                Err(e) => {
+                    eprintln!("format {}: {}", index, e.message());
+                    failed = true;
+                }
+            }
+        }
+    }
+
+    if failed {
+        exit(1);
+    }
+}
@@ -0,0 +1,85 @@
+use crate::config::var;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::OwnedMutexGuard;
+
+// HashMap<key of work in flight, lock held by the task doing it>
+static IN_FLIGHT: Lazy<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+static COALESCE_IDENTICAL_WORK: Lazy<bool> = Lazy::new(|| {
+    var("COALESCE_IDENTICAL_WORK")
+        .map(|v| v != "false")
+        .unwrap_or(true)
+});
+
+/// Held by the task doing the work for a key. Other tasks asking for the same key wait until it is
+/// dropped.
+pub struct InFlightGuard {
+    key: String,
+    lock: Option<Arc<tokio::sync::Mutex<()>>>,
+    guard: Option<OwnedMutexGuard<()>>,
+    // Whether another task was doing the same work when this guard was requested
+    pub waited: bool,
+}
+
+/// Waits until no other task is doing the work identified by `key`, such as downloading a source or
+/// encoding a rendition of it, then marks it as in flight for the life of the returned guard. A
+/// task that waited can then reuse the other task's result, from the transcode cache or the
+/// downloaded file, instead of doing the same work again. Does not wait if
+/// `COALESCE_IDENTICAL_WORK` is "false".
+///
+/// # Arguments
+/// * `key` - Identifies the work, e.g. the path the work writes to.
+///
+pub async fn begin(key: &str) -> InFlightGuard {
+    if !*COALESCE_IDENTICAL_WORK {
+        return InFlightGuard {
+            key: key.to_string(),
+            lock: None,
+            guard: None,
+            waited: false,
+        };
+    }
+
+    let lock = IN_FLIGHT
+        .lock()
+        .unwrap()
+        .entry(key.to_string())
+        .or_default()
+        .clone();
+
+    let (guard, waited) = match lock.clone().try_lock_owned() {
+        Ok(guard) => (guard, false),
+        Err(_) => (lock.clone().lock_owned().await, true),
+    };
+
+    InFlightGuard {
+        key: key.to_string(),
+        lock: Some(lock),
+        guard: Some(guard),
+        waited,
+    }
+}
+
+/// Returns whether a task is doing the work identified by `key`.
+pub fn is_in_flight(key: &str) -> bool {
+    IN_FLIGHT.lock().unwrap().contains_key(key)
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let lock = match self.lock.take() {
+            Some(lock) => lock,
+            None => return,
+        };
+
+        let mut in_flight = IN_FLIGHT.lock().unwrap();
+        self.guard = None;
+        // Only the map and this guard hold the lock, so no task is waiting for it
+        if Arc::strong_count(&lock) == 2 {
+            in_flight.remove(&self.key);
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use crate::probe::{probe_source, SourceProbe};
+use crate::transcode_video::FFMPEG_PATH;
+use std::fs;
+use std::process::Command;
+
+/// Returns the properties of a source that must match for its streams to be joined with the concat
+/// demuxer without re-encoding: video codec, resolution, frame rate and pixel format, and audio
+/// codec, sample rate and channel count.
+///
+fn stream_signature(source_probe: &SourceProbe) -> Vec<Option<String>> {
+    let video = source_probe.streams_of_type("video").first().cloned().cloned();
+    let audio = source_probe.streams_of_type("audio").first().cloned().cloned();
+
+    vec![
+        video.as_ref().and_then(|s| s.codec_name.clone()),
+        video.as_ref().and_then(|s| s.width.map(|w| w.to_string())),
+        video.as_ref().and_then(|s| s.height.map(|h| h.to_string())),
+        video.as_ref().and_then(|s| s.r_frame_rate.clone()),
+        video.as_ref().and_then(|s| s.pix_fmt.clone()),
+        audio.as_ref().and_then(|s| s.codec_name.clone()),
+        audio.as_ref().and_then(|s| s.sample_rate.clone()),
+        audio.as_ref().and_then(|s| s.channels.map(|c| c.to_string())),
+    ]
+}
+
+fn run_ffmpeg_command(mut cmd: Command) -> Result<(), String> {
+    let args: Vec<String> = cmd
+        .get_args()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    println!("ffmpeg {}", args.join(" "));
+
+    let output = cmd
+        .output()
+        .map_err(|e| format!("Failed to execute ffmpeg: {}", e))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "ffmpeg failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns the frame rate of a source's first video stream, or 30 if it is unknown.
+fn frame_rate_of(source_probe: &SourceProbe) -> String {
+    source_probe
+        .streams_of_type("video")
+        .first()
+        .and_then(|video| video.r_frame_rate.clone())
+        .filter(|fps| fps != "0/0")
+        .unwrap_or_else(|| "30".to_string())
+}
+
+/// Re-encodes a source to the given resolution and constant frame rate, with yuv420p H.264 video
+/// and stereo 48kHz AAC audio, so that sources from different cameras can be joined. The frame
+/// rate filter also sets the video timebase to one tick per frame and audio is resampled to fill
+/// timestamp gaps, so every normalized file has the same timing. Sources without audio get a
+/// silent track so that every joined segment has the same streams.
+///
+/// # Arguments
+/// * `input_path` - The source to normalize.
+/// * `output_path` - Where to write the normalized file.
+/// * `source_probe` - The probe result of the source.
+/// * `width`, `height`, `fps` - The target resolution and frame rate.
+///
+fn normalize_source(
+    input_path: &str,
+    output_path: &str,
+    source_probe: &SourceProbe,
+    width: u32,
+    height: u32,
+    fps: &str,
+) -> Result<(), String> {
+    let mut cmd = Command::new(FFMPEG_PATH.as_str());
+    cmd.args(["-v", "error", "-i", input_path]);
+
+    let has_audio = !source_probe.streams_of_type("audio").is_empty();
+    if !has_audio {
+        cmd.args([
+            "-f",
+            "lavfi",
+            "-i",
+            "anullsrc=channel_layout=stereo:sample_rate=48000",
+            "-shortest",
+        ]);
+    }
+
+    cmd.args(["-map", "0:v:0"]);
+    cmd.args(["-map", if has_audio { "0:a:0" } else { "1:a:0" }]);
+
+    cmd.arg("-vf").arg(format!(
+        "scale={w}:{h}:force_original_aspect_ratio=decrease,pad={w}:{h}:(ow-iw)/2:(oh-ih)/2,setsar=1,fps={fps},format=yuv420p",
+        w = width,
+        h = height,
+        fps = fps
+    ));
+    cmd.args(["-af", "aresample=async=1:first_pts=0"]);
+    cmd.args([
+        "-c:v", "libx264", "-preset", "veryfast", "-crf", "18", "-c:a", "aac", "-ar", "48000",
+        "-ac", "2", "-y", output_path,
+    ]);
+
+    run_ffmpeg_command(cmd)
+}
+
+/// Normalizes a single source at its own resolution and frame rate before it is transcoded, so
+/// renditions of sources from different cameras share the same pixel format, frame rate and
+/// timebase.
+///
+/// # Arguments
+/// * `input_path` - The source to normalize.
+/// * `output_path` - Where to write the normalized file.
+///
+/// # Returns
+/// `Result<(), String>` - Ok on success or error message.
+///
+pub fn normalize_single_source(input_path: &str, output_path: &str) -> Result<(), String> {
+    let source_probe = probe_source(input_path)?;
+    let video = source_probe
+        .streams_of_type("video")
+        .first()
+        .cloned()
+        .cloned()
+        .ok_or_else(|| String::from("Only sources with a video stream can be normalized"))?;
+
+    normalize_source(
+        input_path,
+        output_path,
+        &source_probe,
+        video.width.unwrap_or(1280),
+        video.height.unwrap_or(720),
+        &frame_rate_of(&source_probe),
+    )
+}
+
+/// Joins the given sources, in order, into a single file using ffmpeg's concat demuxer. If the
+/// sources differ in codec, resolution, frame rate or audio layout, or `normalize` is set, they
+/// are first normalized to match the first source so the joined output plays continuously.
+///
+/// # Arguments
+/// * `input_paths` - Paths to the sources to join.
+/// * `output_path` - Where to write the joined file.
+/// * `normalize` - Whether to normalize the sources even if their streams are compatible.
+///
+/// # Returns
+/// `Result<(), String>` - Ok on success or error message.
+///
+pub fn join_sources(
+    input_paths: &[String],
+    output_path: &str,
+    normalize: bool,
+) -> Result<(), String> {
+    if input_paths.is_empty() {
+        return Err(String::from("No sources to join"));
+    }
+
+    let mut source_probes = Vec::new();
+    for input_path in input_paths {
+        source_probes.push(probe_source(input_path)?);
+    }
+
+    if source_probes
+        .iter()
+        .any(|source_probe| source_probe.streams_of_type("video").is_empty())
+    {
+        return Err(String::from("Every source to join must have a video stream"));
+    }
+
+    let first_signature = stream_signature(&source_probes[0]);
+    let compatible = !normalize
+        && source_probes
+            .iter()
+            .all(|source_probe| stream_signature(source_probe) == first_signature);
+
+    let mut parts = Vec::new();
+    if compatible {
+        parts.extend(input_paths.iter().cloned());
+    } else {
+        println!("Normalizing sources before joining");
+
+        let first_video = source_probes[0].streams_of_type("video")[0].clone();
+        let width = first_video.width.unwrap_or(1280);
+        let height = first_video.height.unwrap_or(720);
+        let fps = frame_rate_of(&source_probes[0]);
+
+        for (index, (input_path, source_probe)) in
+            input_paths.iter().zip(source_probes.iter()).enumerate()
+        {
+            let normalized_path = format!("{}_part{}.mkv", output_path, index);
+            normalize_source(input_path, &normalized_path, source_probe, width, height, &fps)?;
+            parts.push(normalized_path);
+        }
+    }
+
+    let list_path = format!("{}.txt", output_path);
+    let list: String = parts
+        .iter()
+        .map(|part| format!("file '{}'\n", part.replace('\'', "'\\''")))
+        .collect();
+    fs::write(&list_path, list).map_err(|e| format!("Failed to write concat list: {}", e))?;
+
+    let mut cmd = Command::new(FFMPEG_PATH.as_str());
+    cmd.args([
+        "-v", "error", "-f", "concat", "-safe", "0", "-i", list_path.as_str(), "-c", "copy", "-y",
+        output_path,
+    ]);
+    let result = run_ffmpeg_command(cmd);
+
+    let _ = fs::remove_file(&list_path);
+    if !compatible {
+        for part in &parts {
+            let _ = fs::remove_file(part);
+        }
+    }
+
+    result
+}
@@ -0,0 +1,161 @@
+use once_cell::sync::OnceCell;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// The settings of the file given by `CONFIG_FILE`, as the strings environment variables would
+/// hold.
+#[derive(Debug, Default)]
+pub struct Config {
+    settings: HashMap<String, String>,
+}
+
+// Set once by `load_config_file`; settings read before then, or without a file, come from the
+// environment only
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+/// Reads a setting: the environment variable, including one set by `.env`, or else the value from
+/// `CONFIG_FILE`. An empty value counts as unset, so the `KEY=` lines of a `.env` based on the
+/// template fall through to the file and then to the setting's default.
+///
+/// # Arguments
+/// * `key` - The name of the setting.
+///
+pub fn var(key: &str) -> Result<String, env::VarError> {
+    match env::var(key) {
+        Ok(value) if !value.is_empty() => return Ok(value),
+        Err(env::VarError::NotUnicode(value)) => return Err(env::VarError::NotUnicode(value)),
+        _ => {}
+    }
+
+    CONFIG
+        .get()
+        .and_then(|config| config.settings.get(key))
+        .filter(|value| !value.is_empty())
+        .cloned()
+        .ok_or(env::VarError::NotPresent)
+}
+
+// The `.env` template, which lists every setting the server reads, one `KEY=` per line
+const ENV_TEMPLATE: &str = include_str!("../.env_temp");
+
+/// Returns the names of the settings listed in the `.env` template.
+pub fn known_keys() -> Vec<&'static str> {
+    ENV_TEMPLATE
+        .lines()
+        .filter_map(|line| line.split('=').next())
+        .map(str::trim)
+        .filter(|key| !key.is_empty() && !key.starts_with('#'))
+        .collect()
+}
+
+/// Converts a config file value to the string an environment variable would hold. Only strings,
+/// numbers and booleans are accepted.
+fn to_env_value(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        Value::Bool(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// Parses a config file into its top-level settings. Files ending in `.toml` are read as TOML,
+/// anything else as a JSON object.
+///
+/// # Arguments
+/// * `config_file` - Path to the config file.
+/// * `contents` - The contents of the config file.
+///
+fn parse_config(
+    config_file: &str,
+    contents: &str,
+) -> Result<serde_json::Map<String, Value>, String> {
+    let is_toml = Path::new(config_file)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("toml"));
+
+    let config: Value = if is_toml {
+        let config: toml::Value = toml::from_str(contents)
+            .map_err(|e| format!("Failed to parse config file {}: {}", config_file, e))?;
+        serde_json::to_value(config)
+            .map_err(|e| format!("Failed to read config file {}: {}", config_file, e))?
+    } else {
+        serde_json::from_str(contents)
+            .map_err(|e| format!("Failed to parse config file {}: {}", config_file, e))?
+    };
+
+    match config {
+        Value::Object(settings) => Ok(settings),
+        _ => Err(format!(
+            "Config file {} must hold a table of settings",
+            config_file
+        )),
+    }
+}
+
+/// Loads the settings of the JSON or TOML file given by `CONFIG_FILE`, which `var` then reads like
+/// any other setting. Variables set to a value in the environment or `.env` take precedence over
+/// the file. Must be called at startup, before the async runtime starts and any setting is read.
+///
+/// # Returns
+/// The keys of the file that are not known settings, which are still applied, or an error if the
+/// file could not be read or holds a value that is not a string, number or boolean.
+///
+pub fn load_config_file() -> Result<Vec<String>, String> {
+    let config_file = match var("CONFIG_FILE") {
+        Ok(config_file) => config_file,
+        Err(_) => return Ok(Vec::new()),
+    };
+
+    let contents = fs::read_to_string(&config_file)
+        .map_err(|e| format!("Failed to read config file {}: {}", config_file, e))?;
+    let settings = parse_config(&config_file, &contents)?;
+
+    let known_keys = known_keys();
+    let mut config = Config::default();
+    let mut unknown_keys = Vec::new();
+    for (key, value) in &settings {
+        let value = to_env_value(value).ok_or_else(|| {
+            format!(
+                "Config file {} setting {} must be a string, number or boolean",
+                config_file, key
+            )
+        })?;
+
+        if !known_keys.contains(&key.as_str()) {
+            unknown_keys.push(key.clone());
+        }
+        config.settings.insert(key.clone(), value);
+    }
+
+    CONFIG
+        .set(config)
+        .map_err(|_| "The config file was already loaded".to_string())?;
+    Ok(unknown_keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config_reads_toml_and_json_tables() {
+        let toml = "PATH_TO_FILE = \"/data/\"\nPORT = 50051";
+        let settings = parse_config("config.toml", toml).unwrap();
+        assert_eq!(to_env_value(&settings["PATH_TO_FILE"]).unwrap(), "/data/");
+        assert_eq!(to_env_value(&settings["PORT"]).unwrap(), "50051");
+
+        let settings = parse_config("config.json", r#"{"USE_TMPFS": true}"#).unwrap();
+        assert_eq!(to_env_value(&settings["USE_TMPFS"]).unwrap(), "true");
+        assert!(parse_config("config.json", "[1]").is_err());
+    }
+
+    #[test]
+    fn var_treats_an_empty_value_as_unset() {
+        env::set_var("CONFIG_TEST_EMPTY_SETTING", "");
+        assert!(var("CONFIG_TEST_EMPTY_SETTING").is_err());
+    }
+}
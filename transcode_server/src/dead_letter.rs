@@ -0,0 +1,149 @@
+use crate::error_code::TranscodeErrorCode;
+use chrono::Utc;
+use crate::config::var;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetterEntry {
+    pub task_id: String,
+    pub source_cid: String,
+    pub media_formats: String,
+    pub is_encrypted: bool,
+    pub is_gpu: bool,
+    pub error: String,
+    // Missing from entries recorded before error codes were introduced
+    #[serde(default)]
+    pub error_code: Option<TranscodeErrorCode>,
+    pub failed_at: i64,
+}
+
+// Permanently failed tasks, oldest first
+static DEAD_LETTERS: Lazy<Mutex<Vec<DeadLetterEntry>>> =
+    Lazy::new(|| Mutex::new(load_dead_letters()));
+
+static DEAD_LETTER_MAX_ENTRIES: Lazy<usize> = Lazy::new(|| {
+    var("DEAD_LETTER_MAX_ENTRIES")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(1000)
+});
+
+static DEAD_LETTER_TTL_SECS: Lazy<i64> = Lazy::new(|| {
+    var("DEAD_LETTER_TTL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(604800) // default to 7 days
+});
+
+// File the list is persisted to; kept in memory only when not set or empty
+static DEAD_LETTER_FILE: Lazy<Option<String>> =
+    Lazy::new(|| var("DEAD_LETTER_FILE").ok().filter(|v| !v.is_empty()));
+
+/// Loads the dead-letter list from the file given by `DEAD_LETTER_FILE`. If the variable is not set
+/// or empty the list is kept in memory only and is lost on restart.
+///
+fn load_dead_letters() -> Vec<DeadLetterEntry> {
+    let dead_letter_file = match DEAD_LETTER_FILE.as_ref() {
+        Some(path) => path,
+        None => return Vec::new(),
+    };
+
+    match fs::read_to_string(dead_letter_file) {
+        Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+            eprintln!(
+                "Failed to parse dead-letter file {}: {}",
+                dead_letter_file, e
+            );
+            Vec::new()
+        }),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn save_dead_letters(dead_letters: &[DeadLetterEntry]) {
+    let dead_letter_file = match DEAD_LETTER_FILE.as_ref() {
+        Some(path) => path,
+        None => return,
+    };
+
+    match serde_json::to_string(dead_letters) {
+        Ok(contents) => {
+            if let Err(e) = fs::write(dead_letter_file, contents) {
+                eprintln!(
+                    "Failed to write dead-letter file {}: {}",
+                    dead_letter_file, e
+                );
+            }
+        }
+        Err(e) => eprintln!("Error serializing dead-letter list: {:?}", e),
+    }
+}
+
+/// Removes entries older than `DEAD_LETTER_TTL_SECS`, then the oldest entries beyond
+/// `DEAD_LETTER_MAX_ENTRIES`. Returns whether anything was removed.
+fn prune(dead_letters: &mut Vec<DeadLetterEntry>) -> bool {
+    let len = dead_letters.len();
+
+    if *DEAD_LETTER_TTL_SECS > 0 {
+        let now = Utc::now().timestamp();
+        dead_letters.retain(|entry| now - entry.failed_at <= *DEAD_LETTER_TTL_SECS);
+    }
+    if dead_letters.len() > *DEAD_LETTER_MAX_ENTRIES {
+        let excess = dead_letters.len() - *DEAD_LETTER_MAX_ENTRIES;
+        dead_letters.drain(..excess);
+    }
+
+    dead_letters.len() != len
+}
+
+/// Records a task that failed permanently, along with its inputs and final error, so operators can
+/// investigate and resubmit it.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+/// * `source_cid` - The CID of the source, or the comma-joined CIDs of joined sources.
+/// * `media_formats` - The media formats JSON the task was submitted with.
+/// * `is_encrypted` - Whether the task requested encrypted outputs.
+/// * `is_gpu` - Whether the task requested GPU transcoding.
+/// * `error` - The error the task failed with.
+/// * `error_code` - The category of the error.
+///
+pub fn record_failed_task(
+    task_id: &str,
+    source_cid: &str,
+    media_formats: &str,
+    is_encrypted: bool,
+    is_gpu: bool,
+    error: &str,
+    error_code: TranscodeErrorCode,
+) {
+    let mut dead_letters = DEAD_LETTERS.lock().unwrap();
+
+    dead_letters.push(DeadLetterEntry {
+        task_id: task_id.to_string(),
+        source_cid: source_cid.to_string(),
+        media_formats: media_formats.to_string(),
+        is_encrypted,
+        is_gpu,
+        error: error.to_string(),
+        error_code: Some(error_code),
+        failed_at: Utc::now().timestamp(),
+    });
+    prune(&mut dead_letters);
+
+    save_dead_letters(&dead_letters);
+}
+
+/// Returns the recorded permanently failed tasks, oldest first.
+pub fn failed_tasks() -> Vec<DeadLetterEntry> {
+    let mut dead_letters = DEAD_LETTERS.lock().unwrap();
+
+    if prune(&mut dead_letters) {
+        save_dead_letters(&dead_letters);
+    }
+
+    dead_letters.clone()
+}
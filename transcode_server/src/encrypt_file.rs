@@ -1,10 +1,43 @@
-use anyhow::{anyhow, Result};
+use anyhow::anyhow;
 use chacha20poly1305::{
     aead::{generic_array::GenericArray, Aead, KeyInit, OsRng},
     XChaCha20Poly1305, XNonce,
 };
 use std::fs::File;
-use std::io::{BufReader, Cursor, Read, Write};
+use std::io::{BufReader, Read, Write};
+
+/// Plaintext size of each chunk in the S5 encrypted file format.
+pub const ENCRYPTION_CHUNK_SIZE: usize = 262144;
+/// `ENCRYPTION_CHUNK_SIZE` as a power of two, as stored in the encrypted CID.
+pub const CHUNK_SIZE_AS_POWER_OF_2: u8 = 18;
+/// Size of the Poly1305 authentication tag appended to each encrypted chunk.
+pub const ENCRYPTION_TAG_SIZE: usize = 16;
+
+/// Reads from `reader` until `buffer` is full or the end of input is reached, so that every chunk
+/// except the last is exactly `buffer.len()` bytes regardless of how the reader splits its reads.
+///
+/// # Returns
+/// The number of bytes read, which is less than `buffer.len()` only for the last chunk.
+///
+fn read_chunk<R: Read>(reader: &mut R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(count) => filled += count,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Builds the XChaCha20 nonce for a chunk: the little-endian chunk index zero-extended to 24 bytes.
+fn chunk_nonce(chunk_index: u32) -> XNonce {
+    let mut nonce = XNonce::default();
+    nonce[..4].copy_from_slice(&chunk_index.to_le_bytes());
+    nonce
+}
 
 pub fn encrypt_file_xchacha20(
     input_file_path: String,
@@ -16,54 +49,48 @@ pub fn encrypt_file_xchacha20(
 
     let output = File::create(output_file_path)?;
 
-    let res = encrypt_file_xchacha20_internal(reader, output, padding);
-
-    Ok(res.unwrap())
+    encrypt_file_xchacha20_internal(reader, output, padding)
 }
 
+/// Encrypts the input in `ENCRYPTION_CHUNK_SIZE` chunks, each followed by its authentication tag,
+/// as S5 expects. `padding` zero bytes are appended to the last chunk before it is encrypted.
+///
 fn encrypt_file_xchacha20_internal<R: Read>(
     mut reader: R,
     mut output_file: File,
     padding: usize,
 ) -> anyhow::Result<Vec<u8>> {
-    //let key = GenericArray::from_slice(&[0u8; 32]);
     let key = XChaCha20Poly1305::generate_key(&mut OsRng);
     let cipher = XChaCha20Poly1305::new(&key);
 
     let mut chunk_index: u32 = 0;
 
-    let chunk_size = 262144;
-
-    let mut buffer = [0u8; 262144];
+    let mut buffer = vec![0u8; ENCRYPTION_CHUNK_SIZE];
 
     loop {
-        let count = reader.read(&mut buffer)?;
+        let count = read_chunk(&mut reader, &mut buffer)?;
         if count == 0 {
             break;
         }
 
-        let length = if count < chunk_size {
-            count + padding
-        } else {
-            count
-        };
-
-        let mut nonce = XNonce::default();
-
-        let mut foo = [0u8; 24];
-        for (place, data) in foo.iter_mut().zip(chunk_index.to_le_bytes().iter()) {
-            *place = *data
+        let mut chunk = buffer[..count].to_vec();
+        if count < ENCRYPTION_CHUNK_SIZE {
+            chunk.resize(count + padding, 0);
         }
 
-        nonce.copy_from_slice(&foo);
+        let ciphertext = cipher
+            .encrypt(&chunk_nonce(chunk_index), chunk.as_slice())
+            .map_err(|e| anyhow!("encryption error: {}", e))?;
 
-        let ciphertext = cipher.encrypt(&nonce, &buffer[..length]);
+        output_file.write_all(&ciphertext)?;
+        chunk_index += 1;
 
-        output_file.write(&ciphertext.unwrap()).unwrap();
-        chunk_index = chunk_index + 1;
+        if count < ENCRYPTION_CHUNK_SIZE {
+            break;
+        }
     }
 
-    output_file.flush().unwrap();
+    output_file.flush()?;
 
     Ok(key.to_vec())
 }
@@ -80,10 +107,7 @@ pub fn decrypt_file_xchacha20(
 
     let output = File::create(output_file_path)?;
 
-    println!("let res = decrypt_file_xchacha20_internal(reader, output, key, padding, last_chunk_index);");
-    let res = decrypt_file_xchacha20_internal(reader, output, key, padding, last_chunk_index);
-
-    Ok(res.unwrap())
+    decrypt_file_xchacha20_internal(reader, output, key, padding, last_chunk_index)
 }
 
 fn decrypt_file_xchacha20_internal<R: Read>(
@@ -97,131 +121,29 @@ fn decrypt_file_xchacha20_internal<R: Read>(
 
     let mut chunk_index: u32 = 0;
 
-    let mut buffer = [0u8; 262160];
+    let mut buffer = vec![0u8; ENCRYPTION_CHUNK_SIZE + ENCRYPTION_TAG_SIZE];
 
     loop {
-        let count = reader.read(&mut buffer)?;
+        let count = read_chunk(&mut reader, &mut buffer)?;
         if count == 0 {
             break;
         }
 
-        let mut nonce = XNonce::default();
-
-        let mut foo = [0u8; 24];
-        for (place, data) in foo.iter_mut().zip(chunk_index.to_le_bytes().iter()) {
-            *place = *data
-        }
-
-        nonce.copy_from_slice(&foo);
-
-        let ciphertext = cipher.decrypt(&nonce, &buffer[..count]);
+        let plaintext = cipher
+            .decrypt(&chunk_nonce(chunk_index), &buffer[..count])
+            .map_err(|e| anyhow!("decryption error in chunk {}: {}", chunk_index, e))?;
 
         if chunk_index == last_chunk_index {
-            output_file
-                .write(&ciphertext.unwrap()[..(count - 16 - padding)])
-                .unwrap();
+            let length = plaintext.len().saturating_sub(padding);
+            output_file.write_all(&plaintext[..length])?;
         } else {
-            output_file.write(&ciphertext.unwrap()).unwrap();
-        }
-
-        chunk_index = chunk_index + 1;
-    }
-
-    output_file.flush().unwrap();
-
-    Ok(1)
-}
-
-fn decrypt_file_xchacha20_internal2<R: Read>(
-    mut reader: R,
-    mut output_file: File,
-    key: Vec<u8>,
-    padding: usize,
-    last_chunk_index: u32,
-) -> anyhow::Result<u8> {
-    println!("decrypt_file_xchacha20_internal key: {:?}", key);
-    println!("let cipher = XChaCha20Poly1305::new(GenericArray::from_slice(&key));");
-    let cipher = XChaCha20Poly1305::new(GenericArray::from_slice(&key));
-
-    println!("let mut chunk_index: u32 = 0;");
-    let mut chunk_index: u32 = 0;
-
-    println!("let mut buffer = [0u8; 262160];");
-    let mut buffer = [0u8; 262160];
-
-    println!("loop");
-    loop {
-        let count = reader.read(&mut buffer)?;
-        if count == 0 {
-            break;
-        }
-
-        println!("count: {}", &count);
-
-        println!("First 32 bytes of buffer: {}", hex::encode(&buffer[..32]));
-
-        println!("let mut nonce = XNonce::default();");
-        let mut nonce = XNonce::default();
-
-        let mut foo = [0u8; 24];
-        println!("for (place, data) in foo.iter_mut().zip(chunk_index.to_le_bytes().iter())");
-        for (place, data) in foo.iter_mut().zip(chunk_index.to_le_bytes().iter()) {
-            *place = *data
-        }
-
-        println!("nonce.copy_from_slice(&foo);");
-        nonce.copy_from_slice(&foo);
-
-        //        let ciphertext = cipher.decrypt(&nonce, &buffer[..count]);
-        let ciphertext = cipher.decrypt(&nonce, &buffer[..count]);
-        match ciphertext {
-            Ok(ciphertext) => {
-                if chunk_index == last_chunk_index {
-                    output_file
-                        .write(&ciphertext[..(count - 16 - padding)])
-                        .unwrap();
-
-                //                    output_file.write_all(&ciphertext[..(count - 16 - padding)])?;
-                } else {
-                    println!("output_file.write(&ciphertext.unwrap()).unwrap();");
-                    output_file.write(&ciphertext).unwrap();
-                    //        output_file.write_all(&ciphertext)?;
-                    println!("after output_file.write(&ciphertext.unwrap()).unwrap();");
-                }
-            }
-            Err(e) => return Err(anyhow!("encryption error: {}", e)),
+            output_file.write_all(&plaintext)?;
         }
 
-        println!("if chunk_index == last_chunk_index");
-        // if chunk_index == last_chunk_index {
-        //     output_file
-        //         .write(&ciphertext.unwrap()[..(count - 16 - padding)])
-        //         .unwrap();
-        // } else {
-        //     println!("output_file.write(&ciphertext.unwrap()).unwrap();");
-        //                output_file.write(&ciphertext.unwrap()).unwrap();
-        //     println!("after output_file.write(&ciphertext.unwrap()).unwrap();");
-        // }
-
-        // if chunk_index == last_chunk_index {
-        //     if let Err(e) =
-        //         output_file.write_all(&ciphertext.as_ref().unwrap()[..(count - 16 - padding)])
-        //     {
-        //         return Err(anyhow!("write error: {}", e));
-        //     }
-        // } else {
-        //     println!("output_file.write(&ciphertext.unwrap()).unwrap();");
-        //     if let Err(e) = output_file.write_all(ciphertext.as_ref().unwrap()) {
-        //         return Err(anyhow!("write error: {}", e));
-        //     }
-        //     println!("after output_file.write(&ciphertext.unwrap()).unwrap();");
-        // }
-
-        println!("chunk_index = chunk_index + 1;");
-        chunk_index = chunk_index + 1;
+        chunk_index += 1;
     }
 
-    output_file.flush().unwrap();
+    output_file.flush()?;
 
     Ok(1)
 }
@@ -0,0 +1,224 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use tonic::metadata::{MetadataMap, MetadataValue};
+use tonic::{Code, Status};
+
+// Metadata key of a status tagged with the error code of the failure that produced it
+const ERROR_CODE_METADATA_KEY: &str = "x-transcode-error-code";
+
+// Every error code, to parse one back from its name
+const ERROR_CODES: [TranscodeErrorCode; 13] = [
+    TranscodeErrorCode::DownloadFailed,
+    TranscodeErrorCode::DecryptFailed,
+    TranscodeErrorCode::InvalidSource,
+    TranscodeErrorCode::InvalidFormat,
+    TranscodeErrorCode::EncodeFailed,
+    TranscodeErrorCode::EncryptFailed,
+    TranscodeErrorCode::UploadFailed,
+    TranscodeErrorCode::QualityBelowTarget,
+    TranscodeErrorCode::OutputBudgetExceeded,
+    TranscodeErrorCode::Timeout,
+    TranscodeErrorCode::Cancelled,
+    TranscodeErrorCode::QueueWaitExceeded,
+    TranscodeErrorCode::DiskQuotaExceeded,
+];
+
+/// Machine-readable category of a task or rendition failure, reported as `error_code` next to the
+/// free-form `error` message so clients can react to failures without parsing messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum TranscodeErrorCode {
+    // The source could not be downloaded or assembled from its parts
+    DownloadFailed,
+    // An encrypted source could not be decrypted, or decrypted to invalid media
+    DecryptFailed,
+    // The source CID is invalid, or the source has no media ffmpeg can read
+    InvalidSource,
+    // The media formats, or a format's options, are invalid or unsupported
+    InvalidFormat,
+    // ffmpeg could not be started or failed encoding
+    EncodeFailed,
+    // A rendition could not be encrypted
+    EncryptFailed,
+    // A rendition could not be uploaded
+    UploadFailed,
+    // A rendition's VMAF score is below the format's `min_vmaf` with `vmaf_strict`
+    QualityBelowTarget,
+    // The renditions already reached the task's `max_total_output_bytes`
+    OutputBudgetExceeded,
+    // A download or encode did not finish in time
+    Timeout,
+    // The task was cancelled by an admin
+    Cancelled,
+    // The task waited in the queue longer than `MAX_QUEUE_WAIT_SECS` and was never started
+    QueueWaitExceeded,
+    // The task's source and outputs took more than `MAX_TASK_DISK_BYTES` on disk
+    DiskQuotaExceeded,
+}
+
+impl TranscodeErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TranscodeErrorCode::DownloadFailed => "DOWNLOAD_FAILED",
+            TranscodeErrorCode::DecryptFailed => "DECRYPT_FAILED",
+            TranscodeErrorCode::InvalidSource => "INVALID_SOURCE",
+            TranscodeErrorCode::InvalidFormat => "INVALID_FORMAT",
+            TranscodeErrorCode::EncodeFailed => "ENCODE_FAILED",
+            TranscodeErrorCode::EncryptFailed => "ENCRYPT_FAILED",
+            TranscodeErrorCode::UploadFailed => "UPLOAD_FAILED",
+            TranscodeErrorCode::QualityBelowTarget => "QUALITY_BELOW_TARGET",
+            TranscodeErrorCode::OutputBudgetExceeded => "OUTPUT_BUDGET_EXCEEDED",
+            TranscodeErrorCode::Timeout => "TIMEOUT",
+            TranscodeErrorCode::Cancelled => "CANCELLED",
+            TranscodeErrorCode::QueueWaitExceeded => "QUEUE_WAIT_EXCEEDED",
+            TranscodeErrorCode::DiskQuotaExceeded => "DISK_QUOTA_EXCEEDED",
+        }
+    }
+
+    /// Creates a status tagged with this error code, for a failure whose category is known where
+    /// it happens. See `classify_transcode_error`.
+    ///
+    /// # Arguments
+    /// * `code` - The gRPC code of the status.
+    /// * `message` - The error message.
+    ///
+    pub fn status(self, code: Code, message: impl Into<String>) -> Status {
+        let mut metadata = MetadataMap::new();
+        metadata.insert(ERROR_CODE_METADATA_KEY, MetadataValue::from_static(self.as_str()));
+        Status::with_metadata(code, message, metadata)
+    }
+
+    /// Creates a download error of this category that would fail the same way again.
+    pub fn error(self, message: impl Into<String>) -> CodedError {
+        CodedError {
+            code: self,
+            message: message.into(),
+            transient: false,
+        }
+    }
+}
+
+impl fmt::Display for TranscodeErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// An error returned by `download_source` or `download_and_join_sources`, with the category it
+/// was given where it happened. Errors without one, such as those converted from a message with
+/// `?`, are `DownloadFailed`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodedError {
+    pub code: TranscodeErrorCode,
+    pub message: String,
+    // Whether the task may succeed if retried, see `is_transient_code`
+    pub transient: bool,
+}
+
+impl CodedError {
+    /// Creates the error of a download that failed with `status`.
+    pub fn download(status: &Status, message: impl Into<String>) -> Self {
+        CodedError {
+            transient: is_transient_code(status.code()),
+            ..download_error_code(status).error(message)
+        }
+    }
+}
+
+impl From<String> for CodedError {
+    fn from(message: String) -> Self {
+        TranscodeErrorCode::DownloadFailed.error(message)
+    }
+}
+
+impl fmt::Display for CodedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Returns the error code a status was tagged with by `TranscodeErrorCode::status`, if any.
+pub fn error_code_of(status: &Status) -> Option<TranscodeErrorCode> {
+    let value = status.metadata().get(ERROR_CODE_METADATA_KEY)?.to_str().ok()?;
+    ERROR_CODES.into_iter().find(|code| code.as_str() == value)
+}
+
+/// Classifies an error returned when transcoding a rendition: the error code it was tagged with
+/// where it happened, otherwise one derived from its gRPC code.
+///
+/// # Arguments
+/// * `status` - The error status.
+///
+pub fn classify_transcode_error(status: &Status) -> TranscodeErrorCode {
+    error_code_of(status).unwrap_or(match status.code() {
+        Code::Cancelled => TranscodeErrorCode::Cancelled,
+        Code::InvalidArgument | Code::Unimplemented => TranscodeErrorCode::InvalidFormat,
+        Code::DeadlineExceeded => TranscodeErrorCode::Timeout,
+        _ => TranscodeErrorCode::EncodeFailed,
+    })
+}
+
+/// Returns whether a failure with the gRPC `code` is likely transient: a network failure or
+/// server error, reported as `Unavailable`, or a timeout. Anything else, such as an invalid
+/// request, a rejected or missing source or a failed encode, would fail the same way again.
+pub fn is_transient_code(code: Code) -> bool {
+    matches!(code, Code::Unavailable | Code::DeadlineExceeded)
+}
+
+/// Returns the error code of a failed download that returned `status`.
+pub fn download_error_code(status: &Status) -> TranscodeErrorCode {
+    error_code_of(status).unwrap_or(match status.code() {
+        Code::DeadlineExceeded => TranscodeErrorCode::Timeout,
+        _ => TranscodeErrorCode::DownloadFailed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_transcode_error() {
+        let status = TranscodeErrorCode::UploadFailed.status(Code::Internal, "Streamed upload failed");
+        assert_eq!(error_code_of(&status), Some(TranscodeErrorCode::UploadFailed));
+        assert_eq!(classify_transcode_error(&status), TranscodeErrorCode::UploadFailed);
+
+        // Untagged statuses are classified by their gRPC code, whatever their message says
+        let status = Status::new(Code::Internal, "Failed to upload");
+        assert_eq!(error_code_of(&status), None);
+        assert_eq!(classify_transcode_error(&status), TranscodeErrorCode::EncodeFailed);
+        assert_eq!(
+            classify_transcode_error(&Status::deadline_exceeded("ffmpeg")),
+            TranscodeErrorCode::Timeout
+        );
+
+        for code in ERROR_CODES {
+            assert_eq!(error_code_of(&code.status(Code::Unknown, "")), Some(code));
+        }
+    }
+
+    #[test]
+    fn test_transient_failures() {
+        // Network failures, server errors and timeouts are retried
+        let error = CodedError::download(&Status::unavailable("502 Bad Gateway"), "Failed");
+        assert!(error.transient);
+        assert_eq!(error.code, TranscodeErrorCode::DownloadFailed);
+        let error = CodedError::download(&Status::deadline_exceeded("timed out"), "Failed");
+        assert!(error.transient);
+        assert_eq!(error.code, TranscodeErrorCode::Timeout);
+
+        // Rejected and missing sources, invalid input and failed encodes are not
+        for status in [
+            Status::permission_denied("internal address"),
+            Status::not_found("404 Not Found"),
+            Status::internal("ffmpeg exited with 1"),
+            Status::invalid_argument("invalid format"),
+            TranscodeErrorCode::DiskQuotaExceeded.status(Code::ResourceExhausted, "quota"),
+        ] {
+            assert!(!is_transient_code(status.code()), "{:?}", status);
+            assert!(!CodedError::download(&status, "Failed").transient);
+        }
+        assert!(!TranscodeErrorCode::InvalidSource.error("Invalid source CID").transient);
+        assert!(!CodedError::from("Failed to read metadata".to_string()).transient);
+    }
+}
@@ -7,10 +7,12 @@ use dotenv::dotenv;
 struct Claims {
     sub: String,
     exp: usize,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    scope: String,
 }
 
-/// Generates a JWT token using the secret key from the environment variable
-/// `FABSTIR_TRANSCODER_SECRET_KEY` and prints the generated token.
+/// Generates a JWT token using the primary secret key, the first of `FABSTIR_TRANSCODER_SECRET_KEYS`
+/// or else `FABSTIR_TRANSCODER_SECRET_KEY`, and prints the generated token.
 fn main() {
     // Load environment variables from .env file
     dotenv().ok();
@@ -20,13 +22,18 @@ fn main() {
         println!("{}: {}", key, value);
     }
 
-    // Retrieve the secret key from the environment variable
-    let secret_key = env::var("FABSTIR_TRANSCODER_SECRET_KEY").expect("FABSTIR_TRANSCODER_SECRET_KEY must be set");
+    // Retrieve the primary secret key; the remaining keys are only accepted by the server
+    let secret_key = env::var("FABSTIR_TRANSCODER_SECRET_KEYS")
+        .ok()
+        .and_then(|keys| keys.split(',').map(|key| key.trim().to_string()).find(|key| !key.is_empty()))
+        .or_else(|| env::var("FABSTIR_TRANSCODER_SECRET_KEY").ok())
+        .expect("FABSTIR_TRANSCODER_SECRET_KEYS or FABSTIR_TRANSCODER_SECRET_KEY must be set");
 
     // Set the claims for the token
     let claims = Claims {
         sub: "user_id".to_string(),
         exp: 10000000000, // Set an appropriate expiration time
+        scope: env::var("TOKEN_SCOPE").unwrap_or_default(), // e.g. "admin" for admin endpoints
     };
 
     // Encode the token
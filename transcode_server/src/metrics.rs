@@ -0,0 +1,144 @@
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+// BTreeMap<metric name with labels, e.g. `source_downloads_total{origin="s5_portal"}`, count>
+static COUNTERS: Lazy<Mutex<BTreeMap<String, u64>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+// Upper bounds of the histogram buckets, in seconds, from a short audio encode to a long 4K one
+const HISTOGRAM_BUCKETS: [f64; 11] = [
+    1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0, 7200.0,
+];
+
+struct Histogram {
+    labels: Vec<(String, String)>,
+    // Count of observations per bucket of `HISTOGRAM_BUCKETS`, not cumulative
+    bucket_counts: [u64; HISTOGRAM_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+// BTreeMap<metric name with labels, histogram>
+static HISTOGRAMS: Lazy<Mutex<BTreeMap<String, Histogram>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// Formats a metric name and its labels as a Prometheus series, e.g. `name{key="value"}`.
+fn series(name: &str, labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return name.to_string();
+    }
+
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| format!("{}=\"{}\"", key, value.replace('"', "\\\"")))
+        .collect();
+    format!("{}{{{}}}", name, labels.join(","))
+}
+
+/// Increments a counter by one.
+///
+/// # Arguments
+/// * `name` - The metric name, e.g. `source_downloads_total`.
+/// * `labels` - Label names and values distinguishing the series.
+///
+pub fn increment_counter(name: &str, labels: &[(&str, &str)]) {
+    let mut counters = COUNTERS.lock().unwrap();
+    *counters.entry(series(name, labels)).or_insert(0) += 1;
+}
+
+/// Records an observation, such as a duration in seconds, in a histogram.
+///
+/// # Arguments
+/// * `name` - The metric name, e.g. `encode_duration_seconds`.
+/// * `labels` - Label names and values distinguishing the series.
+/// * `value` - The observed value.
+///
+pub fn observe_histogram(name: &str, labels: &[(&str, &str)], value: f64) {
+    let mut histograms = HISTOGRAMS.lock().unwrap();
+    let histogram = histograms
+        .entry(series(name, labels))
+        .or_insert_with(|| Histogram {
+            labels: labels
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            bucket_counts: [0; HISTOGRAM_BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        });
+
+    if let Some(bucket) = HISTOGRAM_BUCKETS.iter().position(|bound| value <= *bound) {
+        histogram.bucket_counts[bucket] += 1;
+    }
+    histogram.sum += value;
+    histogram.count += 1;
+}
+
+/// Renders a histogram as cumulative `_bucket` series plus `_sum` and `_count`.
+fn render_histogram(output: &mut String, name: &str, histogram: &Histogram) {
+    let labels: Vec<(&str, &str)> = histogram
+        .labels
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect();
+
+    let mut cumulative = 0;
+    for (bound, bucket_count) in HISTOGRAM_BUCKETS.iter().zip(histogram.bucket_counts) {
+        cumulative += bucket_count;
+        let bound = bound.to_string();
+        let mut bucket_labels = labels.clone();
+        bucket_labels.push(("le", bound.as_str()));
+        output.push_str(&format!(
+            "{} {}\n",
+            series(&format!("{}_bucket", name), &bucket_labels),
+            cumulative
+        ));
+    }
+    let mut bucket_labels = labels.clone();
+    bucket_labels.push(("le", "+Inf"));
+    output.push_str(&format!(
+        "{} {}\n",
+        series(&format!("{}_bucket", name), &bucket_labels),
+        histogram.count
+    ));
+    output.push_str(&format!(
+        "{} {}\n",
+        series(&format!("{}_sum", name), &labels),
+        histogram.sum
+    ));
+    output.push_str(&format!(
+        "{} {}\n",
+        series(&format!("{}_count", name), &labels),
+        histogram.count
+    ));
+}
+
+/// Renders all counters and histograms in the Prometheus text exposition format for the
+/// `/metrics` endpoint.
+pub fn render() -> String {
+    let counters = COUNTERS.lock().unwrap();
+
+    let mut output = String::new();
+    let mut last_name = "";
+    for (series, value) in counters.iter() {
+        let name = series.split('{').next().unwrap_or(series);
+        if name != last_name {
+            output.push_str(&format!("# TYPE {} counter\n", name));
+            last_name = name;
+        }
+        output.push_str(&format!("{} {}\n", series, value));
+    }
+
+    let histograms = HISTOGRAMS.lock().unwrap();
+    let mut last_name = "";
+    for (series, histogram) in histograms.iter() {
+        let name = series.split('{').next().unwrap_or(series);
+        if name != last_name {
+            output.push_str(&format!("# TYPE {} histogram\n", name));
+            last_name = name;
+        }
+        render_histogram(&mut output, name, histogram);
+    }
+
+    output
+}
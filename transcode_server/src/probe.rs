@@ -0,0 +1,350 @@
+use crate::config::var;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime};
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ProbeStream {
+    pub index: u32,
+    pub codec_type: Option<String>,
+    pub codec_name: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub bit_rate: Option<String>,
+    pub r_frame_rate: Option<String>,
+    // Missing from probes cached before it was read
+    #[serde(default)]
+    pub avg_frame_rate: Option<String>,
+    pub pix_fmt: Option<String>,
+    pub sample_rate: Option<String>,
+    pub channels: Option<u32>,
+    #[serde(default)]
+    pub side_data_list: Vec<Value>,
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+    #[serde(default)]
+    pub disposition: HashMap<String, i64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ProbeFormat {
+    pub format_name: Option<String>,
+    pub duration: Option<String>,
+    pub size: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct SourceProbe {
+    #[serde(default)]
+    pub streams: Vec<ProbeStream>,
+    #[serde(default)]
+    pub format: ProbeFormat,
+}
+
+impl ProbeStream {
+    /// Returns the clockwise rotation in degrees (0, 90, 180 or 270) that players apply to this
+    /// stream when displaying it, as stored by phones that record in portrait. Read from the
+    /// display matrix side data, falling back to the legacy `rotate` tag.
+    pub fn rotation(&self) -> u32 {
+        // The display matrix holds the counter-clockwise angle, the `rotate` tag the clockwise one
+        let degrees = self
+            .side_data_list
+            .iter()
+            .find_map(|side_data| side_data.get("rotation").and_then(Value::as_f64))
+            .map(|rotation| -rotation)
+            .or_else(|| self.tags.get("rotate").and_then(|r| r.parse::<f64>().ok()))
+            .unwrap_or(0.0);
+
+        ((degrees / 90.0).round() as i64 * 90).rem_euclid(360) as u32
+    }
+
+    /// Returns whether the stream is a still image attached to the file, such as cover art or a
+    /// thumbnail, rather than a video track.
+    pub fn is_attached_picture(&self) -> bool {
+        self.disposition
+            .get("attached_pic")
+            .is_some_and(|v| *v != 0)
+    }
+
+    /// Returns the stream's average frame rate in frames per second, parsed from `avg_frame_rate`,
+    /// which ffprobe gives as a fraction such as "30000/1001" or "25/1". `r_frame_rate`, the lowest
+    /// rate all timestamps can be represented in, is only used when the average is unknown, as it
+    /// is far above the real rate of many variable frame rate sources.
+    pub fn frame_rate(&self) -> Option<f64> {
+        self.avg_frame_rate
+            .as_deref()
+            .and_then(parse_frame_rate)
+            .or_else(|| self.r_frame_rate.as_deref().and_then(parse_frame_rate))
+    }
+}
+
+/// Parses a frame rate given by ffprobe as a fraction, returning `None` for the "0/0" of an unknown
+/// rate.
+fn parse_frame_rate(frame_rate: &str) -> Option<f64> {
+    let (numerator, denominator) = match frame_rate.split_once('/') {
+        Some((numerator, denominator)) => (numerator, denominator),
+        None => (frame_rate, "1"),
+    };
+    let numerator = numerator.trim().parse::<f64>().ok()?;
+    let denominator = denominator.trim().parse::<f64>().ok()?;
+
+    Some(numerator / denominator).filter(|rate| rate.is_finite() && *rate > 0.0)
+}
+
+impl ProbeFormat {
+    /// Returns the duration of the file in seconds, if ffprobe could read it.
+    pub fn duration_secs(&self) -> Option<f64> {
+        self.duration.as_deref()?.trim().parse::<f64>().ok()
+    }
+
+    /// Returns whether a file extension names the container ffprobe detected, e.g. "mkv" matches
+    /// "matroska,webm" and "mp4" matches "mov,mp4,m4a,3gp,3g2,mj2".
+    ///
+    /// # Arguments
+    /// * `ext` - The file extension without its dot.
+    ///
+    pub fn matches_extension(&self, ext: &str) -> bool {
+        let ext = ext.to_ascii_lowercase();
+        let name = match ext.as_str() {
+            "mkv" | "mka" => "matroska",
+            "m4v" => "mp4",
+            "ts" | "m2ts" | "mts" => "mpegts",
+            "mpg" | "mpeg" => "mpeg",
+            "oga" | "opus" => "ogg",
+            "m3u8" => "hls",
+            _ => ext.as_str(),
+        };
+        self.format_name.as_deref().is_some_and(|format_name| {
+            format_name.split(',').any(|n| n == name)
+        })
+    }
+}
+
+impl SourceProbe {
+    /// Returns the streams of the given `codec_type` ("video", "audio", "subtitle", ...) in the
+    /// order ffmpeg numbers them for stream specifiers such as `0:a:1`.
+    ///
+    /// # Arguments
+    /// * `codec_type` - The type of stream to select.
+    ///
+    pub fn streams_of_type(&self, codec_type: &str) -> Vec<&ProbeStream> {
+        self.streams
+            .iter()
+            .filter(|stream| stream.codec_type.as_deref() == Some(codec_type))
+            .collect()
+    }
+
+    /// Returns the index among the video streams, as used in `0:v:N`, of the primary video
+    /// stream: the one with the most pixels, then the highest bitrate, ignoring attached pictures
+    /// unless the source has nothing else. The first stream wins a tie.
+    pub fn primary_video_stream_index(&self) -> Option<usize> {
+        let video_streams = self.streams_of_type("video");
+        let has_video_track = video_streams.iter().any(|s| !s.is_attached_picture());
+        video_streams
+            .iter()
+            .enumerate()
+            .filter(|(_, stream)| !has_video_track || !stream.is_attached_picture())
+            .map(|(index, stream)| {
+                let pixels = stream.width.unwrap_or(0) as u64 * stream.height.unwrap_or(0) as u64;
+                let bit_rate = stream
+                    .bit_rate
+                    .as_deref()
+                    .and_then(|bit_rate| bit_rate.parse::<u64>().ok())
+                    .unwrap_or(0);
+                (index, (pixels, bit_rate))
+            })
+            .fold(
+                None,
+                |primary: Option<(usize, (u64, u64))>, candidate| match primary {
+                    Some(primary) if primary.1 >= candidate.1 => Some(primary),
+                    _ => Some(candidate),
+                },
+            )
+            .map(|(index, _)| index)
+    }
+}
+
+struct ProbeCacheEntry {
+    probe: SourceProbe,
+    file_size: u64,
+    modified: Option<SystemTime>,
+    created_at: Instant,
+    last_used: Instant,
+}
+
+// HashMap<source CID (the downloaded file's name), probe result>
+static PROBE_CACHE: Lazy<Mutex<HashMap<String, ProbeCacheEntry>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+static PROBE_CACHE_MAX_ENTRIES: Lazy<usize> = Lazy::new(|| {
+    var("PROBE_CACHE_MAX_ENTRIES")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(256)
+});
+
+static PROBE_CACHE_TTL_SECS: Lazy<u64> = Lazy::new(|| {
+    var("PROBE_CACHE_TTL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(3600)
+});
+
+/// Checks that a probed source looks transcodable: it must have at least one video or audio stream
+/// and a positive duration. Catches empty, truncated or corrupt files before ffmpeg is launched.
+///
+/// # Arguments
+/// * `source_probe`: The probe result of the source.
+///
+/// # Returns:
+/// `Result<(), String>` - An error message describing why the source was rejected.
+///
+pub fn check_source_playable(source_probe: &SourceProbe) -> Result<(), String> {
+    let has_media_streams = !source_probe.streams_of_type("video").is_empty()
+        || !source_probe.streams_of_type("audio").is_empty();
+    if !has_media_streams {
+        return Err("Source appears corrupt or empty: no video or audio streams".to_string());
+    }
+
+    let duration = source_probe
+        .format
+        .duration
+        .as_deref()
+        .and_then(|duration| duration.parse::<f64>().ok());
+    match duration {
+        Some(duration) if duration > 0.0 => Ok(()),
+        Some(_) => Err("Source appears corrupt or empty: zero duration".to_string()),
+        None => Err("Source appears corrupt or empty: unknown duration".to_string()),
+    }
+}
+
+/// Probes a media file, serving the result from an in-memory LRU cache when the same source was
+/// probed within `PROBE_CACHE_TTL_SECS`. Sources are downloaded to a file named after their CID,
+/// so entries are keyed by file name. An entry is discarded if the file's size or modification time
+/// has changed since it was probed. Setting `PROBE_CACHE_MAX_ENTRIES` to 0 disables the cache.
+///
+/// # Arguments
+/// * `file_path`: Path to the media file.
+///
+/// # Returns:
+/// `Result<SourceProbe, String>` - The probe result or error message.
+///
+pub fn probe_source(file_path: &str) -> Result<SourceProbe, String> {
+    if *PROBE_CACHE_MAX_ENTRIES == 0 {
+        return run_ffprobe(file_path);
+    }
+
+    let key = Path::new(file_path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| file_path.to_string());
+    let metadata = fs::metadata(file_path).ok();
+    let file_size = metadata.as_ref().map_or(0, |m| m.len());
+    let modified = metadata.as_ref().and_then(|m| m.modified().ok());
+
+    {
+        let mut cache = PROBE_CACHE.lock().unwrap();
+        if let Some(entry) = cache.get_mut(&key) {
+            if entry.created_at.elapsed().as_secs() < *PROBE_CACHE_TTL_SECS
+                && entry.file_size == file_size
+                && entry.modified == modified
+            {
+                entry.last_used = Instant::now();
+                return Ok(entry.probe.clone());
+            }
+            cache.remove(&key);
+        }
+    }
+
+    let probe = run_ffprobe(file_path)?;
+
+    let mut cache = PROBE_CACHE.lock().unwrap();
+    let now = Instant::now();
+    cache.insert(
+        key,
+        ProbeCacheEntry {
+            probe: probe.clone(),
+            file_size,
+            modified,
+            created_at: now,
+            last_used: now,
+        },
+    );
+
+    while cache.len() > *PROBE_CACHE_MAX_ENTRIES {
+        let least_recently_used = cache
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(key, _)| key.clone());
+
+        match least_recently_used {
+            Some(key) => {
+                cache.remove(&key);
+            }
+            None => break,
+        }
+    }
+
+    Ok(probe)
+}
+
+/// Probes a media file with `ffprobe`, returning its streams and container format.
+///
+/// # Arguments
+/// * `file_path`: Path to the media file.
+///
+/// # Returns:
+/// `Result<SourceProbe, String>` - The probe result or error message.
+///
+fn run_ffprobe(file_path: &str) -> Result<SourceProbe, String> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-print_format",
+            "json",
+            "-show_format",
+            "-show_streams",
+            file_path,
+        ])
+        .output()
+        .map_err(|e| format!("Failed to execute ffprobe: {}", e))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "ffprobe failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    serde_json::from_slice::<SourceProbe>(&output.stdout)
+        .map_err(|e| format!("Failed to parse ffprobe output: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_rate_prefers_the_average_rate() {
+        let stream = ProbeStream {
+            r_frame_rate: Some("120/1".to_string()),
+            avg_frame_rate: Some("30000/1001".to_string()),
+            ..Default::default()
+        };
+        assert!((stream.frame_rate().unwrap() - 29.97).abs() < 0.01);
+
+        let stream = ProbeStream {
+            r_frame_rate: Some("25/1".to_string()),
+            avg_frame_rate: Some("0/0".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(stream.frame_rate(), Some(25.0));
+    }
+}
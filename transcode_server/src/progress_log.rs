@@ -0,0 +1,145 @@
+use crate::shared;
+use chrono::Utc;
+use crate::config::var;
+use once_cell::sync::Lazy;
+use sanitize_filename::sanitize;
+use serde_json::json;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+
+// Directory the progress logs of tasks with `log_progress` are written to
+static PROGRESS_LOG_DIR: Lazy<String> = Lazy::new(|| {
+    let dir = var("PROGRESS_LOG_DIR")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| "./progress_logs/".to_string());
+    if dir.ends_with('/') {
+        dir
+    } else {
+        format!("{}/", dir)
+    }
+});
+
+// Seconds between the progress snapshots of a task with `log_progress`
+static PROGRESS_LOG_INTERVAL_SECS: Lazy<u64> = Lazy::new(|| {
+    var("PROGRESS_LOG_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(5)
+});
+
+/// Returns the path of a task's progress log.
+///
+/// # Arguments
+/// * `task_id` - The identifier of the task.
+///
+pub fn progress_log_path(task_id: &str) -> String {
+    format!("{}{}.jsonl", *PROGRESS_LOG_DIR, sanitize(task_id))
+}
+
+/// Deletes the progress logs left from before a restart. Their tasks are forgotten when the server
+/// restarts, so the logs could not be fetched or deleted with their tasks.
+pub fn remove_stale_logs() {
+    let entries = match fs::read_dir(&*PROGRESS_LOG_DIR) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if path.extension().is_some_and(|extension| extension == "jsonl") {
+            if let Err(e) = fs::remove_file(&path) {
+                eprintln!("Failed to delete progress log {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Takes a snapshot of a task's progress as one line of JSON: the Unix time in milliseconds, the
+/// overall progress and the progress of each format.
+///
+/// # Arguments
+/// * `task_id` - The identifier of the task.
+///
+fn snapshot(task_id: &str) -> String {
+    json!({
+        "timestamp": Utc::now().timestamp_millis(),
+        "progress": shared::calculate_overall_progress(task_id),
+        "formats": shared::per_format_progress(task_id),
+    })
+    .to_string()
+}
+
+/// Appends a snapshot taken with `snapshot` to a task's progress log.
+///
+/// # Arguments
+/// * `task_id` - The identifier of the task.
+/// * `snapshot` - The line of JSON to append.
+///
+async fn append_snapshot(task_id: &str, snapshot: String) -> std::io::Result<()> {
+    tokio::fs::create_dir_all(&*PROGRESS_LOG_DIR).await?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(progress_log_path(task_id))
+        .await?;
+    file.write_all(format!("{}\n", snapshot).as_bytes()).await
+}
+
+/// Logs the progress of a task every `PROGRESS_LOG_INTERVAL_SECS` for as long as it is held, and
+/// once more when it is dropped, so the last snapshot shows how the task finished.
+pub struct ProgressLogger {
+    task_id: String,
+    stopped: Arc<AtomicBool>,
+}
+
+/// Starts logging the progress of a task that has `log_progress` set. A retried task appends to the
+/// log of its earlier attempts.
+///
+/// # Arguments
+/// * `task_id` - The identifier of the task.
+///
+pub fn start(task_id: &str) -> ProgressLogger {
+    let stopped = Arc::new(AtomicBool::new(false));
+    let logger = ProgressLogger {
+        task_id: task_id.to_string(),
+        stopped: Arc::clone(&stopped),
+    };
+
+    let task_id = task_id.to_string();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(*PROGRESS_LOG_INTERVAL_SECS));
+        loop {
+            interval.tick().await;
+            if stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            if let Err(e) = append_snapshot(&task_id, snapshot(&task_id)).await {
+                eprintln!("Failed to log progress of task {}: {}", task_id, e);
+                break;
+            }
+        }
+    });
+
+    logger
+}
+
+impl Drop for ProgressLogger {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+
+        // The snapshot is taken now, as the task finishes, and written without blocking the runtime
+        let task_id = self.task_id.clone();
+        let snapshot = snapshot(&task_id);
+        tokio::spawn(async move {
+            if let Err(e) = append_snapshot(&task_id, snapshot).await {
+                eprintln!("Failed to log progress of task {}: {}", task_id, e);
+            }
+        });
+    }
+}
@@ -0,0 +1,62 @@
+use crate::config::var;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// HashMap<JWT subject, number of queued or running tasks>
+static ACTIVE_TASKS_PER_SUBJECT: Lazy<Mutex<HashMap<String, usize>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+static MAX_TASKS_PER_SUBJECT: Lazy<usize> = Lazy::new(|| {
+    var("MAX_TASKS_PER_SUBJECT")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(0) // 0 means unlimited
+});
+
+/// Reserves a task slot for `subject`, failing if the subject already has
+/// `MAX_TASKS_PER_SUBJECT` tasks queued or running. A successful reservation must be released with
+/// `release_task_slot`, normally by dropping a `TaskSlotGuard` once the task completes.
+///
+/// # Arguments
+/// * `subject` - The `sub` claim of the JWT that submitted the task.
+///
+/// # Returns
+/// `true` if a slot was reserved, `false` if the subject is at its cap.
+///
+pub fn try_acquire_task_slot(subject: &str) -> bool {
+    let mut active_tasks = ACTIVE_TASKS_PER_SUBJECT.lock().unwrap();
+    let count = active_tasks.entry(subject.to_string()).or_insert(0);
+
+    if *MAX_TASKS_PER_SUBJECT > 0 && *count >= *MAX_TASKS_PER_SUBJECT {
+        return false;
+    }
+
+    *count += 1;
+    true
+}
+
+/// Releases a task slot reserved with `try_acquire_task_slot`.
+///
+/// # Arguments
+/// * `subject` - The `sub` claim of the JWT that submitted the task.
+///
+pub fn release_task_slot(subject: &str) {
+    let mut active_tasks = ACTIVE_TASKS_PER_SUBJECT.lock().unwrap();
+
+    if let Some(count) = active_tasks.get_mut(subject) {
+        *count = count.saturating_sub(1);
+        if *count == 0 {
+            active_tasks.remove(subject);
+        }
+    }
+}
+
+/// Releases the task slot of a subject when dropped, so the slot is freed however a task finishes.
+pub struct TaskSlotGuard(pub String);
+
+impl Drop for TaskSlotGuard {
+    fn drop(&mut self) {
+        release_task_slot(&self.0);
+    }
+}
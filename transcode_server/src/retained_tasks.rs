@@ -0,0 +1,109 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// The renditions JSON of finished tasks, keeping at most `capacity` of them and evicting the
+/// least recently accessed when more are stored. Storing or reading a task's renditions counts as
+/// an access.
+#[derive(Debug, Default)]
+pub struct RetainedTasks {
+    // Most tasks kept, 0 for no limit
+    capacity: usize,
+    // HashMap<task_id, (renditions JSON, access number of its last access)>
+    entries: HashMap<String, (String, u64)>,
+    // BTreeMap<access number, task_id>, least recently accessed first
+    order: BTreeMap<u64, String>,
+    // Number given to the next access
+    next_access: u64,
+}
+
+impl RetainedTasks {
+    /// Creates an empty store keeping at most `capacity` tasks, or any number if 0.
+    pub fn new(capacity: usize) -> Self {
+        RetainedTasks {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    fn touch(&mut self, task_id: &str) {
+        if let Some((_, access)) = self.entries.get_mut(task_id) {
+            self.order.remove(access);
+            *access = self.next_access;
+            self.order.insert(self.next_access, task_id.to_string());
+            self.next_access += 1;
+        }
+    }
+
+    /// Stores a task's renditions JSON, replacing any stored before, such as by an earlier attempt.
+    ///
+    /// # Returns
+    /// The IDs of the tasks evicted to stay within the capacity, least recently accessed first.
+    ///
+    pub fn insert(&mut self, task_id: String, renditions_json: String) -> Vec<String> {
+        if let Some((_, access)) = self.entries.remove(&task_id) {
+            self.order.remove(&access);
+        }
+        self.entries
+            .insert(task_id.clone(), (renditions_json, self.next_access));
+        self.order.insert(self.next_access, task_id);
+        self.next_access += 1;
+
+        let mut evicted = Vec::new();
+        while self.capacity > 0 && self.entries.len() > self.capacity {
+            match self.order.pop_first() {
+                Some((_, oldest)) => {
+                    self.entries.remove(&oldest);
+                    evicted.push(oldest);
+                }
+                None => break,
+            }
+        }
+        evicted
+    }
+
+    /// Returns a task's renditions JSON, if it has finished and has not been evicted, and marks it
+    /// as the most recently accessed.
+    pub fn get(&mut self, task_id: &str) -> Option<String> {
+        self.touch(task_id);
+        self.entries.get(task_id).map(|(json, _)| json.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_evicts_the_least_recently_accessed_task() {
+        let mut tasks = RetainedTasks::new(2);
+        assert!(tasks.insert("a".to_string(), "[1]".to_string()).is_empty());
+        assert!(tasks.insert("b".to_string(), "[2]".to_string()).is_empty());
+
+        // Reading "a" makes "b" the least recently accessed
+        assert_eq!(tasks.get("a"), Some("[1]".to_string()));
+        assert_eq!(tasks.insert("c".to_string(), "[3]".to_string()), vec!["b".to_string()]);
+
+        assert_eq!(tasks.get("b"), None);
+        assert_eq!(tasks.get("a"), Some("[1]".to_string()));
+        assert_eq!(tasks.get("c"), Some("[3]".to_string()));
+    }
+
+    #[test]
+    fn insert_replaces_a_task_without_evicting() {
+        let mut tasks = RetainedTasks::new(2);
+        tasks.insert("a".to_string(), "[]".to_string());
+        tasks.insert("b".to_string(), "[]".to_string());
+
+        assert!(tasks.insert("a".to_string(), "[1]".to_string()).is_empty());
+        assert_eq!(tasks.get("a"), Some("[1]".to_string()));
+        assert_eq!(tasks.insert("c".to_string(), "[]".to_string()), vec!["b".to_string()]);
+    }
+
+    #[test]
+    fn a_capacity_of_zero_keeps_every_task() {
+        let mut tasks = RetainedTasks::new(0);
+        for index in 0..100 {
+            assert!(tasks.insert(index.to_string(), "[]".to_string()).is_empty());
+        }
+        assert_eq!(tasks.get("0"), Some("[]".to_string()));
+    }
+}
@@ -1,34 +1,384 @@
+use crate::throttle;
 use crate::utils;
 
 use anyhow::{anyhow, Result};
 use base64::{engine::general_purpose, Engine as _};
-use dotenv::var;
+use crate::config::var;
 use reqwest::multipart;
 use serde_json::Value;
-use std::env;
 use std::fs::File;
 use std::io::copy;
 use std::io::{BufReader, Read};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use std::process::Command;
 use std::result::Result::{Err, Ok};
 use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use std::{collections::HashMap, fs, path::Path};
-use tokio::io::AsyncReadExt;
-use tokio::runtime::Runtime;
 use tus_client::Client;
 
 use utils::bytes_to_base64url;
 
-pub fn download_file(url: &str, path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    // Create a new client with default configuration
-    let client = reqwest::Client::new();
+/// Returns whether `url` points at the same host and port as `configured_url`.
+fn is_same_origin(url: &reqwest::Url, configured_url: &str) -> bool {
+    match reqwest::Url::parse(configured_url) {
+        Ok(configured_url) => {
+            url.host_str().is_some()
+                && url.host_str() == configured_url.host_str()
+                && url.port_or_known_default() == configured_url.port_or_known_default()
+        }
+        Err(_) => false,
+    }
+}
+
+/// Returns the `Authorization` header value to send with a download from `url`. The
+/// `PORTAL_AUTH_TOKEN` is only attached when the URL is on one of the configured portal or gateway
+/// hosts, so the token is never leaked to arbitrary external URLs.
+///
+/// # Arguments
+/// * `url` - The URL being downloaded.
+///
+pub fn portal_auth_header(url: &str) -> Option<String> {
+    let token = var("PORTAL_AUTH_TOKEN").ok().filter(|token| !token.is_empty())?;
+    let url = reqwest::Url::parse(url).ok()?;
+
+    let is_portal = ["PORTAL_URL", "PORTAL_ENCRYPT_URL", "IPFS_GATEWAY"]
+        .iter()
+        .filter_map(|name| var(name).ok())
+        .any(|configured_url| is_same_origin(&url, &configured_url));
+
+    if is_portal {
+        Some(format!("Bearer {}", token))
+    } else {
+        None
+    }
+}
+
+/// Returns whether `ip` is in a loopback, private, link-local, shared or otherwise non-public
+/// range that a download must not reach unless explicitly allowed.
+fn is_internal_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || octets[0] == 0
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+        }
+        IpAddr::V6(ip) => {
+            let first_segment = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local, fc00::/7, and link-local, fe80::/10
+                || (first_segment & 0xfe00) == 0xfc00
+                || (first_segment & 0xffc0) == 0xfe80
+                || ip
+                    .to_ipv4_mapped()
+                    .is_some_and(|ip| is_internal_ip(&IpAddr::V4(ip)))
+        }
+    }
+}
+
+/// Returns whether `host` matches an `ALLOWED_DOWNLOAD_HOSTS` entry, either exactly or, for an
+/// entry such as "*.example.com", as a subdomain.
+fn is_allowed_host(host: &str, allowed_hosts: &str) -> bool {
+    allowed_hosts
+        .split(',')
+        .map(|allowed| allowed.trim().to_lowercase())
+        .filter(|allowed| !allowed.is_empty())
+        .any(|allowed| match allowed.strip_prefix("*.") {
+            Some(domain) => host.ends_with(&format!(".{}", domain)),
+            None => host == allowed,
+        })
+}
+
+/// Checks that a download URL may be fetched, to stop clients from making the transcoder request
+/// internal services. The configured portal and gateway hosts are always allowed. When
+/// `ALLOWED_DOWNLOAD_HOSTS` is set, other hosts must be on it; hosts neither configured nor
+/// allowlisted are also rejected if they resolve to a loopback, private or link-local address,
+/// unless `ALLOW_INTERNAL_DOWNLOADS` is on.
+///
+/// # Arguments
+/// * `url` - The URL to be downloaded.
+///
+pub fn check_download_url(url: &str) -> Result<(), String> {
+    resolve_download_url(url).map(|_| ())
+}
+
+/// Checks a download URL like `check_download_url`.
+///
+/// # Returns
+/// The public address the host resolved to if it had to be resolved for the check, so the
+/// download connects to that address rather than resolving the host again, which could then
+/// return an internal one.
+///
+fn resolve_download_url(url: &str) -> Result<Option<SocketAddr>, String> {
+    let parsed_url = reqwest::Url::parse(url).map_err(|e| format!("Invalid URL {}: {}", url, e))?;
+    if !["http", "https"].contains(&parsed_url.scheme()) {
+        return Err(format!(
+            "Downloads over {} are not allowed",
+            parsed_url.scheme()
+        ));
+    }
+    let host = parsed_url
+        .host_str()
+        .ok_or_else(|| format!("URL {} has no host", url))?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_lowercase();
+
+    let is_configured = ["PORTAL_URL", "PORTAL_ENCRYPT_URL", "IPFS_GATEWAY"]
+        .iter()
+        .filter_map(|name| var(name).ok())
+        .any(|configured_url| is_same_origin(&parsed_url, &configured_url));
+    if is_configured {
+        return Ok(None);
+    }
+
+    if let Some(allowed_hosts) = var("ALLOWED_DOWNLOAD_HOSTS")
+        .ok()
+        .filter(|hosts| !hosts.trim().is_empty())
+    {
+        if !is_allowed_host(&host, &allowed_hosts) {
+            return Err(format!("Download host {} is not allowed", host));
+        }
+        return Ok(None);
+    }
+
+    if utils::env_flag("ALLOW_INTERNAL_DOWNLOADS") {
+        return Ok(None);
+    }
+
+    let port = parsed_url.port_or_known_default().unwrap_or(80);
+    let addresses: Vec<SocketAddr> = (host.as_str(), port)
+        .to_socket_addrs()
+        .map_err(|e| format!("Failed to resolve download host {}: {}", host, e))?
+        .collect();
+    for address in &addresses {
+        if is_internal_ip(&address.ip()) {
+            return Err(format!(
+                "Download host {} resolves to internal address {}",
+                host,
+                address.ip()
+            ));
+        }
+    }
+
+    addresses
+        .first()
+        .copied()
+        .map(Some)
+        .ok_or_else(|| format!("Download host {} resolves to no address", host))
+}
+
+// Most redirects followed by a download
+const MAX_DOWNLOAD_REDIRECTS: usize = 10;
+
+/// Why `download_file` failed.
+#[derive(Debug)]
+pub enum DownloadError {
+    // The URL, or a redirect, was rejected by `check_download_url`
+    Rejected(String),
+    // The server answered with a status other than success
+    Status(reqwest::StatusCode, String),
+    // The request could not be sent or its body not be read or saved
+    Network(String),
+    // No bytes were received for the stall timeout
+    Stalled(String),
+}
+
+impl DownloadError {
+    /// Returns whether the download may succeed if tried again: network failures and server
+    /// errors, but not rejected URLs or client errors such as a missing source.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            DownloadError::Rejected(_) => false,
+            DownloadError::Status(status, _) => status.is_server_error(),
+            DownloadError::Network(_) | DownloadError::Stalled(_) => true,
+        }
+    }
+}
+
+impl std::fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DownloadError::Rejected(e) | DownloadError::Network(e) | DownloadError::Stalled(e) => {
+                f.write_str(e)
+            }
+            DownloadError::Status(status, url) => write!(f, "{} returned {}", url, status),
+        }
+    }
+}
+
+impl std::error::Error for DownloadError {}
+
+// Reads `inner` until `cancelled` is set, then fails the next read
+struct CancellableReader<'a, R> {
+    inner: R,
+    cancelled: &'a AtomicBool,
+}
+
+impl<R: Read> Read for CancellableReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.cancelled.load(Ordering::SeqCst) {
+            return Err(std::io::Error::other("download cancelled"));
+        }
+        self.inner.read(buf)
+    }
+}
+
+/// Checks `url` with `check_download_url` and builds a request for it that connects to the checked
+/// address. Plain HTTP requests are sent to that address with the original `Host`; HTTPS ones,
+/// whose certificate must match the host name, must be checked with `check_connected_address`
+/// once sent.
+///
+/// # Arguments
+/// * `client` - The client to send the request with, which must not follow redirects.
+/// * `method` - The request method.
+/// * `url` - The URL to request.
+///
+/// # Returns
+/// The request and the address it was checked against, if the host had to be resolved, or an
+/// error message if the URL is rejected.
+///
+pub(crate) fn checked_request(
+    client: &reqwest::Client,
+    method: reqwest::Method,
+    url: &reqwest::Url,
+) -> Result<(reqwest::RequestBuilder, Option<SocketAddr>), String> {
+    let checked_address = resolve_download_url(url.as_str())?;
+
+    let mut request_url = url.clone();
+    let mut host_header = None;
+    if let (Some(address), "http") = (checked_address, url.scheme()) {
+        host_header = url.host_str().map(|host| match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        });
+        let _ = request_url.set_ip_host(address.ip());
+    }
+
+    let mut request = client.request(method, request_url);
+    if let Some(host_header) = host_header {
+        request = request.header(reqwest::header::HOST, host_header);
+    }
+    Ok((request, checked_address))
+}
+
+/// Fails if a request built by `checked_request` connected to an internal address, as an HTTPS
+/// request can when its host resolves differently than when it was checked.
+pub(crate) fn check_connected_address(
+    response: &reqwest::Response,
+    url: &reqwest::Url,
+    checked_address: Option<SocketAddr>,
+) -> Result<(), String> {
+    if checked_address.is_none() {
+        return Ok(());
+    }
+    match response.remote_addr() {
+        Some(remote_address) if is_internal_ip(&remote_address.ip()) => Err(format!(
+            "Host {} connected to internal address {}",
+            url.host_str().unwrap_or_default(),
+            remote_address.ip()
+        )),
+        _ => Ok(()),
+    }
+}
 
-    // Send a GET request to the download URL
-    let mut response = client.get(url).send()?;
+/// Downloads `url` to `path`, no faster than the download rate limit. The URL and every redirect
+/// are requested with `checked_request`, and HTTPS responses are checked with
+/// `check_connected_address` before their body is read. Nothing is written to `path` unless
+/// the final response is a success, and a partly written file is removed if the download fails.
+///
+/// # Arguments
+/// * `url` - The URL to download.
+/// * `path` - Where to save the response body.
+/// * `stall_timeout` - How long to wait for the server to answer or send more of the body before
+///   failing with `DownloadError::Stalled`. Time spent throttled does not count.
+/// * `cancelled` - Stops the download at its next read once set.
+///
+pub fn download_file(
+    url: &str,
+    path: &str,
+    stall_timeout: Duration,
+    cancelled: &AtomicBool,
+) -> Result<(), DownloadError> {
+    // Redirects are followed here, so each one is checked like the original URL. The timeout
+    // bounds each wait for the server rather than the whole download.
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::RedirectPolicy::none())
+        .timeout(stall_timeout)
+        .build()
+        .map_err(|e| DownloadError::Network(format!("Failed to create HTTP client: {}", e)))?;
+
+    let mut url = reqwest::Url::parse(url)
+        .map_err(|e| DownloadError::Rejected(format!("Invalid URL {}: {}", url, e)))?;
+    let mut redirects = 0;
+    let mut response = loop {
+        let (mut request, checked_address) =
+            checked_request(&client, reqwest::Method::GET, &url).map_err(DownloadError::Rejected)?;
+        if let Some(auth_header) = portal_auth_header(url.as_str()) {
+            request = request.header(reqwest::header::AUTHORIZATION, auth_header);
+        }
+        let response = request.send().map_err(|e| {
+            let message = format!("Request to {} failed: {}", url, e);
+            if e.is_timeout() {
+                DownloadError::Stalled(message)
+            } else {
+                DownloadError::Network(message)
+            }
+        })?;
+
+        check_connected_address(&response, &url, checked_address)
+            .map_err(DownloadError::Rejected)?;
+
+        if !response.status().is_redirection() {
+            break response;
+        }
+        if redirects >= MAX_DOWNLOAD_REDIRECTS {
+            return Err(DownloadError::Rejected(format!(
+                "Download of {} redirected more than {} times",
+                url, MAX_DOWNLOAD_REDIRECTS
+            )));
+        }
+        let location = response
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| url.join(location).ok())
+            .ok_or_else(|| DownloadError::Status(response.status(), url.to_string()))?;
+        println!("Following redirect from {} to {}", url, location);
+        url = location;
+        redirects += 1;
+    };
 
-    // Save the response body to the specified file
-    let mut file = File::create(path)?;
-    copy(&mut response, &mut file)?;
+    if !response.status().is_success() {
+        return Err(DownloadError::Status(response.status(), url.to_string()));
+    }
+
+    // Save the response body to the specified file, no faster than the download rate limit
+    let mut file = File::create(path)
+        .map_err(|e| DownloadError::Network(format!("Failed to create {}: {}", path, e)))?;
+    let mut body = CancellableReader {
+        inner: throttle::throttle_download(&mut response),
+        cancelled,
+    };
+    copy(&mut body, &mut file).map_err(|e| {
+        let _ = fs::remove_file(path);
+        let message = format!("Failed to download {}: {}", url, e);
+        if e.kind() == std::io::ErrorKind::TimedOut {
+            DownloadError::Stalled(message)
+        } else {
+            DownloadError::Network(message)
+        }
+    })?;
 
     Ok(())
 }
@@ -89,7 +439,7 @@ pub async fn upload_video_s5(path: &str) -> Result<String, anyhow::Error> {
 }
 
 pub async fn upload_video_ipfs(path: &str) -> Result<String, anyhow::Error> {
-    let pinata_jwt = std::env::var("PINATA_JWT")
+    let pinata_jwt = var("PINATA_JWT")
         .map_err(|_| anyhow!("PINATA_JWT environment variable not set"))?;
 
     // Using `curl` to upload the file
@@ -133,6 +483,57 @@ pub async fn upload_video_ipfs(path: &str) -> Result<String, anyhow::Error> {
     Ok(cid)
 }
 
+/// Uploads a file to IPFS through Pinata while it is still being written, reading it from
+/// `reader` until end of stream. The request body is sent chunked as it is read, so the upload
+/// finishes shortly after the last bytes are produced. An error from `reader` aborts the upload.
+///
+/// # Arguments
+/// * `reader` - The stream of the file's contents.
+/// * `file_name` - The name the file is pinned under.
+///
+/// # Returns
+/// The IPFS CID of the uploaded file.
+///
+pub fn upload_stream_ipfs<R: Read + Send + 'static>(
+    reader: R,
+    file_name: &str,
+) -> Result<String, anyhow::Error> {
+    let pinata_jwt = var("PINATA_JWT")
+        .map_err(|_| anyhow!("PINATA_JWT environment variable not set"))?;
+
+    let part = multipart::Part::reader(reader).file_name(file_name.to_string());
+    let form = multipart::Form::new().part("file", part);
+
+    let mut response = reqwest::Client::new()
+        .post("https://api.pinata.cloud/pinning/pinFileToIPFS")
+        .bearer_auth(pinata_jwt)
+        .multipart(form)
+        .send()
+        .map_err(|e| anyhow!("Failed to stream upload to Pinata: {}", e))?;
+
+    let response_body = response
+        .text()
+        .map_err(|_| anyhow!("Failed to read Pinata response"))?;
+    if !response.status().is_success() {
+        return Err(anyhow!(
+            "Pinata upload failed with {}: {}",
+            response.status(),
+            response_body
+        ));
+    }
+
+    let response_json: Value = serde_json::from_str(&response_body)
+        .map_err(|_| anyhow!("Failed to parse JSON response from Pinata"))?;
+
+    let cid = response_json["IpfsHash"]
+        .as_str()
+        .ok_or_else(|| anyhow!("IPFS hash not found in response"))?
+        .to_string();
+    println!("Streamed upload CID: {}", cid);
+
+    Ok(cid)
+}
+
 pub async fn upload_video(
     path: &str,
     storage_network: Option<String>,
@@ -190,3 +591,51 @@ pub fn hash_to_cid(hash: &str, file_size: u64) -> Vec<u8> {
 
     bytes
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_internal_ip() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:192.168.1.1",
+        ] {
+            assert!(is_internal_ip(&ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["8.8.8.8", "100.128.0.1", "2606:4700::1111"] {
+            assert!(!is_internal_ip(&ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_check_download_url() {
+        assert!(check_download_url("http://127.0.0.1:8080/admin").is_err());
+        assert!(check_download_url("http://[::1]/").is_err());
+        assert!(check_download_url("http://169.254.169.254/latest/meta-data").is_err());
+        assert!(check_download_url("file:///etc/passwd").is_err());
+
+        assert!(is_allowed_host("cdn.example.com", "gateway.example.com, *.example.com"));
+        assert!(!is_allowed_host("example.com.evil.net", "*.example.com"));
+    }
+
+    #[test]
+    fn test_download_error_is_transient() {
+        let url = "https://example.com/source".to_string();
+        assert!(DownloadError::Network("connection reset".to_string()).is_transient());
+        assert!(DownloadError::Stalled("no data for 300s".to_string()).is_transient());
+        assert!(DownloadError::Status(reqwest::StatusCode::BAD_GATEWAY, url.clone()).is_transient());
+        assert!(!DownloadError::Status(reqwest::StatusCode::NOT_FOUND, url).is_transient());
+        assert!(!DownloadError::Rejected("internal address".to_string()).is_transient());
+    }
+}
@@ -0,0 +1,619 @@
+use openssl::symm::{Cipher, Crypter, Mode};
+use std::collections::{HashMap, HashSet};
+
+// Size of an MPEG-TS packet, and of its header
+const TS_PACKET_SIZE: usize = 188;
+const TS_HEADER_SIZE: usize = 4;
+
+// MPEG-TS stream type of H.264 video, and of H.264 video encrypted with SAMPLE-AES
+const STREAM_TYPE_H264: u8 = 0x1b;
+const STREAM_TYPE_H264_SAMPLE_AES: u8 = 0xdb;
+
+// private_data_indicator_descriptor marking an encrypted H.264 stream
+const SAMPLE_AES_H264_DESCRIPTOR: [u8; 6] = [0x0f, 0x04, b'z', b'a', b'v', b'c'];
+
+// NAL unit types of coded slices, the only NAL units SAMPLE-AES encrypts
+const NAL_TYPE_SLICE: u8 = 1;
+const NAL_TYPE_IDR_SLICE: u8 = 5;
+
+// NAL units this long or shorter are left unencrypted
+const MAX_CLEAR_NAL_SIZE: usize = 48;
+// Bytes at the start of a NAL unit, including its header, that are left unencrypted
+const CLEAR_LEADER_SIZE: usize = 32;
+// Bytes left unencrypted after each encrypted block
+const CLEAR_RUN_SIZE: usize = 144;
+const AES_BLOCK_SIZE: usize = 16;
+
+/// The fields of an MPEG-TS packet needed to rewrite it.
+struct TsPacket<'a> {
+    pid: u16,
+    payload_unit_start: bool,
+    continuity_counter: u8,
+    // The adaptation field after its length byte, empty if there is none
+    adaptation_field: &'a [u8],
+    payload: &'a [u8],
+}
+
+fn parse_packet(packet: &[u8]) -> Result<TsPacket<'_>, String> {
+    if packet.len() != TS_PACKET_SIZE || packet[0] != 0x47 {
+        return Err("the segment is not an MPEG-TS stream".to_string());
+    }
+    let adaptation_field_control = (packet[3] >> 4) & 0x03;
+    let mut payload_start = TS_HEADER_SIZE;
+    let mut adaptation_field: &[u8] = &[];
+    if adaptation_field_control & 0x02 != 0 {
+        let length = packet[4] as usize;
+        if TS_HEADER_SIZE + 1 + length > TS_PACKET_SIZE {
+            return Err("the segment has an invalid adaptation field".to_string());
+        }
+        adaptation_field = &packet[5..5 + length];
+        payload_start += 1 + length;
+    }
+    let payload = if adaptation_field_control & 0x01 != 0 {
+        &packet[payload_start..]
+    } else {
+        &[]
+    };
+
+    Ok(TsPacket {
+        pid: (u16::from(packet[1] & 0x1f) << 8) | u16::from(packet[2]),
+        payload_unit_start: packet[1] & 0x40 != 0,
+        continuity_counter: packet[3] & 0x0f,
+        adaptation_field,
+        payload,
+    })
+}
+
+/// Returns the section that starts in the payload of a packet with `payload_unit_start`, from its
+/// table ID through its CRC.
+fn packet_section(payload: &[u8]) -> Result<&[u8], String> {
+    let pointer = *payload.first().ok_or("the segment has an empty table packet")? as usize;
+    let section = payload
+        .get(1 + pointer..)
+        .filter(|section| section.len() >= 3)
+        .ok_or("the segment has a truncated table")?;
+    let section_length = ((usize::from(section[1]) & 0x0f) << 8) | usize::from(section[2]);
+    section
+        .get(..3 + section_length)
+        .filter(|_| section_length >= 4)
+        .ok_or_else(|| "the segment has a table spanning packets, which is not supported".to_string())
+}
+
+/// Computes the CRC-32 MPEG-TS tables end with.
+fn crc32_mpeg2(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffff_u32;
+    for byte in data {
+        crc ^= u32::from(*byte) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Returns the PID of the first program's PMT from a PAT section.
+fn pmt_pid(pat: &[u8]) -> Option<u16> {
+    pat.get(8..pat.len().saturating_sub(4))?
+        .chunks_exact(4)
+        .find(|program| program[0] != 0 || program[1] != 0)
+        .map(|program| (u16::from(program[2] & 0x1f) << 8) | u16::from(program[3]))
+}
+
+/// Rewrites a PMT section so its H.264 streams are signalled as encrypted with SAMPLE-AES.
+///
+/// # Returns
+/// The rewritten section and the PIDs of the H.264 streams, or an error message.
+///
+fn rewrite_pmt(pmt: &[u8]) -> Result<(Vec<u8>, Vec<u16>), String> {
+    let invalid = || "the segment has an invalid PMT".to_string();
+    let program_info_length =
+        ((usize::from(*pmt.get(10).ok_or_else(invalid)?) & 0x0f) << 8) | usize::from(pmt[11]);
+    let streams_start = 12 + program_info_length;
+    let streams_end = pmt.len() - 4;
+    if streams_start > streams_end {
+        return Err(invalid());
+    }
+
+    let mut section = pmt[..streams_start].to_vec();
+    let mut h264_pids = Vec::new();
+    let mut position = streams_start;
+    while position < streams_end {
+        let stream = pmt.get(position..position + 5).ok_or_else(invalid)?;
+        let pid = (u16::from(stream[1] & 0x1f) << 8) | u16::from(stream[2]);
+        let es_info_length = ((usize::from(stream[3]) & 0x0f) << 8) | usize::from(stream[4]);
+        let descriptors = pmt
+            .get(position + 5..position + 5 + es_info_length)
+            .ok_or_else(invalid)?;
+
+        if stream[0] == STREAM_TYPE_H264 {
+            let es_info_length = es_info_length + SAMPLE_AES_H264_DESCRIPTOR.len();
+            section.extend([
+                STREAM_TYPE_H264_SAMPLE_AES,
+                stream[1],
+                stream[2],
+                (stream[3] & 0xf0) | (es_info_length >> 8) as u8,
+                es_info_length as u8,
+            ]);
+            section.extend(descriptors);
+            section.extend(SAMPLE_AES_H264_DESCRIPTOR);
+            h264_pids.push(pid);
+        } else {
+            section.extend(stream);
+            section.extend(descriptors);
+        }
+        position += 5 + descriptors.len();
+    }
+
+    // The section length counts the bytes after it, including the CRC
+    let section_length = section.len() + 4 - 3;
+    section[1] = (section[1] & 0xf0) | (section_length >> 8) as u8;
+    section[2] = section_length as u8;
+    let crc = crc32_mpeg2(&section);
+    section.extend(crc.to_be_bytes());
+    Ok((section, h264_pids))
+}
+
+/// Returns the fields of an adaptation field without its trailing stuffing bytes, so they can be
+/// written again with different stuffing.
+fn adaptation_field_without_stuffing(adaptation_field: &[u8]) -> Vec<u8> {
+    let flags = match adaptation_field.first() {
+        Some(flags) => *flags,
+        None => return Vec::new(),
+    };
+    let mut length = 1;
+    // PCR and OPCR
+    length += 6 * usize::from(flags & 0x10 != 0) + 6 * usize::from(flags & 0x08 != 0);
+    // Splice countdown
+    length += usize::from(flags & 0x04 != 0);
+    for flag in [0x02, 0x01] {
+        // Transport private data and adaptation field extension, each prefixed with its length
+        if flags & flag != 0 {
+            length += 1 + adaptation_field.get(length).copied().unwrap_or(0) as usize;
+        }
+    }
+    adaptation_field[..length.min(adaptation_field.len())].to_vec()
+}
+
+/// Builds an MPEG-TS packet carrying as much of `payload` as fits, stuffing the adaptation field
+/// if less is left than fills the packet. The continuity counter is set later.
+///
+/// # Returns
+/// The packet and the bytes of `payload` it carries.
+///
+fn build_packet(
+    pid: u16,
+    payload_unit_start: bool,
+    adaptation_field: &[u8],
+    payload: &[u8],
+) -> ([u8; TS_PACKET_SIZE], usize) {
+    let mut packet = [0xff_u8; TS_PACKET_SIZE];
+    packet[0] = 0x47;
+    packet[1] = ((pid >> 8) as u8 & 0x1f) | if payload_unit_start { 0x40 } else { 0 };
+    packet[2] = pid as u8;
+
+    let space = TS_PACKET_SIZE - TS_HEADER_SIZE;
+    let fields_size = if adaptation_field.is_empty() {
+        0
+    } else {
+        1 + adaptation_field.len()
+    };
+    let carried = payload.len().min(space - fields_size);
+    let stuffing = space - fields_size - carried;
+
+    let mut position = TS_HEADER_SIZE;
+    if fields_size + stuffing > 0 {
+        packet[3] = 0x30;
+        if adaptation_field.is_empty() && stuffing == 1 {
+            packet[position] = 0;
+            position += 1;
+        } else {
+            let fields: &[u8] = if adaptation_field.is_empty() {
+                &[0x00]
+            } else {
+                adaptation_field
+            };
+            let length = if adaptation_field.is_empty() {
+                stuffing - 1
+            } else {
+                fields.len() + stuffing
+            };
+            packet[position] = length as u8;
+            packet[position + 1..position + 1 + fields.len()].copy_from_slice(fields);
+            position += 1 + length;
+        }
+    } else {
+        packet[3] = 0x10;
+    }
+    packet[position..position + carried].copy_from_slice(&payload[..carried]);
+    (packet, carried)
+}
+
+/// Removes the emulation prevention bytes of a NAL unit.
+fn unescape_nal(nal: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(nal.len());
+    let mut zeros = 0;
+    for &byte in nal {
+        if zeros >= 2 && byte == 0x03 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        unescaped.push(byte);
+    }
+    unescaped
+}
+
+/// Inserts emulation prevention bytes into a NAL unit, so no start code appears inside it.
+fn escape_nal(nal: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(nal.len() + nal.len() / 64);
+    let mut zeros = 0;
+    for &byte in nal {
+        if zeros >= 2 && byte <= 0x03 {
+            escaped.push(0x03);
+            zeros = 0;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        escaped.push(byte);
+    }
+    escaped
+}
+
+/// Encrypts a NAL unit of a coded slice with SAMPLE-AES: after its first 32 bytes, one 16-byte
+/// block in every ten is encrypted with AES-128 in CBC mode, starting again from `iv` for every
+/// NAL unit. Shorter NAL units, other NAL units and the final partial block are left clear.
+fn encrypt_nal(nal: &[u8], key: &[u8; 16], iv: &[u8; 16]) -> Result<Vec<u8>, String> {
+    let nal_type = nal.first().map_or(0, |header| header & 0x1f);
+    if (nal_type != NAL_TYPE_SLICE && nal_type != NAL_TYPE_IDR_SLICE)
+        || nal.len() <= MAX_CLEAR_NAL_SIZE
+    {
+        return Ok(nal.to_vec());
+    }
+
+    let mut data = unescape_nal(nal);
+    let mut crypter = Crypter::new(Cipher::aes_128_cbc(), Mode::Encrypt, key, Some(iv))
+        .map_err(|e| format!("Failed to create the SAMPLE-AES cipher: {}", e))?;
+    crypter.pad(false);
+    let mut block = [0u8; AES_BLOCK_SIZE * 2];
+    let mut position = CLEAR_LEADER_SIZE;
+    while data.len().saturating_sub(position) > AES_BLOCK_SIZE {
+        crypter
+            .update(&data[position..position + AES_BLOCK_SIZE], &mut block)
+            .map_err(|e| format!("Failed to encrypt a NAL unit: {}", e))?;
+        data[position..position + AES_BLOCK_SIZE].copy_from_slice(&block[..AES_BLOCK_SIZE]);
+        position += AES_BLOCK_SIZE + CLEAR_RUN_SIZE;
+    }
+    Ok(escape_nal(&data))
+}
+
+/// Returns the positions of the start codes of an H.264 Annex B stream, as the offset of the
+/// start code and of the NAL unit after it.
+fn start_codes(stream: &[u8]) -> Vec<(usize, usize)> {
+    let mut start_codes = Vec::new();
+    let mut i = 0;
+    while i + 3 <= stream.len() {
+        if stream[i] == 0 && stream[i + 1] == 0 && stream[i + 2] == 1 {
+            start_codes.push((i, i + 3));
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    start_codes
+}
+
+/// Encrypts the coded slices of an H.264 Annex B stream with SAMPLE-AES, leaving the start codes
+/// and the bytes between NAL units as they are.
+fn encrypt_h264(stream: &[u8], key: &[u8; 16], iv: &[u8; 16]) -> Result<Vec<u8>, String> {
+    let start_codes = start_codes(stream);
+    let mut encrypted = Vec::with_capacity(stream.len() + 64);
+    let mut copied = 0;
+    for (i, (_, nal_start)) in start_codes.iter().enumerate() {
+        // Zero bytes before the next start code belong to it, not to this NAL unit
+        let mut nal_end = start_codes
+            .get(i + 1)
+            .map_or(stream.len(), |(next_start_code, _)| *next_start_code);
+        while nal_end > *nal_start && stream[nal_end - 1] == 0 {
+            nal_end -= 1;
+        }
+        encrypted.extend(&stream[copied..*nal_start]);
+        encrypted.extend(encrypt_nal(&stream[*nal_start..nal_end], key, iv)?);
+        copied = nal_end;
+    }
+    encrypted.extend(&stream[copied..]);
+    Ok(encrypted)
+}
+
+/// Encrypts the H.264 data of a PES packet, keeping its header and updating its length.
+fn encrypt_pes(pes: &[u8], key: &[u8; 16], iv: &[u8; 16]) -> Result<Vec<u8>, String> {
+    if pes.len() < 9 || pes[..3] != [0, 0, 1] {
+        return Err("the segment has an invalid PES packet".to_string());
+    }
+    let data_start = 9 + pes[8] as usize;
+    let header = pes
+        .get(..data_start)
+        .ok_or("the segment has a truncated PES header")?;
+
+    let mut encrypted = header.to_vec();
+    encrypted.extend(encrypt_h264(&pes[data_start..], key, iv)?);
+    // A length of 0, allowed for video, leaves it unbounded
+    let pes_length = pes[4..6] != [0, 0] && encrypted.len() - 6 <= 0xffff;
+    let pes_length = if pes_length { encrypted.len() - 6 } else { 0 };
+    encrypted[4..6].copy_from_slice(&(pes_length as u16).to_be_bytes());
+    Ok(encrypted)
+}
+
+/// A PES packet of an H.264 stream being collected from its MPEG-TS packets.
+struct PendingPes {
+    // Indices in the output of the packets that carried it
+    slots: Vec<usize>,
+    // Adaptation field of its first packet, without stuffing, such as its PCR
+    adaptation_field: Vec<u8>,
+    data: Vec<u8>,
+}
+
+/// Encrypts an MPEG-TS HLS segment with SAMPLE-AES, as described in Apple's "MPEG-2 Stream
+/// Encryption Format for HTTP Live Streaming": the coded slices of its H.264 video are encrypted
+/// in place and its PMT signals the stream as encrypted. Other streams, such as audio, are left
+/// clear. A PES packet that grows because of new emulation prevention bytes takes extra packets,
+/// written right after its last one.
+///
+/// # Arguments
+/// * `segment` - The contents of the segment.
+/// * `key` - The AES-128 key.
+/// * `iv` - The IV, the one given in the playlist's `#EXT-X-KEY`.
+///
+/// # Returns
+/// The encrypted segment, or an error message if it is not MPEG-TS or has no H.264 video.
+///
+pub fn encrypt_segment(segment: &[u8], key: &[u8; 16], iv: &[u8; 16]) -> Result<Vec<u8>, String> {
+    if !segment.len().is_multiple_of(TS_PACKET_SIZE) {
+        return Err("the segment is not a whole number of MPEG-TS packets".to_string());
+    }
+
+    let mut pmt_pid_found = None;
+    let mut h264_pids: HashSet<u16> = HashSet::new();
+    let mut output: Vec<Option<Vec<u8>>> = Vec::new();
+    // Packets written after the output packet at an index
+    let mut extra_packets: HashMap<usize, Vec<Vec<u8>>> = HashMap::new();
+    let mut pending: HashMap<u16, PendingPes> = HashMap::new();
+
+    let flush = |pid: u16,
+                     pes: PendingPes,
+                     output: &mut Vec<Option<Vec<u8>>>,
+                     extra_packets: &mut HashMap<usize, Vec<Vec<u8>>>|
+     -> Result<(), String> {
+        let encrypted = encrypt_pes(&pes.data, key, iv)?;
+        let mut packets = Vec::new();
+        let mut carried = 0;
+        while carried < encrypted.len() {
+            let adaptation_field: &[u8] = if carried == 0 {
+                &pes.adaptation_field
+            } else {
+                &[]
+            };
+            let (packet, size) =
+                build_packet(pid, carried == 0, adaptation_field, &encrypted[carried..]);
+            packets.push(packet.to_vec());
+            carried += size;
+        }
+
+        let mut packets = packets.into_iter();
+        for slot in &pes.slots {
+            output[*slot] = packets.next();
+        }
+        let last_slot = *pes.slots.last().unwrap_or(&0);
+        extra_packets.entry(last_slot).or_default().extend(packets);
+        Ok(())
+    };
+
+    for packet in segment.chunks_exact(TS_PACKET_SIZE) {
+        let parsed = parse_packet(packet)?;
+        let index = output.len();
+        output.push(Some(packet.to_vec()));
+
+        if parsed.pid == 0 && parsed.payload_unit_start {
+            pmt_pid_found = pmt_pid(packet_section(parsed.payload)?);
+        } else if Some(parsed.pid) == pmt_pid_found && parsed.payload_unit_start {
+            let (section, pids) = rewrite_pmt(packet_section(parsed.payload)?)?;
+            h264_pids.extend(pids);
+
+            // The section is followed by 0xff stuffing rather than stuffed in the adaptation field
+            let adaptation_field = adaptation_field_without_stuffing(parsed.adaptation_field);
+            let space = TS_PACKET_SIZE
+                - TS_HEADER_SIZE
+                - if adaptation_field.is_empty() {
+                    0
+                } else {
+                    1 + adaptation_field.len()
+                };
+            let pointer = parsed.payload[0] as usize;
+            let mut payload = parsed.payload[..1 + pointer].to_vec();
+            payload.extend(&section);
+            if payload.len() > space {
+                return Err("the segment's PMT does not fit in a packet once rewritten".to_string());
+            }
+            payload.resize(space, 0xff);
+            let (mut rewritten, _) =
+                build_packet(parsed.pid, true, &adaptation_field, &payload);
+            rewritten[3] |= parsed.continuity_counter;
+            output[index] = Some(rewritten.to_vec());
+        } else if h264_pids.contains(&parsed.pid) {
+            if parsed.payload_unit_start {
+                if let Some(pes) = pending.remove(&parsed.pid) {
+                    flush(parsed.pid, pes, &mut output, &mut extra_packets)?;
+                }
+                pending.insert(
+                    parsed.pid,
+                    PendingPes {
+                        slots: vec![index],
+                        adaptation_field: adaptation_field_without_stuffing(
+                            parsed.adaptation_field,
+                        ),
+                        data: parsed.payload.to_vec(),
+                    },
+                );
+            } else if let Some(pes) = pending.get_mut(&parsed.pid) {
+                pes.slots.push(index);
+                pes.data.extend(parsed.payload);
+            }
+        }
+    }
+    if h264_pids.is_empty() {
+        return Err("the segment has no H.264 video stream".to_string());
+    }
+    for (pid, pes) in pending.drain() {
+        flush(pid, pes, &mut output, &mut extra_packets)?;
+    }
+
+    // Continuity counters of the encrypted streams run on from the segment's first packet of each
+    let mut continuity_counters: HashMap<u16, u8> = HashMap::new();
+    let mut encrypted = Vec::with_capacity(segment.len() + TS_PACKET_SIZE * 8);
+    for (index, packet) in output.into_iter().enumerate() {
+        let packets = packet.into_iter().chain(extra_packets.remove(&index).unwrap_or_default());
+        for mut packet in packets {
+            let parsed = parse_packet(&packet)?;
+            if h264_pids.contains(&parsed.pid) && packet[3] & 0x10 != 0 {
+                let counter = continuity_counters
+                    .entry(parsed.pid)
+                    .or_insert(parsed.continuity_counter);
+                packet[3] = (packet[3] & 0xf0) | *counter;
+                *counter = (*counter + 1) & 0x0f;
+            }
+            encrypted.extend(packet);
+        }
+    }
+    Ok(encrypted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 16] = [7; 16];
+    const IV: [u8; 16] = [9; 16];
+
+    fn packet(pid: u16, start: bool, counter: u8, payload: &[u8]) -> Vec<u8> {
+        let (mut packet, carried) = build_packet(pid, start, &[], payload);
+        assert_eq!(carried, payload.len());
+        packet[3] |= counter;
+        packet.to_vec()
+    }
+
+    fn table(table_id: u8, body: &[u8]) -> Vec<u8> {
+        let mut section = vec![table_id, 0xb0, 0, 0, 1, 0xc1, 0, 0];
+        section.extend(body);
+        let section_length = section.len() + 4 - 3;
+        section[2] = section_length as u8;
+        let crc = crc32_mpeg2(&section);
+        section.extend(crc.to_be_bytes());
+        [vec![0], section].concat()
+    }
+
+    fn segment(slice: &[u8]) -> Vec<u8> {
+        let pat = table(0x00, &[0, 1, 0xf0, 0x00]);
+        // Video on PID 0x100, audio on PID 0x101
+        let pmt = table(
+            0x02,
+            &[0xe1, 0x00, 0xf0, 0x00, 0x1b, 0xe1, 0x00, 0xf0, 0x00, 0x0f, 0xe1, 0x01, 0xf0, 0x00],
+        );
+        let mut pes = vec![0, 0, 1, 0xe0, 0, 0, 0x80, 0x80, 5, 0x21, 0, 1, 0, 1];
+        pes.extend([0, 0, 0, 1, 0x09, 0xf0]);
+        pes.extend([0, 0, 0, 1]);
+        pes.extend(slice);
+
+        let mut segment = packet(0, true, 0, &pat);
+        segment.extend(packet(0x1000, true, 0, &pmt));
+        let mut carried = 0;
+        let mut counter = 0;
+        while carried < pes.len() {
+            let (mut video, size) = build_packet(0x100, carried == 0, &[], &pes[carried..]);
+            video[3] |= counter;
+            segment.extend(video);
+            segment.extend(packet(0x101, counter == 0, counter, &[0xff; 10]));
+            carried += size;
+            counter += 1;
+        }
+        segment
+    }
+
+    #[test]
+    fn crc32_mpeg2_matches_the_check_value() {
+        assert_eq!(crc32_mpeg2(b"123456789"), 0x0376_e6e7);
+    }
+
+    #[test]
+    fn escape_nal_round_trips() {
+        let nal = [0x65, 0, 0, 0, 0, 0, 1, 0, 0, 2, 0, 0, 3, 0xaa];
+        let escaped = escape_nal(&nal);
+        assert_eq!(start_codes(&escaped), Vec::new());
+        assert_eq!(unescape_nal(&escaped), nal);
+    }
+
+    #[test]
+    fn encrypt_nal_encrypts_one_block_in_ten_after_the_leader() {
+        let mut slice = vec![0x65];
+        slice.extend((1..400).map(|i| (i % 200 + 20) as u8));
+        let encrypted = encrypt_nal(&slice, &KEY, &IV).unwrap();
+
+        assert_eq!(encrypted.len(), slice.len());
+        assert_eq!(encrypted[..32], slice[..32]);
+        assert_ne!(encrypted[32..48], slice[32..48]);
+        assert_eq!(encrypted[48..192], slice[48..192]);
+        assert_ne!(encrypted[192..208], slice[192..208]);
+        assert_eq!(encrypted[208..352], slice[208..352]);
+        // 48 bytes are left, more than a block, so one more is encrypted
+        assert_ne!(encrypted[352..368], slice[352..368]);
+        assert_eq!(encrypted[368..], slice[368..]);
+
+        // Short slices and NAL units other than slices stay clear
+        assert_eq!(encrypt_nal(&slice[..48], &KEY, &IV).unwrap(), slice[..48]);
+        let mut sps = slice.clone();
+        sps[0] = 0x67;
+        assert_eq!(encrypt_nal(&sps, &KEY, &IV).unwrap(), sps);
+    }
+
+    #[test]
+    fn encrypt_segment_encrypts_the_video_and_signals_it_in_the_pmt() {
+        let mut slice = vec![0x65];
+        slice.extend((1..1000).map(|i| (i % 250 + 1) as u8));
+        let segment = segment(&slice);
+
+        let encrypted = encrypt_segment(&segment, &KEY, &IV).unwrap();
+        assert_eq!(encrypted.len() % TS_PACKET_SIZE, 0);
+
+        let packets: Vec<TsPacket> = encrypted
+            .chunks_exact(TS_PACKET_SIZE)
+            .map(|packet| parse_packet(packet).unwrap())
+            .collect();
+        let pmt = packet_section(packets[1].payload).unwrap();
+        assert_eq!(crc32_mpeg2(pmt), 0);
+        assert!(pmt.windows(6).any(|w| w == SAMPLE_AES_H264_DESCRIPTOR));
+        assert_eq!(pmt[12], STREAM_TYPE_H264_SAMPLE_AES);
+
+        let video: Vec<&TsPacket> = packets.iter().filter(|p| p.pid == 0x100).collect();
+        let counters: Vec<u8> = video.iter().map(|p| p.continuity_counter).collect();
+        assert_eq!(counters, (0..video.len() as u8).collect::<Vec<u8>>());
+        let pes: Vec<u8> = video.iter().flat_map(|p| p.payload.to_vec()).collect();
+        let (_, nal_start) = *start_codes(&pes).last().unwrap();
+        assert_eq!(
+            unescape_nal(&pes[nal_start..]),
+            unescape_nal(&encrypt_nal(&slice, &KEY, &IV).unwrap())
+        );
+        assert_ne!(pes[nal_start..nal_start + 48], slice[..48]);
+
+        // Audio packets are untouched
+        let audio = |ts: &[u8]| -> Vec<Vec<u8>> {
+            ts.chunks_exact(TS_PACKET_SIZE)
+                .filter(|p| parse_packet(p).unwrap().pid == 0x101)
+                .map(|p| p.to_vec())
+                .collect()
+        };
+        assert_eq!(audio(&encrypted), audio(&segment));
+
+        assert!(encrypt_segment(&segment[..100], &KEY, &IV).is_err());
+    }
+}
@@ -11,30 +11,80 @@
  * Date: 28 May 2023
  */
 
+// tonic::Status is the error type throughout, large as it is
+#![allow(clippy::result_large_err)]
+
 mod s5;
 mod auth;
 
 mod encrypt_file;
 
+mod config;
 mod utils;
-use utils::{base64url_to_bytes, bytes_to_base64url, download_and_concat_files, download_video};
+use utils::{
+    base64url_to_bytes, bytes_to_base64url, check_endpoint_reachable, download_and_concat_files,
+    download_video, jittered_backoff, post_json_with_retry,
+};
 
 mod transcode_video;
-use transcode_video::{get_video_format_from_str, transcode_video, TranscodeVideoResponse};
+use transcode_video::{
+    get_video_format_from_str, remove_partial_outputs, resolve_ffmpeg_args, source_file_name,
+    transcode_video, validate_quality_mode, TranscodeVideoResponse,
+};
 
 mod shared;
 
+mod cache;
+
+mod probe;
+
+mod concat;
+
+mod quota;
+
+mod version;
+
+mod capabilities;
+
+mod metrics;
+
+mod dead_letter;
+
+mod coalesce;
+
+mod source_cache;
+
+mod error_code;
+
+mod throttle;
+
+mod progress_log;
+
+mod callback;
+
+mod retained_tasks;
+use retained_tasks::RetainedTasks;
+
+mod test_pattern;
+use test_pattern::parse_test_pattern;
+
+mod sample_aes;
+use error_code::{CodedError, TranscodeErrorCode};
+
 use tonic::{transport::Server, Request, Response, Status};
-use warp::Filter;
+use warp::{Filter, Reply};
 
 use async_trait::async_trait;
 
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use tokio::sync::mpsc;
 use tokio::sync::Mutex;
+use tokio::sync::Notify;
 use transcode::{
     transcode_service_server::{TranscodeService, TranscodeServiceServer},
-    GetTranscodedRequest, GetTranscodedResponse, TranscodeRequest, TranscodeResponse,
+    FormatProgress, GetTranscodedRequest, GetTranscodedResponse, TaskStatus, TranscodeRequest,
+    TranscodeResponse,
 };
 
 mod encrypted_cid;
@@ -44,21 +94,94 @@ use serde::{Deserialize, Serialize};
 use serde_json::{from_str, json, Value};
 use std::fs::read_to_string;
 
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use once_cell::sync::Lazy;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::Path;
 
 use chrono::Utc;
-use uuid::{Uuid, Version};
+use uuid::Uuid;
 
-use base64;
 use std::convert::TryInto;
 
-use dotenv::{dotenv, var};
+use config::var;
+use dotenv::dotenv;
+
+use tracing::{error, info, warn};
 
-static TRANSCODED: Lazy<Mutex<HashMap<String, String>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+// Finished tasks whose renditions, status and metadata are kept, see `MAX_RETAINED_TASKS`
+static TRANSCODED: Lazy<Mutex<RetainedTasks>> =
+    Lazy::new(|| Mutex::new(RetainedTasks::new(*MAX_RETAINED_TASKS)));
+// Most finished tasks kept before the least recently accessed is forgotten
+static MAX_RETAINED_TASKS: Lazy<usize> = Lazy::new(|| {
+    var("MAX_RETAINED_TASKS")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(0) // 0 keeps every finished task
+});
+type RenditionKeys = Vec<(usize, String)>;
+// HashMap<task_id, (index in the task's renditions, hex key) of each rendition with "key_uri"
+// HLS key delivery>. Kept out of `TRANSCODED` and the manifest, and handed out once
+static HLS_KEYS: Lazy<Mutex<HashMap<String, RenditionKeys>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+// HashMap<task_id, JSON object of task-level metadata such as `original_cid`>
+static TASK_METADATA: Lazy<Mutex<HashMap<String, String>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+// Minimum free space required on the download and output filesystems to accept work. 0 disables it
+static MIN_FREE_DISK_BYTES: Lazy<u64> = Lazy::new(|| {
+    var("MIN_FREE_DISK_BYTES")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0)
+});
+type FormatCommands = HashMap<u32, Vec<String>>;
+// HashMap<task_id, HashMap<format id, ffmpeg program and arguments the rendition was made with>>
+static FFMPEG_COMMANDS: Lazy<Mutex<HashMap<String, FormatCommands>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+// HashMap<task_id, JWT subject that submitted the task>
+static TASK_SUBJECTS: Lazy<Mutex<HashMap<String, String>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+static TASK_MAX_RETRIES: Lazy<u32> = Lazy::new(|| {
+    var("TASK_MAX_RETRIES")
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(0) // 0 disables task retries
+});
+static TASK_RETRY_DELAY_SECS: Lazy<u64> = Lazy::new(|| {
+    var("TASK_RETRY_DELAY_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(30)
+});
+// Tasks still queued after this many seconds are expired instead of started
+static MAX_QUEUE_WAIT_SECS: Lazy<i64> = Lazy::new(|| {
+    var("MAX_QUEUE_WAIT_SECS")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(0) // 0 lets tasks wait indefinitely
+});
+// Number of tasks transcoded at once
+static TRANSCODE_WORKERS: Lazy<usize> = Lazy::new(|| {
+    var("TRANSCODE_WORKERS")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(1)
+});
+static COMPRESS_RESPONSES: Lazy<bool> =
+    Lazy::new(|| var("COMPRESS_RESPONSES").map(|v| v != "false").unwrap_or(true));
+// IDs of every task issued since startup, to tell tasks still in progress from unknown ones
+static ISSUED_TASK_IDS: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+// "not_found" to report task IDs that were never issued as unknown, "in_progress" to report them
+// as in progress like earlier versions
+static UNKNOWN_TASK_RESPONSE: Lazy<String> =
+    Lazy::new(|| var("UNKNOWN_TASK_RESPONSE").unwrap_or_else(|_| "not_found".to_string()));
+// HashMap<task_id, final `TaskStatus` of a finished task>
+static TASK_STATUS: Lazy<Mutex<HashMap<String, TaskStatus>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+// Set by the `pause` admin endpoint to stop the worker dequeuing new tasks
+static WORKER_PAUSED: AtomicBool = AtomicBool::new(false);
+static WORKER_RESUMED: Lazy<Notify> = Lazy::new(Notify::new);
 static PATH_TO_FILE: Lazy<String> =
     Lazy::new(|| var("PATH_TO_FILE").unwrap_or_else(|_| panic!("PATH_TO_FILE not set in .env")));
 static PATH_TO_TRANSCODED_FILE: Lazy<String> = Lazy::new(|| {
@@ -76,10 +199,49 @@ static GARBAGE_COLLECTOR_INTERVAL: Lazy<String> = Lazy::new(|| {
     var("GARBAGE_COLLECTOR_INTERVAL")
         .unwrap_or_else(|_| panic!("GARBAGE_COLLECTOR_INTERVAL not set in .env"))
 });
+// Receives a summary of each garbage collection run that deletes files
+static GC_WEBHOOK_URL: Lazy<Option<String>> =
+    Lazy::new(|| var("GC_WEBHOOK_URL").ok().filter(|url| !url.is_empty()));
+static GC_WEBHOOK_RETRIES: Lazy<u32> = Lazy::new(|| {
+    var("GC_WEBHOOK_RETRIES")
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(3)
+});
+// Retries of a task's completion callback to its `callback_url`
+static CALLBACK_RETRIES: Lazy<u32> = Lazy::new(|| {
+    var("CALLBACK_RETRIES")
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(3)
+});
 static IPFS_GATEWAY: Lazy<String> = Lazy::new(|| {
     var("IPFS_GATEWAY")
         .unwrap_or_else(|_| panic!("IPFS_GATEWAY not set in .env"))
 });
+// URL paths appended to the portal and gateway URLs, for deployments that use non-default routes.
+// An empty value keeps the default.
+static S5_BLOB_PATH: Lazy<String> = Lazy::new(|| {
+    var("S5_BLOB_PATH")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| "/s5/blob/".to_string())
+});
+static IPFS_PATH: Lazy<String> = Lazy::new(|| {
+    var("IPFS_PATH")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| "/ipfs/".to_string())
+});
+static PORTAL_LOCATIONS_PATH: Lazy<String> = Lazy::new(|| {
+    var("PORTAL_LOCATIONS_PATH")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| "/api/locations/".to_string())
+});
+static UPLOAD_DECRYPTED_ORIGINAL: Lazy<bool> = Lazy::new(|| {
+    utils::env_flag("UPLOAD_DECRYPTED_ORIGINAL")
+});
 
 
 fn get_file_size(file_path: String) -> std::io::Result<u64> {
@@ -87,12 +249,36 @@ fn get_file_size(file_path: String) -> std::io::Result<u64> {
     Ok(metadata.len())
 }
 
+/// Returns the ratio of the input size to the output size, rounded to two decimal places, e.g. 4.0
+/// when the output is a quarter of the size of the source.
+fn compression_ratio(input_size: u64, output_size: u64) -> f64 {
+    if output_size == 0 {
+        return 0.0;
+    }
+    (input_size as f64 / output_size as f64 * 100.0).round() / 100.0
+}
+
 const CID_TYPE_ENCRYPTED_SIZE: usize = 1;
 const ENCRYPTION_ALGORITHM_SIZE: usize = 1;
 const CHUNK_SIZE_AS_POWEROF2_SIZE: usize = 1;
 
 const ENCRYPTED_BLOB_HASH_SIZE: usize = 33;
 const KEY_SIZE: usize = 32;
+const PADDING_SIZE: usize = 4;
+// Raw CID type byte and blake3 multihash prefix, followed by the 32 byte hash
+const RAW_CID_PREFIX_SIZE: usize = 2;
+const BLAKE3_RAW_CID_PREFIX: [u8; RAW_CID_PREFIX_SIZE] = [0x26, 0x1f];
+const BLAKE3_HASH_SIZE: usize = 32;
+// Index of the plaintext's raw CID in an encrypted CID, after its key and padding
+const ENCRYPTED_RAW_CID_INDEX: usize = CID_TYPE_ENCRYPTED_SIZE
+    + ENCRYPTION_ALGORITHM_SIZE
+    + CHUNK_SIZE_AS_POWEROF2_SIZE
+    + ENCRYPTED_BLOB_HASH_SIZE
+    + KEY_SIZE
+    + PADDING_SIZE;
+
+const ENCRYPTION_CHUNK_SIZE: u64 = encrypt_file::ENCRYPTION_CHUNK_SIZE as u64;
+const ENCRYPTION_TAG_SIZE: u64 = encrypt_file::ENCRYPTION_TAG_SIZE as u64;
 
 /**
  * Extracts the encryption key from an encrypted CID.
@@ -131,19 +317,188 @@ pub fn get_key_from_encrypted_cid(encrypted_cid: &str) -> String {
     let key = bytes_to_base64url(selected_bytes);
     println!("get_key_from_encrypted_cid: key = {}", key);
 
-    return key;
+    key
 }
 
-fn number_of_bytes(value: u32) -> usize {
-    let mut value = value;
-    let mut bytes = 1;
+/// Extracts the padding and the declared size of the unencrypted file from an encrypted CID.
+/// @param encrypted_cid - The encrypted CID, without extension, prefixed with its multibase `u`.
+/// @returns `(padding, size)`, or `None` if the CID is too short to hold them.
+///
+fn get_padding_and_size_from_encrypted_cid(encrypted_cid: &str) -> Option<(u64, u64)> {
+    let cid_bytes = base64url_to_bytes(encrypted_cid.get(1..)?);
 
-    while value >= 256 {
-        value >>= 8;
-        bytes += 1;
+    let (_, size) = split_raw_cid(cid_bytes.get(ENCRYPTED_RAW_CID_INDEX..)?)?;
+
+    let padding_index = ENCRYPTED_RAW_CID_INDEX - PADDING_SIZE;
+    let padding_bytes: [u8; 4] = cid_bytes[padding_index..ENCRYPTED_RAW_CID_INDEX]
+        .try_into()
+        .ok()?;
+    let padding = u32::from_be_bytes(padding_bytes) as u64;
+
+    Some((padding, size))
+}
+
+/// Splits a raw CID into its blake3 hash and the size of the file it addresses.
+/// @param raw_cid - The raw CID: its type and multihash prefix, the hash, then the size.
+/// @returns `(hash, size)`, or `None` if the CID has no size or one longer than 8 bytes.
+///
+fn split_raw_cid(raw_cid: &[u8]) -> Option<(&[u8], u64)> {
+    let size_index = RAW_CID_PREFIX_SIZE + BLAKE3_HASH_SIZE;
+    if raw_cid.len() <= size_index || raw_cid.len() - size_index > 8 {
+        return None;
     }
 
-    bytes
+    // The size is little-endian with its trailing zero bytes removed
+    let mut size_bytes = [0u8; 8];
+    size_bytes[..raw_cid.len() - size_index].copy_from_slice(&raw_cid[size_index..]);
+    let size = u64::from_le_bytes(size_bytes);
+
+    Some((&raw_cid[RAW_CID_PREFIX_SIZE..size_index], size))
+}
+
+/// Returns the blake3 hash and size of the plaintext an S5 CID addresses: for an unencrypted CID
+/// the raw CID itself, and for an encrypted CID the raw CID that follows its key and padding, which
+/// is also where `get_padding_and_size_from_encrypted_cid` reads the size from. Returns `None` for
+/// CIDs in another form, such as IPFS CIDs.
+///
+/// # Arguments
+/// * `source_cid` - The CID in base64url multibase form ("u...").
+/// * `is_encrypted` - Whether the CID is an encrypted CID.
+///
+fn get_plaintext_hash_and_size_from_cid(
+    source_cid: &str,
+    is_encrypted: bool,
+) -> Option<(Vec<u8>, u64)> {
+    use base64::Engine as _;
+
+    // Decoded without `base64url_to_bytes`, which panics on CIDs that are not base64url
+    let cid_bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(source_cid.strip_prefix('u')?)
+        .ok()?;
+    let raw_cid_index = if is_encrypted { ENCRYPTED_RAW_CID_INDEX } else { 0 };
+    let raw_cid = cid_bytes.get(raw_cid_index..)?;
+    if !raw_cid.starts_with(&BLAKE3_RAW_CID_PREFIX) {
+        return None;
+    }
+
+    let (hash, size) = split_raw_cid(raw_cid)?;
+    Some((hash.to_vec(), size))
+}
+
+/// Checks a downloaded source against the blake3 hash and size its CID addresses, so that a
+/// corrupt cached download is not reused. Sources whose CID can't be decoded are accepted, as
+/// there is nothing to check them against.
+///
+/// # Arguments
+/// * `file_path` - The path of the downloaded (and decrypted) source.
+/// * `source_cid` - The CID of the source.
+/// * `is_encrypted` - Whether the CID is an encrypted CID.
+///
+/// # Returns
+/// An error message describing the mismatch, if any.
+///
+fn verify_cid(file_path: &str, source_cid: &str, is_encrypted: bool) -> Result<(), String> {
+    let (expected_hash, expected_size) =
+        match get_plaintext_hash_and_size_from_cid(source_cid, is_encrypted) {
+            Some(hash_and_size) => hash_and_size,
+            None => return Ok(()),
+        };
+
+    let size = get_file_size(file_path.to_string()).map_err(|e| e.to_string())?;
+    if size != expected_size {
+        return Err(format!(
+            "size is {} bytes but the CID addresses {} bytes",
+            size, expected_size
+        ));
+    }
+
+    let hash = s5::hash_blake3_file(file_path.to_string()).map_err(|e| e.to_string())?;
+    if hash.as_bytes()[..] != expected_hash[..] {
+        return Err("blake3 hash does not match the CID".to_string());
+    }
+
+    Ok(())
+}
+
+/// Returns whether a source downloaded before can be used as it is. A cached copy that fails
+/// `verify_cid` is removed, so the source is downloaded again rather than its corruption reaching
+/// every task that reuses it.
+///
+/// # Arguments
+/// * `file_path` - The path the source is downloaded to.
+/// * `source_cid` - The CID of the source.
+/// * `is_encrypted` - Whether the CID is an encrypted CID.
+///
+fn is_cached_source_valid(file_path: &str, source_cid: &str, is_encrypted: bool) -> bool {
+    if !Path::new(file_path).exists() {
+        return false;
+    }
+
+    match verify_cid(file_path, source_cid, is_encrypted) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!(
+                file_path = %file_path,
+                "Cached source failed verification, downloading it again: {}", e
+            );
+            let _ = fs::remove_file(file_path);
+            false
+        }
+    }
+}
+
+/// Computes the index of the last chunk of a file encrypted in chunks of 256 KiB plaintext plus a
+/// 16 byte tag. The final chunk may be partial, so the chunk count is rounded up. When the size
+/// declared by the CID is known, the encrypted size is checked against it so that a truncated
+/// download is rejected instead of being decrypted into a corrupt file.
+///
+/// # Arguments
+/// * `encrypted_size` - Size of the encrypted file in bytes.
+/// * `padding_and_size` - The padding and unencrypted size declared by the CID, if known.
+///
+fn last_chunk_index(
+    encrypted_size: u64,
+    padding_and_size: Option<(u64, u64)>,
+) -> Result<u32, String> {
+    let encrypted_chunk_size = ENCRYPTION_CHUNK_SIZE + ENCRYPTION_TAG_SIZE;
+
+    if encrypted_size == 0 {
+        return Err(String::from("Encrypted file is empty"));
+    }
+
+    let chunk_count = encrypted_size.div_ceil(encrypted_chunk_size);
+    let last_chunk_size = encrypted_size - (chunk_count - 1) * encrypted_chunk_size;
+
+    if last_chunk_size <= ENCRYPTION_TAG_SIZE {
+        return Err(format!(
+            "Encrypted file size {} leaves a final chunk of {} bytes, too small for its tag",
+            encrypted_size, last_chunk_size
+        ));
+    }
+
+    if let Some((padding, declared_size)) = padding_and_size {
+        let plaintext_size = encrypted_size - chunk_count * ENCRYPTION_TAG_SIZE;
+        if plaintext_size != declared_size + padding {
+            return Err(format!(
+                "Encrypted file size {} does not match the size {} plus padding {} in the CID",
+                encrypted_size, declared_size, padding
+            ));
+        }
+    }
+
+    Ok((chunk_count - 1) as u32)
+}
+
+/// Returns the size of a file encrypted in chunks of 256 KiB plaintext plus a 16 byte tag, from
+/// the padding and unencrypted size declared by its CID.
+///
+/// # Arguments
+/// * `padding_and_size` - The padding and unencrypted size declared by the CID.
+///
+fn encrypted_size((padding, size): (u64, u64)) -> u64 {
+    let plaintext_size = size + padding;
+    let chunk_count = plaintext_size.div_ceil(ENCRYPTION_CHUNK_SIZE).max(1);
+    plaintext_size + chunk_count * ENCRYPTION_TAG_SIZE
 }
 
 /// Calculates the SHA-256 hash of the given `encrypted_cid`, encrypts it using AES-256-CBC with
@@ -169,19 +524,711 @@ pub fn get_base64_url_encrypted_blob_hash(encrypted_cid: &str) -> Option<String>
     Some(base64_url)
 }
 
-/// Generates a random filename with the given `prefix` and `extension`.
-/// The filename is guaranteed to be unique and not already exist in the
-/// current directory. Returns the resulting filename as a `String`.
+/// Downloads the source video for a task into `PATH_TO_FILE`, decrypting it first if it is
+/// encrypted. If the source has already been downloaded the cached copy is used. Sources given as
+/// an `http://` or `https://` URL are downloaded directly, and `testsrc://` sources are generated.
+///
+/// # Arguments
+/// * `orig_source_cid` - The source CID as submitted, prefixed with its storage network.
+/// * `is_encrypted` - Whether the source is encrypted.
+/// * `task_id` - The task downloading the source, which names the intermediate files of an
+///   encrypted download so tasks downloading the same source concurrently never share them.
+///
+/// # Returns
+/// The path to the downloaded (and decrypted) source file and where it was fetched from
+/// ("local_cache", "s5_portal", "ipfs_gateway", "encrypted_portal", "direct_http" or
+/// "test_pattern"), or an error message.
+///
+async fn download_source(
+    orig_source_cid: &str,
+    is_encrypted: bool,
+    task_id: &str,
+) -> Result<(String, &'static str), CodedError> {
+    let source_cid = source_file_name(orig_source_cid).ok_or_else(|| {
+        TranscodeErrorCode::InvalidSource.error(format!("Invalid source CID: {}", orig_source_cid))
+    })?;
+
+    let storage_network: Option<&str> = orig_source_cid.split_once("://").map(|(network, _)| network);
+    if storage_network.is_none() {
+        return Err(TranscodeErrorCode::InvalidSource
+            .error(format!("Invalid source CID: {}", orig_source_cid)));
+    }
+
+    if let Some(test_pattern) = parse_test_pattern(orig_source_cid) {
+        let test_pattern = test_pattern.map_err(|e| {
+            TranscodeErrorCode::InvalidSource
+                .error(format!("Invalid source CID: {}: {}", orig_source_cid, e))
+        })?;
+        if is_encrypted {
+            return Err(TranscodeErrorCode::InvalidSource.error(format!(
+                "Invalid source CID: {}: a test pattern cannot be encrypted",
+                orig_source_cid
+            )));
+        }
+
+        let file_path = format!("{}{}", *PATH_TO_FILE, test_pattern.file_name());
+        let _generation = coalesce::begin(&file_path).await;
+        if Path::new(&file_path).exists() {
+            println!("Test pattern already generated: {}", &file_path);
+        } else {
+            let generate_path = file_path.clone();
+            tokio::task::spawn_blocking(move || test_pattern.generate(&generate_path))
+                .await
+                .map_err(|e| format!("Failed to generate test pattern: {}", e))??;
+        }
+        return Ok((file_path, "test_pattern"));
+    }
+
+    let portal_url_result = if is_encrypted {
+        var("PORTAL_ENCRYPT_URL")
+    } else {
+        var("PORTAL_URL")
+    };
+
+    let portal_url = portal_url_result
+        .map_err(|_| "Required environment variable for PORTAL_URL not found".to_string())?;
+
+    println!("source_cid: {}", source_cid);
+    println!("portal_url: {}", portal_url);
+
+    let file_path = format!("{}{}", *PATH_TO_FILE, source_cid);
+
+    // A task downloading the same source concurrently finishes first, then its download is reused
+    let download = coalesce::begin(&file_path).await;
+
+    let source_origin = if is_cached_source_valid(&file_path, &source_cid, is_encrypted) {
+        println!("File already exists: {}", &file_path);
+        if download.waited {
+            metrics::increment_counter("coalesced_work_total", &[("kind", "download")]);
+        }
+        "local_cache"
+    } else if is_encrypted {
+        println!("source_cid: {}", source_cid);
+        let base64_url_encrypted_blob_hash = get_base64_url_encrypted_blob_hash(&source_cid)
+            .ok_or_else(|| {
+                TranscodeErrorCode::InvalidSource.error(format!(
+                    "Invalid source CID {}: it has no encrypted blob hash",
+                    source_cid
+                ))
+            })?;
+
+        let url = format!(
+            "{}{}{}?types=5,3",
+            portal_url, *PORTAL_LOCATIONS_PATH, base64_url_encrypted_blob_hash
+        );
+        println!("Downloading and then transcoding video from URL: {}", &url);
+
+        let encrypted_file_path = format!("{}{}_{}_", *PATH_TO_FILE, source_cid, task_id);
+        // Named after the source and task so a retry of the task resumes a download interrupted
+        // by a restart where it stopped
+        let file_path_encrypted = format!("{}{}_{}_concat", *PATH_TO_FILE, source_cid, task_id);
+        // Renamed to `file_path` once it is checked, so no task reads a partly decrypted source
+        let file_path_decrypted = format!("{}{}_{}_decrypted", *PATH_TO_FILE, source_cid, task_id);
+        // Kept from the garbage collector while they are written
+        let _intermediate_files = ActiveSourceGuard::new(
+            vec![
+                encrypted_file_path.clone(),
+                file_path_encrypted.clone(),
+                file_path_decrypted.clone(),
+            ],
+            false,
+        );
+
+        match download_video(&url, encrypted_file_path.as_str()).await {
+            Ok(_) => println!("Video downloaded successfully"),
+            Err(e) => {
+                return Err(CodedError::download(
+                    &e,
+                    format!("Failed to download encrypted video from URL {}: {}", &url, e.message()),
+                ));
+            }
+        };
+
+        let encrypted_metadata = std::fs::read_to_string(&encrypted_file_path);
+        // Downloaded again by a retry, so it is not kept
+        let _ = fs::remove_file(&encrypted_file_path);
+        let encrypted_metadata = match encrypted_metadata {
+            Ok(contents) => contents,
+            Err(e) => {
+                return Err(format!(
+                    "Failed to read encrypted metadata from file {}: {}",
+                    &encrypted_file_path, e
+                )
+                .into());
+            }
+        };
+
+        println!("file_encrypted_metadata: {:?}", file_path_encrypted);
+        println!("encrypted_metadata: {:?}", encrypted_metadata);
+
+        let padding_and_size = get_padding_and_size_from_encrypted_cid(&source_cid);
+        match download_and_concat_files(
+            encrypted_metadata,
+            file_path_encrypted.clone(),
+            padding_and_size.map(encrypted_size),
+        )
+        .await
+        {
+            Ok(()) => println!("Download and concatenation succeeded"),
+            Err(e) => {
+                let message = format!("Download and concatenation failed: {}", e);
+                return Err(match e.downcast_ref::<Status>() {
+                    Some(status) => CodedError::download(status, message),
+                    None => message.into(),
+                });
+            }
+        }
+
+        let file_encrypted_size = get_file_size(file_path_encrypted.clone()).map_err(|e| {
+            format!(
+                "Failed to read the size of the encrypted file {}: {}",
+                file_path_encrypted, e
+            )
+        })?;
+        println!("file_path_encrypted: {}", file_path_encrypted);
+        println!("file_encrypted_size: {}", file_encrypted_size);
+
+        let last_index_size = last_chunk_index(file_encrypted_size, padding_and_size)
+            .map_err(|e| TranscodeErrorCode::DecryptFailed.error(e))?;
+        let padding = padding_and_size.map(|(padding, _)| padding as usize).unwrap_or(0);
+
+        let key = get_key_from_encrypted_cid(&source_cid);
+        let key_bytes = base64url_to_bytes(&key);
+
+        println!("file_path: {}", file_path_decrypted);
+        println!("key: {}", key);
+        println!("key_bytes: {:?}", key_bytes);
+        println!("last_index_size: {}", last_index_size);
+
+        match decrypt_file_xchacha20(
+            file_path_encrypted.clone(),
+            file_path_decrypted.clone(),
+            key_bytes,
+            padding,
+            last_index_size,
+        ) {
+            Ok(_) => {
+                println!("Decryption succeeded");
+                if let Err(e) = fs::remove_file(&file_path_encrypted) {
+                    eprintln!("Failed to delete encrypted file {}: {}", file_path_encrypted, e);
+                }
+            }
+            Err(error) => {
+                let _ = fs::remove_file(&file_path_decrypted);
+                return Err(TranscodeErrorCode::DecryptFailed.error(format!(
+                    "Decryption error: {:?}; decryption produced invalid media (wrong key?)",
+                    error
+                )));
+            }
+        }
+
+        // A wrong key or corrupt download would otherwise only surface as an obscure ffmpeg failure.
+        // The decrypted file is removed so it is not reused as a cached download.
+        let is_valid_media = probe::probe_source(&file_path_decrypted)
+            .map(|source_probe| !source_probe.streams.is_empty())
+            .unwrap_or(false);
+        if !is_valid_media {
+            let _ = fs::remove_file(&file_path_decrypted);
+            return Err(TranscodeErrorCode::DecryptFailed
+                .error("Decryption produced invalid media (wrong key?)"));
+        }
+
+        fs::rename(&file_path_decrypted, &file_path).map_err(|e| {
+            let _ = fs::remove_file(&file_path_decrypted);
+            format!("Failed to move decrypted source to {}: {}", file_path, e)
+        })?;
+
+        "encrypted_portal"
+    } else {
+        let (url, source_origin) = match storage_network {
+            Some("ipfs") => (
+                format!("{}{}{}", *IPFS_GATEWAY, *IPFS_PATH, source_cid),
+                "ipfs_gateway",
+            ),
+            Some("http") | Some("https") => (orig_source_cid.to_string(), "direct_http"),
+            _ => (
+                format!("{}{}{}", portal_url, *S5_BLOB_PATH, source_cid),
+                "s5_portal",
+            ),
+        };
+
+        match download_video(&url, file_path.as_str()).await {
+            Ok(_) => println!("Video downloaded successfully from URL: {}", url),
+            Err(e) => {
+                return Err(CodedError::download(
+                    &e,
+                    format!("Failed to download video from URL {}: {}", &url, e.message()),
+                ));
+            }
+        };
+
+        source_origin
+    };
+
+    metrics::increment_counter("source_downloads_total", &[("origin", source_origin)]);
+
+    Ok((file_path, source_origin))
+}
+
+/// Returns the path `download_source` downloads, or generates, a source to.
+fn source_file_path(orig_source_cid: &str) -> Option<String> {
+    if let Some(test_pattern) = parse_test_pattern(orig_source_cid) {
+        return test_pattern
+            .ok()
+            .map(|test_pattern| format!("{}{}", *PATH_TO_FILE, test_pattern.file_name()));
+    }
+    source_file_name(orig_source_cid).map(|source_cid| format!("{}{}", *PATH_TO_FILE, source_cid))
+}
+
+/// Returns the path `download_and_join_sources` joins sources to.
+fn joined_source_path(source_cids: &[String], normalize: bool) -> String {
+    format!(
+        "{}concat_{}{}.mkv",
+        *PATH_TO_FILE,
+        blake3::hash(source_cids.join(",").as_bytes()).to_hex(),
+        if normalize { "_normalized" } else { "" }
+    )
+}
+
+// HashMap<downloaded source path, number of running tasks using it>
+static ACTIVE_SOURCES: Lazy<std::sync::Mutex<HashMap<String, usize>>> =
+    Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
+
+/// Marks a task's source files as in use for as long as it lives. If the task set
+/// `delete_source_after`, each file is deleted when the guard is dropped, unless another running
+/// task is still using it.
+struct ActiveSourceGuard {
+    paths: Vec<String>,
+    delete_after: bool,
+}
+
+impl ActiveSourceGuard {
+    fn new(paths: Vec<String>, delete_after: bool) -> Self {
+        let mut active_sources = ACTIVE_SOURCES.lock().unwrap();
+        for path in &paths {
+            *active_sources.entry(path.clone()).or_insert(0) += 1;
+            source_cache::touch(path);
+        }
+        ActiveSourceGuard {
+            paths,
+            delete_after,
+        }
+    }
+}
+
+impl Drop for ActiveSourceGuard {
+    fn drop(&mut self) {
+        let mut active_sources = ACTIVE_SOURCES.lock().unwrap();
+        for path in &self.paths {
+            let in_use = match active_sources.get_mut(path) {
+                Some(count) if *count > 1 => {
+                    *count -= 1;
+                    true
+                }
+                _ => {
+                    active_sources.remove(path);
+                    false
+                }
+            };
+
+            if self.delete_after && !in_use {
+                match fs::remove_file(path) {
+                    Ok(()) => println!("Deleted source {}", path),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => eprintln!("Failed to delete source {}: {}", path, e),
+                }
+            } else if self.delete_after {
+                println!("Source {} is still in use by another task, not deleting it", path);
+            }
+        }
+    }
+}
+
+/// Sends a `task_started` callback for a task the worker has started on.
+fn send_task_started_callback(
+    task_id: &str,
+    callback_url: &str,
+    callback_headers: &[(String, String)],
+) {
+    let task_id = task_id.to_string();
+    let callback_url = callback_url.to_string();
+    let callback_headers = callback_headers.to_vec();
+    tokio::spawn(async move {
+        let body = json!({
+            "event": "task_started",
+            "task_id": task_id,
+            "timestamp": Utc::now().to_rfc3339(),
+        });
+        if let Err(e) =
+            callback::send_callback(&callback_url, &body, &callback_headers, *CALLBACK_RETRIES).await
+        {
+            warn!(task_id = %task_id, "Failed to send task callback: {}", e);
+        }
+    });
+}
+
+/// POSTs a task's outcome to its `callback_url` with its `callback_headers` when dropped, however
+/// the worker finished with it. The task's status must be recorded by then. Forgotten instead of
+/// dropped when the task is retried, so only its last attempt is reported.
+struct TaskCallbackGuard {
+    task_id: String,
+    callback_url: String,
+    callback_headers: Vec<(String, String)>,
+}
+
+impl Drop for TaskCallbackGuard {
+    fn drop(&mut self) {
+        let task_id = std::mem::take(&mut self.task_id);
+        let callback_url = std::mem::take(&mut self.callback_url);
+        let callback_headers = std::mem::take(&mut self.callback_headers);
+        tokio::spawn(async move {
+            let status = TASK_STATUS
+                .lock()
+                .await
+                .get(&task_id)
+                .copied()
+                .unwrap_or(TaskStatus::Unknown);
+            let task_metadata = TASK_METADATA
+                .lock()
+                .await
+                .get(&task_id)
+                .and_then(|task_metadata| serde_json::from_str::<Value>(task_metadata).ok())
+                .unwrap_or(Value::Null);
+
+            let body = json!({
+                "event": "task_finished",
+                "task_id": task_id,
+                "status": status.as_str_name(),
+                "task_metadata": task_metadata,
+                "timestamp": Utc::now().to_rfc3339(),
+            });
+            if let Err(e) =
+                callback::send_callback(&callback_url, &body, &callback_headers, *CALLBACK_RETRIES).await
+            {
+                warn!(task_id = %task_id, "Failed to send task callback: {}", e);
+            }
+        });
+    }
+}
+
+/// Returns whether a running task is using, or still downloading, the source file at `path`.
+fn is_source_in_use(path: &str) -> bool {
+    ACTIVE_SOURCES.lock().unwrap().contains_key(path) || coalesce::is_in_flight(path)
+}
+
+/// Downloads each source of a multi-source task and joins them into a single file with ffmpeg's
+/// concat demuxer, normalizing them first if their streams are not compatible.
+///
+/// # Arguments
+/// * `source_cids` - The source CIDs in the order they are joined.
+/// * `is_encrypted` - Whether the sources are encrypted.
+/// * `normalize` - Whether to normalize the sources even if their streams are compatible.
+/// * `task_id` - The task downloading the sources.
+///
+/// # Returns
+/// The path to the joined source file and the distinct origins its sources were fetched from,
+/// comma-separated, or an error message.
+///
+async fn download_and_join_sources(
+    source_cids: &[String],
+    is_encrypted: bool,
+    normalize: bool,
+    task_id: &str,
+) -> Result<(String, String), CodedError> {
+    let joined_file_path = joined_source_path(source_cids, normalize);
+
+    let _join = coalesce::begin(&joined_file_path).await;
+    if Path::new(&joined_file_path).exists() {
+        println!("File already exists: {}", &joined_file_path);
+        metrics::increment_counter("source_downloads_total", &[("origin", "local_cache")]);
+        return Ok((joined_file_path, "local_cache".to_string()));
+    }
+
+    let mut file_paths = Vec::new();
+    let mut source_origins: Vec<&str> = Vec::new();
+    for source_cid in source_cids {
+        let (file_path, source_origin) = download_source(source_cid, is_encrypted, task_id).await?;
+        file_paths.push(file_path);
+        if !source_origins.contains(&source_origin) {
+            source_origins.push(source_origin);
+        }
+    }
+
+    concat::join_sources(&file_paths, &joined_file_path, normalize).map_err(|e| {
+        TranscodeErrorCode::EncodeFailed.error(format!("Failed to join sources: {}", e))
+    })?;
+
+    Ok((joined_file_path, source_origins.join(",")))
+}
+
+/// Deletes the files of a task aborted for exceeding `MAX_TASK_DISK_BYTES`: those it recorded with
+/// `shared::record_task_file` that no later task has recorded since, and its source unless another
+/// running task is using it.
+///
+/// # Arguments
+/// * `task_id` - The task's id.
+/// * `file_path` - The path of the task's source.
+///
+fn remove_task_files(task_id: &str, file_path: &str) {
+    for path in shared::task_files(task_id) {
+        match fs::remove_file(&path) {
+            Ok(()) => println!("Removed output {}", path),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => eprintln!("Failed to remove output {}: {}", path, e),
+        }
+    }
+    transcode_video::remove_empty_output_dirs(PATH_TO_TRANSCODED_FILE.as_str());
+
+    // Only this task's guard marks the source in use
+    let is_shared = ACTIVE_SOURCES.lock().unwrap().get(file_path).is_some_and(|count| *count > 1);
+    if !is_shared {
+        match fs::remove_file(file_path) {
+            Ok(()) => println!("Removed source {}", file_path),
+            Err(e) => eprintln!("Failed to remove source {}: {}", file_path, e),
+        }
+    }
+}
+
+/// Downloads the files a task's formats refer to by CID or URL, their `preset_file` and
+/// `audio_description` track, cached like sources, so they can be read when the formats are
+/// transcoded.
+///
+/// # Arguments
+/// * `media_formats_vec` - The task's media formats.
+/// * `task_id` - The task downloading the files.
+///
+/// # Returns
+/// The paths of the downloaded files, or an error message.
+///
+async fn download_format_files(
+    media_formats_vec: &[Value],
+    task_id: &str,
+) -> Result<Vec<String>, String> {
+    let mut file_paths = Vec::new();
+    let preset_cids = media_formats_vec
+        .iter()
+        .filter_map(|video_format| video_format["preset_file"].as_str())
+        .filter(|preset_file| preset_file.contains("://"))
+        .map(|preset_cid| ("preset_file", preset_cid));
+    let description_cids = media_formats_vec
+        .iter()
+        .filter_map(|video_format| video_format["audio_description"]["cid"].as_str())
+        .map(|description_cid| ("audio_description", description_cid));
+
+    for (option, cid) in preset_cids.chain(description_cids) {
+        let (file_path, _) = download_source(cid, false, task_id)
+            .await
+            .map_err(|e| format!("Failed to download {} {}: {}", option, cid, e))?;
+        if !file_paths.contains(&file_path) {
+            file_paths.push(file_path);
+        }
+    }
+
+    Ok(file_paths)
+}
+
+/// Parses the media formats of a task, or of the file given by `MEDIA_FORMATS_FILE` if the task has
+/// none.
+///
+/// # Arguments
+/// * `media_formats` - The media formats JSON the task was submitted with, empty for the default.
+///
+/// # Returns
+/// The media formats, with a `name_suffix` on formats that share an `id` with an earlier one, or an
+/// error message if there are none, they cannot be read, a format's `priority` is not an integer
+/// or `NAMING_COLLISION_STRATEGY` rejects formats sharing an id.
+///
+fn resolve_media_formats(media_formats: &str) -> Result<Vec<Value>, String> {
+    let media_formats_json = if !media_formats.trim().is_empty() {
+        media_formats.to_string()
+    } else {
+        let media_formats_file = var("MEDIA_FORMATS_FILE")
+            .ok()
+            .filter(|media_formats_file| !media_formats_file.is_empty())
+            .ok_or("No media formats provided and no default configured in MEDIA_FORMATS_FILE")?;
+        read_to_string(&media_formats_file).map_err(|e| {
+            format!(
+                "No media formats provided and the default media formats file {} could not be read: {}",
+                media_formats_file, e
+            )
+        })?
+    };
+
+    println!("media_formats_json: {}", media_formats_json);
+    let mut media_formats_vec: Vec<Value> = serde_json::from_str(&media_formats_json)
+        .map_err(|e| format!("Failed to parse media formats: {}", e))?;
+    if media_formats_vec.is_empty() {
+        return Err("No media formats provided".to_string());
+    }
+
+    // Outputs are named after the source and the format's id, so formats sharing an id would
+    // overwrite each other's outputs
+    let ids: Vec<Option<String>> = media_formats_vec
+        .iter()
+        .map(|video_format| video_format["id"].as_u64().map(|id| id.to_string()))
+        .collect();
+    let names = transcode_video::resolve_name_collisions(
+        ids.clone(),
+        transcode_video::naming_collision_strategy()?,
+    )?;
+    for (video_format, (id, name)) in media_formats_vec.iter_mut().zip(ids.iter().zip(names)) {
+        if let Some(video_format) = video_format.as_object_mut() {
+            video_format.remove("name_suffix");
+            if let (Some(id), Some(name)) = (id, name) {
+                if name != *id {
+                    video_format.insert("name_suffix".to_string(), json!(name[id.len()..]));
+                }
+            }
+        }
+    }
+
+    for video_format in &media_formats_vec {
+        if let Some(priority) = video_format.get("priority") {
+            if priority.as_i64().is_none() {
+                return Err(format!(
+                    "Format {} has priority {}, which must be an integer",
+                    video_format["id"], priority
+                ));
+            }
+        }
+    }
+
+    Ok(media_formats_vec)
+}
+
+/// A transcoding task as queued by the gRPC and REST handlers and consumed by
+/// `transcode_task_receiver`.
+#[derive(Debug, Clone, Default)]
+struct TranscodeTask {
+    task_id: String,
+    source_cid: String,
+    media_formats: String,
+    is_encrypted: bool,
+    is_gpu: bool,
+    include_original: bool,
+    verify: bool,
+    source_cids: Vec<String>,
+    build_manifest: bool,
+    force: bool,
+    // Default `quality_mode` for formats that don't set their own; empty for none
+    quality_mode: String,
+    // Once the renditions produced add up to this many bytes the rest are skipped; 0 for no limit
+    max_total_output_bytes: u64,
+    // Delete the downloaded source as soon as the task finishes instead of leaving it to the GC
+    delete_source_after: bool,
+    // Standardize the source's pixel format, frame rate and timebase before transcoding it
+    normalize: bool,
+    // Append snapshots of the task's progress to its progress log while it runs
+    log_progress: bool,
+    // URL the task's outcome is POSTed to when it finishes; empty for none
+    callback_url: String,
+    // Extra headers of the callback, validated by `callback::validate_callback`
+    callback_headers: Vec<(String, String)>,
+    // JWT subject that submitted the task, counted against `MAX_TASKS_PER_SUBJECT`
+    subject: Option<String>,
+    // Number of times the task has been retried after a transient failure
+    attempt: u32,
+    // Unix time the task was queued, or re-queued for a retry
+    queued_at: i64,
+}
+
+/// Re-queues a task that failed transiently, after a delay of `TASK_RETRY_DELAY_SECS` doubled for
+/// each earlier retry and jittered by `jittered_backoff`, unless it has already been retried
+/// `TASK_MAX_RETRIES` times.
 ///
 /// # Arguments
+/// * `task` - The task as it was dequeued.
+/// * `sender` - The sender of the task queue.
+///
+/// # Returns
+/// `true` if the task will be retried.
+///
+fn retry_task(task: &TranscodeTask, sender: &mpsc::Sender<TranscodeTask>) -> bool {
+    if task.attempt >= *TASK_MAX_RETRIES || shared::is_task_cancelled(&task.task_id) {
+        return false;
+    }
+
+    let delay = jittered_backoff(
+        std::time::Duration::from_secs(*TASK_RETRY_DELAY_SECS),
+        std::time::Duration::from_secs(*TASK_RETRY_DELAY_SECS << 16),
+        task.attempt,
+        rand::random::<f64>(),
+    );
+    warn!(
+        task_id = %task.task_id,
+        attempt = task.attempt + 1,
+        "Task failed transiently, retrying in {}s",
+        delay.as_secs()
+    );
+
+    shared::clear_failed_formats(&task.task_id);
+    let task = TranscodeTask {
+        attempt: task.attempt + 1,
+        queued_at: Utc::now().timestamp() + delay.as_secs() as i64,
+        ..task.clone()
+    };
+    let sender = sender.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        if let Err(e) = sender.send(task).await {
+            eprintln!("Failed to re-queue task for retry: {}", e);
+        }
+    });
+
+    true
+}
+
+/// Version of the manifest schema produced by `build_manifest`. Bump when fields change
+/// incompatibly.
+const MANIFEST_VERSION: u32 = 1;
+
+/// Builds the task manifest: a single JSON document referencing every output of a task, so that
+/// clients only need to keep the manifest's CID. The schema (version 1) is:
+///
+/// ```json
+/// {
+///   "version": 1,
+///   "task_id": "...",
+///   "source_cid": "...",
+///   "original_cid": "...",   // present when `include_original` was set
+///   "renditions": [ ... ]    // the successfully transcoded formats, each with its `cid`
+/// }
+/// ```
 ///
-/// * `prefix` - The prefix to use for the filename.
-/// * `extension` - The extension to use for the filename.
+/// Any further CIDs recorded in the task metadata (e.g. thumbnails, subtitles or playlists) are
+/// added as top-level fields under the same key.
+///
+/// # Arguments
+/// * `task_id` - The task the manifest describes.
+/// * `source_cid` - The source the task was transcoded from.
+/// * `transcoded_formats` - The formats produced by the task.
+/// * `task_metadata` - Task-level metadata collected so far.
 ///
-fn generate_random_filename() -> String {
-    let uuid = Uuid::new_v4();
-    let timestamp = Utc::now().timestamp_nanos();
-    format!("{}_{}", uuid, timestamp)
+fn build_task_manifest(
+    task_id: &str,
+    source_cid: &str,
+    transcoded_formats: &[Value],
+    task_metadata: &serde_json::Map<String, Value>,
+) -> Value {
+    let renditions: Vec<&Value> = transcoded_formats
+        .iter()
+        .filter(|format| format.get("cid").is_some() && format.get("error").is_none())
+        .collect();
+
+    let mut manifest = serde_json::Map::new();
+    manifest.insert("version".to_string(), json!(MANIFEST_VERSION));
+    manifest.insert("task_id".to_string(), json!(task_id));
+    manifest.insert("source_cid".to_string(), json!(source_cid));
+
+    for (key, value) in task_metadata.iter() {
+        if key.ends_with("_cid") {
+            manifest.insert(key.clone(), value.clone());
+        }
+    }
+
+    manifest.insert("renditions".to_string(), json!(renditions));
+
+    Value::Object(manifest)
 }
 
 /// Asynchronously receives transcoding tasks from a channel and processes them using the specified transcoder. Each
@@ -190,174 +1237,430 @@ fn generate_random_filename() -> String {
 /// is sent through another channel to indicate completion.
 ///
 /// # Arguments
-/// * `receiver` - An `Arc<Mutex<mpsc::Receiver<(String, String, String, bool, bool)>>>` representing a shared receiver
-///   channel for transcoding tasks. Each task includes the input file path, output file path, desired format,
-///   encryption flag, and GPU usage flag.
+/// * `receiver` - An `Arc<Mutex<mpsc::Receiver<TranscodeTask>>>` representing a shared receiver channel for
+///   transcoding tasks. Each task includes the task id, source CID, desired formats, encryption flag, GPU usage
+///   flag and request options.
 ///
 async fn transcode_task_receiver(
-    receiver: Arc<Mutex<mpsc::Receiver<(String, String, String, bool, bool)>>>,
+    receiver: Arc<Mutex<mpsc::Receiver<TranscodeTask>>>,
+    sender: mpsc::Sender<TranscodeTask>,
 ) {
-    while let Some((task_id, orig_source_cid, media_formats, is_encrypted, is_gpu)) =
-        receiver.lock().await.recv().await
-    {
-        let source_cid = Path::new(&orig_source_cid)
-            .with_extension("")
-            .file_stem()
-            .and_then(|s| s.to_str())
-            .map(|s| s.to_string());
-
-        if source_cid.is_none() {
-            eprintln!("Invalid source CID: {}", orig_source_cid);
+    loop {
+        wait_while_paused().await;
+        let task = match receiver.lock().await.recv().await {
+            Some(task) => task,
+            None => break,
+        };
+        // The worker may have been paused while it was waiting for a task
+        wait_while_paused().await;
+
+        // Kept to re-queue the task if it fails transiently
+        let dequeued_task = task.clone();
+
+        let TranscodeTask {
+            task_id,
+            source_cid: orig_source_cid,
+            media_formats,
+            is_encrypted,
+            is_gpu,
+            include_original,
+            verify,
+            source_cids,
+            build_manifest,
+            force,
+            quality_mode,
+            max_total_output_bytes,
+            delete_source_after,
+            normalize,
+            log_progress,
+            callback_url,
+            callback_headers,
+            subject,
+            attempt,
+            queued_at,
+        } = task;
+
+        // Retries of a task are reported as one task, which started with its first attempt
+        if attempt == 0 && !callback_url.is_empty() {
+            send_task_started_callback(&task_id, &callback_url, &callback_headers);
+        }
+
+        // Frees the subject's task slot however this iteration finishes, unless the task is retried
+        let task_slot = subject.map(quota::TaskSlotGuard);
+        // Reports the task's outcome however this iteration finishes, unless the task is retried
+        let task_callback = (!callback_url.is_empty()).then(|| TaskCallbackGuard {
+            task_id: task_id.clone(),
+            callback_url,
+            callback_headers,
+        });
+        // A retried task starts counting its disk footprint again
+        shared::clear_task_disk_usage(&task_id);
+
+        info!(task_id = %task_id, source_cid = %orig_source_cid, "Transcoding task received");
+
+        if shared::is_task_cancelled(&task_id) {
+            info!(task_id = %task_id, "Skipping task cancelled while queued");
+            store_transcoded(&task_id, "[]".to_string()).await;
+            TASK_STATUS.lock().await.insert(task_id.clone(), TaskStatus::Cancelled);
             continue;
         }
 
-        let storage_network: Option<&str> = orig_source_cid.split_once("://").map(|(network, _)| network);
-        if storage_network.is_none() {
-            eprintln!("Invalid source CID: {}", orig_source_cid);
+        let queue_wait_secs = Utc::now().timestamp() - queued_at;
+        if *MAX_QUEUE_WAIT_SECS > 0 && queue_wait_secs > *MAX_QUEUE_WAIT_SECS {
+            let e = format!(
+                "Queue wait exceeded: the task waited {}s to start, more than MAX_QUEUE_WAIT_SECS of {}s",
+                queue_wait_secs, *MAX_QUEUE_WAIT_SECS
+            );
+            let error_code = TranscodeErrorCode::QueueWaitExceeded;
+            warn!(task_id = %task_id, source_cid = %orig_source_cid, error_code = %error_code, "{}", e);
+            metrics::increment_counter("tasks_expired_total", &[]);
+
+            TASK_METADATA
+                .lock()
+                .await
+                .insert(task_id.clone(), json!({ "error": e, "error_code": error_code }).to_string());
+            store_transcoded(&task_id, "[]".to_string()).await;
+            TASK_STATUS.lock().await.insert(task_id.clone(), TaskStatus::Expired);
+            dead_letter::record_failed_task(
+                &task_id,
+                &orig_source_cid,
+                &media_formats,
+                is_encrypted,
+                is_gpu,
+                &e,
+                error_code,
+            );
             continue;
         }
 
-        let source_cid = source_cid.unwrap();
+        // Logs until this iteration finishes, however it finishes
+        let _progress_logger = log_progress.then(|| progress_log::start(&task_id));
+
+        wait_for_disk_space(&task_id).await;
 
-        let portal_url_result = if is_encrypted {
-            var("PORTAL_ENCRYPT_URL")
+        let orig_source_cid = if source_cids.is_empty() {
+            orig_source_cid
         } else {
-            var("PORTAL_URL")
+            source_cids.join(",")
         };
 
-        let portal_url = match portal_url_result {
-            Ok(url) => url,
-            Err(_) => {
-                eprintln!("Required environment variable for PORTAL_URL not found");
-                continue; // Skip the rest of this loop iteration
-            }
-        };
+        let storage_network: Option<&str> = orig_source_cid.split_once("://").map(|(network, _)| network);
 
-        println!("source_cid: {}", source_cid);
-        println!("portal_url: {}", portal_url);
+        // Marked in use before the download starts, so neither the garbage collector nor an
+        // eviction deletes the source while it is downloaded. Joined sources are downloaded
+        // individually first, so their downloads are deleted too.
+        let source_paths: Vec<String> = if source_cids.is_empty() {
+            source_file_path(&orig_source_cid).into_iter().collect()
+        } else {
+            std::iter::once(joined_source_path(&source_cids, normalize))
+                .chain(source_cids.iter().filter_map(|cid| source_file_path(cid)))
+                .collect()
+        };
+        let _active_source = ActiveSourceGuard::new(source_paths, delete_source_after);
 
-        let file_path = format!("{}{}", *PATH_TO_FILE, source_cid);
+        let file_path_result = if source_cids.is_empty() {
+            download_source(&orig_source_cid, is_encrypted, &task_id)
+                .await
+                .map(|(file_path, source_origin)| (file_path, source_origin.to_string()))
+        } else {
+            download_and_join_sources(&source_cids, is_encrypted, normalize, &task_id).await
+        };
 
-        if !Path::new(&file_path).exists() {
-            if is_encrypted {
-                println!("source_cid: {}", source_cid);
-                let base64_url_encrypted_blob_hash =
-                    get_base64_url_encrypted_blob_hash(&source_cid)
-                        .expect("Failed to get base64 URL encrypted blob hash");
+        let (file_path, source_origin) = match file_path_result {
+            Ok(result) => result,
+            Err(e) => {
+                let error_code = e.code;
+                eprintln!("{}", e);
+                error!(task_id = %task_id, source_cid = %orig_source_cid, error_code = %error_code, "{}", e);
+
+                if e.transient && retry_task(&dequeued_task, &sender) {
+                    // The retry keeps the subject's slot and reports the outcome itself
+                    std::mem::forget(task_slot);
+                    std::mem::forget(task_callback);
+                    continue;
+                }
 
-                let url = format!(
-                    "{}{}{}?types=5,3",
-                    portal_url, "/api/locations/", base64_url_encrypted_blob_hash
+                store_transcoded(&task_id, "[]".to_string()).await;
+                TASK_METADATA
+                    .lock()
+                    .await
+                    .insert(task_id.clone(), json!({ "error": e.message, "error_code": error_code }).to_string());
+                TASK_STATUS.lock().await.insert(task_id.clone(), TaskStatus::Failed);
+                dead_letter::record_failed_task(
+                    &task_id,
+                    &orig_source_cid,
+                    &media_formats,
+                    is_encrypted,
+                    is_gpu,
+                    &e.message,
+                    error_code,
                 );
-                println!("Downloading and then transcoding video from URL: {}", &url);
-
-                let encrypted_file_path = format!("{}{}_", *PATH_TO_FILE, source_cid);
+                continue;
+            }
+        };
 
-                match download_video(&url, encrypted_file_path.as_str()).await {
-                    Ok(_) => println!("Video downloaded successfully"),
-                    Err(e) => {
-                        eprintln!(
-                            "Failed to download encrypted video from URL {}: {}",
-                            &url, e
-                        );
-                        continue;
-                    }
-                };
+        let mut task_metadata = serde_json::Map::new();
+        task_metadata.insert("source_origin".to_string(), json!(source_origin));
 
-                let encrypted_metadata = match std::fs::read_to_string(&encrypted_file_path) {
-                    Ok(contents) => contents,
-                    Err(e) => {
-                        eprintln!(
-                            "Failed to read encrypted metadata from file {}: {}",
-                            &encrypted_file_path, e
-                        );
-                        continue;
+        if include_original && !verify {
+            if is_encrypted && !*UPLOAD_DECRYPTED_ORIGINAL && source_cids.is_empty() {
+                // The encrypted source is already addressable by its own CID
+                task_metadata.insert("original_cid".to_string(), json!(orig_source_cid));
+            } else {
+                let dest = storage_network.map(|network| network.to_string());
+                let is_ipfs = dest.as_deref() == Some("ipfs");
+
+                match s5::upload_video(file_path.as_str(), dest).await {
+                    Ok(cid) => {
+                        let original_cid = if is_ipfs {
+                            format!("ipfs://{}", cid)
+                        } else {
+                            format!("s5://{}", cid)
+                        };
+                        println!("Original uploaded with cid: {}", original_cid);
+                        task_metadata.insert("original_cid".to_string(), json!(original_cid));
                     }
-                };
-
-                let file_path_encrypted =
-                    format!("{}{}", *PATH_TO_FILE, generate_random_filename());
+                    Err(e) => eprintln!("Failed to upload original {}: {}", &file_path, e),
+                }
+            }
+        }
 
-                println!("file_encrypted_metadata: {:?}", file_path_encrypted);
-                println!("encrypted_metadata: {:?}", encrypted_metadata);
+        // Joined sources were already normalized while joining
+        let normalized_path = format!("{}_normalized.mkv", file_path);
+        let normalize_source = normalize && source_cids.is_empty();
+        let file_path = if normalize_source {
+            let _normalization = coalesce::begin(&normalized_path).await;
+            if Path::new(&normalized_path).exists() {
+                println!("File already exists: {}", &normalized_path);
+            } else if let Err(e) = concat::normalize_single_source(&file_path, &normalized_path) {
+                let e = format!("Failed to normalize source: {}", e);
+                let error_code = TranscodeErrorCode::EncodeFailed;
+                error!(task_id = %task_id, source_cid = %orig_source_cid, error_code = %error_code, "{}", e);
+                let _ = fs::remove_file(&normalized_path);
+
+                store_transcoded(&task_id, "[]".to_string()).await;
+                TASK_METADATA
+                    .lock()
+                    .await
+                    .insert(task_id.clone(), json!({ "error": e, "error_code": error_code }).to_string());
+                TASK_STATUS.lock().await.insert(task_id.clone(), TaskStatus::Failed);
+                dead_letter::record_failed_task(
+                    &task_id,
+                    &orig_source_cid,
+                    &media_formats,
+                    is_encrypted,
+                    is_gpu,
+                    &e,
+                    error_code,
+                );
+                continue;
+            }
+            normalized_path
+        } else {
+            file_path
+        };
+        let normalized_paths = if normalize_source { vec![file_path.clone()] } else { Vec::new() };
+        let _normalized_source = ActiveSourceGuard::new(normalized_paths, delete_source_after);
+
+        let mut media_formats_vec = match resolve_media_formats(&media_formats) {
+            Ok(media_formats_vec) => media_formats_vec,
+            Err(e) => {
+                let error_code = TranscodeErrorCode::InvalidFormat;
+                eprintln!("{}", e);
+                error!(task_id = %task_id, source_cid = %orig_source_cid, error_code = %error_code, "{}", e);
+
+                task_metadata.insert("error".to_string(), json!(e));
+                task_metadata.insert("error_code".to_string(), json!(error_code));
+                store_transcoded(&task_id, "[]".to_string()).await;
+                TASK_METADATA
+                    .lock()
+                    .await
+                    .insert(task_id.clone(), Value::Object(task_metadata).to_string());
+                TASK_STATUS.lock().await.insert(task_id.clone(), TaskStatus::Failed);
+                dead_letter::record_failed_task(
+                    &task_id,
+                    &orig_source_cid,
+                    &media_formats,
+                    is_encrypted,
+                    is_gpu,
+                    &e,
+                    error_code,
+                );
+                continue;
+            }
+        };
 
-                match download_and_concat_files(encrypted_metadata, file_path_encrypted.clone())
+        let format_file_paths = match download_format_files(&media_formats_vec, &task_id).await {
+            Ok(format_file_paths) => format_file_paths,
+            Err(e) => {
+                let error_code = TranscodeErrorCode::DownloadFailed;
+                eprintln!("{}", e);
+                error!(task_id = %task_id, source_cid = %orig_source_cid, error_code = %error_code, "{}", e);
+
+                task_metadata.insert("error".to_string(), json!(e));
+                task_metadata.insert("error_code".to_string(), json!(error_code));
+                store_transcoded(&task_id, "[]".to_string()).await;
+                TASK_METADATA
+                    .lock()
                     .await
-                {
-                    Ok(()) => println!("Download and concatenation succeeded"),
-                    Err(e) => eprintln!("Download and concatenation failed: {}", e),
+                    .insert(task_id.clone(), Value::Object(task_metadata).to_string());
+                TASK_STATUS.lock().await.insert(task_id.clone(), TaskStatus::Failed);
+                dead_letter::record_failed_task(
+                    &task_id,
+                    &orig_source_cid,
+                    &media_formats,
+                    is_encrypted,
+                    is_gpu,
+                    &e,
+                    error_code,
+                );
+                continue;
+            }
+        };
+        // Kept from the garbage collector while the formats are transcoded
+        let _active_format_files = ActiveSourceGuard::new(format_file_paths, false);
+
+        // Recorded on each format so renditions of the normalized source are cached apart
+        if normalize {
+            for video_format in media_formats_vec.iter_mut() {
+                if let Some(video_format) = video_format.as_object_mut() {
+                    video_format.insert("normalize".to_string(), json!(true));
                 }
+            }
+        }
+
+        // The task's quality mode applies to every format without one of its own
+        if !quality_mode.is_empty() {
+            for video_format in media_formats_vec.iter_mut() {
+                if let Some(video_format) = video_format.as_object_mut() {
+                    video_format
+                        .entry("quality_mode")
+                        .or_insert_with(|| json!(quality_mode));
+                }
+            }
+        }
 
-                let file_encrypted_size = get_file_size(file_path_encrypted.clone()).unwrap();
-                println!("file_path_encrypted: {}", file_path_encrypted);
-                println!("file_encrypted_size: {}", file_encrypted_size);
-
-                let last_index_size =
-                    (file_encrypted_size as f64 / (262144 + 16) as f64).floor() as u32;
-
-                let key = get_key_from_encrypted_cid(&source_cid);
-                let key_bytes = base64url_to_bytes(&key);
-
-                println!("file_path: {}", file_path);
-                println!("key: {}", key);
-                println!("key_bytes: {:?}", key_bytes);
-                println!("last_index_size: {}", last_index_size);
-
-                match decrypt_file_xchacha20(
-                    file_path_encrypted,
-                    file_path.clone(),
-                    key_bytes,
-                    0,
-                    last_index_size,
-                ) {
-                    Ok(_) => println!("Decryption succeeded"),
-                    Err(error) => {
-                        eprintln!("Decryption error: {:?}", error);
-                        continue;
+        // Formats with a higher `priority` are transcoded and listed first; the sort is stable, so
+        // formats of equal priority keep their order. The priority is removed so it doesn't change
+        // the formats' cache keys
+        media_formats_vec.sort_by_key(|video_format| {
+            std::cmp::Reverse(video_format["priority"].as_i64().unwrap_or(0))
+        });
+        for video_format in media_formats_vec.iter_mut() {
+            if let Some(video_format) = video_format.as_object_mut() {
+                video_format.remove("priority");
+            }
+        }
+
+        if verify {
+            // Report the renditions that would be produced without running ffmpeg
+            let mut renditions = Vec::new();
+            for video_format in media_formats_vec.iter() {
+                let mut rendition = video_format.clone();
+                let video_format_str = video_format.to_string();
+
+                match resolve_ffmpeg_args(&file_path, &video_format_str, is_gpu) {
+                    Ok(args) => {
+                        let format = get_video_format_from_str(&video_format_str).ok();
+                        let encrypt_flag = format
+                            .as_ref()
+                            .and_then(|format| format.encrypt)
+                            .unwrap_or(is_encrypted);
+                        let cache_key =
+                            cache::cache_key(&orig_source_cid, video_format, encrypt_flag);
+
+                        let skip = !force
+                            && transcode_video::existing_output_path(
+                                &file_path,
+                                &video_format_str,
+                                encrypt_flag,
+                            )
+                            .is_some();
+
+                        rendition["ffmpeg_args"] = json!(args);
+                        rendition["skip"] = json!(skip);
+                        rendition["cached_cid"] = json!(cache::get_cached_cid(&cache_key));
                     }
+                    Err(e) => rendition["error"] = json!(e.message()),
                 }
-            } else {
-                match storage_network.as_deref() {
-                    Some("ipfs") => {
-                        let url = format!("{}{}{}", *IPFS_GATEWAY, "/ipfs/", source_cid);
-
-                        match download_video(&url, file_path.as_str()).await {
-                            Ok(_) => println!("Video downloaded successfully from URL: {}", url),
-                            Err(e) => {
-                                eprintln!("Failed to download video from URL {}: {}", &url, e);
-                                continue;
-                            }
-                        };                    
-                    },
-                    _ => 
-                    {
-                        let url = format!("{}{}{}", portal_url, "/s5/blob/", source_cid);
-
-                        match download_video(&url, file_path.as_str()).await {
-                            Ok(_) => println!("Video downloaded successfully from URL: {}", url),
-                            Err(e) => {
-                                eprintln!("Failed to download video from URL {}: {}", &url, e);
-                                continue;
-                            }
-                        };        
-                    },
+                renditions.push(rendition);
+            }
+
+            task_metadata.insert("verify".to_string(), json!(true));
+            match probe::probe_source(&file_path) {
+                Ok(source_probe) => {
+                    task_metadata.insert("source_probe".to_string(), json!(source_probe));
+                }
+                Err(e) => {
+                    task_metadata.insert("source_probe_error".to_string(), json!(e));
                 }
             }
-        } else {
-            println!("File already exists: {}", &file_path);
-        }
 
-        let media_formats_file = var("MEDIA_FORMATS_FILE").unwrap();
+            let renditions_json = Value::Array(renditions).to_string();
+            store_transcoded(&task_id, renditions_json).await;
+            TASK_METADATA
+                .lock()
+                .await
+                .insert(task_id.clone(), Value::Object(task_metadata).to_string());
+            shared::update_progress(&task_id, 0, 100);
+            TASK_STATUS.lock().await.insert(task_id.clone(), TaskStatus::Completed);
+            continue;
+        }
 
-        let media_formats_json = if !media_formats.is_empty() {
-            media_formats.clone()
-        } else {
-            read_to_string(media_formats_file.as_str()).expect("Failed to read video format file")
-        };
+        // Fail the task early rather than launching ffmpeg on a source it cannot read
+        let source_check = probe::probe_source(&file_path)
+            .map_err(|e| format!("Source appears corrupt or empty: {}", e))
+            .and_then(|source_probe| {
+                probe::check_source_playable(&source_probe)?;
+                Ok(source_probe)
+            });
+        if let Err(e) = &source_check {
+            let error_code = TranscodeErrorCode::InvalidSource;
+            eprintln!("{}", e);
+            error!(task_id = %task_id, source_cid = %orig_source_cid, error_code = %error_code, "{}", e);
+
+            task_metadata.insert("error".to_string(), json!(e));
+            task_metadata.insert("error_code".to_string(), json!(error_code));
+            store_transcoded(&task_id, "[]".to_string()).await;
+            TASK_METADATA
+                .lock()
+                .await
+                .insert(task_id.clone(), Value::Object(task_metadata).to_string());
+            for index in 0..media_formats_vec.len() {
+                shared::mark_format_failed(&task_id, index);
+                shared::update_progress(&task_id, index, 100);
+            }
+            TASK_STATUS.lock().await.insert(task_id.clone(), TaskStatus::Failed);
+            dead_letter::record_failed_task(
+                &task_id,
+                &orig_source_cid,
+                &media_formats,
+                is_encrypted,
+                is_gpu,
+                e,
+                error_code,
+            );
+            continue;
+        }
 
-        println!("media_formats_json: {}", media_formats_json);
-        let media_formats_vec: Vec<Value> =
-            serde_json::from_str(&media_formats_json).expect("Failed to parse video formats");
+        // ffmpeg reads the source as the container it detects, whatever extension its CID has
+        if let Ok(source_probe) = &source_check {
+            if let Some(container) = source_probe.format.format_name.as_deref() {
+                task_metadata.insert("source_container".to_string(), json!(container));
+            }
+            // A URL's query string or fragment is not part of its extension
+            let source_path = orig_source_cid.split(['?', '#']).next().unwrap_or_default();
+            let extension = Path::new(source_path)
+                .extension()
+                .map(|ext| ext.to_string_lossy().to_string())
+                .filter(|_| source_cids.is_empty());
+            if let Some(extension) = extension {
+                if !source_probe.format.matches_extension(&extension) {
+                    warn!(task_id = %task_id, source_cid = %orig_source_cid, "Source extension {} does not match its container {:?}", extension, source_probe.format.format_name);
+                    task_metadata.insert("source_extension_mismatch".to_string(), json!(extension));
+                }
+            }
+        }
 
         // Initialize progress to 0 at the start for all formats
         let formats_count = media_formats_vec.len();
@@ -365,9 +1668,50 @@ async fn transcode_task_receiver(
             shared::update_progress(&task_id, i, 0);
         }
 
+        // Weight each format's share of the overall progress by its estimated encode cost
+        for (index, video_format) in media_formats_vec.iter().enumerate() {
+            if let Ok(format) = get_video_format_from_str(&video_format.to_string()) {
+                shared::set_progress_weight(&task_id, index, format.estimated_cost());
+                shared::set_format_id(&task_id, index, format.id);
+            }
+        }
+
+        let input_size = get_file_size(file_path.clone()).unwrap_or_default();
+        // The source counts against the task's disk quota along with its outputs
+        shared::add_task_disk_bytes(&task_id, input_size);
+        let mut total_output_size: u64 = 0;
+        let mut has_transient_failure = false;
+
         // Then, we transcode the downloaded video with each video format
         let mut transcoded_formats = Vec::new();
+        let mut hls_keys = Vec::new();
         for (index, video_format) in media_formats_vec.iter().enumerate() {
+            if shared::is_task_cancelled(&task_id) {
+                info!(task_id = %task_id, "Task cancelled, skipping its remaining formats");
+                break;
+            }
+            if !shared::check_task_disk_quota(&task_id) {
+                warn!(task_id = %task_id, "Task exceeded MAX_TASK_DISK_BYTES, skipping its remaining formats");
+                break;
+            }
+
+            if max_total_output_bytes > 0 && total_output_size >= max_total_output_bytes {
+                let reason = format!(
+                    "Skipped: renditions already total {} bytes, reaching max_total_output_bytes of {}",
+                    total_output_size, max_total_output_bytes
+                );
+                warn!(task_id = %task_id, format_index = index, "{}", reason);
+
+                shared::mark_format_failed(&task_id, index);
+                task_metadata.insert("reason".to_string(), json!("max_total_output_bytes reached"));
+
+                let mut video_format_modified = video_format.clone();
+                video_format_modified["error"] = json!(reason);
+                video_format_modified["error_code"] = json!(TranscodeErrorCode::OutputBudgetExceeded);
+                transcoded_formats.push(video_format_modified);
+                continue;
+            }
+
             let video_format_str = match serde_json::to_string(&video_format) {
                 Ok(str) => str,
                 Err(e) => {
@@ -381,75 +1725,357 @@ async fn transcode_task_receiver(
                 Ok(format) => format,
                 Err(e) => {
                     eprintln!("Failed to get video format from string: {}", e);
+                    shared::mark_format_failed(&task_id, index);
+
+                    let mut video_format_modified = video_format.clone();
+                    video_format_modified["error"] = json!(e.message());
+                    video_format_modified["error_code"] = json!(TranscodeErrorCode::InvalidFormat);
+                    transcoded_formats.push(video_format_modified);
                     continue; // Skip the rest of this loop iteration
                 }
             };
 
-            if !check_transcoded_file_exists(
-                file_path.as_str(),
-                &format.id.to_string(),
-                format.ext.as_str(),
+            let encrypt_flag = format.encrypt.unwrap_or(is_encrypted);
+            let cache_key = cache::cache_key(&orig_source_cid, video_format, encrypt_flag);
+
+            // Keyed by the cache key, so tasks encoding the same source with the same settings
+            // concurrently take turns and the later ones are served from the cache
+            let rendition = coalesce::begin(&format!("rendition_{}", cache_key)).await;
+            // Keyed by the output's file name, so renditions that would write the same file, such
+            // as formats with the same id and different settings, don't overwrite each other
+            let _output = coalesce::begin(&format!("{}_{}", file_path, format.id)).await;
+
+            // `force` bypasses both the transcode cache and the existing-output check
+            let cached_rendition = if force {
+                None
+            } else {
+                cache::get_cached_rendition(&cache_key)
+            };
+
+            if let Some(cached_rendition) = cached_rendition {
+                println!("Transcode cache hit for format {}: {}", format.id, cached_rendition["cid"]);
+                if rendition.waited {
+                    info!(task_id = %task_id, format_id = format.id, "Reused a rendition encoded concurrently by another task");
+                    metrics::increment_counter("coalesced_work_total", &[("kind", "rendition")]);
+                }
+
+                let mut video_format_modified = video_format.clone();
+                if let Some(fields) = cached_rendition.as_object() {
+                    for (field, value) in fields {
+                        video_format_modified[field] = value.clone();
+                    }
+                }
+                transcoded_formats.push(video_format_modified);
+
+                shared::update_progress(&task_id, index, 100);
+                continue;
+            }
+
+            let transcode_result: std::prelude::v1::Result<
+                Response<TranscodeVideoResponse>,
+                Status,
+            > = transcode_video(
+                task_id.clone(),
+                index,
+                &file_path,
+                &video_format_str,
+                is_encrypted,
+                is_gpu,
+                !force,
             )
-            .await
-            {
-                let transcode_result: std::prelude::v1::Result<
-                    Response<TranscodeVideoResponse>,
-                    Status,
-                > = transcode_video(
-                    task_id.clone(),
-                    index,
-                    &file_path,
-                    &video_format_str,
-                    is_encrypted,
-                    is_gpu,
-                )
-                .await;
-
-                match transcode_result {
-                    Ok(transcode_video_response) => {
-                        // Handle the successful response
-                        let response = transcode_video_response.into_inner();
-                        println!(
-                            "Response: status_code: {}, message: {}, cid: {}",
-                            response.status_code, response.message, response.cid
-                        );
+            .await;
+
+            match transcode_result {
+                Ok(transcode_video_response) => {
+                    // Handle the successful response
+                    let response = transcode_video_response.into_inner();
+                    println!(
+                        "Response: status_code: {}, message: {}, cid: {}",
+                        response.status_code, response.message, response.cid
+                    );
+
+                    // Create a mutable clone of video_format
+                    let mut video_format_modified = video_format.clone();
+
+                    let cid = match &format.dest {
+                        Some(dest) if dest == "ipfs" => format!("ipfs://{}", response.cid),
+                        _ => format!("s5://{}", response.cid),
+                    };
+
+                    let is_uploaded = response.status_code == 200 && !response.cid.is_empty();
+                    if !is_uploaded {
+                        // The upload failed, or packaging its segments went over the disk quota
+                        shared::mark_format_failed(&task_id, index);
+                        let error_code = if shared::is_disk_quota_exceeded(&task_id) {
+                            TranscodeErrorCode::DiskQuotaExceeded
+                        } else {
+                            has_transient_failure = true;
+                            TranscodeErrorCode::UploadFailed
+                        };
+                        video_format_modified["error"] = json!(response.message);
+                        video_format_modified["error_code"] = json!(error_code);
+                    }
 
-                        // Create a mutable clone of video_format
-                        let mut video_format_modified = video_format.clone();
-
-                        match &format.dest {
-                            Some(dest) if dest == "ipfs" => {
-                                video_format_modified["cid"] =
-                                    json!(format!("ipfs://{}", response.cid));
-                            }
-                            _ => {
-                                video_format_modified["cid"] =
-                                    json!(format!("s5://{}", response.cid));
-                            }
-                        }
-                        transcoded_formats.push(video_format_modified);
+                    if !response.ffmpeg_command.is_empty() {
+                        FFMPEG_COMMANDS
+                            .lock()
+                            .await
+                            .entry(task_id.clone())
+                            .or_default()
+                            .insert(format.id, response.ffmpeg_command.clone());
+                    }
+
+                    video_format_modified["cid"] = json!(cid);
+                    if !response.blake3.is_empty() {
+                        video_format_modified["blake3"] = json!(response.blake3);
+                    }
+                    if !response.sidecar_cid.is_empty() {
+                        video_format_modified["sidecar_cid"] = json!(response.sidecar_cid);
                     }
-                    Err(e) => {
-                        // Log the error and continue with the next format
-                        eprintln!("Error transcoding video: {:?}", e);
-                        continue;
+                    if !response.encode_log_cid.is_empty() {
+                        video_format_modified["encode_log_cid"] = json!(response.encode_log_cid);
                     }
+                    if !response.audio_cid.is_empty() {
+                        video_format_modified["audio_cid"] = json!(match &format.dest {
+                            Some(dest) if dest == "ipfs" => format!("ipfs://{}", response.audio_cid),
+                            _ => format!("s5://{}", response.audio_cid),
+                        });
+                    }
+                    if !response.hls_key_cid.is_empty() {
+                        video_format_modified["hls_key_cid"] = json!(match &format.dest {
+                            Some(dest) if dest == "ipfs" => format!("ipfs://{}", response.hls_key_cid),
+                            _ => format!("s5://{}", response.hls_key_cid),
+                        });
+                    }
+                    if !response.iframe_playlist_cid.is_empty() {
+                        video_format_modified["iframe_playlist_cid"] = json!(match &format.dest {
+                            Some(dest) if dest == "ipfs" => format!("ipfs://{}", response.iframe_playlist_cid),
+                            _ => format!("s5://{}", response.iframe_playlist_cid),
+                        });
+                    }
+                    if !response.hls_key.is_empty() {
+                        hls_keys.push((transcoded_formats.len(), response.hls_key.clone()));
+                    }
+                    if !response.warnings.is_empty() {
+                        video_format_modified["warnings"] = json!(response.warnings);
+                    }
+                    if !response.scenes.is_empty() {
+                        let scenes: Vec<Value> = response
+                            .scenes
+                            .iter()
+                            .map(|scene| {
+                                json!({
+                                    "cid": match &format.dest {
+                                        Some(dest) if dest == "ipfs" => format!("ipfs://{}", scene.cid),
+                                        _ => format!("s5://{}", scene.cid),
+                                    },
+                                    "start": scene.start,
+                                    "end": scene.end,
+                                })
+                            })
+                            .collect();
+                        video_format_modified["scenes"] = json!(scenes);
+                    }
+                    if let Some(vmaf_score) = response.vmaf_score {
+                        video_format_modified["vmaf"] = json!((vmaf_score * 100.0).round() / 100.0);
+                        video_format_modified["low_quality"] = json!(response.low_quality);
+                    }
+                    if !response.perceptual_hashes.is_empty() {
+                        video_format_modified["perceptual_hashes"] = json!(response.perceptual_hashes);
+                    }
+                    if response.encode_secs > 0.0 {
+                        video_format_modified["encode_secs"] =
+                            json!((response.encode_secs * 100.0).round() / 100.0);
+                        video_format_modified["encode_speed"] =
+                            json!(format!("{:.1}x", response.encode_speed));
+
+                        let codec = video_format["vcodec"]
+                            .as_str()
+                            .filter(|vcodec| !vcodec.is_empty())
+                            .or_else(|| video_format["acodec"].as_str())
+                            .unwrap_or("unknown");
+                        metrics::observe_histogram(
+                            "encode_duration_seconds",
+                            &[("codec", codec)],
+                            response.encode_secs,
+                        );
+                    }
+                    if response.output_size > 0 {
+                        total_output_size += response.output_size;
+                        video_format_modified["input_size"] = json!(input_size);
+                        video_format_modified["output_size"] = json!(response.output_size);
+                        video_format_modified["compression_ratio"] =
+                            json!(compression_ratio(input_size, response.output_size));
+                    }
+                    // A rendition whose HLS key is handed to this task only is encoded again for
+                    // each task, each with its own key
+                    if is_uploaded && response.hls_key.is_empty() {
+                        cache::insert_cached_rendition(&cache_key, &video_format_modified);
+                    }
+                    transcoded_formats.push(video_format_modified);
+                }
+                Err(e) => {
+                    // Log the error, record it against the format and continue with the next format
+                    let error_code = error_code::classify_transcode_error(&e);
+                    eprintln!("Error transcoding video: {:?}", e);
+                    error!(
+                        task_id = %task_id,
+                        source_cid = %orig_source_cid,
+                        format_id = format.id,
+                        error_code = %error_code,
+                        "{}",
+                        e.message()
+                    );
+
+                    shared::mark_format_failed(&task_id, index);
+                    has_transient_failure |= error_code::is_transient_code(e.code());
+
+                    let mut video_format_modified = video_format.clone();
+                    video_format_modified["error"] = json!(e.message());
+                    video_format_modified["error_code"] = json!(error_code);
+                    transcoded_formats.push(video_format_modified);
+                    continue;
                 }
             }
         }
 
+        if total_output_size > 0 {
+            task_metadata.insert("input_size".to_string(), json!(input_size));
+            task_metadata.insert("total_output_size".to_string(), json!(total_output_size));
+            task_metadata.insert(
+                "compression_ratio".to_string(),
+                json!(compression_ratio(input_size, total_output_size)),
+            );
+        }
+
+        if build_manifest {
+            let manifest =
+                build_task_manifest(&task_id, &orig_source_cid, &transcoded_formats, &task_metadata);
+            let manifest_path = format!("{}{}_manifest.json", *PATH_TO_TRANSCODED_FILE, task_id);
+
+            match fs::write(&manifest_path, manifest.to_string()) {
+                Ok(()) => match s5::upload_video(manifest_path.as_str(), None).await {
+                    Ok(cid) => {
+                        println!("Manifest uploaded with cid: {}", cid);
+                        task_metadata
+                            .insert("manifest_cid".to_string(), json!(format!("s5://{}", cid)));
+                    }
+                    Err(e) => eprintln!("Failed to upload manifest {}: {}", &manifest_path, e),
+                },
+                Err(e) => eprintln!("Failed to write manifest {}: {}", &manifest_path, e),
+            }
+        }
+
+        let disk_quota_error = shared::is_disk_quota_exceeded(&task_id).then(|| {
+            format!(
+                "Task exceeded its disk quota of {} bytes (MAX_TASK_DISK_BYTES) and was aborted",
+                *shared::MAX_TASK_DISK_BYTES
+            )
+        });
+        if let Some(e) = &disk_quota_error {
+            error!(task_id = %task_id, source_cid = %orig_source_cid, error_code = %TranscodeErrorCode::DiskQuotaExceeded, "{}", e);
+            task_metadata.insert("error".to_string(), json!(e));
+            remove_task_files(&task_id, &file_path);
+        }
+        let disk_quota_exceeded = disk_quota_error.is_some();
+
+        let failed_count = shared::per_format_progress(&task_id)
+            .iter()
+            .filter(|format_progress| format_progress.status == "failed")
+            .count();
+        let task_status = if shared::is_task_cancelled(&task_id) {
+            TaskStatus::Cancelled
+        } else if disk_quota_exceeded {
+            TaskStatus::Failed
+        } else if failed_count == 0 {
+            TaskStatus::Completed
+        } else if failed_count < formats_count {
+            TaskStatus::Partial
+        } else {
+            TaskStatus::Failed
+        };
+
+        if task_status == TaskStatus::Failed
+            && has_transient_failure
+            && !disk_quota_exceeded
+            && retry_task(&dequeued_task, &sender)
+        {
+            // The retry keeps the subject's slot and reports the outcome itself
+            std::mem::forget(task_slot);
+            std::mem::forget(task_callback);
+            continue;
+        }
+
+        TASK_STATUS.lock().await.insert(task_id.clone(), task_status);
+
+        if task_status == TaskStatus::Failed {
+            let errors: Vec<String> = transcoded_formats
+                .iter()
+                .filter_map(|video_format| {
+                    let error = video_format.get("error")?.as_str()?;
+                    Some(format!("format {}: {}", video_format["id"], error))
+                })
+                .collect();
+            let error = if let Some(e) = &disk_quota_error {
+                e.clone()
+            } else if errors.is_empty() {
+                "All formats failed".to_string()
+            } else {
+                errors.join("; ")
+            };
+            // Otherwise the task fails with the code of its first failed format
+            let error_code = if disk_quota_exceeded {
+                TranscodeErrorCode::DiskQuotaExceeded
+            } else {
+                transcoded_formats
+                    .iter()
+                    .find_map(|video_format| {
+                        serde_json::from_value::<TranscodeErrorCode>(video_format.get("error_code")?.clone()).ok()
+                    })
+                    .unwrap_or(TranscodeErrorCode::EncodeFailed)
+            };
+            task_metadata.insert("error_code".to_string(), json!(error_code));
+            dead_letter::record_failed_task(
+                &task_id,
+                &orig_source_cid,
+                &media_formats,
+                is_encrypted,
+                is_gpu,
+                &error,
+                error_code,
+            );
+        }
+
         let transcoded_json = serde_json::to_string(&transcoded_formats).unwrap_or_else(|e| {
             eprintln!("Error serializing transcoded formats: {:?}", e);
             "".to_string()
         });
 
-        let mut transcoded = TRANSCODED.lock().await;
-        transcoded.insert(task_id.clone(), transcoded_json);
+        if hls_keys.is_empty() {
+            HLS_KEYS.lock().await.remove(&task_id);
+        } else {
+            HLS_KEYS.lock().await.insert(task_id.clone(), hls_keys);
+        }
+        store_transcoded(&task_id, transcoded_json).await;
+
+        TASK_METADATA
+            .lock()
+            .await
+            .insert(task_id.clone(), Value::Object(task_metadata).to_string());
 
         // Mark progress as complete (100%) for all formats
         for i in 0..formats_count {
             shared::update_progress(&task_id, i, 100);
         }
+
+        shared::clear_task_disk_usage(&task_id);
+
+        info!(
+            task_id = %task_id,
+            source_cid = %orig_source_cid,
+            formats = formats_count,
+            "Transcoding task finished"
+        );
     }
 }
 
@@ -457,7 +2083,7 @@ async fn transcode_task_receiver(
 // The gRPC service implementation
 #[derive(Debug, Clone)]
 struct TranscodeServiceHandler {
-    transcode_task_sender: Option<Arc<Mutex<mpsc::Sender<(String, String, String, bool, bool)>>>>,
+    transcode_task_sender: Option<Arc<Mutex<mpsc::Sender<TranscodeTask>>>>,
 }
 
 #[async_trait]
@@ -480,27 +2106,117 @@ impl TranscodeService for TranscodeServiceHandler {
         let is_encrypted = request.get_ref().is_encrypted;
         println!("Received is_encrypted: {}", is_encrypted);
 
-        let is_gpu = request.get_ref().is_gpu;
-        println!("Received is_gpu: {}", is_gpu);
+        let is_gpu = request.get_ref().is_gpu;
+        println!("Received is_gpu: {}", is_gpu);
+
+        let include_original = request.get_ref().include_original;
+        println!("Received include_original: {}", include_original);
+
+        let verify = request.get_ref().verify;
+        println!("Received verify: {}", verify);
+
+        let source_cids = request.get_ref().source_cids.clone();
+        println!("Received source_cids: {:?}", source_cids);
+
+        let build_manifest = request.get_ref().build_manifest;
+        println!("Received build_manifest: {}", build_manifest);
+
+        let force = request.get_ref().force;
+        println!("Received force: {}", force);
+
+        let quality_mode = request.get_ref().quality_mode.clone();
+        println!("Received quality_mode: {}", quality_mode);
+
+        let max_total_output_bytes = request.get_ref().max_total_output_bytes;
+        println!("Received max_total_output_bytes: {}", max_total_output_bytes);
+
+        let delete_source_after = request.get_ref().delete_source_after;
+        println!("Received delete_source_after: {}", delete_source_after);
+
+        let normalize = request.get_ref().normalize;
+        println!("Received normalize: {}", normalize);
+
+        let log_progress = request.get_ref().log_progress;
+        println!("Received log_progress: {}", log_progress);
+
+        let callback_url = request.get_ref().callback_url.clone();
+        println!("Received callback_url: {}", callback_url);
+        // Header values may hold credentials, so only their names are logged
+        let callback_headers =
+            callback::validate_callback(&callback_url, &request.get_ref().callback_headers)
+                .map_err(Status::invalid_argument)?;
+        println!(
+            "Received callback_headers: {:?}",
+            callback_headers.iter().map(|(name, _)| name).collect::<Vec<_>>()
+        );
+
+        if !quality_mode.is_empty() {
+            validate_quality_mode(&quality_mode).map_err(Status::invalid_argument)?;
+        }
 
         println!(
             "transcode_task_sender is None: {}",
             self.transcode_task_sender.is_none()
         );
 
+        if let Err(e) = check_disk_space() {
+            return Err(Status::resource_exhausted(e));
+        }
+
+        // The subject of a bearer token sent as `authorization` metadata, counted against
+        // `MAX_TASKS_PER_SUBJECT` as for a task submitted over REST
+        let subject = request
+            .metadata()
+            .get("authorization")
+            .and_then(|token| token.to_str().ok())
+            .and_then(|token| auth::decode_claims(token, &auth::secret_keys()))
+            .map(|claims| claims.sub);
+        if let Some(ref subject) = subject {
+            if !quota::try_acquire_task_slot(subject) {
+                return Err(Status::resource_exhausted(
+                    "Too many concurrent tasks for this subject",
+                ));
+            }
+        }
+
         let task_id = Uuid::new_v4();
+        ISSUED_TASK_IDS.lock().await.insert(task_id.to_string());
+        if let Some(ref subject) = subject {
+            TASK_SUBJECTS
+                .lock()
+                .await
+                .insert(task_id.to_string(), subject.clone());
+        }
         if let Some(ref sender) = self.transcode_task_sender {
             let sender = sender.lock().await.clone();
             if let Err(e) = sender
-                .send((
-                    task_id.to_string(),
-                    source_cid.clone(),
-                    media_formats.clone(),
+                .send(TranscodeTask {
+                    task_id: task_id.to_string(),
+                    source_cid: source_cid.clone(),
+                    media_formats: media_formats.clone(),
                     is_encrypted,
                     is_gpu,
-                ))
+                    include_original,
+                    verify,
+                    source_cids: source_cids.clone(),
+                    build_manifest,
+                    force,
+                    quality_mode,
+                    max_total_output_bytes,
+                    delete_source_after,
+                    normalize,
+                    log_progress,
+                    callback_url,
+                    callback_headers,
+                    subject: subject.clone(),
+                    attempt: 0,
+                    queued_at: Utc::now().timestamp(),
+                })
                 .await
             {
+                if let Some(subject) = subject {
+                    quota::release_task_slot(&subject);
+                }
                 return Err(Status::internal(format!(
                     "Failed to send transcoding task: {}",
                     e
@@ -522,17 +2238,54 @@ impl TranscodeService for TranscodeServiceHandler {
         request: Request<GetTranscodedRequest>,
     ) -> Result<Response<GetTranscodedResponse>, Status> {
         let task_id = &request.get_ref().task_id;
-        let transcoded = TRANSCODED.lock().await;
-        let metadata_option = transcoded.get(task_id).cloned();
+        if !is_known_task(task_id).await {
+            return Ok(Response::new(GetTranscodedResponse {
+                status_code: 404,
+                metadata: "Unknown task".to_string(),
+                status: TaskStatus::Unknown as i32,
+                ..Default::default()
+            }));
+        }
+
+        let metadata_option = TRANSCODED.lock().await.get(task_id);
 
-        let metadata = metadata_option.unwrap_or_else(|| "Transcoding in progress".to_string());
+        let metadata = match metadata_option {
+            Some(renditions_json) => take_hls_keys(task_id, renditions_json).await,
+            None => "Transcoding in progress".to_string(),
+        };
 
         let progress = shared::calculate_overall_progress(task_id);
 
+        let per_format_progress = shared::per_format_progress(task_id)
+            .into_iter()
+            .map(|format_progress| FormatProgress {
+                format_id: format_progress.format_id,
+                percent: format_progress.percent,
+                status: format_progress.status,
+            })
+            .collect();
+
+        let task_metadata = TASK_METADATA
+            .lock()
+            .await
+            .get(task_id)
+            .cloned()
+            .unwrap_or_default();
+
+        let status = TASK_STATUS
+            .lock()
+            .await
+            .get(task_id)
+            .copied()
+            .unwrap_or(TaskStatus::InProgress);
+
         let response = GetTranscodedResponse {
             status_code: 200,
             metadata,
             progress,
+            task_metadata,
+            per_format_progress,
+            status: status as i32,
         };
 
         Ok(Response::new(response))
@@ -546,6 +2299,8 @@ impl Drop for TranscodeServiceHandler {
     }
 }
 
+// The message is only read through `Debug`, when warp reports the rejection
+#[allow(dead_code)]
 #[derive(Debug)]
 struct TranscodeError(String);
 
@@ -568,42 +2323,72 @@ impl From<transcode::TranscodeResponse> for TranscodeResponseWrapper {
     }
 }
 
-impl From<tokio::sync::mpsc::error::SendError<(String, String, String, bool, bool)>>
-    for TranscodeError
-{
-    fn from(e: tokio::sync::mpsc::error::SendError<(String, String, String, bool, bool)>) -> Self {
+impl From<tokio::sync::mpsc::error::SendError<TranscodeTask>> for TranscodeError {
+    fn from(e: tokio::sync::mpsc::error::SendError<TranscodeTask>) -> Self {
         TranscodeError(format!("Failed to send transcoding task: {}", e))
     }
 }
 
 #[derive(Debug, Clone)]
 struct RestHandler {
-    transcode_task_sender: Option<Arc<Mutex<mpsc::Sender<(String, String, String, bool, bool)>>>>,
+    transcode_task_sender: Option<Arc<Mutex<mpsc::Sender<TranscodeTask>>>>,
 }
 
 impl RestHandler {
-    async fn transcode(
-        &self,
-        source_cid: String,
-        media_formats: String,
-        is_encrypted: bool,
-        is_gpu: bool,
-    ) -> Result<impl warp::Reply, warp::Rejection> {
+    async fn transcode(&self, task: TranscodeTask) -> Result<impl warp::Reply, warp::Rejection> {
         let task_id = Uuid::new_v4();
 
+        if let Err(e) = check_disk_space() {
+            let response = TranscodeResponseWrapper {
+                status_code: 507,
+                message: e,
+                task_id: String::new(),
+            };
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&response),
+                warp::http::StatusCode::INSUFFICIENT_STORAGE,
+            ));
+        }
+
+        // Reject the task if its subject already has `MAX_TASKS_PER_SUBJECT` tasks queued or running
+        if let Some(ref subject) = task.subject {
+            if !quota::try_acquire_task_slot(subject) {
+                let response = TranscodeResponseWrapper {
+                    status_code: 429,
+                    message: "Too many concurrent tasks for this subject".to_string(),
+                    task_id: String::new(),
+                };
+                return Ok(warp::reply::with_status(
+                    warp::reply::json(&response),
+                    warp::http::StatusCode::TOO_MANY_REQUESTS,
+                ));
+            }
+        }
+
         if let Some(ref sender) = self.transcode_task_sender {
             let sender = sender.lock().await.clone();
+            let subject = task.subject.clone();
+
+            // Recorded before queueing so the task can be cancelled as soon as it exists
+            ISSUED_TASK_IDS.lock().await.insert(task_id.to_string());
+            if let Some(ref subject) = subject {
+                TASK_SUBJECTS
+                    .lock()
+                    .await
+                    .insert(task_id.to_string(), subject.clone());
+            }
 
             if let Err(e) = sender
-                .send((
-                    task_id.to_string(),
-                    source_cid.clone(),
-                    media_formats.clone(),
-                    is_encrypted,
-                    is_gpu,
-                ))
+                .send(TranscodeTask {
+                    task_id: task_id.to_string(),
+                    queued_at: Utc::now().timestamp(),
+                    ..task
+                })
                 .await
             {
+                if let Some(subject) = subject {
+                    quota::release_task_slot(&subject);
+                }
                 return Err(warp::reject::custom(TranscodeError::from(e)));
             }
         }
@@ -614,7 +2399,10 @@ impl RestHandler {
             task_id: task_id.to_string(),
         };
 
-        Ok(warp::reply::json(&TranscodeResponseWrapper::from(response)))
+        Ok(warp::reply::with_status(
+            warp::reply::json(&TranscodeResponseWrapper::from(response)),
+            warp::http::StatusCode::OK,
+        ))
     }
 }
 
@@ -623,6 +2411,10 @@ struct GetTranscodedResponseWrapper {
     status_code: i32,
     metadata: String,
     progress: i32,
+    task_metadata: String,
+    per_format_progress: Vec<shared::FormatProgress>,
+    // "IN_PROGRESS", "COMPLETED", "PARTIAL", "FAILED", "CANCELLED" or "UNKNOWN"
+    status: String,
 }
 
 impl From<transcode::GetTranscodedResponse> for GetTranscodedResponseWrapper {
@@ -631,59 +2423,234 @@ impl From<transcode::GetTranscodedResponse> for GetTranscodedResponseWrapper {
             status_code: response.status_code,
             metadata: response.metadata,
             progress: response.progress,
+            task_metadata: response.task_metadata,
+            per_format_progress: response
+                .per_format_progress
+                .into_iter()
+                .map(|format_progress| shared::FormatProgress {
+                    format_id: format_progress.format_id,
+                    percent: format_progress.percent,
+                    status: format_progress.status,
+                })
+                .collect(),
+            status: TaskStatus::from_i32(response.status)
+                .unwrap_or(TaskStatus::InProgress)
+                .as_str_name()
+                .to_string(),
         }
     }
 }
 
 impl RestHandler {
     async fn get_transcoded(&self, task_id: String) -> Result<impl warp::Reply, warp::Rejection> {
+    if !is_known_task(&task_id).await {
+        let response = GetTranscodedResponseWrapper {
+            status_code: 404,
+            metadata: "Unknown task".to_string(),
+            progress: 0,
+            task_metadata: String::new(),
+            per_format_progress: Vec::new(),
+            status: TaskStatus::Unknown.as_str_name().to_string(),
+        };
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&response),
+            warp::http::StatusCode::NOT_FOUND,
+        ));
+    }
+
     // Retrieve the metadata and the progress for the given task ID.
-    let transcoded = TRANSCODED.lock().await;
-    let metadata_option = transcoded.get(&task_id).cloned();
+    let metadata_option = TRANSCODED.lock().await.get(&task_id);
 
     // Use a default value for metadata if it's not available.
-    let metadata = metadata_option.unwrap_or_else(|| "Transcoding in progress".to_string());
+    let metadata = match metadata_option {
+        Some(renditions_json) => take_hls_keys(&task_id, renditions_json).await,
+        None => "Transcoding in progress".to_string(),
+    };
 
     let progress = shared::calculate_overall_progress(&task_id);
+    let per_format_progress = shared::per_format_progress(&task_id);
+
+    let status = TASK_STATUS
+        .lock()
+        .await
+        .get(&task_id)
+        .copied()
+        .unwrap_or(TaskStatus::InProgress);
+
+    let task_metadata = TASK_METADATA
+        .lock()
+        .await
+        .get(&task_id)
+        .cloned()
+        .unwrap_or_default();
 
     // Construct the response including the progress
     let response = GetTranscodedResponseWrapper {
         status_code: 200,
         metadata,
         progress,
+        task_metadata,
+        per_format_progress,
+        status: status.as_str_name().to_string(),
     };
 
-    Ok(warp::reply::json(&response))
+    Ok(warp::reply::with_status(
+        warp::reply::json(&response),
+        warp::http::StatusCode::OK,
+    ))
     }
 }
 
-async fn check_transcoded_file_exists(cid: &str, label: &str, ext: &str) -> bool {
-    let filename = format!("{}{}_{}.{}", *PATH_TO_TRANSCODED_FILE, cid, label, ext); // Adjust the path and format as needed.
-    Path::new(&filename).exists()
+/// Returns whether `task_id` was issued by this server, so `get_transcoded` can tell a task still
+/// in progress from a mistyped or forgotten ID. Task IDs are kept in memory and are forgotten on
+/// restart. Always true when `UNKNOWN_TASK_RESPONSE` is "in_progress".
+///
+/// # Arguments
+///
+/// * `task_id` - The task ID given to `get_transcoded`.
+///
+async fn is_known_task(task_id: &str) -> bool {
+    UNKNOWN_TASK_RESPONSE.as_str() == "in_progress"
+        || ISSUED_TASK_IDS.lock().await.contains(task_id)
 }
 
-fn garbage_collect(directory: &str, size_threshold: u64) {
-    let mut files: Vec<_> = fs::read_dir(directory)
-        .unwrap()
-        .filter_map(|entry| {
-            entry.ok().and_then(|e| {
-                e.metadata()
-                    .ok()
-                    .map(|m| (e.path(), m.len(), m.created().unwrap()))
-            })
+/// Adds the keys of a finished task's renditions with "key_uri" HLS key delivery to its renditions
+/// JSON as `hls_key`, then forgets them, so each key is handed out by the first `get_transcoded`
+/// after the task finishes and the server keeps no copy of it.
+///
+/// # Arguments
+/// * `task_id` - The task.
+/// * `renditions_json` - The task's renditions JSON from `TRANSCODED`.
+///
+async fn take_hls_keys(task_id: &str, renditions_json: String) -> String {
+    let hls_keys = match HLS_KEYS.lock().await.remove(task_id) {
+        Some(hls_keys) => hls_keys,
+        None => return renditions_json,
+    };
+    let mut renditions: Vec<Value> = match serde_json::from_str(&renditions_json) {
+        Ok(renditions) => renditions,
+        Err(_) => return renditions_json,
+    };
+    for (index, hls_key) in hls_keys {
+        if let Some(rendition) = renditions.get_mut(index) {
+            rendition["hls_key"] = json!(hls_key);
+        }
+    }
+    serde_json::to_string(&renditions).unwrap_or(renditions_json)
+}
+
+/// Stores the renditions JSON of a finished task in `TRANSCODED`, and forgets the tasks evicted
+/// to stay within `MAX_RETAINED_TASKS`: their renditions, status, metadata, progress, progress log
+/// and whether they were cancelled. A forgotten task is reported as unknown by `get_transcoded`.
+///
+/// # Arguments
+///
+/// * `task_id` - The ID of the finished task.
+/// * `renditions_json` - The JSON array of the task's renditions.
+///
+async fn store_transcoded(task_id: &str, renditions_json: String) {
+    let evicted = TRANSCODED
+        .lock()
+        .await
+        .insert(task_id.to_string(), renditions_json);
+
+    for evicted_id in evicted {
+        info!(task_id = %evicted_id, "Forgetting task beyond MAX_RETAINED_TASKS");
+        TASK_METADATA.lock().await.remove(&evicted_id);
+        HLS_KEYS.lock().await.remove(&evicted_id);
+        TASK_STATUS.lock().await.remove(&evicted_id);
+        FFMPEG_COMMANDS.lock().await.remove(&evicted_id);
+        TASK_SUBJECTS.lock().await.remove(&evicted_id);
+        ISSUED_TASK_IDS.lock().await.remove(&evicted_id);
+        shared::forget_task_progress(&evicted_id);
+        shared::forget_task_cancelled(&evicted_id);
+        if let Err(e) = fs::remove_file(progress_log::progress_log_path(&evicted_id)) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                eprintln!("Failed to delete progress log of task {}: {}", evicted_id, e);
+            }
+        }
+    }
+}
+
+/// Deletes files from `directory`, skipping sources in use, until the total size of the remaining
+/// files is no more than `size_threshold`.
+///
+/// # Returns
+/// The number of files deleted and the bytes they freed.
+///
+fn garbage_collect(directory: &str, size_threshold: u64) -> (usize, u64) {
+    let entries = match utils::list_output_files(directory) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Failed to read directory {}: {}", directory, e);
+            return (0, 0);
+        }
+    };
+    let mut files: Vec<_> = entries
+        .into_iter()
+        .filter_map(|e| {
+            let m = e.metadata().ok()?;
+            Some((e.path(), m.len(), m.created().ok()?))
         })
         .collect();
 
+    // Sources in use by a running task, and the outputs of renditions being written, are never
+    // collected
+    {
+        let active_sources = ACTIVE_SOURCES.lock().unwrap();
+        files.retain(|(path, _, _)| {
+            !active_sources.contains_key(path.to_string_lossy().as_ref())
+                && !transcode_video::is_in_active_output_dir(path)
+        });
+    }
+
     files.sort_by_key(|k| k.2); // Sort files by creation time
 
     let mut total_size: u64 = files.iter().map(|(_, size, _)| size).sum();
+    let mut files_deleted = 0;
+    let mut bytes_freed = 0;
 
     while total_size > size_threshold && !files.is_empty() {
         if let Some((file, size, _)) = files.pop() {
-            fs::remove_file(file).unwrap();
             total_size -= size;
+            match fs::remove_file(&file) {
+                Ok(()) => {
+                    files_deleted += 1;
+                    bytes_freed += size;
+                }
+                Err(e) => eprintln!("Failed to delete {}: {}", file.display(), e),
+            }
         }
     }
+    transcode_video::remove_empty_output_dirs(directory);
+
+    (files_deleted, bytes_freed)
+}
+
+/// POSTs a summary of a garbage collection run to `GC_WEBHOOK_URL`, if set, so operators are told
+/// when files are deleted. Runs that delete nothing are not reported.
+///
+/// # Arguments
+/// * `directory` - The directory that was collected.
+/// * `files_deleted` - The number of files deleted.
+/// * `bytes_freed` - The total size of the deleted files.
+///
+async fn notify_gc_webhook(directory: &str, files_deleted: usize, bytes_freed: u64) {
+    let webhook_url = match GC_WEBHOOK_URL.as_deref() {
+        Some(webhook_url) if files_deleted > 0 => webhook_url,
+        _ => return,
+    };
+
+    let summary = json!({
+        "event": "garbage_collection",
+        "directory": directory,
+        "files_deleted": files_deleted,
+        "bytes_freed": bytes_freed,
+        "timestamp": Utc::now().to_rfc3339(),
+    });
+    if let Err(e) = post_json_with_retry(webhook_url, &summary, &[], *GC_WEBHOOK_RETRIES).await {
+        warn!(directory = %directory, "Failed to send garbage collection webhook: {}", e);
+    }
 }
 
 pub mod transcode {
@@ -693,10 +2660,318 @@ pub mod transcode {
 // Define a struct to receive the query parameters.
 #[derive(Deserialize)]
 struct QueryParams {
+    #[serde(default)]
     source_cid: String,
     media_formats: String,
     is_encrypted: bool,
     is_gpu: bool,
+    #[serde(default)]
+    include_original: bool,
+    #[serde(default)]
+    verify: bool,
+    source_cids: Option<String>,
+    #[serde(default)]
+    build_manifest: bool,
+    #[serde(default)]
+    force: bool,
+    #[serde(default)]
+    quality_mode: String,
+    #[serde(default)]
+    max_total_output_bytes: u64,
+    #[serde(default)]
+    delete_source_after: bool,
+    #[serde(default)]
+    normalize: bool,
+    #[serde(default)]
+    log_progress: bool,
+    #[serde(default)]
+    callback_url: String,
+    callback_headers: Option<String>,
+}
+
+impl QueryParams {
+    /// Converts the query parameters into a `TranscodeTask`. The task id is assigned when the task
+    /// is queued.
+    fn into_task(self) -> Result<TranscodeTask, TranscodeError> {
+        // `source_cids` is a JSON array of CIDs to join, encoded like `media_formats`
+        let source_cids = match self.source_cids.as_deref() {
+            Some(source_cids) => from_str::<Vec<String>>(source_cids)
+                .map_err(|e| TranscodeError(format!("Invalid source_cids: {}", e)))?,
+            None => Vec::new(),
+        };
+
+        if !self.quality_mode.is_empty() {
+            validate_quality_mode(&self.quality_mode).map_err(TranscodeError)?;
+        }
+
+        // `callback_headers` is a JSON object of header names to values
+        let callback_headers = match self.callback_headers.as_deref() {
+            Some(callback_headers) => from_str::<HashMap<String, String>>(callback_headers)
+                .map_err(|e| TranscodeError(format!("Invalid callback_headers: {}", e)))?,
+            None => HashMap::new(),
+        };
+        let callback_headers = callback::validate_callback(&self.callback_url, &callback_headers)
+            .map_err(TranscodeError)?;
+
+        Ok(TranscodeTask {
+            task_id: String::new(),
+            source_cid: self.source_cid,
+            media_formats: self.media_formats,
+            is_encrypted: self.is_encrypted,
+            is_gpu: self.is_gpu,
+            include_original: self.include_original,
+            verify: self.verify,
+            source_cids,
+            build_manifest: self.build_manifest,
+            force: self.force,
+            quality_mode: self.quality_mode,
+            max_total_output_bytes: self.max_total_output_bytes,
+            delete_source_after: self.delete_source_after,
+            normalize: self.normalize,
+            log_progress: self.log_progress,
+            callback_url: self.callback_url,
+            callback_headers,
+            subject: None,
+            attempt: 0,
+            queued_at: Utc::now().timestamp(),
+        })
+    }
+}
+
+/// Chooses the content coding to compress a response with from an `Accept-Encoding` header such
+/// as "gzip, deflate;q=0.5, br". The coding with the highest q-value is chosen, preferring gzip over
+/// deflate when they are equal. A coding with a q-value of 0, or not listed and not matched by "*",
+/// is not accepted.
+///
+/// # Arguments
+/// * `accept_encoding` - The `Accept-Encoding` header of the request, if it has one.
+///
+/// # Returns
+/// "gzip", "deflate", or `None` to send the response uncompressed.
+///
+fn negotiate_encoding(accept_encoding: Option<&str>) -> Option<&'static str> {
+    let codings: Vec<(String, f32)> = accept_encoding
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|coding| {
+            let mut params = coding.split(';');
+            let name = params.next()?.trim().to_ascii_lowercase();
+            if name.is_empty() {
+                return None;
+            }
+            // A malformed q-value is taken as 0, so the coding is not used
+            let quality = params
+                .find_map(|param| {
+                    let (key, value) = param.split_once('=')?;
+                    key.trim().eq_ignore_ascii_case("q").then_some(value)
+                })
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())
+                .unwrap_or(0.0);
+            Some((name, quality))
+        })
+        .collect();
+
+    let quality_of = |encoding: &str| {
+        codings
+            .iter()
+            .find(|(name, _)| name == encoding)
+            .or_else(|| codings.iter().find(|(name, _)| name == "*"))
+            .map_or(0.0, |(_, quality)| *quality)
+    };
+
+    ["gzip", "deflate"]
+        .into_iter()
+        .map(|encoding| (encoding, quality_of(encoding)))
+        .filter(|(_, quality)| *quality > 0.0)
+        .fold(None, |best: Option<(&'static str, f32)>, (encoding, quality)| match best {
+            Some((_, best_quality)) if best_quality >= quality => best,
+            _ => Some((encoding, quality)),
+        })
+        .map(|(encoding, _)| encoding)
+}
+
+/// Matches requests whose response is to be compressed with `encoding`, as chosen by
+/// `negotiate_encoding`, or uncompressed if `encoding` is `None`. Responses are never compressed
+/// when `COMPRESS_RESPONSES` is "false". Exactly one encoding matches each request, so the routes
+/// behind these filters are evaluated once.
+///
+/// # Arguments
+/// * `encoding` - The content coding, e.g. "gzip".
+///
+fn negotiated_encoding(
+    encoding: Option<&'static str>,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("accept-encoding")
+        .and_then(move |accept_encoding: Option<String>| async move {
+            let negotiated = if *COMPRESS_RESPONSES {
+                negotiate_encoding(accept_encoding.as_deref())
+            } else {
+                None
+            };
+
+            if negotiated == encoding {
+                Ok(())
+            } else {
+                Err(warp::reject())
+            }
+        })
+        .untuple_one()
+}
+
+#[derive(Debug, Deserialize)]
+struct CancelTasksQuery {
+    subject: String,
+}
+
+/// Cancels every queued or running task submitted by a JWT subject. Tasks that have already
+/// finished are left alone.
+///
+/// # Arguments
+/// * `subject` - The `sub` claim of the JWT that submitted the tasks.
+///
+/// # Returns
+/// The ids of the cancelled tasks.
+///
+async fn cancel_subject_tasks(subject: &str) -> Vec<String> {
+    let task_subjects = TASK_SUBJECTS.lock().await;
+    let task_status = TASK_STATUS.lock().await;
+
+    let mut task_ids: Vec<String> = task_subjects
+        .iter()
+        .filter(|(task_id, task_subject)| {
+            task_subject.as_str() == subject
+                && !task_status.contains_key(*task_id)
+                && !shared::is_task_cancelled(task_id)
+        })
+        .map(|(task_id, _)| task_id.clone())
+        .collect();
+    task_ids.sort();
+
+    for task_id in &task_ids {
+        shared::cancel_task(task_id);
+        info!(task_id = %task_id, subject = %subject, "Task cancelled");
+    }
+
+    task_ids
+}
+
+/// Pings the configured portal and gateway URLs when `STARTUP_CHECKS=true`, logging a warning for
+/// each one that is unreachable within `STARTUP_CHECK_TIMEOUT_SECS`. With `STRICT_STARTUP=true` an
+/// unreachable dependency is returned as an error so the server does not start.
+///
+async fn run_startup_checks() -> Result<(), String> {
+    if var("STARTUP_CHECKS").map(|v| v != "true").unwrap_or(true) {
+        return Ok(());
+    }
+
+    let strict = utils::env_flag("STRICT_STARTUP");
+    let timeout = std::time::Duration::from_secs(
+        var("STARTUP_CHECK_TIMEOUT_SECS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(5),
+    );
+
+    let mut unreachable = Vec::new();
+    for name in ["PORTAL_URL", "PORTAL_ENCRYPT_URL", "IPFS_GATEWAY"] {
+        let url = match var(name) {
+            Ok(url) if !url.is_empty() => url,
+            _ => continue,
+        };
+
+        let result = tokio::task::spawn_blocking(move || check_endpoint_reachable(&url, timeout))
+            .await
+            .unwrap_or_else(|e| Err(format!("Startup check panicked: {}", e)));
+
+        if let Err(e) = result {
+            eprintln!("Warning: {} ({})", e, name);
+            unreachable.push(name);
+        }
+    }
+
+    if strict && !unreachable.is_empty() {
+        return Err(format!(
+            "Unreachable dependencies with STRICT_STARTUP=true: {}",
+            unreachable.join(", ")
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks the download and output filesystems against `MIN_FREE_DISK_BYTES`.
+///
+/// # Returns
+/// An error message describing the filesystem that is low on space.
+///
+fn check_disk_space() -> Result<(), String> {
+    if *MIN_FREE_DISK_BYTES == 0 {
+        return Ok(());
+    }
+
+    utils::check_free_disk_space(
+        &[PATH_TO_FILE.as_str(), PATH_TO_TRANSCODED_FILE.as_str()],
+        *MIN_FREE_DISK_BYTES,
+    )
+}
+
+/// Holds the worker until there is enough free disk space to download and transcode a task, so that
+/// queued tasks are not started only to fail with ENOSPC.
+///
+async fn wait_for_disk_space(task_id: &str) {
+    while let Err(e) = check_disk_space() {
+        warn!(task_id = %task_id, "{}; waiting for space to be freed", e);
+        tokio::time::sleep(std::time::Duration::from_secs(30)).await;
+    }
+}
+
+/// Waits until the worker queue is resumed if it has been paused with the `pause` admin endpoint.
+/// Tasks submitted while paused stay queued.
+///
+async fn wait_while_paused() {
+    loop {
+        // Created before checking the flag so a resume in between is not missed
+        let resumed = WORKER_RESUMED.notified();
+        if !WORKER_PAUSED.load(Ordering::SeqCst) {
+            return;
+        }
+        resumed.await;
+    }
+}
+
+/// Pauses or resumes the worker queue. In-flight tasks are allowed to finish when pausing.
+///
+/// # Arguments
+/// * `paused` - Whether the worker should stop dequeuing new tasks.
+///
+fn set_worker_paused(paused: bool) {
+    WORKER_PAUSED.store(paused, Ordering::SeqCst);
+    if !paused {
+        WORKER_RESUMED.notify_waiters();
+    }
+    info!(paused, "Worker queue {}", if paused { "paused" } else { "resumed" });
+}
+
+/// Configures the `tracing` subscriber. Setting `LOG_FORMAT=json` emits one JSON object per line with
+/// the event fields (such as `task_id` and `source_cid`), level and message at the top level, for log
+/// aggregation pipelines. Any other value keeps the human-readable format.
+///
+fn init_logging() {
+    let json_format = var("LOG_FORMAT")
+        .map(|v| v.eq_ignore_ascii_case("json"))
+        .unwrap_or(false);
+
+    let subscriber = tracing_subscriber::fmt().with_max_level(tracing::Level::INFO);
+
+    if json_format {
+        subscriber
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .init();
+    } else {
+        subscriber.init();
+    }
 }
 
 /// The main entry point for the transcode server. Initializes the server
@@ -704,13 +2979,70 @@ struct QueryParams {
 /// for incoming requests. Once a request is received, it spawns a new thread
 /// to handle the request and continues listening for more requests.
 ///
-#[tokio::main]
-async fn main() {
+fn main() {
     dotenv().ok();
+    // Loaded before the runtime starts, and before logging is set up as the file may configure it
+    let config_file = config::load_config_file();
+    serve(config_file);
+}
+
+#[tokio::main]
+async fn serve(config_file: Result<Vec<String>, String>) {
+    init_logging();
+
+    match config_file {
+        Ok(unknown_keys) => {
+            for key in unknown_keys {
+                warn!(key = %key, "Unknown setting in CONFIG_FILE");
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+
+    if let Err(e) = transcode_video::check_default_dest() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = transcode_video::naming_collision_strategy() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = throttle::rate_limit_mode() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    if let Err(e) = run_startup_checks().await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 
-    let (task_sender, task_receiver) = mpsc::channel::<(String, String, String, bool, bool)>(100);
+    let build_info = tokio::task::spawn_blocking(|| Lazy::force(&version::BUILD_INFO).clone())
+        .await
+        .expect("Failed to detect build info");
+    info!(
+        version = %build_info.version,
+        git_commit = %build_info.git_commit,
+        ffmpeg_version = %build_info.ffmpeg_version,
+        "Starting transcode server"
+    );
+
+    // Outputs are renamed into place only when complete, so anything still partial is left over
+    // from a transcode that was interrupted
+    remove_partial_outputs();
+    progress_log::remove_stale_logs();
+
+    let (task_sender, task_receiver) = mpsc::channel::<TranscodeTask>(100);
     let task_receiver = Arc::new(Mutex::new(task_receiver));
-    tokio::spawn(transcode_task_receiver(Arc::clone(&task_receiver)));
+    for _ in 0..(*TRANSCODE_WORKERS).max(1) {
+        tokio::spawn(transcode_task_receiver(
+            Arc::clone(&task_receiver),
+            task_sender.clone(),
+        ));
+    }
 
     let task_sender = Arc::new(Mutex::new(task_sender));
 
@@ -728,24 +3060,24 @@ async fn main() {
 
     let cors = warp::cors()
         .allow_any_origin()
-        .allow_methods(vec!["POST", "GET"])
+        .allow_methods(vec!["POST", "GET", "DELETE"])
         .allow_headers(vec!["Content-Type"]);
 
     let transcode_handler = Arc::clone(&rest_handler);
     let transcode = warp::path!("transcode")
-    .and(auth::with_auth()) // Apply JWT authentication middleware
+    .and(auth::with_claims()) // Apply JWT authentication middleware
         .and(warp::query::<QueryParams>())
-        .and_then(move |params: QueryParams| {
+        .and_then(move |claims: auth::Claims, params: QueryParams| {
             let rest_handler = Arc::clone(&transcode_handler);
             async move {
-                rest_handler
-                    .transcode(
-                        params.source_cid,
-                        params.media_formats,
-                        params.is_encrypted,
-                        params.is_gpu,
-                    )
-                    .await
+                let task = match params.into_task() {
+                    Ok(task) => TranscodeTask {
+                        subject: Some(claims.sub),
+                        ..task
+                    },
+                    Err(e) => return Err(warp::reject::custom(e)),
+                };
+                rest_handler.transcode(task).await
             }
         })
         .with(cors.clone())
@@ -761,7 +3093,229 @@ async fn main() {
         .with(cors.clone())
         .boxed();
 
-    let routes = transcode.or(get_transcoded);
+    let pause = warp::path!("pause")
+        .and(warp::post())
+        .and(auth::with_admin()) // Admin endpoints require a token with the "admin" scope
+        .map(|| {
+            set_worker_paused(true);
+            warp::reply::json(&json!({ "status_code": 200, "paused": true }))
+        })
+        .with(cors.clone())
+        .boxed();
+
+    let resume = warp::path!("resume")
+        .and(warp::post())
+        .and(auth::with_admin())
+        .map(|| {
+            set_worker_paused(false);
+            warp::reply::json(&json!({ "status_code": 200, "paused": false }))
+        })
+        .with(cors.clone())
+        .boxed();
+
+    let failed_tasks = warp::path!("tasks" / "failed")
+        .and(warp::get())
+        .and(auth::with_admin())
+        .map(|| {
+            let failed_tasks = dead_letter::failed_tasks();
+            warp::reply::json(&json!({
+                "status_code": 200,
+                "count": failed_tasks.len(),
+                "tasks": failed_tasks,
+            }))
+        })
+        .with(cors.clone())
+        .boxed();
+
+    let cancel_subject_tasks = warp::path!("tasks")
+        .and(warp::delete())
+        .and(auth::with_admin())
+        .and(warp::query::<CancelTasksQuery>())
+        .and_then(|query: CancelTasksQuery| async move {
+            let task_ids = cancel_subject_tasks(&query.subject).await;
+            Ok::<_, warp::Rejection>(warp::reply::json(&json!({
+                "status_code": 200,
+                "count": task_ids.len(),
+                "task_ids": task_ids,
+            })))
+        })
+        .with(cors.clone())
+        .boxed();
+
+    // Renditions served from the cache or an existing output have no recorded command
+    let format_command = warp::path!("tasks" / String / "formats" / u32 / "command")
+        .and(warp::get())
+        .and(auth::with_admin())
+        .and_then(|task_id: String, format_id: u32| async move {
+            let command = FFMPEG_COMMANDS
+                .lock()
+                .await
+                .get(&task_id)
+                .and_then(|commands| commands.get(&format_id))
+                .cloned();
+
+            let reply = match command {
+                Some(command) => warp::reply::with_status(
+                    warp::reply::json(&json!({
+                        "status_code": 200,
+                        "task_id": task_id,
+                        "format_id": format_id,
+                        "command": command,
+                    })),
+                    warp::http::StatusCode::OK,
+                ),
+                None => warp::reply::with_status(
+                    warp::reply::json(&json!({
+                        "status_code": 404,
+                        "message": format!(
+                            "No ffmpeg command recorded for format {} of task {}",
+                            format_id, task_id
+                        ),
+                    })),
+                    warp::http::StatusCode::NOT_FOUND,
+                ),
+            };
+            Ok::<_, warp::Rejection>(reply)
+        })
+        .with(cors.clone())
+        .boxed();
+
+    // Snapshots of a task's progress, for tasks queued with `log_progress`. Only the subject that
+    // submitted the task, or an admin, can read its log.
+    let progress_log = warp::path!("tasks" / String / "progress_log")
+        .and(warp::get())
+        .and(auth::with_claims())
+        .and_then(|task_id: String, claims: auth::Claims| async move {
+            let is_owner = TASK_SUBJECTS.lock().await.get(&task_id) == Some(&claims.sub);
+            let contents = if is_owner || claims.is_admin() {
+                tokio::fs::read_to_string(progress_log::progress_log_path(&task_id)).await
+            } else {
+                Err(std::io::ErrorKind::NotFound.into())
+            };
+
+            let reply = match contents {
+                Ok(contents) => warp::reply::with_status(
+                    warp::reply::with_header(contents, "content-type", "application/x-ndjson"),
+                    warp::http::StatusCode::OK,
+                )
+                .into_response(),
+                Err(_) => warp::reply::with_status(
+                    warp::reply::json(&json!({
+                        "status_code": 404,
+                        "message": format!("No progress log for task {}", task_id),
+                    })),
+                    warp::http::StatusCode::NOT_FOUND,
+                )
+                .into_response(),
+            };
+            Ok::<_, warp::Rejection>(reply)
+        })
+        .with(cors.clone())
+        .boxed();
+
+    let list_source_cache = warp::path!("cache")
+        .and(warp::get())
+        .and(auth::with_admin())
+        .map(|| {
+            let sources = source_cache::list_sources(&PATH_TO_FILE, &is_source_in_use);
+            warp::reply::json(&json!({
+                "status_code": 200,
+                "count": sources.len(),
+                "total_size": sources.iter().map(|source| source.size).sum::<u64>(),
+                "sources": sources,
+            }))
+        })
+        .with(cors.clone())
+        .boxed();
+
+    let evict_cached_source = warp::path!("cache" / String)
+        .and(warp::delete())
+        .and(auth::with_admin())
+        .map(|source_cid: String| {
+            let source_name = source_file_name(&source_cid).unwrap_or(source_cid);
+            // Held until the files are deleted, so no task starts using them after the check
+            let active_sources = ACTIVE_SOURCES.lock().unwrap();
+            let in_use = |path: &str| -> bool {
+                active_sources.contains_key(path) || coalesce::is_in_flight(path)
+            };
+            let (reply, status) =
+                match source_cache::evict_source(&PATH_TO_FILE, &source_name, &in_use) {
+                    Ok(evicted) if evicted.is_empty() => (
+                        json!({
+                            "status_code": 404,
+                            "message": format!("Source {} is not cached", source_name),
+                        }),
+                        warp::http::StatusCode::NOT_FOUND,
+                    ),
+                    Ok(evicted) => (
+                        json!({
+                            "status_code": 200,
+                            "count": evicted.len(),
+                            "bytes_freed": evicted.iter().map(|source| source.size).sum::<u64>(),
+                            "evicted": evicted,
+                        }),
+                        warp::http::StatusCode::OK,
+                    ),
+                    Err(e) => (
+                        json!({ "status_code": 409, "message": e }),
+                        warp::http::StatusCode::CONFLICT,
+                    ),
+                };
+            warp::reply::with_status(warp::reply::json(&reply), status)
+        })
+        .with(cors.clone())
+        .boxed();
+
+    // Lets clients check which token they are using before submitting work
+    let whoami = warp::path!("whoami")
+        .and(warp::get())
+        .and(auth::with_claims())
+        .map(|claims: auth::Claims| {
+            let scopes: Vec<&str> = claims.scope.split_whitespace().collect();
+            warp::reply::json(&json!({
+                "status_code": 200,
+                "sub": claims.sub,
+                "exp": claims.exp,
+                "scopes": scopes,
+            }))
+        })
+        .with(cors.clone())
+        .boxed();
+
+    let version = warp::path!("version")
+        .and(warp::get())
+        .map(|| warp::reply::json(&*version::BUILD_INFO))
+        .with(cors.clone())
+        .boxed();
+
+    let metrics = warp::path!("metrics")
+        .and(warp::get())
+        .map(metrics::render)
+        .with(cors.clone())
+        .boxed();
+
+    let routes = transcode
+        .or(get_transcoded)
+        .or(metrics)
+        .or(pause)
+        .or(resume)
+        .or(format_command)
+        .or(progress_log)
+        .or(cancel_subject_tasks)
+        .or(failed_tasks)
+        .or(list_source_cache)
+        .or(evict_cached_source)
+        .or(whoami)
+        .or(version);
+
+    // Responses are compressed for clients that accept it, preferring gzip over deflate
+    let routes = negotiated_encoding(Some("gzip"))
+        .and(routes.clone())
+        .with(warp::compression::gzip())
+        .or(negotiated_encoding(Some("deflate"))
+            .and(routes.clone())
+            .with(warp::compression::deflate()))
+        .or(negotiated_encoding(None).and(routes));
     let rest_server = warp::serve(routes).run(([0, 0, 0, 0], 8000));
 
     let garbage_collection_secs = GARBAGE_COLLECTOR_INTERVAL.parse::<u64>().unwrap_or_else(|_| {
@@ -777,12 +3331,15 @@ async fn main() {
                 eprintln!("Failed to parse FILE_SIZE_THRESHOLD into a u64");
                 1000000000 // default to 1GB
             });
-            garbage_collect(PATH_TO_FILE.as_str(), threshold);
+            let (files_deleted, bytes_freed) = garbage_collect(PATH_TO_FILE.as_str(), threshold);
+            notify_gc_webhook(PATH_TO_FILE.as_str(), files_deleted, bytes_freed).await;
             let transcoded_threshold = TRANSCODED_FILE_SIZE_THRESHOLD.parse::<u64>().unwrap_or_else(|_| {
                 eprintln!("Failed to parse TRANSCODED_FILE_SIZE_THRESHOLD into a u64");
                 1000000000 // default to 1GB
             });
-            garbage_collect(PATH_TO_TRANSCODED_FILE.as_str(), transcoded_threshold);
+            let (files_deleted, bytes_freed) =
+                garbage_collect(PATH_TO_TRANSCODED_FILE.as_str(), transcoded_threshold);
+            notify_gc_webhook(PATH_TO_TRANSCODED_FILE.as_str(), files_deleted, bytes_freed).await;
         }
     });
 
@@ -799,3 +3356,61 @@ async fn main() {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_corrupt_cached_source_is_removed_to_be_downloaded_again() {
+        let file_path = std::env::temp_dir()
+            .join(format!("cached_source_{}", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let contents = b"cached source contents";
+        fs::write(&file_path, contents).unwrap();
+
+        let mut raw_cid = BLAKE3_RAW_CID_PREFIX.to_vec();
+        raw_cid.extend_from_slice(blake3::hash(contents).as_bytes());
+        raw_cid.push(contents.len() as u8);
+        let source_cid = format!("u{}", bytes_to_base64url(&raw_cid));
+
+        assert!(is_cached_source_valid(&file_path, &source_cid, false));
+        assert!(Path::new(&file_path).exists());
+
+        // Corrupted in place, keeping its size
+        fs::write(&file_path, b"cached source c0ntents").unwrap();
+        assert!(!is_cached_source_valid(&file_path, &source_cid, false));
+        assert!(!Path::new(&file_path).exists());
+
+        // A CID in another form has nothing to verify against
+        fs::write(&file_path, contents).unwrap();
+        assert!(is_cached_source_valid(&file_path, "bafybeigdyrzt", false));
+        fs::remove_file(&file_path).unwrap();
+    }
+
+    #[test]
+    fn encrypted_size_is_the_size_last_chunk_index_accepts() {
+        for (padding, size) in [(0, 1), (3, ENCRYPTION_CHUNK_SIZE - 3), (0, ENCRYPTION_CHUNK_SIZE * 2 + 5)] {
+            let encrypted = encrypted_size((padding, size));
+            assert!(last_chunk_index(encrypted, Some((padding, size))).is_ok());
+            assert!(last_chunk_index(encrypted - 1, Some((padding, size))).is_err());
+        }
+        assert_eq!(encrypted_size((0, ENCRYPTION_CHUNK_SIZE)), ENCRYPTION_CHUNK_SIZE + ENCRYPTION_TAG_SIZE);
+    }
+
+    #[test]
+    fn negotiate_encoding_follows_q_values() {
+        assert_eq!(negotiate_encoding(None), None);
+        assert_eq!(negotiate_encoding(Some("gzip, deflate;q=0.5, br")), Some("gzip"));
+        assert_eq!(negotiate_encoding(Some("deflate, gzip")), Some("gzip"));
+        assert_eq!(negotiate_encoding(Some("gzip;q=0.4, deflate;q=0.8")), Some("deflate"));
+        assert_eq!(negotiate_encoding(Some("GZIP;Q=0.4")), Some("gzip"));
+        assert_eq!(negotiate_encoding(Some("gzip;q=oops")), None);
+        assert_eq!(negotiate_encoding(Some("gzip;q=0")), None);
+        assert_eq!(negotiate_encoding(Some("gzip;q=0, deflate")), Some("deflate"));
+        assert_eq!(negotiate_encoding(Some("*")), Some("gzip"));
+        assert_eq!(negotiate_encoding(Some("gzip;q=0, *")), Some("deflate"));
+        assert_eq!(negotiate_encoding(Some("br, identity")), None);
+    }
+}
@@ -1,12 +1,331 @@
+use crate::config::var;
 use once_cell::sync::Lazy;
-use std::collections::HashMap;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
 use std::sync::Mutex;
-use tokio::sync::RwLock;
 
 // HashMap<task_id, Vec<progress for each format>>
 pub static PROGRESS_MAP: Lazy<Mutex<HashMap<String, Vec<Option<i32>>>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
 
+// HashMap<task_id, Vec<estimated cost of each format>>
+pub static PROGRESS_WEIGHTS: Lazy<Mutex<HashMap<String, Vec<f64>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// HashMap<task_id, Vec<format id of each format>>
+pub static FORMAT_IDS: Lazy<Mutex<HashMap<String, Vec<u32>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// HashMap<task_id, Vec<whether each format failed>>
+pub static FAILED_FORMATS: Lazy<Mutex<HashMap<String, Vec<bool>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Ids of tasks that have been cancelled while queued or running
+pub static CANCELLED_TASKS: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+// HashMap<task_id, bytes of the task's source on disk>
+static TASK_DISK_BYTES: Lazy<Mutex<HashMap<String, u64>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// HashMap<task_id, paths of the files the task has written or is writing>
+static TASK_FILES: Lazy<Mutex<HashMap<String, HashSet<String>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Ids of tasks that exceeded `MAX_TASK_DISK_BYTES`
+static DISK_QUOTA_EXCEEDED: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+// Most bytes a task's source and outputs may take on disk while it runs
+pub static MAX_TASK_DISK_BYTES: Lazy<u64> = Lazy::new(|| {
+    var("MAX_TASK_DISK_BYTES")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0) // 0 disables the quota
+});
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FormatProgress {
+    pub format_id: u32,
+    pub percent: i32,
+    pub status: String,
+}
+
+/// Marks a task as cancelled. A queued task is skipped when it is dequeued; a running task stops at
+/// its next format, and its running ffmpeg process is killed.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+pub fn cancel_task(task_id: &str) {
+    CANCELLED_TASKS.lock().unwrap().insert(task_id.to_string());
+}
+
+/// Returns whether a task has been cancelled with `cancel_task`.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+pub fn is_task_cancelled(task_id: &str) -> bool {
+    CANCELLED_TASKS.lock().unwrap().contains(task_id)
+}
+
+/// Forgets that a task was cancelled, once the task itself is forgotten.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+pub fn forget_task_cancelled(task_id: &str) {
+    CANCELLED_TASKS.lock().unwrap().remove(task_id);
+}
+
+/// Adds to the bytes a task has on disk that are not in a file recorded with `record_task_file`,
+/// e.g. its downloaded source, which count against `MAX_TASK_DISK_BYTES`.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+/// * `bytes` - The size of the files added.
+///
+pub fn add_task_disk_bytes(task_id: &str, bytes: u64) {
+    *TASK_DISK_BYTES
+        .lock()
+        .unwrap()
+        .entry(task_id.to_string())
+        .or_insert(0) += bytes;
+}
+
+/// Records a file a task is about to write, such as an output, an HLS or scene segment, a
+/// first-pass log, an encrypted copy or an encode log. Its size counts against the task's
+/// `MAX_TASK_DISK_BYTES` for as long as it exists, and it is one of the files `task_files` returns
+/// for the task. A file another task recorded before, such as an output name shared by formats
+/// with the same id, now belongs to this task only.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+/// * `path` - The path of the file.
+///
+pub fn record_task_file(task_id: &str, path: &str) {
+    let mut task_files = TASK_FILES.lock().unwrap();
+    for (other_task_id, paths) in task_files.iter_mut() {
+        if other_task_id != task_id {
+            paths.remove(path);
+        }
+    }
+    task_files
+        .entry(task_id.to_string())
+        .or_default()
+        .insert(path.to_string());
+}
+
+/// Returns the files recorded with `record_task_file` for a task that still belong to it.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+pub fn task_files(task_id: &str) -> Vec<String> {
+    TASK_FILES
+        .lock()
+        .unwrap()
+        .get(task_id)
+        .map(|paths| paths.iter().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// Returns the bytes a task has on disk: those added with `add_task_disk_bytes` and the current
+/// size of each of its files that still exists.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+pub fn task_disk_bytes(task_id: &str) -> u64 {
+    let added_bytes = TASK_DISK_BYTES
+        .lock()
+        .unwrap()
+        .get(task_id)
+        .copied()
+        .unwrap_or(0);
+    let file_bytes: u64 = task_files(task_id)
+        .iter()
+        .filter_map(|path| std::fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    added_bytes + file_bytes
+}
+
+/// Checks a task's footprint on disk, as given by `task_disk_bytes`, against
+/// `MAX_TASK_DISK_BYTES`. A task over the quota is marked so it can be aborted.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+/// # Returns
+/// `false` if the task is over its quota.
+///
+pub fn check_task_disk_quota(task_id: &str) -> bool {
+    if *MAX_TASK_DISK_BYTES == 0 {
+        return true;
+    }
+
+    if task_disk_bytes(task_id) <= *MAX_TASK_DISK_BYTES {
+        return true;
+    }
+
+    DISK_QUOTA_EXCEEDED
+        .lock()
+        .unwrap()
+        .insert(task_id.to_string());
+    false
+}
+
+/// Returns whether a task has exceeded `MAX_TASK_DISK_BYTES`.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+pub fn is_disk_quota_exceeded(task_id: &str) -> bool {
+    DISK_QUOTA_EXCEEDED.lock().unwrap().contains(task_id)
+}
+
+/// Forgets a task's footprint on disk and the files it recorded, when it finishes or before it is
+/// retried.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+pub fn clear_task_disk_usage(task_id: &str) {
+    TASK_DISK_BYTES.lock().unwrap().remove(task_id);
+    TASK_FILES.lock().unwrap().remove(task_id);
+    DISK_QUOTA_EXCEEDED.lock().unwrap().remove(task_id);
+}
+
+/// Records the id of the format at `format_index` so per-format progress can be reported by id.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+/// * `format_index` - Index of the format being transcoded.
+/// * `format_id` - The `id` of the format.
+///
+pub fn set_format_id(task_id: &str, format_index: usize, format_id: u32) {
+    let mut format_ids_map = FORMAT_IDS.lock().unwrap();
+    let format_ids = format_ids_map
+        .entry(task_id.to_string())
+        .or_default();
+
+    if format_ids.len() <= format_index {
+        format_ids.resize(format_index + 1, 0);
+    }
+
+    format_ids[format_index] = format_id;
+}
+
+/// Marks the format at `format_index` as failed so it is reported with a "failed" status.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+/// * `format_index` - Index of the format that failed.
+///
+pub fn mark_format_failed(task_id: &str, format_index: usize) {
+    let mut failed_map = FAILED_FORMATS.lock().unwrap();
+    let failed = failed_map
+        .entry(task_id.to_string())
+        .or_default();
+
+    if failed.len() <= format_index {
+        failed.resize(format_index + 1, false);
+    }
+
+    failed[format_index] = true;
+}
+
+/// Clears the failed formats of a task, so that a retried task starts with none.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+pub fn clear_failed_formats(task_id: &str) {
+    FAILED_FORMATS.lock().unwrap().remove(task_id);
+}
+
+/// Forgets the progress, weights, format ids and failed formats of a task that is no longer kept.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+///
+pub fn forget_task_progress(task_id: &str) {
+    PROGRESS_MAP.lock().unwrap().remove(task_id);
+    PROGRESS_WEIGHTS.lock().unwrap().remove(task_id);
+    FORMAT_IDS.lock().unwrap().remove(task_id);
+    FAILED_FORMATS.lock().unwrap().remove(task_id);
+}
+
+/// Returns the progress of each format of a task along with its id and a status of "pending",
+/// "transcoding", "completed" or "failed". Returns an empty list if the task ID is not found.
+///
+/// # Arguments
+/// * `task_id` - The identifier for the task whose progress is being reported.
+///
+pub fn per_format_progress(task_id: &str) -> Vec<FormatProgress> {
+    let progress_map = PROGRESS_MAP.lock().unwrap();
+    let format_ids_map = FORMAT_IDS.lock().unwrap();
+    let failed_map = FAILED_FORMATS.lock().unwrap();
+
+    let progress_list = match progress_map.get(task_id) {
+        Some(progress_list) => progress_list,
+        None => return Vec::new(),
+    };
+    let format_ids = format_ids_map.get(task_id);
+    let failed = failed_map.get(task_id);
+
+    progress_list
+        .iter()
+        .enumerate()
+        .map(|(index, progress)| {
+            let percent = progress.unwrap_or(0);
+            let is_failed = failed
+                .and_then(|failed| failed.get(index).copied())
+                .unwrap_or(false);
+
+            let status = if is_failed {
+                "failed"
+            } else if percent >= 100 {
+                "completed"
+            } else if percent > 0 {
+                "transcoding"
+            } else {
+                "pending"
+            };
+
+            FormatProgress {
+                format_id: format_ids
+                    .and_then(|format_ids| format_ids.get(index).copied())
+                    .unwrap_or(0),
+                percent,
+                status: status.to_string(),
+            }
+        })
+        .collect()
+}
+
+/// Sets the weight of a format in the overall progress of a task, typically its estimated encode
+/// cost, so that expensive formats count for more of the overall percentage. Formats without a
+/// weight count as 1.0.
+///
+/// # Arguments
+/// * `task_id` - Identifier for the transcoding task.
+/// * `format_index` - Index of the format being transcoded.
+/// * `weight` - Relative weight of the format.
+///
+pub fn set_progress_weight(task_id: &str, format_index: usize, weight: f64) {
+    let mut weights_map = PROGRESS_WEIGHTS.lock().unwrap();
+    let weights = weights_map
+        .entry(task_id.to_string())
+        .or_default();
+
+    if weights.len() <= format_index {
+        weights.resize(format_index + 1, 1.0);
+    }
+
+    weights[format_index] = weight;
+}
+
 /// Updates the transcoding progress for a specific format of a given task in a global progress map.
 /// If the task or format index does not exist, they are created. Progress is stored as a percentage.
 ///
@@ -19,7 +338,7 @@ pub fn update_progress(task_id: &str, format_index: usize, progress: i32) {
     let mut progress_map = PROGRESS_MAP.lock().unwrap();
     let progress_list = progress_map
         .entry(task_id.to_string())
-        .or_insert_with(Vec::new);
+        .or_default();
 
     // Ensure the vector is large enough to hold progress for all formats
     if progress_list.len() <= format_index {
@@ -30,19 +349,35 @@ pub fn update_progress(task_id: &str, format_index: usize, progress: i32) {
     progress_list[format_index] = Some(progress);
 }
 
-/// Calculates the overall progress for a given task by averaging the progress values stored in
-/// `PROGRESS_MAP`. Returns 0 if the task ID is not found or if there are no progress values.
+/// Calculates the overall progress for a given task as the average of the progress values stored
+/// in `PROGRESS_MAP`, weighted by the format weights in `PROGRESS_WEIGHTS`. Returns 0 if the task
+/// ID is not found or if there are no progress values.
 ///
 /// # Arguments
 /// * `task_id` - The identifier for the task whose progress is being calculated.
 ///
 pub fn calculate_overall_progress(task_id: &str) -> i32 {
     let progress_map = PROGRESS_MAP.lock().unwrap();
+    let weights_map = PROGRESS_WEIGHTS.lock().unwrap();
+    let weights = weights_map.get(task_id);
+
     if let Some(progress_list) = progress_map.get(task_id) {
-        let sum: i32 = progress_list.iter().filter_map(|&p| p).sum();
-        let count: i32 = progress_list.iter().filter_map(|&p| p).count() as i32;
-        if count > 0 {
-            sum / count
+        let mut weighted_sum = 0.0;
+        let mut total_weight = 0.0;
+
+        for (index, progress) in progress_list.iter().enumerate() {
+            if let Some(progress) = progress {
+                let weight = weights
+                    .and_then(|weights| weights.get(index).copied())
+                    .filter(|weight| *weight > 0.0)
+                    .unwrap_or(1.0);
+                weighted_sum += *progress as f64 * weight;
+                total_weight += weight;
+            }
+        }
+
+        if total_weight > 0.0 {
+            (weighted_sum / total_weight) as i32
         } else {
             0
         }
@@ -50,3 +385,37 @@ pub fn calculate_overall_progress(task_id: &str) -> i32 {
         0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn task_disk_bytes_sums_the_files_each_task_still_owns() {
+        let dir = std::env::temp_dir();
+        let segment = dir.join("shared_test_task_hls_00000.ts").to_string_lossy().to_string();
+        let output = dir.join("shared_test_task_ue.mp4").to_string_lossy().to_string();
+        std::fs::write(&segment, [0u8; 100]).unwrap();
+        std::fs::write(&output, [0u8; 1000]).unwrap();
+
+        add_task_disk_bytes("shared_test_first", 10);
+        record_task_file("shared_test_first", &segment);
+        record_task_file("shared_test_first", &output);
+        assert_eq!(task_disk_bytes("shared_test_first"), 1110);
+
+        // A later task writing the same output takes it over
+        record_task_file("shared_test_second", &output);
+        assert_eq!(task_files("shared_test_first"), vec![segment.clone()]);
+        assert_eq!(task_disk_bytes("shared_test_first"), 110);
+        assert_eq!(task_disk_bytes("shared_test_second"), 1000);
+
+        // A file removed once uploaded no longer counts
+        std::fs::remove_file(&segment).unwrap();
+        assert_eq!(task_disk_bytes("shared_test_first"), 10);
+
+        clear_task_disk_usage("shared_test_first");
+        clear_task_disk_usage("shared_test_second");
+        assert!(task_files("shared_test_second").is_empty());
+        std::fs::remove_file(&output).unwrap();
+    }
+}
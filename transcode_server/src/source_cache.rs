@@ -0,0 +1,213 @@
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CachedSource {
+    // File name in the source cache directory, the source CID for a downloaded source
+    pub name: String,
+    pub size: u64,
+    // Unix time a task last used the file, or when it was last written if no task has used it
+    // since the server started
+    pub last_access: i64,
+    // Whether a running task is using the file
+    pub in_use: bool,
+}
+
+// HashMap<path of a cached source, unix time a task last used it>
+static LAST_ACCESS: Lazy<Mutex<HashMap<String, i64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Suffix of a source's normalized copy, added to its name
+const NORMALIZED_SUFFIX: &str = "_normalized.mkv";
+
+// Suffixes of the files a task writes while downloading an encrypted source, added to the source's
+// name and `_<task_id>_`: the locations metadata, the assembled parts, the record of their progress
+// and the decrypted source before it is checked
+const TASK_FILE_SUFFIXES: [&str; 5] = [
+    "",
+    "concat",
+    "concat.progress.json",
+    "concat.progress.json.tmp",
+    "decrypted",
+];
+
+/// Records that a task is using a cached source now.
+///
+/// # Arguments
+/// * `path` - The path of the cached source.
+///
+pub fn touch(path: &str) {
+    LAST_ACCESS
+        .lock()
+        .unwrap()
+        .insert(path.to_string(), Utc::now().timestamp());
+}
+
+/// Lists the files in the source cache directory, most recently used first.
+///
+/// # Arguments
+/// * `directory` - The source cache directory, `PATH_TO_FILE`.
+/// * `in_use` - Returns whether a running task is using the file at a path.
+///
+pub fn list_sources(directory: &str, in_use: &dyn Fn(&str) -> bool) -> Vec<CachedSource> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Failed to read source cache directory {}: {}", directory, e);
+            return Vec::new();
+        }
+    };
+
+    // Copied so `in_use` is not called with the lock held, as it may take locks that are held
+    // while `touch` is called
+    let last_access = LAST_ACCESS.lock().unwrap().clone();
+    let mut sources: Vec<CachedSource> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok().filter(|m| m.is_file())?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let path = format!("{}{}", directory, name);
+
+            let written_at = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |modified| modified.as_secs() as i64);
+            Some(CachedSource {
+                size: metadata.len(),
+                last_access: last_access.get(&path).copied().unwrap_or(written_at),
+                in_use: in_use(&path),
+                name,
+            })
+        })
+        .collect();
+
+    sources.sort_by_key(|source| std::cmp::Reverse(source.last_access));
+    sources
+}
+
+/// Returns whether `name` is the file of the source `source_name` or one derived from it, such as
+/// the parts of an encrypted download or its normalized copy. Only these exact names match, so
+/// another source whose CID starts with this one's is never mistaken for one of its files.
+fn is_source_file(name: &str, source_name: &str) -> bool {
+    let suffix = match name.strip_prefix(source_name) {
+        Some(suffix) => suffix,
+        None => return false,
+    };
+    if suffix.is_empty() || suffix == NORMALIZED_SUFFIX {
+        return true;
+    }
+
+    suffix
+        .strip_prefix('_')
+        .and_then(|suffix| suffix.split_once('_'))
+        .is_some_and(|(task_id, suffix)| {
+            Uuid::parse_str(task_id).is_ok() && TASK_FILE_SUFFIXES.contains(&suffix)
+        })
+}
+
+/// Deletes a source from the source cache along with the files derived from it. The caller must
+/// hold whatever lock tasks take to mark a source in use, so that no task starts using the source
+/// between the check and the deletion.
+///
+/// # Arguments
+/// * `directory` - The source cache directory, `PATH_TO_FILE`.
+/// * `source_name` - The name the source was downloaded to, its CID without network prefix.
+/// * `in_use` - Returns whether a running task is using, or still downloading, the file at a path.
+///
+/// # Returns
+/// The deleted files, none if the source is not cached, or an error message if a running task is
+/// using the source or any of its files, in which case nothing is deleted.
+///
+pub fn evict_source(
+    directory: &str,
+    source_name: &str,
+    in_use: &dyn Fn(&str) -> bool,
+) -> Result<Vec<CachedSource>, String> {
+    let source_path = format!("{}{}", directory, source_name);
+    if in_use(&source_path) {
+        return Err(format!("Source {} is in use by a running task", source_name));
+    }
+
+    let source_files: Vec<CachedSource> = list_sources(directory, in_use)
+        .into_iter()
+        .filter(|source| is_source_file(&source.name, source_name))
+        .collect();
+
+    if let Some(source) = source_files.iter().find(|source| source.in_use) {
+        return Err(format!(
+            "Source {} is in use by a running task",
+            source.name
+        ));
+    }
+
+    let mut last_access = LAST_ACCESS.lock().unwrap();
+    let mut evicted = Vec::new();
+    for source in source_files {
+        let path = format!("{}{}", directory, source.name);
+        match fs::remove_file(&path) {
+            Ok(()) => {
+                println!("Evicted cached source {}", path);
+                last_access.remove(&path);
+                evicted.push(source);
+            }
+            Err(e) => eprintln!("Failed to evict cached source {}: {}", path, e),
+        }
+    }
+
+    Ok(evicted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn only_a_sources_own_files_are_its_files() {
+        assert!(is_source_file("abc", "abc"));
+        assert!(is_source_file("abc_normalized.mkv", "abc"));
+        let task_id = Uuid::new_v4();
+        assert!(is_source_file(&format!("abc_{}_", task_id), "abc"));
+        assert!(is_source_file(&format!("abc_{}_concat", task_id), "abc"));
+        assert!(is_source_file(&format!("abc_{}_concat.progress.json", task_id), "abc"));
+        assert!(is_source_file(&format!("abc_{}_decrypted", task_id), "abc"));
+        assert!(!is_source_file(&format!("abc_{}_other", task_id), "abc"));
+
+        // Another source whose CID starts with this one's
+        assert!(!is_source_file("abc_def", "abc"));
+        assert!(!is_source_file("abc.mp4", "abc"));
+        assert!(!is_source_file("abcd", "abc"));
+        assert!(!is_source_file("ab", "abc"));
+    }
+
+    #[test]
+    fn eviction_refuses_a_source_in_use() {
+        let directory = std::env::temp_dir().join(format!("source_cache_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let directory = format!("{}/", directory.display());
+        let concat_name = format!("abc_{}_concat", Uuid::new_v4());
+        for name in ["abc", concat_name.as_str(), "abc_def"] {
+            fs::write(format!("{}{}", directory, name), name).unwrap();
+        }
+
+        let source_path = format!("{}abc", directory);
+        assert!(evict_source(&directory, "abc", &|path: &str| path == source_path).is_err());
+
+        let mut evicted: Vec<String> = evict_source(&directory, "abc", &|_: &str| false)
+            .unwrap()
+            .into_iter()
+            .map(|source| source.name)
+            .collect();
+        evicted.sort();
+        assert_eq!(evicted, vec!["abc".to_string(), concat_name]);
+        assert!(Path::new(&format!("{}abc_def", directory)).exists());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,192 @@
+use crate::transcode_video::FFMPEG_PATH;
+use std::fs;
+use std::process::Command;
+use uuid::Uuid;
+
+// Prefix of a source that is generated instead of downloaded
+pub const TEST_PATTERN_PREFIX: &str = "testsrc://";
+
+// Longest test pattern that can be generated
+const MAX_TEST_PATTERN_SECS: u32 = 3600;
+
+// Largest test pattern width and height, those of 8K UHD
+const MAX_TEST_PATTERN_WIDTH: u32 = 7680;
+const MAX_TEST_PATTERN_HEIGHT: u32 = 4320;
+
+// Frame rate of the generated video
+const TEST_PATTERN_FRAME_RATE: u32 = 30;
+
+/// A synthetic source given as `testsrc://<resolution>:<duration>`, such as `testsrc://1080p:60s`
+/// or `testsrc://1280x720:10s`. A resolution of `<height>p` is 16:9.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TestPattern {
+    pub width: u32,
+    pub height: u32,
+    pub duration_secs: u32,
+}
+
+impl TestPattern {
+    /// The name of the generated file in `PATH_TO_FILE`, the same for every task asking for the
+    /// same pattern so it is generated once and then reused like a downloaded source.
+    pub fn file_name(&self) -> String {
+        format!(
+            "testsrc_{}x{}_{}s.mkv",
+            self.width, self.height, self.duration_secs
+        )
+    }
+
+    /// Generates the pattern with ffmpeg: the `testsrc2` video at `TEST_PATTERN_FRAME_RATE` with a
+    /// 1 kHz `sine` tone as its audio. The file is written under a temporary name and then renamed,
+    /// so a task never reads a partly generated pattern.
+    ///
+    /// # Arguments
+    /// * `file_path` - The path to generate the pattern to.
+    ///
+    pub fn generate(&self, file_path: &str) -> Result<(), String> {
+        let video = format!(
+            "testsrc2=size={}x{}:rate={}:duration={}",
+            self.width, self.height, TEST_PATTERN_FRAME_RATE, self.duration_secs
+        );
+        let audio = format!(
+            "sine=frequency=1000:sample_rate=48000:duration={}",
+            self.duration_secs
+        );
+        let partial_path = format!("{}.{}.partial.mkv", file_path, Uuid::new_v4());
+
+        let mut cmd = Command::new(FFMPEG_PATH.as_str());
+        cmd.args(["-v", "error", "-f", "lavfi", "-i", video.as_str()]);
+        cmd.args(["-f", "lavfi", "-i", audio.as_str()]);
+        cmd.args(["-map", "0:v", "-map", "1:a"]);
+        cmd.args(["-c:v", "libx264", "-preset", "ultrafast", "-crf", "10"]);
+        cmd.args(["-pix_fmt", "yuv420p", "-c:a", "flac"]);
+        cmd.args(["-y", partial_path.as_str()]);
+        println!("Generating test pattern {}", file_path);
+
+        let output = cmd
+            .output()
+            .map_err(|e| format!("Failed to execute ffmpeg: {}", e))?;
+        if !output.status.success() {
+            let _ = fs::remove_file(&partial_path);
+            return Err(format!(
+                "Failed to generate test pattern: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        fs::rename(&partial_path, file_path).map_err(|e| {
+            let _ = fs::remove_file(&partial_path);
+            format!("Failed to generate test pattern: {}", e)
+        })
+    }
+}
+
+/// Parses a `testsrc://` source.
+///
+/// # Arguments
+/// * `source` - The source CID as submitted.
+///
+/// # Returns
+/// The test pattern, `None` if the source is not a test pattern, or an error message.
+///
+pub fn parse_test_pattern(source: &str) -> Option<Result<TestPattern, String>> {
+    let spec = source.strip_prefix(TEST_PATTERN_PREFIX)?;
+    Some(parse_spec(spec))
+}
+
+fn parse_spec(spec: &str) -> Result<TestPattern, String> {
+    let (resolution, duration) = spec
+        .split_once(':')
+        .ok_or_else(|| "a test pattern must be testsrc://<resolution>:<duration>".to_string())?;
+
+    let (width, height) = match resolution.strip_suffix('p') {
+        Some(height) => {
+            let height = height
+                .parse::<u32>()
+                .map_err(|_| format!("invalid test pattern resolution {}", resolution))?;
+            // Rounded to an even width, as yuv420p needs
+            ((height.saturating_mul(16) / 9).div_ceil(2) * 2, height)
+        }
+        None => resolution
+            .split_once('x')
+            .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+            .ok_or_else(|| format!("invalid test pattern resolution {}", resolution))?,
+    };
+    if width == 0
+        || height == 0
+        || width > MAX_TEST_PATTERN_WIDTH
+        || height > MAX_TEST_PATTERN_HEIGHT
+        || width % 2 != 0
+        || height % 2 != 0
+    {
+        return Err(format!(
+            "test pattern resolution {}x{} must be even and at most {}x{}",
+            width, height, MAX_TEST_PATTERN_WIDTH, MAX_TEST_PATTERN_HEIGHT
+        ));
+    }
+
+    let duration_secs = duration
+        .strip_suffix('s')
+        .unwrap_or(duration)
+        .parse::<u32>()
+        .ok()
+        .filter(|secs| (1..=MAX_TEST_PATTERN_SECS).contains(secs))
+        .ok_or_else(|| {
+            format!(
+                "test pattern duration {} must be whole seconds from 1 to {}",
+                duration, MAX_TEST_PATTERN_SECS
+            )
+        })?;
+
+    Ok(TestPattern {
+        width,
+        height,
+        duration_secs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_spec_reads_the_resolution_and_duration() {
+        assert_eq!(
+            parse_test_pattern("testsrc://1080p:60s"),
+            Some(Ok(TestPattern {
+                width: 1920,
+                height: 1080,
+                duration_secs: 60,
+            }))
+        );
+        // Rounded to an even width
+        assert_eq!(parse_spec("360p:10").map(|pattern| pattern.width), Ok(640));
+        assert_eq!(parse_spec("574p:1s").map(|pattern| pattern.width), Ok(1020));
+        assert_eq!(
+            parse_spec("1280x720:10s"),
+            Ok(TestPattern {
+                width: 1280,
+                height: 720,
+                duration_secs: 10,
+            })
+        );
+        assert_eq!(parse_test_pattern("ipfs://QmSource"), None);
+    }
+
+    #[test]
+    fn parse_spec_rejects_invalid_patterns() {
+        for spec in [
+            "1080p",
+            "1080p:0s",
+            "1080p:3601s",
+            "1080p:1.5s",
+            "hd:10s",
+            "1280x:10s",
+            "1281x720:10s",
+            "0x720:10s",
+            "7682x4320:10s",
+            "4322p:10s",
+        ] {
+            assert!(parse_spec(spec).is_err(), "{}", spec);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use crate::config::var;
+use once_cell::sync::Lazy;
+use std::io::{self, Read};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Bytes per second source downloads are throttled to, 0 for no limit
+static DOWNLOAD_RATE_LIMIT_BYTES_PER_SEC: Lazy<u64> = Lazy::new(|| {
+    var("DOWNLOAD_RATE_LIMIT_BYTES_PER_SEC")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0)
+});
+
+/// How `DOWNLOAD_RATE_LIMIT_BYTES_PER_SEC` is applied to concurrent downloads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitMode {
+    // The limit is shared between all concurrent downloads
+    Global,
+    // Each download gets the whole limit
+    PerDownload,
+}
+
+/// Reads `DOWNLOAD_RATE_LIMIT_MODE`, which is "global" when not set or empty, or "per_download".
+pub fn rate_limit_mode() -> Result<RateLimitMode, String> {
+    match var("DOWNLOAD_RATE_LIMIT_MODE").unwrap_or_default().trim() {
+        "" | "global" => Ok(RateLimitMode::Global),
+        "per_download" => Ok(RateLimitMode::PerDownload),
+        mode => Err(format!(
+            "Invalid DOWNLOAD_RATE_LIMIT_MODE {}; expected global or per_download",
+            mode
+        )),
+    }
+}
+
+// Checked by `rate_limit_mode` at startup
+static DOWNLOAD_RATE_LIMIT_MODE: Lazy<RateLimitMode> =
+    Lazy::new(|| rate_limit_mode().unwrap_or(RateLimitMode::Global));
+
+// Fewest bytes a throttled read waits for, so a saturated limit is not spent on tiny reads
+const MIN_READ_BYTES: u64 = 16 * 1024;
+
+// The bucket shared by every download in "global" mode
+static GLOBAL_BUCKET: Lazy<Mutex<TokenBucket>> =
+    Lazy::new(|| Mutex::new(TokenBucket::new(*DOWNLOAD_RATE_LIMIT_BYTES_PER_SEC)));
+
+/// A token bucket holding up to one second of bytes at `rate`, refilled continuously.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket for `rate` bytes per second.
+    pub fn new(rate: u64) -> Self {
+        TokenBucket {
+            rate,
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.last_refill = now;
+    }
+
+    /// Takes tokens for a read of up to `wanted` bytes.
+    ///
+    /// # Returns
+    /// The number of bytes that may be read, at least `MIN_READ_BYTES` or `wanted` if less, or how
+    /// long to wait until that many tokens are available.
+    ///
+    pub fn take(&mut self, wanted: u64, now: Instant) -> Result<u64, Duration> {
+        self.refill(now);
+        let needed = wanted.min(MIN_READ_BYTES).min(self.rate).max(1) as f64;
+        if self.tokens < needed {
+            return Err(Duration::from_secs_f64(
+                (needed - self.tokens) / self.rate as f64,
+            ));
+        }
+
+        let taken = (wanted as f64).min(self.tokens.floor());
+        self.tokens -= taken;
+        Ok(taken as u64)
+    }
+
+    /// Returns tokens taken for bytes that were not read.
+    pub fn refund(&mut self, unused: u64) {
+        self.tokens = (self.tokens + unused as f64).min(self.rate as f64);
+    }
+}
+
+/// A reader that limits how fast `inner` is read to `DOWNLOAD_RATE_LIMIT_BYTES_PER_SEC`, by
+/// sleeping until its token bucket has enough tokens for each read. Meant for the blocking
+/// download path, so it must not be read on an async worker thread.
+pub struct ThrottledReader<R> {
+    inner: R,
+    // The download's own bucket in "per_download" mode, `None` to use the global one
+    bucket: Option<TokenBucket>,
+}
+
+impl<R: Read> ThrottledReader<R> {
+    fn take(&mut self, wanted: u64) -> Result<u64, Duration> {
+        match &mut self.bucket {
+            Some(bucket) => bucket.take(wanted, Instant::now()),
+            None => GLOBAL_BUCKET.lock().unwrap().take(wanted, Instant::now()),
+        }
+    }
+
+    fn refund(&mut self, unused: u64) {
+        match &mut self.bucket {
+            Some(bucket) => bucket.refund(unused),
+            None => GLOBAL_BUCKET.lock().unwrap().refund(unused),
+        }
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if *DOWNLOAD_RATE_LIMIT_BYTES_PER_SEC == 0 || buf.is_empty() {
+            return self.inner.read(buf);
+        }
+
+        let allowed = loop {
+            match self.take(buf.len() as u64) {
+                Ok(allowed) => break allowed as usize,
+                Err(wait) => std::thread::sleep(wait),
+            }
+        };
+        let result = self.inner.read(&mut buf[..allowed]);
+        let read = *result.as_ref().unwrap_or(&0);
+        self.refund((allowed - read) as u64);
+        result
+    }
+}
+
+/// Wraps a download's response body so it is read no faster than
+/// `DOWNLOAD_RATE_LIMIT_BYTES_PER_SEC`, shared with every other download unless
+/// `DOWNLOAD_RATE_LIMIT_MODE` is "per_download". Downloads are not throttled when the limit is 0,
+/// the default.
+///
+/// # Arguments
+/// * `inner` - The response body.
+///
+pub fn throttle_download<R: Read>(inner: R) -> ThrottledReader<R> {
+    let bucket = if *DOWNLOAD_RATE_LIMIT_MODE == RateLimitMode::PerDownload {
+        Some(TokenBucket::new(*DOWNLOAD_RATE_LIMIT_BYTES_PER_SEC))
+    } else {
+        None
+    };
+    ThrottledReader { inner, bucket }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket_allows_a_second_of_bytes_then_waits_for_the_refill() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(100_000);
+
+        assert_eq!(bucket.take(60_000, start), Ok(60_000));
+        // Only the rest of the first second's bytes are left
+        assert_eq!(bucket.take(60_000, start), Ok(40_000));
+        assert_eq!(
+            bucket.take(60_000, start),
+            Err(Duration::from_secs_f64(MIN_READ_BYTES as f64 / 100_000.0))
+        );
+
+        // Refilled at the rate, up to one second of bytes
+        let later = start + Duration::from_millis(500);
+        assert_eq!(bucket.take(100_000, later), Ok(50_000));
+        let much_later = later + Duration::from_secs(10);
+        assert_eq!(bucket.take(1_000_000, much_later), Ok(100_000));
+    }
+
+    #[test]
+    fn token_bucket_refunds_unread_bytes() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(100_000);
+
+        assert_eq!(bucket.take(100_000, now), Ok(100_000));
+        bucket.refund(40_000);
+        assert_eq!(bucket.take(100_000, now), Ok(40_000));
+        // A refund never fills the bucket past its capacity
+        bucket.refund(500_000);
+        assert_eq!(bucket.take(500_000, now), Ok(100_000));
+    }
+}
@@ -1,27 +1,28 @@
+use crate::capabilities;
 use crate::shared;
 
-use crate::encrypt_file::encrypt_file_xchacha20;
+use crate::encrypt_file::{encrypt_file_xchacha20, CHUNK_SIZE_AS_POWER_OF_2};
 use crate::encrypted_cid::create_encrypted_cid;
+use crate::error_code::TranscodeErrorCode;
+use crate::probe::{probe_source, ProbeStream, SourceProbe};
 use crate::s5::hash_blake3_file;
-use crate::s5::upload_video;
-use crate::utils::{
-    base64url_to_bytes, bytes_to_base64url, download_and_concat_files, download_video,
-    hash_bytes_to_cid,
-};
-use base64::{engine::general_purpose, DecodeError, Engine as _};
-use dotenv::var;
+use crate::s5::{upload_stream_ipfs, upload_video};
+use crate::sample_aes;
+use crate::utils::{bytes_to_base64url, env_flag, hash_bytes_to_cid, list_files_recursive};
+use base64::{engine::general_purpose, Engine as _};
+use crate::config::var;
 use once_cell::sync::Lazy;
 use regex::Regex;
 use sanitize_filename::sanitize;
-use serde::Deserialize;
-use serde_json;
-use std::error::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fs::metadata;
-use std::io::{BufRead, BufReader};
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::path::Path;
-use std::process::{Command, Stdio};
-use tokio::io::AsyncReadExt;
-use tonic::{transport::Server, Code, Request, Response, Status};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use tonic::{Code, Response, Status};
 
 static PATH_TO_FILE: Lazy<String> =
     Lazy::new(|| var("PATH_TO_FILE").unwrap_or_else(|_| panic!("PATH_TO_FILE not set in .env")));
@@ -29,28 +30,324 @@ static PATH_TO_TRANSCODED_FILE: Lazy<String> = Lazy::new(|| {
     var("PATH_TO_TRANSCODED_FILE")
         .unwrap_or_else(|_| panic!("PATH_TO_TRANSCODED_FILE not set in .env"))
 });
+pub static FFMPEG_PATH: Lazy<String> =
+    Lazy::new(|| var("FFMPEG_PATH").unwrap_or_else(|_| "ffmpeg".to_string()));
+// `-loglevel` of transcodes, e.g. "warning" for quieter logs or "verbose" for more detail
+static FFMPEG_LOGLEVEL: Lazy<String> = Lazy::new(|| {
+    var("FFMPEG_LOGLEVEL")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| "info".to_string())
+});
+
+// Font file drawn with by `text_watermark`; fontconfig's default font when not set
+static WATERMARK_FONT_FILE: Lazy<Option<String>> =
+    Lazy::new(|| var("WATERMARK_FONT_FILE").ok().filter(|v| !v.is_empty()));
+
+// URL players fetch an uploaded HLS segment or key from, with `{cid}` replaced by its CID; the
+// CID prefixed with its storage network when not set
+static HLS_CONTENT_URL: Lazy<Option<String>> =
+    Lazy::new(|| var("HLS_CONTENT_URL").ok().filter(|v| !v.is_empty()));
+
+// Storage network the outputs of a format without `dest` are uploaded to
+pub static DEFAULT_DEST: Lazy<String> = Lazy::new(|| {
+    var("DEFAULT_DEST")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| "s5".to_string())
+});
+
+// Storage networks outputs can be uploaded to
+const DESTS: [&str; 2] = ["s5", "ipfs"];
+
+// Directory holding the local preset files a format's `preset_file` can name; local preset files
+// are rejected when not set
+static PRESET_DIR: Lazy<Option<String>> =
+    Lazy::new(|| var("PRESET_DIR").ok().filter(|v| !v.is_empty()));
+
+// Largest preset file accepted, in bytes
+const MAX_PRESET_FILE_SIZE: u64 = 64 * 1024;
+
+// Frames of a rendition hashed for `perceptual_hash`, spread evenly over its duration
+const PERCEPTUAL_HASH_FRAMES: usize = 16;
+
+// Width and height of the grayscale image a frame is scaled to before it is hashed
+const PHASH_IMAGE_SIZE: usize = 32;
+
+// Most distinct ffmpeg warnings kept per rendition
+const MAX_FFMPEG_WARNINGS: usize = 20;
+
+// Largest encode log saved for a format with `save_encode_log`, in bytes. Half of it holds the
+// start of ffmpeg's output and half its end
+static MAX_ENCODE_LOG_BYTES: Lazy<u64> = Lazy::new(|| {
+    var("MAX_ENCODE_LOG_BYTES")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(10 * 1024 * 1024)
+});
+
+// Default ffmpeg `-threads` for every encode. 0 keeps ffmpeg's automatic thread selection
+static FFMPEG_THREADS: Lazy<u32> = Lazy::new(|| {
+    var("FFMPEG_THREADS")
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(0)
+});
+
+// Small sources can be transcoded on a tmpfs/ramdisk to avoid disk I/O for intermediates
+static USE_TMPFS: Lazy<bool> = Lazy::new(|| env_flag("USE_TMPFS"));
+// Whether each rendition is written to a directory of its own, named by `rendition_dir_name`
+static UNIQUE_OUTPUT_DIRS: Lazy<bool> = Lazy::new(|| env_flag("UNIQUE_OUTPUT_DIRS"));
+// HashMap<directory name, number of renditions writing to it>, of `UNIQUE_OUTPUT_DIRS` directories
+static ACTIVE_OUTPUT_DIRS: Lazy<Mutex<HashMap<String, usize>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+static TMPFS_PATH: Lazy<String> = Lazy::new(|| {
+    var("TMPFS_PATH")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| "/dev/shm/transcode/".to_string())
+});
+static TMPFS_MAX_SOURCE_SIZE: Lazy<u64> = Lazy::new(|| {
+    var("TMPFS_MAX_SOURCE_SIZE")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(268435456) // default to 256 MiB
+});
+
+// Maximum output width x height; formats above it are clamped (or rejected). 0 disables the cap
+static MAX_OUTPUT_PIXELS: Lazy<u64> = Lazy::new(|| {
+    var("MAX_OUTPUT_PIXELS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0)
+});
+static REJECT_OVERSIZED_OUTPUT: Lazy<bool> = Lazy::new(|| {
+    var("MAX_OUTPUT_PIXELS_ACTION")
+        .map(|v| v.eq_ignore_ascii_case("reject"))
+        .unwrap_or(false)
+});
+
+// Maximum ffmpeg processes encoding on the GPU at once, as consumer cards only allow a few NVENC
+// sessions. 0 disables the limit
+static MAX_GPU_SESSIONS: Lazy<u32> = Lazy::new(|| {
+    var("MAX_GPU_SESSIONS")
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(3)
+});
+// Maximum ffmpeg processes encoding on the CPU at once. 0 disables the limit
+static MAX_CPU_SESSIONS: Lazy<u32> = Lazy::new(|| {
+    var("MAX_CPU_SESSIONS")
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(0)
+});
+
+static GPU_SESSIONS: Lazy<EncodeSessions> = Lazy::new(|| EncodeSessions::new(*MAX_GPU_SESSIONS));
+static CPU_SESSIONS: Lazy<EncodeSessions> = Lazy::new(|| EncodeSessions::new(*MAX_CPU_SESSIONS));
 
 pub mod transcode {
     tonic::include_proto!("transcode");
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct TranscodeVideoResponse {
     pub status_code: i32,
     pub message: String,
     pub cid: String,
+    pub blake3: String,
+    // Size in bytes of the transcoded output before encryption
+    pub output_size: u64,
+    // CID of the rendition's ffmpeg log when the format sets `save_encode_log`
+    pub encode_log_cid: String,
+    // CID of the rendition's sidecar JSON when the format sets `emit_sidecar`
+    pub sidecar_cid: String,
+    // The ffmpeg program and arguments the rendition was transcoded with
+    pub ffmpeg_command: Vec<String>,
+    // CID of the audio-only output when the format sets `also_extract_audio`
+    pub audio_cid: String,
+    // Wall-clock seconds ffmpeg took to encode the rendition, including every pass
+    pub encode_secs: f64,
+    // Seconds of output encoded per wall-clock second, e.g. 2.3 for 2.3x realtime
+    pub encode_speed: f64,
+    // VMAF score of the output against the source when the format sets `min_vmaf`
+    pub vmaf_score: Option<f64>,
+    // Whether the VMAF score is below the format's `min_vmaf`
+    pub low_quality: bool,
+    // Distinct warnings ffmpeg logged while encoding, e.g. non-monotonic timestamps
+    pub warnings: Vec<String>,
+    // Segments of the rendition cut at scene changes when the format sets `scene_split`
+    pub scenes: Vec<SceneSegment>,
+    // CID of the AES-128 key of an encrypted HLS rendition whose key was uploaded
+    pub hls_key_cid: String,
+    // Hex AES-128 key of an encrypted HLS rendition served from the format's own `key_uri`
+    pub hls_key: String,
+    // CID of the `#EXT-X-I-FRAMES-ONLY` playlist of an HLS rendition with `iframe_playlist`
+    pub iframe_playlist_cid: String,
+    // Hex 64-bit pHashes of keyframes of the rendition when the format sets `perceptual_hash`
+    pub perceptual_hashes: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HlsEncryption {
+    // One of `HLS_ENCRYPTION_METHODS`, "aes-128" when not given
+    method: Option<String>,
+    // One of `HLS_KEY_DELIVERIES`, "upload" when not given
+    key_delivery: Option<String>,
+    // URI of the key on the client's key server, for "key_uri" delivery
+    key_uri: Option<String>,
+    // Target segment duration in seconds, `DEFAULT_HLS_SEGMENT_SECS` when not given
+    segment_secs: Option<u32>,
+}
+
+pub const HLS_ENCRYPTION_METHODS: [&str; 2] = ["aes-128", "sample-aes"];
+// "upload" uploads the key and points the playlist at it; "key_uri" points the playlist at the
+// format's `key_uri` and returns the key with the rendition
+pub const HLS_KEY_DELIVERIES: [&str; 2] = ["upload", "key_uri"];
+const DEFAULT_HLS_SEGMENT_SECS: u32 = 6;
+const MAX_HLS_SEGMENT_SECS: u32 = 60;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LlHls {
+    // Target segment duration in seconds, `DEFAULT_HLS_SEGMENT_SECS` when not given
+    segment_secs: Option<u32>,
+    // Target partial segment duration in seconds, `DEFAULT_LL_HLS_PART_SECS` when not given
+    part_secs: Option<f64>,
+}
+
+const DEFAULT_LL_HLS_PART_SECS: f64 = 1.0;
+// Shortest partial segment, as players fetch each one with a request of its own
+const MIN_LL_HLS_PART_SECS: f64 = 0.2;
+
+/// An HLS rendition uploaded by `package_hls`.
+#[derive(Debug, Clone, Default)]
+pub struct PackagedHls {
+    pub playlist_cid: String,
+    // Set with `iframe_playlist`
+    pub iframe_playlist_cid: String,
+    // Set for "upload" key delivery
+    pub key_cid: String,
+    // Set for "key_uri" key delivery
+    pub key: String,
+}
+
+/// A keyframe of an HLS segment, referenced by an I-frame playlist.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keyframe {
+    // Presentation time in seconds
+    pub time: f64,
+    // Byte offset in its segment of the PAT and PMT preceding the keyframe, or of the keyframe
+    pub offset: u64,
+    // Number of bytes from `offset` holding the tables and the keyframe
+    pub length: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AudioExtract {
+    codec: String,
+    bitrate: Option<String>,
+    // Derived from the codec when not given
+    ext: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AudioDescription {
+    // CID or URL of the narration track, downloaded like the source
+    cid: String,
+    // Gain applied to the narration, 1.0 when not given
+    level: Option<f64>,
+    // Gain applied to the main audio, 1.0 when not given
+    main_level: Option<f64>,
+    // Compression ratio ducking the main audio while the narration plays, 1 for no ducking and
+    // `DEFAULT_DUCK_RATIO` when not given
+    duck_ratio: Option<f64>,
+}
+
+const DEFAULT_DUCK_RATIO: f64 = 4.0;
+// Largest `level` and `main_level` accepted
+const MAX_AUDIO_LEVEL: f64 = 4.0;
+// Largest `duck_ratio` accepted, the most sidechaincompress allows
+const MAX_DUCK_RATIO: f64 = 20.0;
+// How quickly the main audio is ducked when narration starts and restored when it stops
+const DUCK_OPTIONS: &str = "threshold=0.05:attack=20:release=400";
+// amix divides each of its inputs by their number, the 2 of an audio-description mix, and its
+// `normalize=0` that turns this off needs ffmpeg 4.4, so both inputs are scaled back up instead
+const AMIX_GAIN: f64 = 2.0;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TextWatermark {
+    text: String,
+    fontsize: Option<u32>,
+    // One of `WATERMARK_POSITIONS`, "bottom-right" when not given
+    position: Option<String>,
+    // An ffmpeg color such as "white", "#ffcc00" or "white@0.5", "white" when not given
+    color: Option<String>,
+}
+
+pub const WATERMARK_POSITIONS: [&str; 9] = [
+    "top-left",
+    "top",
+    "top-right",
+    "left",
+    "center",
+    "right",
+    "bottom-left",
+    "bottom",
+    "bottom-right",
+];
+
+// Distance in pixels between a text watermark and the edges of the frame
+const WATERMARK_MARGIN: u32 = 10;
+
+/// A format's `fps`: frames per second, or "source" to encode at the source's frame rate.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum FrameRate {
+    Rate(f64),
+    Named(String),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Chapter {
+    // Seconds into the output
+    start: f64,
+    title: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SceneSplit {
+    // Minimum scene change score from 0 to 1 that starts a new segment
+    threshold: Option<f64>,
+}
+
+// Scene change score used when `scene_split` sets no threshold
+const DEFAULT_SCENE_THRESHOLD: f64 = 0.4;
+
+/// A segment of a rendition split at scene cuts, with its times in the rendition in seconds.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SceneSegment {
+    pub cid: String,
+    pub start: f64,
+    pub end: f64,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct VideoFormat {
     pub id: u32,
+    #[serde(default)]
     pub ext: String,
+    #[serde(rename = "type")]
+    mime_type: Option<String>,
     vcodec: Option<String>,
     acodec: Option<String>,
     preset: Option<String>,
+    // Accepted in media formats but not passed to ffmpeg
+    #[allow(dead_code)]
     profile: Option<String>,
     ch: Option<u8>,
     vf: Option<String>,
+    // Audio filters, applied after the `audio_description` mix when there is one
+    af: Option<String>,
     b_v: Option<String>,
     c_a: Option<String>,
     b_a: Option<String>,
@@ -61,7 +358,149 @@ pub struct VideoFormat {
     gpu: Option<bool>,
     compression_level: Option<u8>,
     pub dest: Option<String>,
-    encrypt: Option<bool>,
+    pub encrypt: Option<bool>,
+    audio_stream_index: Option<u32>,
+    video_stream_index: Option<u32>,
+    start: Option<String>,
+    end: Option<String>,
+    frame_accurate: Option<bool>,
+    avoid_negative_ts: Option<bool>,
+    color_range: Option<String>,
+    scale_flags: Option<String>,
+    packaging: Option<String>,
+    threads: Option<u32>,
+    fragmented: Option<bool>,
+    faststart: Option<bool>,
+    emit_sidecar: Option<bool>,
+    save_encode_log: Option<bool>,
+    min_keyint: Option<u32>,
+    max_keyint: Option<u32>,
+    crf: Option<u8>,
+    two_pass: Option<bool>,
+    quality_mode: Option<String>,
+    auto_rotate: Option<bool>,
+    audio_mode: Option<String>,
+    audio_quality: Option<f32>,
+    map: Option<Vec<String>>,
+    also_extract_audio: Option<AudioExtract>,
+    stream_upload: Option<bool>,
+    target_size_mb: Option<f64>,
+    min_vmaf: Option<f64>,
+    vmaf_strict: Option<bool>,
+    perceptual_hash: Option<bool>,
+    // Set by the server on formats of a task that share an `id`, so their outputs get different
+    // names, e.g. "_2"
+    name_suffix: Option<String>,
+    scene_split: Option<SceneSplit>,
+    chapters: Option<Vec<Chapter>>,
+    text_watermark: Option<TextWatermark>,
+    fps: Option<FrameRate>,
+    max_fps: Option<f64>,
+    preset_file: Option<String>,
+    hls_encryption: Option<HlsEncryption>,
+    iframe_playlist: Option<bool>,
+    ll_hls: Option<LlHls>,
+    audio_description: Option<AudioDescription>,
+    // Path of the downloaded `audio_description` track, set by `apply_audio_description`
+    #[serde(skip)]
+    audio_description_path: Option<String>,
+    // Path of the preset file named by `preset_file`, set by `apply_preset_file`
+    #[serde(skip)]
+    preset_path: Option<String>,
+    // Frame rate to encode at, resolved against the source's by `apply_source_frame_rate`
+    #[serde(skip)]
+    output_fps: Option<f64>,
+    // ffmpeg metadata file holding the format's `chapters`, written by `write_chapters_file`
+    #[serde(skip)]
+    chapters_file: Option<String>,
+    // Clockwise rotation of the source's video stream, set by `apply_source_rotation`
+    #[serde(skip)]
+    source_rotation: u32,
+    // Index among the source's video streams of the one to encode, set by
+    // `apply_source_video_stream` when the source has several or `video_stream_index` is set
+    #[serde(skip)]
+    source_video_stream: Option<u32>,
+    // Whether `max_pixels_filter` has been added to `vf` to cap the output at `MAX_OUTPUT_PIXELS`
+    #[serde(skip)]
+    output_pixels_capped: bool,
+}
+
+pub const QUALITY_MODES: [&str; 3] = ["speed", "balanced", "quality"];
+
+// Containers from the MP4 family that accept `-movflags`
+const MP4_EXTENSIONS: [&str; 4] = ["mp4", "m4a", "m4v", "mov"];
+
+const DEFAULT_COLOR_RANGE: &str = "tv";
+const DEFAULT_SCALE_FLAGS: &str = "bicubic";
+const COLOR_RANGES: [&str; 2] = ["tv", "pc"];
+const SCALE_FLAGS: [&str; 11] = [
+    "fast_bilinear",
+    "bilinear",
+    "bicubic",
+    "experimental",
+    "neighbor",
+    "area",
+    "bicublin",
+    "gauss",
+    "sinc",
+    "lanczos",
+    "spline",
+];
+
+/// Parses an ffmpeg bitrate such as "3.5M", "800k" or "128000" into bits per second.
+fn parse_bitrate(bitrate: &str) -> Option<f64> {
+    let bitrate = bitrate.trim();
+    let (number, multiplier) = match bitrate.chars().last()? {
+        'k' | 'K' => (&bitrate[..bitrate.len() - 1], 1_000.0),
+        'm' | 'M' => (&bitrate[..bitrate.len() - 1], 1_000_000.0),
+        'g' | 'G' => (&bitrate[..bitrate.len() - 1], 1_000_000_000.0),
+        _ => (bitrate, 1.0),
+    };
+
+    number.parse::<f64>().ok().map(|n| n * multiplier)
+}
+
+/// Parses an ffmpeg time such as "90", "1:30" or "00:01:30.5" into seconds.
+fn parse_time(time: &str) -> Option<f64> {
+    time.trim().split(':').try_fold(0.0, |seconds, part| {
+        part.parse::<f64>().ok().map(|part| seconds * 60.0 + part)
+    })
+}
+
+/// Parses the output resolution from a `scale=WxH` or `scale=W:H` video filter.
+fn parse_scale(vf: &str) -> Option<(u32, u32)> {
+    let re = Regex::new(r"scale=(\d+)[x:](\d+)").unwrap();
+    let caps = re.captures(vf)?;
+    let width = caps.get(1)?.as_str().parse::<u32>().ok()?;
+    let height = caps.get(2)?.as_str().parse::<u32>().ok()?;
+    Some((width, height))
+}
+
+impl VideoFormat {
+    /// Estimates the relative cost of encoding this format as output megapixels times video
+    /// bitrate in Mbit/s, so that a 1080p rendition weighs far more than a 360p one. Audio-only
+    /// formats get a small fixed cost.
+    pub fn estimated_cost(&self) -> f64 {
+        let is_video = self.vcodec.as_deref().is_some_and(|vcodec| !vcodec.is_empty());
+        if !is_video {
+            return 0.05;
+        }
+
+        let megapixels = self
+            .vf
+            .as_deref()
+            .and_then(parse_scale)
+            .map(|(width, height)| (width * height) as f64 / 1_000_000.0)
+            .unwrap_or(1.0);
+        let bitrate_mbps = self
+            .b_v
+            .as_deref()
+            .and_then(parse_bitrate)
+            .map(|bitrate| bitrate / 1_000_000.0)
+            .unwrap_or(1.0);
+
+        (megapixels * bitrate_mbps).max(0.01)
+    }
 }
 
 fn add_arg(cmd: &mut Command, arg: &str, value: Option<&str>) {
@@ -70,223 +509,4466 @@ fn add_arg(cmd: &mut Command, arg: &str, value: Option<&str>) {
     }
 }
 
-pub fn get_video_format_from_str(video_format: &str) -> Result<VideoFormat, Status> {
-    serde_json::from_str::<VideoFormat>(video_format).map_err(|err| {
-        Status::new(
-            Code::InvalidArgument,
-            format!("Invalid video format: {}", err),
-        )
-    })
-}
-
-/// Gets video duration in seconds using `ffprobe`.
+/// Builds the `-map` arguments that select the embedded audio track and video stream to use. As
+/// explicit mapping disables ffmpeg's automatic stream selection, choosing one of them also maps
+/// the other: the first video stream (if any) when only the audio track is chosen, and the first
+/// audio track (if any) when only the video stream is.
 ///
 /// # Arguments
-/// * `file_path`: Path to the video file.
-///
-/// # Returns:
-/// `Result<f64, String>` - Duration in seconds or error message.
+/// * `audio_stream_index` - The index of the audio track among the source's audio streams.
+/// * `video_stream` - The index of the video stream among the source's video streams.
+/// * `is_video` - Whether the output also carries a video stream.
 ///
-fn get_video_duration(file_path: &str) -> Result<f64, String> {
-    let output = Command::new("ffprobe")
-        .args([
-            "-v",
-            "error",
-            "-show_entries",
-            "format=duration",
-            "-of",
-            "default=noprint_wrappers=1:nokey=1",
-            file_path,
-        ])
-        .output()
-        .expect("failed to execute ffprobe");
+fn audio_stream_map_args(
+    audio_stream_index: Option<u32>,
+    video_stream: Option<u32>,
+    is_video: bool,
+) -> Vec<String> {
+    let mut args = Vec::new();
+    if audio_stream_index.is_none() && (video_stream.is_none() || !is_video) {
+        return args;
+    }
 
-    if output.status.success() {
-        let duration_str = String::from_utf8(output.stdout).unwrap();
-        duration_str
-            .trim()
-            .parse::<f64>()
-            .map_err(|e| e.to_string())
-    } else {
-        Err(String::from("Failed to retrieve video duration"))
+    if is_video {
+        args.push("-map".to_string());
+        args.push(video_stream_specifier(video_stream));
     }
+    args.push("-map".to_string());
+    args.push(match audio_stream_index {
+        Some(index) => format!("0:a:{}", index),
+        None => "0:a:0?".to_string(),
+    });
+
+    args
 }
 
-/// Parses ffmpeg progress output to calculate and return the transcoding progress as a percentage.
-/// This function searches for time stamps in the ffmpeg output and calculates the progress based
-/// on the total duration of the video. If the total duration is not positive, it returns 0 to
-/// prevent division by zero errors.
+/// Returns the stream specifier of the source's video stream to encode, the first one unless
+/// `apply_source_video_stream` chose another.
 ///
 /// # Arguments
-/// * `line` - A string slice containing a line of ffmpeg output.
-/// * `total_duration` - The total duration of the video in seconds.
-///
-/// # Returns
-/// An `Option<i32>` representing the transcoding progress percentage, or `None` if the progress
-/// cannot be determined from the given line.
+/// * `video_stream` - The format's `source_video_stream`.
 ///
-fn parse_progress(line: &str, total_duration: f64) -> Option<i32> {
-    if total_duration <= 0.0 {
-        return Some(0); // Prevent division by zero
+fn video_stream_specifier(video_stream: Option<u32>) -> String {
+    match video_stream {
+        Some(index) => format!("0:v:{}", index),
+        None => "0:v:0?".to_string(),
     }
+}
 
-    let re = Regex::new(r"time=(\d+):(\d+):(\d+\.\d+)").unwrap();
-    if let Some(caps) = re.captures(line) {
-        let hours = caps.get(1).unwrap().as_str().parse::<f64>().unwrap_or(0.0);
-        let minutes = caps.get(2).unwrap().as_str().parse::<f64>().unwrap_or(0.0);
-        let seconds = caps.get(3).unwrap().as_str().parse::<f64>().unwrap_or(0.0);
-        let current_time_seconds = hours * 3600.0 + minutes * 60.0 + seconds;
-        let progress = ((current_time_seconds / total_duration) * 100.0).round() as i32;
-        return Some(progress);
+/// Builds the `-map` arguments selecting the source streams written to the output: the format's
+/// explicit `map` if it has one, otherwise the audio track chosen by `audio_stream_index`.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+/// * `is_video` - Whether the output also carries a video stream.
+///
+fn stream_map_args(format: &VideoFormat, is_video: bool) -> Vec<String> {
+    match &format.map {
+        Some(map) => map
+            .iter()
+            .flat_map(|specifier| ["-map".to_string(), specifier.clone()])
+            .collect(),
+        None => audio_stream_map_args(
+            format.audio_stream_index,
+            format.source_video_stream,
+            is_video,
+        ),
     }
-
-    None
 }
 
-/// Executes the ffmpeg command to transcode a video file based on the specified parameters.
-/// This function supports GPU acceleration and handles various video formats.
+/// Checks that every stream specifier in the format's `map` selects a stream the source has.
+/// Specifiers take the form `0`, `0:N`, `0:T` or `0:T:N`, where `T` is one of `v`, `a`, `s`, `d`
+/// or `t` and `N` an index; a trailing `?` marks the stream as optional, so it is not checked.
 ///
 /// # Arguments
-/// * `task_id` - A unique identifier for the transcoding task.
-/// * `format_index` - The index specifying the target video format from a predefined list.
-/// * `file_path` - The path to the input video file to be transcoded.
-/// * `file_name` - The name of the input video file.
-/// * `is_gpu` - A boolean flag indicating whether to use GPU acceleration for transcoding.
-/// * `format` - The desired output video format.
-/// * `total_duration` - The total duration of the video file in seconds.
-///
-/// # Returns
-/// A `Result<(), Status>` indicating the success or failure of the transcoding operation.
+/// * `source_probe` - The result of probing the source with ffprobe.
+/// * `format` - The desired output format.
 ///
-fn run_ffmpeg(
-    task_id: String,
-    format_index: usize,
-    file_path: &str,
-    file_name: &str,
-    is_gpu: bool,
+fn validate_stream_map(
+    source_probe: &Result<SourceProbe, String>,
     format: &VideoFormat,
-    total_duration: f64,
 ) -> Result<(), Status> {
-    let mut cmd = Command::new("ffmpeg");
-    cmd.arg("-v").arg("info");
-    cmd.arg("-progress").arg("pipe:2");
-    cmd.arg("-stats_period").arg("1");
+    let map = match &format.map {
+        Some(map) => map,
+        None => return Ok(()),
+    };
 
-    if is_gpu {
-        println!("GPU transcoding is being executed with vcodec: {:?}", format.vcodec);
+    let source_probe = source_probe
+        .as_ref()
+        .map_err(|e| Status::new(Code::InvalidArgument, e.clone()))?;
 
-        if let Some(file_path) = Some(file_path) {
-            add_arg(&mut cmd, "-i", Some(file_path));
-        }
-        if let Some(vcodec) = format.vcodec.as_deref() {
-            add_arg(&mut cmd, "-c:v", Some(vcodec));
-        }
-        if let Some(b_v) = format.b_v.as_deref() {
-            add_arg(&mut cmd, "-b:v", Some(b_v));
-        }
-        if let Some(c_a) = format.c_a.as_deref() {
-            add_arg(&mut cmd, "-c:a", Some(c_a));
-        }
-        if let Some(b_a) = format.b_a.as_deref() {
-            add_arg(&mut cmd, "-b:a", Some(b_a));
-        }
-        if let Some(ch) = format.ch {
-            add_arg(&mut cmd, "-ac", Some(&ch.to_string()));
-        }
-        if let Some(ar) = format.ar.as_deref() {
-            add_arg(&mut cmd, "-ar", Some(ar));
-        }
-        if let Some(vf) = format.vf.as_deref() {
-            add_arg(&mut cmd, "-vf", Some(vf));
-        }
-        if let Some(ref minrate) = format.minrate {
-            cmd.args(["-minrate", minrate]);
-        }
-        if let Some(ref maxrate) = format.maxrate {
-            cmd.args(["-maxrate", maxrate]);
+    for specifier in map {
+        let invalid = |reason: &str| {
+            Status::new(
+                Code::InvalidArgument,
+                format!(
+                    "Invalid map {} for format {}: {}",
+                    specifier, format.id, reason
+                ),
+            )
+        };
+
+        let (specifier_body, is_optional) = match specifier.strip_suffix('?') {
+            Some(body) => (body, true),
+            None => (specifier.as_str(), false),
+        };
+        let mut parts = specifier_body.split(':');
+        if parts.next() != Some("0") {
+            return Err(invalid("only input 0 exists"));
         }
-        if let Some(ref bufsize) = format.bufsize {
-            cmd.args(["-bufsize", bufsize]);
+
+        let (codec_type, index) = match (parts.next(), parts.next(), parts.next()) {
+            (None, _, _) => (None, None),
+            (Some(part), None, _) if part.parse::<usize>().is_ok() => {
+                (None, part.parse::<usize>().ok())
+            }
+            (Some(part), index, None) => {
+                let codec_type = match part {
+                    "v" | "V" => "video",
+                    "a" => "audio",
+                    "s" => "subtitle",
+                    "d" => "data",
+                    "t" => "attachment",
+                    _ => return Err(invalid("unknown stream type")),
+                };
+                let index = match index {
+                    Some(index) => Some(
+                        index
+                            .parse::<usize>()
+                            .map_err(|_| invalid("stream index is not a number"))?,
+                    ),
+                    None => None,
+                };
+                (Some(codec_type), index)
+            }
+            _ => return Err(invalid("expected 0, 0:N, 0:T or 0:T:N")),
+        };
+
+        if is_optional {
+            continue;
         }
-        cmd.args([
-            "-y",
-            format!(
-                "{}{}_ue.{}",
-                *PATH_TO_TRANSCODED_FILE, file_name, format.ext
-            )
-            .as_str(),
-        ]);
-    
-        // Convert to Vec<String> instead of Vec<Cow<'_, str>>
-        let args: Vec<String> = cmd.get_args()
-            .map(|arg| arg.to_string_lossy().into_owned())
-            .collect();
-        println!("ffmpeg {}", args.join(" "));
-        
-        // Now String implements AsRef<OsStr>
-        for arg in args {
-            cmd.arg(arg);
+
+        let available = match codec_type {
+            Some(codec_type) => source_probe.streams_of_type(codec_type).len(),
+            None => source_probe.streams.len(),
+        };
+        let exists = match index {
+            Some(index) => index < available,
+            None => available > 0,
+        };
+        if !exists {
+            return Err(invalid(&format!(
+                "source has {} matching stream(s)",
+                available
+            )));
         }
+    }
+
+    Ok(())
+}
+
+/// Checks that `DEFAULT_DEST` is one of `DESTS`, so a misconfigured server fails at startup
+/// instead of failing every format without a `dest`.
+pub fn check_default_dest() -> Result<(), String> {
+    if DESTS.contains(&DEFAULT_DEST.as_str()) {
+        Ok(())
     } else {
-        if let Some(vcodec) = &format.vcodec {
-            if !vcodec.is_empty() {
+        Err(format!(
+            "DEFAULT_DEST {} is not a supported storage network; use one of {}",
+            *DEFAULT_DEST,
+            DESTS.join(", ")
+        ))
+    }
+}
 
-                println!("CPU transcoding is being executed with vcodec: {:?}", format.vcodec);
+/// Sets the `dest` of a format that has none to `DEFAULT_DEST`, so every upload and every
+/// returned CID prefix of the format agree on where its outputs went.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+///
+fn apply_default_dest(format: &mut VideoFormat) -> Result<(), Status> {
+    if format.dest.is_none() {
+        check_default_dest().map_err(|e| Status::new(Code::FailedPrecondition, e))?;
+        format.dest = Some(DEFAULT_DEST.clone());
+    }
+    Ok(())
+}
 
-                add_arg(&mut cmd, "-cpu-used", Some("4"));
+/// Applies VBV defaults to renditions packaged for adaptive streaming ("hls", "ll-hls" or "dash").
+/// When the
+/// format has a target video bitrate but no `maxrate` or `bufsize`, `maxrate` defaults to the target
+/// bitrate and `bufsize` to twice the target, so segments stay within the advertised bandwidth.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+///
+fn apply_streaming_vbv_defaults(format: &mut VideoFormat) {
+    let is_streaming = format.packaging.as_deref().is_some_and(|packaging| {
+        ["hls", "ll-hls", "dash"]
+            .iter()
+            .any(|streaming| packaging.eq_ignore_ascii_case(streaming))
+    });
+    if !is_streaming {
+        return;
+    }
 
-                if let Some(file_path) = Some(file_path) {
-                    add_arg(&mut cmd, "-i", Some(file_path));
-                }
-                if let Some(vcodec) = format.vcodec.as_deref() {
-                    add_arg(&mut cmd, "-c:v", Some(vcodec));
-                }
-                if let Some(b_v) = format.b_v.as_deref() {
-                    add_arg(&mut cmd, "-b:v", Some(b_v));
-                }
-                if let Some(c_a) = format.c_a.as_deref() {
-                    add_arg(&mut cmd, "-c:a", Some(c_a));
-                }
-                if let Some(b_a) = format.b_a.as_deref() {
-                    add_arg(&mut cmd, "-b:a", Some(b_a));
-                }
-                if let Some(ch) = format.ch {
-                    add_arg(&mut cmd, "-ac", Some(&ch.to_string()));
-                }
-                if let Some(ar) = format.ar.as_deref() {
-                    add_arg(&mut cmd, "-ar", Some(ar));
-                }
-                if let Some(vf) = format.vf.as_deref() {
-                    add_arg(&mut cmd, "-vf", Some(vf));
-                }
-                if let Some(ref minrate) = format.minrate {
-                    cmd.args(["-minrate", minrate]);
-                }
-                if let Some(ref maxrate) = format.maxrate {
-                    cmd.args(["-maxrate", maxrate]);
-                }
+    let target_bitrate = match format.b_v.as_deref().and_then(parse_bitrate) {
+        Some(bitrate) => bitrate,
+        None => return,
+    };
+
+    if format.maxrate.is_none() {
+        format.maxrate = Some(format!("{}k", (target_bitrate / 1000.0).round() as u64));
+    }
+    if format.bufsize.is_none() {
+        format.bufsize = Some(format!(
+            "{}k",
+            (target_bitrate * 2.0 / 1000.0).round() as u64
+        ));
+    }
+}
+
+/// Checks that `quality_mode` is one of `QUALITY_MODES`.
+///
+/// # Arguments
+/// * `quality_mode` - The requested quality mode.
+///
+pub fn validate_quality_mode(quality_mode: &str) -> Result<(), String> {
+    if QUALITY_MODES.contains(&quality_mode) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Invalid quality_mode {}; expected one of {}",
+            quality_mode,
+            QUALITY_MODES.join(", ")
+        ))
+    }
+}
+
+// Video encoders that have no `-preset` option
+const ENCODERS_WITHOUT_PRESET: [&str; 3] = ["libvpx", "libvpx-vp9", "libaom-av1"];
+
+// Video encoders that encode in two passes with `-pass`
+const TWO_PASS_ENCODERS: [&str; 4] = ["libx264", "libvpx", "libvpx-vp9", "libaom-av1"];
+
+/// Returns the preset and CRF a quality mode implies for a video encoder, as
+/// `(preset, crf)` for "speed", "balanced" and "quality" in turn. Encoders without a matching
+/// option get `None`, e.g. NVENC has no CRF and libvpx has no presets.
+fn quality_mode_defaults(vcodec: &str, quality_mode: &str) -> (Option<&'static str>, Option<u8>) {
+    let (presets, crfs): ([Option<&'static str>; 3], [Option<u8>; 3]) = match vcodec {
+        "libx264" => (
+            [Some("veryfast"), Some("medium"), Some("slow")],
+            [Some(28), Some(23), Some(20)],
+        ),
+        "libx265" => (
+            [Some("veryfast"), Some("medium"), Some("slow")],
+            [Some(30), Some(28), Some(24)],
+        ),
+        "h264_nvenc" | "hevc_nvenc" | "av1_nvenc" => {
+            ([Some("p2"), Some("p4"), Some("p6")], [None, None, None])
+        }
+        "libsvtav1" => (
+            [Some("10"), Some("8"), Some("5")],
+            [Some(40), Some(35), Some(30)],
+        ),
+        "libaom-av1" => ([None, None, None], [Some(38), Some(32), Some(28)]),
+        "libvpx-vp9" => ([None, None, None], [Some(37), Some(33), Some(30)]),
+        _ => return (None, None),
+    };
+
+    match QUALITY_MODES.iter().position(|mode| *mode == quality_mode) {
+        Some(index) => (presets[index], crfs[index]),
+        None => (None, None),
+    }
+}
+
+/// Returns the audio codec of a format: `c_a` for video formats, `acodec` for audio-only ones.
+fn audio_codec(format: &VideoFormat) -> Option<&str> {
+    format
+        .c_a
+        .as_deref()
+        .or(format.acodec.as_deref())
+        .filter(|codec| !codec.is_empty())
+}
+
+/// Escapes a value for an option of a filter in `-vf`. The value is unescaped twice, first when
+/// the filter graph is split into filters and then when the filter's options are split, so
+/// characters special to either step are escaped for both.
+///
+/// # Arguments
+/// * `value` - The option value.
+///
+fn escape_filter_value(value: &str) -> String {
+    let mut option_escaped = String::new();
+    for c in value.chars() {
+        if matches!(c, '\\' | '\'' | ':') {
+            option_escaped.push('\\');
+        }
+        option_escaped.push(c);
+    }
+
+    let mut escaped = String::new();
+    for c in option_escaped.chars() {
+        if matches!(c, '\\' | '\'' | '[' | ']' | ',' | ';') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Builds the `drawtext` filter that draws a format's `text_watermark`. The text is drawn as
+/// given: control characters are dropped, `%` expansion is disabled and every character special
+/// to the filter graph is escaped, so the text cannot add filters or options.
+///
+/// # Arguments
+/// * `watermark` - The text watermark to draw.
+/// * `font_file` - The font file to draw with, or `None` for fontconfig's default font.
+///
+pub fn drawtext_filter(watermark: &TextWatermark, font_file: Option<&str>) -> String {
+    let text: String = watermark.text.chars().filter(|c| !c.is_control()).collect();
+
+    let position = watermark.position.as_deref().unwrap_or("bottom-right");
+    let x = if position.ends_with("left") {
+        WATERMARK_MARGIN.to_string()
+    } else if position.ends_with("right") {
+        format!("w-tw-{}", WATERMARK_MARGIN)
+    } else {
+        "(w-tw)/2".to_string()
+    };
+    let y = if position.starts_with("top") {
+        WATERMARK_MARGIN.to_string()
+    } else if position.starts_with("bottom") {
+        format!("h-th-{}", WATERMARK_MARGIN)
+    } else {
+        "(h-th)/2".to_string()
+    };
+
+    let mut filter = String::from("drawtext=");
+    if let Some(font_file) = font_file {
+        filter.push_str(&format!("fontfile={}:", escape_filter_value(font_file)));
+    }
+    filter.push_str(&format!(
+        "expansion=none:text={}:fontsize={}:fontcolor={}:x={}:y={}",
+        escape_filter_value(&text),
+        watermark.fontsize.unwrap_or(24),
+        escape_filter_value(watermark.color.as_deref().unwrap_or("white")),
+        x,
+        y
+    ));
+    filter
+}
+
+/// Returns the path the server downloads a file a format refers to by CID or URL, such as its
+/// `preset_file`, before transcoding: `PATH_TO_FILE` followed by the CID without network prefix or
+/// extension, as for sources.
+///
+/// # Arguments
+/// * `cid` - The CID or URL of the file.
+///
+pub fn downloaded_file_path(cid: &str) -> Option<String> {
+    source_file_name(cid).map(|name| format!("{}{}", *PATH_TO_FILE, name))
+}
+
+/// Returns the name a source, or a file a format refers to, is downloaded to: its CID without
+/// network prefix or extension.
+///
+/// # Arguments
+/// * `cid` - The CID or URL of the file.
+///
+pub fn source_file_name(cid: &str) -> Option<String> {
+    Path::new(cid)
+        .with_extension("")
+        .file_stem()
+        .and_then(|name| name.to_str())
+        .filter(|name| !name.is_empty())
+        .map(|name| name.to_string())
+}
+
+/// Returns the path of a local preset file named by a format's `preset_file`, which must be the
+/// name of a file in `PRESET_DIR`.
+///
+/// # Arguments
+/// * `preset_file` - The format's `preset_file`.
+///
+pub fn local_preset_path(preset_file: &str) -> Result<String, String> {
+    let preset_dir = PRESET_DIR
+        .as_deref()
+        .ok_or_else(|| format!("preset_file {} needs PRESET_DIR to be set", preset_file))?;
+    let is_file_name = Path::new(preset_file)
+        .file_name()
+        .is_some_and(|name| name == preset_file);
+    if !is_file_name || preset_file.starts_with('.') {
+        return Err(format!(
+            "preset_file {} must be the name of a file in PRESET_DIR",
+            preset_file
+        ));
+    }
+    Ok(Path::new(preset_dir)
+        .join(preset_file)
+        .to_string_lossy()
+        .to_string())
+}
+
+/// Checks that `contents` are an ffmpeg preset file: one `option=value` per line, with blank lines
+/// and lines starting with `#` ignored. Returns the number of options it sets.
+///
+/// # Arguments
+/// * `contents` - The contents of the preset file.
+///
+pub fn validate_preset(contents: &str) -> Result<usize, String> {
+    let mut options = 0;
+    for (line_number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let is_option = line.split_once('=').is_some_and(|(option, value)| {
+            !option.is_empty()
+                && !value.is_empty()
+                && option
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | ':'))
+        });
+        if !is_option {
+            return Err(format!(
+                "line {} is not of the form option=value",
+                line_number + 1
+            ));
+        }
+        options += 1;
+    }
+
+    if options == 0 {
+        return Err("sets no options".to_string());
+    }
+    Ok(options)
+}
+
+/// Validates the preset file named by a format's `preset_file`, so its options are passed to the
+/// format's encoders with ffmpeg's `-fpre`. A local preset file is read now; one given by CID is
+/// only checked to be a valid CID, as the server downloads it with the source, and is read by
+/// `resolve_preset_file` once it has been.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+///
+fn apply_preset_file(format: &mut VideoFormat) -> Result<(), Status> {
+    let preset_file = match format.preset_file.clone() {
+        Some(preset_file) => preset_file,
+        None => return Ok(()),
+    };
+
+    let invalid = |message: String| {
+        Status::new(
+            Code::InvalidArgument,
+            format!("Format {} {}", format.id, message),
+        )
+    };
+    if preset_file.contains("://") {
+        downloaded_file_path(&preset_file)
+            .ok_or_else(|| invalid(format!("preset_file {} is not a valid CID", preset_file)))?;
+        return Ok(());
+    }
+    let preset_path = local_preset_path(&preset_file).map_err(invalid)?;
+    check_preset_file(format, &preset_file, &preset_path)?;
+
+    format.preset_path = Some(preset_path);
+    Ok(())
+}
+
+/// Finds and validates the downloaded preset file of a format whose `preset_file` is a CID.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+///
+fn resolve_preset_file(format: &mut VideoFormat) -> Result<(), Status> {
+    let preset_file = match format.preset_file.clone() {
+        Some(preset_file) if preset_file.contains("://") => preset_file,
+        _ => return Ok(()),
+    };
+
+    let preset_path = downloaded_file_path(&preset_file)
+        .filter(|path| Path::new(path).exists())
+        .ok_or_else(|| {
+            Status::new(
+                Code::FailedPrecondition,
+                format!(
+                    "Format {} preset_file {} has not been downloaded",
+                    format.id, preset_file
+                ),
+            )
+        })?;
+    check_preset_file(format, &preset_file, &preset_path)?;
+
+    format.preset_path = Some(preset_path);
+    Ok(())
+}
+
+/// Checks that the preset file at `preset_path`, named by a format's `preset_file`, is no larger
+/// than `MAX_PRESET_FILE_SIZE` and passes `validate_preset`.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+/// * `preset_file` - The format's `preset_file`.
+/// * `preset_path` - The path of the preset file.
+///
+fn check_preset_file(
+    format: &VideoFormat,
+    preset_file: &str,
+    preset_path: &str,
+) -> Result<(), Status> {
+    let invalid = |message: String| {
+        Status::new(
+            Code::InvalidArgument,
+            format!("Format {} {}", format.id, message),
+        )
+    };
+    let size = metadata(preset_path)
+        .map_err(|e| {
+            invalid(format!(
+                "preset_file {} could not be read: {}",
+                preset_file, e
+            ))
+        })?
+        .len();
+    if size > MAX_PRESET_FILE_SIZE {
+        return Err(invalid(format!(
+            "preset_file {} is {} bytes, more than the {} allowed",
+            preset_file, size, MAX_PRESET_FILE_SIZE
+        )));
+    }
+    let contents = std::fs::read_to_string(preset_path).map_err(|e| {
+        invalid(format!(
+            "preset_file {} could not be read: {}",
+            preset_file, e
+        ))
+    })?;
+    validate_preset(&contents)
+        .map_err(|e| invalid(format!("preset_file {} {}", preset_file, e)))?;
+    Ok(())
+}
+
+/// Builds the `-filter_complex` graph mixing an audio-description track into the main audio. The
+/// narration is padded with silence so a short track ends early without cutting the mix, and the
+/// mix lasts as long as the main audio, so a longer track is cut off. Unless `duck_ratio` is 1, the
+/// main audio is compressed by the narration with `sidechaincompress`, ducking it while the
+/// narration plays. The format's `af` is applied to the mix, which cannot also be given `-af`, and
+/// the mixed audio is labelled `[ad_out]`.
+///
+/// # Arguments
+/// * `description` - The format's `audio_description`.
+/// * `audio_stream_index` - Index of the main audio stream among the source's audio streams.
+/// * `description_input` - Index of the narration among ffmpeg's inputs.
+/// * `af` - The format's `af`.
+///
+pub fn audio_description_filter(
+    description: &AudioDescription,
+    audio_stream_index: u32,
+    description_input: usize,
+    af: Option<&str>,
+) -> String {
+    let level = description.level.unwrap_or(1.0);
+    let main_level = description.main_level.unwrap_or(1.0);
+    let duck_ratio = description.duck_ratio.unwrap_or(DEFAULT_DUCK_RATIO);
+
+    // Gain reduction follows the sidechain, so the main audio can be scaled up before it is ducked
+    let main = format!(
+        "[0:a:{}]volume={}[ad_main]",
+        audio_stream_index,
+        main_level * AMIX_GAIN
+    );
+    let narration = format!("[{}:a:0]volume={},apad", description_input, level);
+    let mix = match af.filter(|af| !af.is_empty()) {
+        Some(af) => format!(
+            "amix=inputs=2:duration=first:dropout_transition=0,{}[ad_out]",
+            af
+        ),
+        None => "amix=inputs=2:duration=first:dropout_transition=0[ad_out]".to_string(),
+    };
+
+    if duck_ratio <= 1.0 {
+        return format!(
+            "{};{},volume={}[ad_narration];[ad_main][ad_narration]{}",
+            main, narration, AMIX_GAIN, mix
+        );
+    }
+    [
+        main,
+        format!("{},asplit=2[ad_sidechain][ad_split]", narration),
+        format!("[ad_split]volume={}[ad_narration]", AMIX_GAIN),
+        format!(
+            "[ad_main][ad_sidechain]sidechaincompress={}:ratio={}[ad_ducked]",
+            DUCK_OPTIONS, duck_ratio
+        ),
+        format!("[ad_ducked][ad_narration]{}", mix),
+    ]
+    .join(";")
+}
+
+/// Validates a format's `audio_description`. Its track is only looked for once the format is
+/// transcoded, by `resolve_audio_description`, as the server downloads it with the source.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+///
+fn apply_audio_description(format: &mut VideoFormat) -> Result<(), Status> {
+    let description = match &format.audio_description {
+        Some(description) => description,
+        None => return Ok(()),
+    };
+
+    let invalid = |message: String| {
+        Status::new(
+            Code::InvalidArgument,
+            format!("Format {} audio_description {}", format.id, message),
+        )
+    };
+    if audio_codec(format).is_none_or(|codec| codec == "none") {
+        return Err(invalid("needs a format with an audio codec".to_string()));
+    }
+    if format.map.is_some() {
+        return Err(invalid("cannot be combined with map".to_string()));
+    }
+    for (name, level) in [
+        ("level", description.level),
+        ("main_level", description.main_level),
+    ] {
+        if let Some(level) = level {
+            if level.is_nan() || level <= 0.0 || level > MAX_AUDIO_LEVEL {
+                return Err(invalid(format!(
+                    "{} must be above 0 and at most {}",
+                    name, MAX_AUDIO_LEVEL
+                )));
+            }
+        }
+    }
+    if let Some(duck_ratio) = description.duck_ratio {
+        if duck_ratio.is_nan() || !(1.0..=MAX_DUCK_RATIO).contains(&duck_ratio) {
+            return Err(invalid(format!(
+                "duck_ratio must be between 1 and {}",
+                MAX_DUCK_RATIO
+            )));
+        }
+    }
+
+    downloaded_file_path(&description.cid)
+        .ok_or_else(|| invalid(format!("cid {} is not a valid CID", description.cid)))?;
+    Ok(())
+}
+
+/// Finds the downloaded track of a format's `audio_description`, so it is mixed into the format's
+/// audio.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+///
+fn resolve_audio_description(format: &mut VideoFormat) -> Result<(), Status> {
+    let description = match &format.audio_description {
+        Some(description) => description,
+        None => return Ok(()),
+    };
+
+    let path = downloaded_file_path(&description.cid).filter(|path| Path::new(path).exists());
+    match path {
+        Some(path) => {
+            format.audio_description_path = Some(path);
+            Ok(())
+        }
+        None => Err(Status::new(
+            Code::FailedPrecondition,
+            format!(
+                "Format {} audio_description track {} has not been downloaded",
+                format.id, description.cid
+            ),
+        )),
+    }
+}
+
+/// Validates a format's `text_watermark` and appends its `drawtext` filter to the format's `vf`,
+/// so the text is drawn on the scaled output frames.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+///
+fn apply_text_watermark(format: &mut VideoFormat) -> Result<(), Status> {
+    let watermark = match &format.text_watermark {
+        Some(watermark) => watermark,
+        None => return Ok(()),
+    };
+
+    let invalid = |message: String| {
+        Status::new(
+            Code::InvalidArgument,
+            format!("Format {} text_watermark {}", format.id, message),
+        )
+    };
+    if format.vcodec.as_deref().is_none_or(str::is_empty) {
+        return Err(invalid("needs a video format".to_string()));
+    }
+    if watermark
+        .text
+        .chars()
+        .all(|c| c.is_control() || c.is_whitespace())
+    {
+        return Err(invalid("has no text".to_string()));
+    }
+    if watermark.fontsize == Some(0) {
+        return Err(invalid("fontsize must be at least 1".to_string()));
+    }
+    if let Some(position) = watermark.position.as_deref() {
+        if !WATERMARK_POSITIONS.contains(&position) {
+            return Err(invalid(format!(
+                "position {} is not one of {}",
+                position,
+                WATERMARK_POSITIONS.join(", ")
+            )));
+        }
+    }
+    if let Some(color) = watermark.color.as_deref() {
+        let is_color = !color.is_empty()
+            && color
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '#' | '@' | '.'));
+        if !is_color {
+            return Err(invalid(format!("color {} is not an ffmpeg color", color)));
+        }
+    }
+    capabilities::check_feature("libfreetype").map_err(invalid)?;
+
+    let drawtext = drawtext_filter(watermark, WATERMARK_FONT_FILE.as_deref());
+    format.vf = Some(match format.vf.take() {
+        Some(vf) if !vf.is_empty() => format!("{},{}", vf, drawtext),
+        _ => drawtext,
+    });
+    Ok(())
+}
+
+/// Validates the format's `audio_mode` ("cbr" or "vbr") and `audio_quality` against its audio
+/// codec. AAC and FDK AAC encode VBR at a quality level rather than a bitrate, so `b_a` is dropped
+/// for them in VBR mode; Opus VBR targets `b_a` and takes no quality level.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+///
+fn apply_audio_mode(format: &mut VideoFormat) -> Result<(), Status> {
+    let audio_mode = match format.audio_mode.as_deref() {
+        Some(audio_mode) => audio_mode.to_string(),
+        None if format.audio_quality.is_some() => "vbr".to_string(),
+        None => return Ok(()),
+    };
+    let codec = audio_codec(format).unwrap_or_default().to_string();
+
+    if audio_mode != "cbr" && audio_mode != "vbr" {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            format!(
+                "Invalid audio_mode {}; expected one of cbr, vbr",
+                audio_mode
+            ),
+        ));
+    }
+    if !["aac", "libfdk_aac", "libopus"].contains(&codec.as_str()) {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            format!(
+                "Format {} sets audio_mode but its audio codec {} is not aac, libfdk_aac or libopus",
+                format.id, codec
+            ),
+        ));
+    }
+
+    if audio_mode == "cbr" {
+        if format.audio_quality.is_some() {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!("Format {} sets audio_quality in cbr mode", format.id),
+            ));
+        }
+        return Ok(());
+    }
+
+    let quality_range = match codec.as_str() {
+        "aac" => Some((0.1, 2.0)),
+        "libfdk_aac" => Some((1.0, 5.0)),
+        _ => None,
+    };
+    match (quality_range, format.audio_quality) {
+        (Some((min, max)), Some(quality)) if quality < min || quality > max => Err(Status::new(
+            Code::InvalidArgument,
+            format!(
+                "Format {} audio_quality {} is out of range {}-{} for {}",
+                format.id, quality, min, max, codec
+            ),
+        )),
+        (Some(_), Some(_)) => {
+            if format.b_a.take().is_some() {
+                println!(
+                    "Format {} uses {} vbr, ignoring b_a in favour of audio_quality",
+                    format.id, codec
+                );
+            }
+            Ok(())
+        }
+        (Some(_), None) => Err(Status::new(
+            Code::InvalidArgument,
+            format!(
+                "Format {} uses {} vbr, which needs an audio_quality",
+                format.id, codec
+            ),
+        )),
+        (None, Some(_)) => Err(Status::new(
+            Code::InvalidArgument,
+            format!(
+                "Format {} uses libopus vbr, which targets b_a and takes no audio_quality",
+                format.id
+            ),
+        )),
+        (None, None) => Ok(()),
+    }
+}
+
+/// Builds the audio rate control arguments for the format's `audio_mode`, validated by
+/// `apply_audio_mode`. AAC takes its VBR level with `-q:a` and FDK AAC with `-vbr`; Opus switches
+/// between VBR and CBR with `-vbr on|off`. CBR AAC needs nothing beyond `-b:a`.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+///
+fn audio_mode_args(format: &VideoFormat) -> Vec<String> {
+    // An `audio_quality` without an `audio_mode` implies vbr
+    let is_vbr = match (format.audio_mode.as_deref(), format.audio_quality) {
+        (Some(audio_mode), _) => audio_mode == "vbr",
+        (None, Some(_)) => true,
+        (None, None) => return Vec::new(),
+    };
+
+    match (audio_codec(format), format.audio_quality) {
+        (Some("aac"), Some(quality)) if is_vbr => vec!["-q:a".to_string(), quality.to_string()],
+        (Some("libfdk_aac"), Some(quality)) if is_vbr => {
+            vec!["-vbr".to_string(), (quality.round() as u32).to_string()]
+        }
+        (Some("libopus"), _) => vec![
+            "-vbr".to_string(),
+            if is_vbr { "on" } else { "off" }.to_string(),
+        ],
+        _ => Vec::new(),
+    }
+}
+
+/// Fills in the preset, CRF and two-pass encoding implied by the format's `quality_mode` ("speed",
+/// "balanced" or "quality"). Values set explicitly on the format take precedence. No CRF is applied
+/// to a format with a target bitrate `b_v`, as that already chooses its rate control; instead
+/// "quality" encodes it in two passes, if its encoder supports them, so the bitrate is spent where
+/// the video needs it.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+///
+fn apply_quality_mode(format: &mut VideoFormat) {
+    let quality_mode = match format.quality_mode.as_deref() {
+        Some(quality_mode) => quality_mode,
+        None => return,
+    };
+    let vcodec = match format.vcodec.as_deref() {
+        Some(vcodec) if !vcodec.is_empty() => vcodec,
+        _ => return,
+    };
+
+    let (preset, crf) = quality_mode_defaults(vcodec, quality_mode);
+    if format.preset.is_none() {
+        format.preset = preset.map(|preset| preset.to_string());
+    }
+    if format.crf.is_none() && format.b_v.is_none() && format.target_size_mb.is_none() {
+        format.crf = crf;
+    }
+    if format.two_pass.is_none()
+        && format.b_v.is_some()
+        && quality_mode == "quality"
+        && TWO_PASS_ENCODERS.contains(&vcodec)
+    {
+        format.two_pass = Some(true);
+    }
+}
+
+// Share of a target size reserved for container overhead
+const TARGET_SIZE_OVERHEAD: f64 = 0.02;
+
+// Audio bitrate assumed when sizing the video of a format without `b_a`
+const DEFAULT_AUDIO_BITRATE: f64 = 128_000.0;
+
+/// Computes the average video bitrate that makes an output of `duration_secs` come out at
+/// `target_size_mb` megabytes (10^6 bytes), after reserving `TARGET_SIZE_OVERHEAD` of the size for
+/// the container and `audio_bitrate` for the audio.
+///
+/// # Arguments
+/// * `target_size_mb` - The target output size in megabytes.
+/// * `duration_secs` - The duration of the output in seconds.
+/// * `audio_bitrate` - The bitrate of the output's audio in bits per second, or 0 without audio.
+///
+/// # Returns
+/// The video bitrate in bits per second, or `None` if the duration is unknown or the target leaves
+/// no room for video.
+///
+pub fn target_video_bitrate(
+    target_size_mb: f64,
+    duration_secs: f64,
+    audio_bitrate: f64,
+) -> Option<f64> {
+    if duration_secs <= 0.0 {
+        return None;
+    }
+
+    let total_bits = target_size_mb * 1_000_000.0 * 8.0 * (1.0 - TARGET_SIZE_OVERHEAD);
+    let video_bitrate = total_bits / duration_secs - audio_bitrate;
+    if video_bitrate > 0.0 {
+        Some(video_bitrate)
+    } else {
+        None
+    }
+}
+
+/// Returns the duration in seconds of a format's output: the source duration, shortened by the
+/// format's `start` and `end` clip times.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+/// * `total_duration` - The duration of the source in seconds.
+///
+fn output_duration(format: &VideoFormat, total_duration: f64) -> f64 {
+    let start = format.start.as_deref().and_then(parse_time).unwrap_or(0.0);
+    let end = format
+        .end
+        .as_deref()
+        .and_then(parse_time)
+        .map_or(total_duration, |end| end.min(total_duration));
+    (end - start).max(0.0)
+}
+
+/// Escapes the characters that are special in an ffmpeg metadata file.
+fn escape_ffmetadata(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Writes a format's `chapters` to an ffmpeg metadata file that the encode reads as a second
+/// input, so the chapters are stored in the output container. Each chapter ends where the next
+/// one starts, and the last at the end of the output. Callers remove the file once ffmpeg has
+/// run.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+/// * `file_name` - The name of the output file without its extension.
+/// * `output_dir` - The directory the output is written to.
+/// * `total_duration` - The duration of the source in seconds, as probed by ffprobe.
+///
+fn write_chapters_file(
+    format: &mut VideoFormat,
+    file_name: &str,
+    output_dir: &str,
+    total_duration: f64,
+) -> Result<(), Status> {
+    let chapters = match &format.chapters {
+        Some(chapters) => chapters,
+        None => return Ok(()),
+    };
+
+    let duration = output_duration(format, total_duration);
+    if duration <= 0.0 {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            format!(
+                "Format {} sets chapters but the duration of its output is unknown",
+                format.id
+            ),
+        ));
+    }
+    if let Some(chapter) = chapters.iter().find(|chapter| chapter.start >= duration) {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            format!(
+                "Format {} chapter {} starts at {}s, after the output ends at {:.3}s",
+                format.id, chapter.title, chapter.start, duration
+            ),
+        ));
+    }
+
+    let mut contents = String::from(";FFMETADATA1\n");
+    for (index, chapter) in chapters.iter().enumerate() {
+        let end = chapters
+            .get(index + 1)
+            .map_or(duration, |next_chapter| next_chapter.start);
+        contents.push_str(&format!(
+            "[CHAPTER]\nTIMEBASE=1/1000\nSTART={}\nEND={}\ntitle={}\n",
+            (chapter.start * 1000.0).round() as u64,
+            (end * 1000.0).round() as u64,
+            escape_ffmetadata(&chapter.title)
+        ));
+    }
+
+    let chapters_file = format!("{}{}_chapters.txt", output_dir, file_name);
+    std::fs::write(&chapters_file, contents).map_err(|e| {
+        Status::new(
+            Code::Internal,
+            format!("Failed to write chapters file {}: {}", chapters_file, e),
+        )
+    })?;
+    format.chapters_file = Some(chapters_file);
+    Ok(())
+}
+
+/// Removes the chapters file written by `write_chapters_file`, if any.
+fn remove_chapters_file(format: &VideoFormat) {
+    if let Some(chapters_file) = &format.chapters_file {
+        let _ = std::fs::remove_file(chapters_file);
+    }
+}
+
+/// Sets the video bitrate of a format with `target_size_mb` to the bitrate that hits the target
+/// for the output's duration, reserving the format's audio bitrate (`DEFAULT_AUDIO_BITRATE` if it
+/// has none).
+///
+/// # Arguments
+/// * `format` - The desired output format.
+/// * `total_duration` - The duration of the source in seconds, as probed by ffprobe.
+///
+fn apply_target_size(format: &mut VideoFormat, total_duration: f64) -> Result<(), Status> {
+    let target_size_mb = match format.target_size_mb {
+        Some(target_size_mb) => target_size_mb,
+        None => return Ok(()),
+    };
+
+    let has_audio = format.c_a.as_deref() != Some("none");
+    let audio_bitrate = if has_audio {
+        format
+            .b_a
+            .as_deref()
+            .and_then(parse_bitrate)
+            .unwrap_or(DEFAULT_AUDIO_BITRATE)
+    } else {
+        0.0
+    };
+    let duration = output_duration(format, total_duration);
+
+    let video_bitrate =
+        target_video_bitrate(target_size_mb, duration, audio_bitrate).ok_or_else(|| {
+            Status::new(
+                Code::InvalidArgument,
+                format!(
+                    "Format {} target_size_mb {} leaves no room for video in a {:.1}s output",
+                    format.id, target_size_mb, duration
+                ),
+            )
+        })?;
+    format.b_v = Some(format!("{}k", (video_bitrate / 1000.0).floor() as u64));
+    println!(
+        "Format {} targets {} MB over {:.1}s, video bitrate {}",
+        format.id,
+        target_size_mb,
+        duration,
+        format.b_v.as_deref().unwrap_or_default()
+    );
+
+    Ok(())
+}
+
+/// Returns whether a format is encoded in two passes: CPU formats with `two_pass`, set explicitly
+/// or by `quality_mode`, and by default those with a `target_size_mb`, so the average bitrate
+/// lands close to the target. GPU encoders encode in a single pass.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+/// * `is_gpu` - Whether the format is transcoded on the GPU.
+///
+fn is_two_pass(format: &VideoFormat, is_gpu: bool) -> bool {
+    format.two_pass.unwrap_or(format.target_size_mb.is_some()) && !is_gpu
+}
+
+// Files ffmpeg writes after the `-passlogfile` prefix: the rate-control log, and x264's macroblock
+// tree and the temporary copies made while writing them
+const PASS_LOG_SUFFIXES: [&str; 4] =
+    ["-0.log", "-0.log.temp", "-0.log.mbtree", "-0.log.mbtree.temp"];
+
+/// Returns the prefix of the rate-control log files a two-pass encode writes, which ffmpeg
+/// extends with e.g. "-0.log".
+fn pass_log_prefix(output_dir: &str, file_name: &str) -> String {
+    format!("{}{}_2pass", output_dir, file_name)
+}
+
+/// Removes the rate-control log files of a two-pass encode.
+fn remove_pass_logs(output_dir: &str, file_name: &str) {
+    let prefix = pass_log_prefix(output_dir, file_name);
+    let prefix_name = match Path::new(&prefix).file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => return,
+    };
+    if let Ok(entries) = std::fs::read_dir(output_dir) {
+        for entry in entries.flatten() {
+            if entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(&prefix_name)
+            {
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
+    }
+}
+
+// Filters that change the frame size in ways `output_size` does not follow
+const SIZE_CHANGING_FILTERS: [&str; 11] = [
+    "scale2ref",
+    "zoompan",
+    "tile",
+    "hqx",
+    "xbr",
+    "super2xsai",
+    "separatefields",
+    "weave",
+    "doubleweave",
+    "tinterlace",
+    "rotate",
+];
+
+/// Splits a filter chain or filter's options at `separator`, skipping separators inside quotes,
+/// parentheses or escaped with a backslash, as ffmpeg does for e.g. `scale='min(1280,iw)':-2`.
+fn split_filter_syntax(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut start, mut depth, mut quoted, mut escaped) = (0, 0, false, false);
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '\'' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth -= 1,
+            _ if c == separator && !quoted && depth == 0 => {
+                parts.push(&text[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+/// Evaluates the width or height expression of a `scale`, `crop` or `pad` filter, such as "1280",
+/// "iw/2" or "min(3840,iw)", for an input of `input_size`. Supports numbers, `iw`/`in_w`,
+/// `ih`/`in_h`, `a` (iw/ih), arithmetic, parentheses and `min`, `max`, `trunc`, `floor`, `ceil`,
+/// `round` and `abs`.
+///
+/// # Returns
+/// The value, or `None` if the expression uses anything else or refers to an unknown input size.
+///
+fn eval_size_expr(expr: &str, input_size: Option<(f64, f64)>) -> Option<f64> {
+    struct Parser<'a> {
+        tokens: &'a [u8],
+        pos: usize,
+        input_size: Option<(f64, f64)>,
+    }
+
+    impl Parser<'_> {
+        fn peek(&self) -> Option<u8> {
+            self.tokens.get(self.pos).copied()
+        }
+
+        fn close(&mut self) -> Option<()> {
+            if self.peek()? != b')' {
+                return None;
+            }
+            self.pos += 1;
+            Some(())
+        }
+
+        fn expr(&mut self) -> Option<f64> {
+            let mut value = self.term()?;
+            while let Some(op @ (b'+' | b'-')) = self.peek() {
+                self.pos += 1;
+                let rhs = self.term()?;
+                value = if op == b'+' { value + rhs } else { value - rhs };
+            }
+            Some(value)
+        }
+
+        fn term(&mut self) -> Option<f64> {
+            let mut value = self.factor()?;
+            while let Some(op @ (b'*' | b'/')) = self.peek() {
+                self.pos += 1;
+                let rhs = self.factor()?;
+                value = if op == b'*' { value * rhs } else { value / rhs };
+            }
+            Some(value)
+        }
+
+        fn factor(&mut self) -> Option<f64> {
+            let start = self.pos;
+            match self.peek()? {
+                b'-' => {
+                    self.pos += 1;
+                    Some(-self.factor()?)
+                }
+                b'+' => {
+                    self.pos += 1;
+                    self.factor()
+                }
+                b'(' => {
+                    self.pos += 1;
+                    let value = self.expr()?;
+                    self.close()?;
+                    Some(value)
+                }
+                c if c.is_ascii_digit() || c == b'.' => {
+                    while self
+                        .peek()
+                        .is_some_and(|c| c.is_ascii_digit() || c == b'.')
+                    {
+                        self.pos += 1;
+                    }
+                    std::str::from_utf8(&self.tokens[start..self.pos])
+                        .ok()?
+                        .parse()
+                        .ok()
+                }
+                c if c.is_ascii_alphabetic() => {
+                    while self
+                        .peek()
+                        .is_some_and(|c| c.is_ascii_alphanumeric() || c == b'_')
+                    {
+                        self.pos += 1;
+                    }
+                    let name = std::str::from_utf8(&self.tokens[start..self.pos]).ok()?;
+                    if self.peek() == Some(b'(') {
+                        self.pos += 1;
+                        let mut args = vec![self.expr()?];
+                        while self.peek()? == b',' {
+                            self.pos += 1;
+                            args.push(self.expr()?);
+                        }
+                        self.close()?;
+                        return match (name, args.as_slice()) {
+                            ("min", [a, b]) => Some(a.min(*b)),
+                            ("max", [a, b]) => Some(a.max(*b)),
+                            ("trunc", [a]) => Some(a.trunc()),
+                            ("floor", [a]) => Some(a.floor()),
+                            ("ceil", [a]) => Some(a.ceil()),
+                            ("round", [a]) => Some(a.round()),
+                            ("abs", [a]) => Some(a.abs()),
+                            _ => None,
+                        };
+                    }
+                    let (width, height) = self.input_size?;
+                    match name {
+                        "iw" | "in_w" => Some(width),
+                        "ih" | "in_h" => Some(height),
+                        "a" => Some(width / height),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            }
+        }
+    }
+
+    let tokens: Vec<u8> = expr
+        .bytes()
+        .filter(|c| !matches!(c, b'\'' | b'\\' | b' '))
+        .collect();
+    let mut parser = Parser {
+        tokens: &tokens,
+        pos: 0,
+        input_size,
+    };
+    let value = parser.expr()?;
+    (parser.pos == tokens.len() && value.is_finite()).then_some(value)
+}
+
+/// Works out the frame size a video filter chain outputs, following its `scale`, `crop`, `pad` and
+/// `transpose` filters. A `scale` width or height of -1 keeps the aspect ratio, and -N also rounds
+/// to a multiple of N, as ffmpeg does.
+///
+/// # Arguments
+/// * `vf` - The format's `vf`.
+/// * `source_size` - The width and height of the source's video stream, if known.
+///
+/// # Returns
+/// The width and height, or `None` if they cannot be worked out, such as when they depend on the
+/// source and its size is unknown.
+///
+fn output_size(vf: &str, source_size: Option<(u32, u32)>) -> Option<(u32, u32)> {
+    let mut size = source_size.map(|(width, height)| (width as f64, height as f64));
+    for filter in split_filter_syntax(vf, ',') {
+        let (name, options) = filter.split_once('=').unwrap_or((filter, ""));
+        let name = name.trim().split('@').next().unwrap_or("");
+        let options: Vec<&str> = match options.trim() {
+            "" => Vec::new(),
+            options => split_filter_syntax(options, ':'),
+        };
+
+        // The width and height options of the filter: their names, and their positions when given
+        // without a name
+        let (width_names, height_names): (&[&str], &[&str]) = match name {
+            "crop" => (&["w", "out_w"], &["h", "out_h"]),
+            "pad" => (&["w", "width"], &["h", "height"]),
+            _ if name == "zscale" || name.starts_with("scale") => {
+                (&["w", "width"], &["h", "height"])
+            }
+            _ if name.starts_with("transpose") => {
+                size = size.map(|(width, height)| (height, width));
+                continue;
+            }
+            _ if SIZE_CHANGING_FILTERS.contains(&name) => return None,
+            _ => continue,
+        };
+
+        let (mut width_expr, mut height_expr) = (None, None);
+        for (position, option) in options.iter().enumerate() {
+            match option.split_once('=') {
+                Some((key, value)) if width_names.contains(&key) => width_expr = Some(value),
+                Some((key, value)) if height_names.contains(&key) => height_expr = Some(value),
+                Some(("s" | "size", value)) if name != "crop" && name != "pad" => {
+                    let (width, height) = value.split_once('x')?;
+                    (width_expr, height_expr) = (Some(width), Some(height));
+                }
+                Some(("force_original_aspect_ratio", value)) if value != "disable" => return None,
+                Some(_) => {}
+                None if position == 0 && option.contains('x') && !option.contains('(') => {
+                    let (width, height) = option.split_once('x')?;
+                    (width_expr, height_expr) = (Some(width), Some(height));
+                }
+                None if position == 0 => width_expr = Some(option),
+                None if position == 1 => height_expr = Some(option),
+                None => {}
+            }
+        }
+
+        let eval = |expr: Option<&str>, default: Option<f64>| match expr {
+            Some(expr) => eval_size_expr(expr, size),
+            None => default,
+        };
+        let mut width = eval(width_expr, size.map(|(width, _)| width))?;
+        let mut height = eval(height_expr, size.map(|(_, height)| height))?;
+        if name != "crop" {
+            // 0 keeps the input's size, and a negative size keeps its aspect ratio
+            let (input_width, input_height) = match size {
+                Some(size) => size,
+                None if width > 0.0 && height > 0.0 => (width, height),
+                None => return None,
+            };
+            if width == 0.0 {
+                width = input_width;
+            }
+            if height == 0.0 {
+                height = input_height;
+            }
+            if width < 0.0 && height < 0.0 {
+                (width, height) = (input_width, input_height);
+            } else if width < 0.0 {
+                let multiple = -width.trunc();
+                width = (height * input_width / input_height / multiple).round() * multiple;
+            } else if height < 0.0 {
+                let multiple = -height.trunc();
+                height = (width * input_height / input_width / multiple).round() * multiple;
+            }
+        }
+        size = Some((width.trunc(), height.trunc()));
+    }
+
+    size.filter(|(width, height)| *width >= 1.0 && *height >= 1.0)
+        .map(|(width, height)| (width as u32, height as u32))
+}
+
+/// Builds a `scale` filter that shrinks frames above `max_pixels` to fit within it, with their
+/// aspect ratio preserved and dimensions rounded down to even numbers, and passes smaller frames
+/// through as they are.
+fn max_pixels_filter(max_pixels: u64) -> String {
+    let fit = |dimension: &str| {
+        format!(
+            "'if(gt(iw*ih,{max}),max(2,trunc({dimension}*sqrt({max}/(iw*ih))/2)*2),{dimension})'",
+            max = max_pixels,
+            dimension = dimension
+        )
+    };
+    format!("scale=w={}:h={}", fit("iw"), fit("ih"))
+}
+
+/// Caps a video format's output at `MAX_OUTPUT_PIXELS` by adding `max_pixels_filter` to the end of
+/// its `vf`, so the cap holds whatever the `vf` scales to, e.g. `scale=7680:-2` or
+/// `scale=iw*4:ih*4`. With `MAX_OUTPUT_PIXELS_ACTION` "reject", or a `vf` that leaves frames in GPU
+/// memory where the filter cannot read them, the output size is instead checked by
+/// `check_output_pixels`, and here only when it does not depend on the source.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+/// * `max_pixels` - The cap, 0 for none.
+/// * `reject` - Whether oversized formats are rejected rather than clamped.
+///
+fn cap_output_pixels(
+    format: &mut VideoFormat,
+    max_pixels: u64,
+    reject: bool,
+) -> Result<(), Status> {
+    let is_video = format
+        .vcodec
+        .as_deref()
+        .is_some_and(|vcodec| !vcodec.is_empty());
+    if max_pixels == 0 || !is_video {
+        return Ok(());
+    }
+
+    if reject || format.vf.as_deref().is_some_and(leaves_gpu_frames) {
+        let size = format.vf.as_deref().and_then(|vf| output_size(vf, None));
+        return check_size(format, size, max_pixels);
+    }
+
+    let cap = max_pixels_filter(max_pixels);
+    format.vf = Some(match format.vf.take() {
+        Some(vf) if !vf.is_empty() => format!("{},{}", vf, cap),
+        _ => cap,
+    });
+    format.output_pixels_capped = true;
+    Ok(())
+}
+
+/// Rejects a format whose output size, if known, exceeds `max_pixels`.
+fn check_size(
+    format: &VideoFormat,
+    size: Option<(u32, u32)>,
+    max_pixels: u64,
+) -> Result<(), Status> {
+    match size {
+        Some((width, height)) if width as u64 * height as u64 > max_pixels => Err(Status::new(
+            Code::InvalidArgument,
+            format!(
+                "Format {} output {}x{} exceeds MAX_OUTPUT_PIXELS {}",
+                format.id, width, height, max_pixels
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Checks the output size of a video format that `cap_output_pixels` did not cap against
+/// `MAX_OUTPUT_PIXELS`, now that the source's size is known. An output whose size still cannot be
+/// worked out is capped with `max_pixels_filter` instead, unless its frames are left in GPU memory.
+///
+/// # Arguments
+/// * `source_probe` - The result of probing the source with ffprobe.
+/// * `format` - The desired output format, after `apply_source_rotation`.
+/// * `max_pixels` - The cap, 0 for none.
+///
+fn check_output_pixels(
+    source_probe: &Result<SourceProbe, String>,
+    format: &mut VideoFormat,
+    max_pixels: u64,
+) -> Result<(), Status> {
+    let is_video = format
+        .vcodec
+        .as_deref()
+        .is_some_and(|vcodec| !vcodec.is_empty());
+    if max_pixels == 0 || !is_video || format.output_pixels_capped {
+        return Ok(());
+    }
+
+    let source_size = source_probe.as_ref().ok().and_then(|source_probe| {
+        let stream = source_video_stream(source_probe, format)?;
+        Some((stream.width?, stream.height?))
+    });
+    let vf = format.vf.as_deref().unwrap_or("");
+    let size = output_size(vf, source_size);
+    if size.is_some() {
+        return check_size(format, size, max_pixels);
+    }
+
+    if leaves_gpu_frames(vf) {
+        eprintln!(
+            "Format {}: cannot work out the output size, so MAX_OUTPUT_PIXELS is not enforced",
+            format.id
+        );
+        return Ok(());
+    }
+    println!(
+        "Format {}: cannot work out the output size; capping it at MAX_OUTPUT_PIXELS {}",
+        format.id, max_pixels
+    );
+    let cap = max_pixels_filter(max_pixels);
+    format.vf = Some(match format.vf.take() {
+        Some(vf) if !vf.is_empty() => format!("{},{}", vf, cap),
+        _ => cap,
+    });
+    format.output_pixels_capped = true;
+    Ok(())
+}
+
+/// Builds the `-movflags` for MP4 outputs. `fragmented` writes a fragmented MP4 with an empty moov
+/// up front, as needed for DASH/CMAF and for uploading while encoding. `faststart` moves the moov
+/// atom of a regular MP4 to the front for progressive playback; a fragmented MP4 already starts
+/// with its moov, so `faststart` is ignored when both are requested.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+///
+fn movflags_args(format: &VideoFormat) -> Vec<String> {
+    if !MP4_EXTENSIONS.contains(&format.ext.as_str()) {
+        return Vec::new();
+    }
+
+    let fragmented = format.fragmented.unwrap_or(false);
+    let faststart = format.faststart.unwrap_or(false);
+
+    if fragmented {
+        if faststart {
+            println!(
+                "Format {} is fragmented, ignoring faststart as the moov is already at the front",
+                format.id
+            );
+        }
+        vec![
+            "-movflags".to_string(),
+            "frag_keyframe+empty_moov+default_base_moof".to_string(),
+        ]
+    } else if faststart {
+        vec!["-movflags".to_string(), "+faststart".to_string()]
+    } else {
+        Vec::new()
+    }
+}
+
+/// Builds the keyframe interval arguments for video outputs. `max_keyint` is the GOP length in
+/// frames (`-g`), `min_keyint` the shortest interval at which a scene cut may insert a keyframe
+/// (`-keyint_min`). Either may be set on its own; unset values are left to the encoder. With
+/// "ll-hls" packaging a keyframe is also forced at the start of every partial segment, so each
+/// part can be played on its own and the HLS packager can cut the output at every part.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+///
+fn keyint_args(format: &VideoFormat) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(max_keyint) = format.max_keyint {
+        args.push("-g".to_string());
+        args.push(max_keyint.to_string());
+    }
+    if let Some(min_keyint) = format.min_keyint {
+        args.push("-keyint_min".to_string());
+        args.push(min_keyint.to_string());
+    }
+    if is_ll_hls(format) {
+        let (_, part_secs) = ll_hls_durations(format);
+        args.push("-force_key_frames".to_string());
+        args.push(format!("expr:gte(t,n_forced*{})", part_secs));
+    }
+    args
+}
+
+/// Builds the color range and scaler arguments for video outputs. `color_range` is "tv" (limited)
+/// or "pc" (full) and defaults to limited range, which is what most players expect. `scale_flags`
+/// selects the scaling algorithm used by the `scale` filter and defaults to bicubic.
+/// `-color_range` only tags the output; `apply_color_range` converts the pixels to the range.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+///
+fn color_args(format: &VideoFormat) -> Vec<String> {
+    vec![
+        "-sws_flags".to_string(),
+        format
+            .scale_flags
+            .as_deref()
+            .unwrap_or(DEFAULT_SCALE_FLAGS)
+            .to_string(),
+        "-color_range".to_string(),
+        format
+            .color_range
+            .as_deref()
+            .unwrap_or(DEFAULT_COLOR_RANGE)
+            .to_string(),
+    ]
+}
+
+/// Converts a video format's output to its `color_range`, limited by default, by ending its `vf`
+/// with a `scale` filter that sets `out_range`. The input range is read from the source, so a
+/// full-range source is compressed into limited range instead of only being tagged as limited,
+/// which would show it washed out. A `vf` that already sets `out_range`, or that leaves frames in
+/// GPU memory where the `scale` filter cannot read them, is left as it is.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+///
+fn apply_color_range(format: &mut VideoFormat) {
+    let is_video = format
+        .vcodec
+        .as_deref()
+        .is_some_and(|vcodec| !vcodec.is_empty());
+    if !is_video
+        || format
+            .vf
+            .as_deref()
+            .is_some_and(|vf| vf.contains("out_range=") || leaves_gpu_frames(vf))
+    {
+        return;
+    }
+
+    let color_range = format
+        .color_range
+        .as_deref()
+        .unwrap_or(DEFAULT_COLOR_RANGE);
+    let range_filter = format!("scale=out_range={}", color_range);
+    format.vf = Some(match format.vf.take() {
+        Some(vf) if !vf.is_empty() => format!("{},{}", vf, range_filter),
+        _ => range_filter,
+    });
+}
+
+/// Returns whether a filter chain ends with its frames in GPU memory, i.e. it uploads them or runs a
+/// hardware filter such as `scale_cuda` and does not `hwdownload` them afterwards.
+fn leaves_gpu_frames(vf: &str) -> bool {
+    let hardware_suffixes = ["_cuda", "_npp", "_vaapi", "_qsv", "_opencl", "_vulkan"];
+    vf.split(',').fold(false, |on_gpu, filter| {
+        let name = filter.split('=').next().unwrap_or("").trim();
+        match name {
+            "hwdownload" => false,
+            "hwupload" => true,
+            _ => on_gpu || hardware_suffixes.iter().any(|suffix| name.ends_with(suffix)),
+        }
+    })
+}
+
+/// Builds the `-ss`/`-to` arguments that extract a clip from the source. By default these are placed
+/// before the input for fast seeking, which snaps to keyframes. With `frame_accurate` they are
+/// placed after the input as output options, which decodes up to the exact cut points at the cost
+/// of speed.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+/// * `before_input` - Whether the arguments are being added before `-i` (input options).
+///
+fn clip_args(format: &VideoFormat, before_input: bool) -> Vec<String> {
+    let frame_accurate = format.frame_accurate.unwrap_or(false);
+    if frame_accurate == before_input {
+        return Vec::new();
+    }
+
+    let mut args = Vec::new();
+    if let Some(start) = format.start.as_deref() {
+        args.push("-ss".to_string());
+        args.push(start.to_string());
+    }
+    if let Some(end) = format.end.as_deref() {
+        args.push("-to".to_string());
+        args.push(end.to_string());
+    }
+
+    args
+}
+
+/// Builds the output options normalizing the output's timestamps for a format with
+/// `avoid_negative_ts`: the timestamps are shifted so the first packet starts at zero, for sources
+/// whose streams start at an offset or with negative timestamps, e.g. from edit lists or
+/// B-frame delay. Must be given for every output of the command, as ffmpeg applies it per output.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+///
+fn timestamp_args(format: &VideoFormat) -> Vec<String> {
+    if !format.avoid_negative_ts.unwrap_or(false) {
+        return Vec::new();
+    }
+
+    vec!["-avoid_negative_ts".to_string(), "make_zero".to_string()]
+}
+
+/// Adds the input file along with any per-format input options.
+///
+/// # Arguments
+/// * `cmd` - The ffmpeg command being built.
+/// * `file_path` - The path to the input video file.
+/// * `format` - The desired output format.
+///
+fn add_input(cmd: &mut Command, file_path: &str, format: &VideoFormat) {
+    cmd.args(clip_args(format, true));
+    // Rotation is handled explicitly by `apply_source_rotation`
+    if format.source_rotation != 0 {
+        cmd.arg("-noautorotate");
+        // The frames are left as they are and the display matrix passed on to the output, which
+        // `-display_rotation` gives as the counter-clockwise angle
+        if !format.auto_rotate.unwrap_or(true) {
+            add_arg(
+                cmd,
+                &format!("-display_rotation:v:{}", format.source_video_stream.unwrap_or(0)),
+                Some(&((360 - format.source_rotation) % 360).to_string()),
+            );
+        }
+    }
+    add_arg(cmd, "-i", Some(file_path));
+    for input in extra_inputs(format) {
+        match input {
+            // The chapters input has no streams, so it doesn't change which streams are selected
+            ExtraInput::Chapters(chapters_file) => {
+                cmd.args(["-f", "ffmetadata", "-i", chapters_file]);
+            }
+            ExtraInput::AudioDescription(path) => {
+                cmd.args(["-i", path]);
+            }
+        }
+    }
+}
+
+// An input added by `add_input` after the source, which is always input 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExtraInput<'a> {
+    Chapters(&'a str),
+    AudioDescription(&'a str),
+}
+
+/// Returns the inputs a format's command reads after the source, in the order `add_input` adds
+/// them.
+fn extra_inputs(format: &VideoFormat) -> Vec<ExtraInput<'_>> {
+    format
+        .chapters_file
+        .as_deref()
+        .map(ExtraInput::Chapters)
+        .into_iter()
+        .chain(
+            format
+                .audio_description_path
+                .as_deref()
+                .map(ExtraInput::AudioDescription),
+        )
+        .collect()
+}
+
+/// Returns the ffmpeg input index of the first of a format's extra inputs that `is_input` matches.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+/// * `is_input` - Whether an input is the one looked for.
+///
+fn extra_input_index(format: &VideoFormat, is_input: fn(&ExtraInput) -> bool) -> Option<usize> {
+    extra_inputs(format)
+        .iter()
+        .position(is_input)
+        .map(|position| position + 1)
+}
+
+/// Adds the per-format output options shared by the GPU, CPU video and audio-only ffmpeg commands.
+/// Must be called after the input has been added and before the output path.
+///
+/// # Arguments
+/// * `cmd` - The ffmpeg command being built.
+/// * `format` - The desired output format.
+/// * `is_video` - Whether the output carries a video stream.
+///
+fn add_format_options(cmd: &mut Command, format: &VideoFormat, is_video: bool) {
+    match (&format.audio_description, &format.audio_description_path) {
+        (Some(description), Some(_)) => {
+            let description_input = extra_input_index(format, |input| {
+                matches!(input, ExtraInput::AudioDescription(_))
+            })
+            .unwrap_or(1);
+            let filter = audio_description_filter(
+                description,
+                format.audio_stream_index.unwrap_or(0),
+                description_input,
+                format.af.as_deref(),
+            );
+            add_arg(cmd, "-filter_complex", Some(&filter));
+            if is_video {
+                add_arg(
+                    cmd,
+                    "-map",
+                    Some(&video_stream_specifier(format.source_video_stream)),
+                );
+            }
+            cmd.args(["-map", "[ad_out]"]);
+        }
+        _ => {
+            cmd.args(stream_map_args(format, is_video));
+            if audio_codec(format).is_some_and(|codec| codec != "none") {
+                add_arg(cmd, "-af", format.af.as_deref());
+            }
+        }
+    };
+    if let Some(chapters_input) =
+        extra_input_index(format, |input| matches!(input, ExtraInput::Chapters(_)))
+    {
+        add_arg(cmd, "-map_chapters", Some(&chapters_input.to_string()));
+    }
+    cmd.args(clip_args(format, false));
+    cmd.args(timestamp_args(format));
+    cmd.args(audio_mode_args(format));
+    // Each option applies to whichever of the format's encoders has it
+    if let Some(preset_path) = format.preset_path.as_deref() {
+        add_arg(cmd, "-fpre", Some(preset_path));
+    }
+
+    // A format's `threads` overrides `FFMPEG_THREADS`; 0 leaves the choice to ffmpeg
+    let threads = format.threads.unwrap_or(*FFMPEG_THREADS);
+    if threads > 0 {
+        add_arg(cmd, "-threads", Some(&threads.to_string()));
+    }
+    if is_video {
+        if !format
+            .vcodec
+            .as_deref()
+            .is_some_and(|vcodec| ENCODERS_WITHOUT_PRESET.contains(&vcodec))
+        {
+            add_arg(cmd, "-preset", format.preset.as_deref());
+        }
+        if let Some(crf) = format.crf {
+            add_arg(cmd, "-crf", Some(&crf.to_string()));
+        }
+        if let Some(output_fps) = format.output_fps {
+            add_arg(cmd, "-r", Some(&output_fps.to_string()));
+        }
+        cmd.args(color_args(format));
+        cmd.args(keyint_args(format));
+    }
+    cmd.args(movflags_args(format));
+}
+
+/// Returns the frame rate to encode a format at, or `None` to keep the source's frame rate as it
+/// is. `fps` of "source" asks for the source's rate. `max_fps` caps the requested rate, or the
+/// source's if none is requested, and never raises the rate above the source's, so frames are only
+/// ever dropped and never duplicated to reach it.
+///
+/// # Arguments
+/// * `fps` - The format's `fps`.
+/// * `max_fps` - The format's `max_fps`.
+/// * `source_fps` - The frame rate of the source's video stream, if known.
+///
+pub fn resolve_output_fps(
+    fps: Option<&FrameRate>,
+    max_fps: Option<f64>,
+    source_fps: Option<f64>,
+) -> Option<f64> {
+    let requested = match fps {
+        Some(FrameRate::Rate(rate)) => Some(*rate),
+        Some(FrameRate::Named(_)) => source_fps,
+        None => None,
+    };
+
+    match (requested, max_fps) {
+        (Some(requested), Some(max_fps)) => {
+            let capped = requested.min(max_fps);
+            Some(source_fps.map_or(capped, |source_fps| capped.min(source_fps)))
+        }
+        (Some(requested), None) => Some(requested),
+        (None, Some(max_fps)) => source_fps
+            .filter(|source_fps| *source_fps > max_fps)
+            .map(|_| max_fps),
+        (None, None) => None,
+    }
+}
+
+/// Resolves the format's `fps` and `max_fps` against the frame rate of the source's video stream,
+/// read with ffprobe, into the rate passed to ffmpeg's `-r`.
+///
+/// # Arguments
+/// * `source_probe` - The result of probing the source with ffprobe.
+/// * `format` - The desired output format.
+///
+fn apply_source_frame_rate(source_probe: &Result<SourceProbe, String>, format: &mut VideoFormat) {
+    if format.fps.is_none() && format.max_fps.is_none() {
+        return;
+    }
+
+    let source_fps = source_probe.as_ref().ok().and_then(|source_probe| {
+        source_video_stream(source_probe, format).and_then(|stream| stream.frame_rate())
+    });
+    if source_fps.is_none() {
+        eprintln!(
+            "Format {}: could not read the source's frame rate",
+            format.id
+        );
+    }
+
+    format.output_fps = resolve_output_fps(format.fps.as_ref(), format.max_fps, source_fps);
+    if let Some(output_fps) = format.output_fps {
+        println!(
+            "Format {}: encoding at {} fps, source is {:?} fps",
+            format.id, output_fps, source_fps
+        );
+    }
+}
+
+/// Handles sources whose video carries rotation metadata, such as portrait phone recordings. With
+/// `auto_rotate` (the default) the matching transpose is prepended to the format's `vf`, so the
+/// output is upright without relying on metadata; otherwise the frames are left as they are and the
+/// rotation is written to the output's display matrix so players still display it upright.
+///
+/// # Arguments
+/// * `source_probe` - The result of probing the source with ffprobe.
+/// * `format` - The desired output format.
+///
+fn apply_source_rotation(source_probe: &Result<SourceProbe, String>, format: &mut VideoFormat) {
+    let is_video = format
+        .vcodec
+        .as_deref()
+        .is_some_and(|vcodec| !vcodec.is_empty());
+    if !is_video {
+        return;
+    }
+
+    let rotation = source_probe
+        .as_ref()
+        .ok()
+        .and_then(|source_probe| {
+            source_video_stream(source_probe, format).map(|stream| stream.rotation())
+        })
+        .unwrap_or(0);
+    if rotation == 0 {
+        return;
+    }
+
+    format.source_rotation = rotation;
+    if !format.auto_rotate.unwrap_or(true) {
+        println!(
+            "Format {}: preserving source rotation of {} degrees in metadata",
+            format.id, rotation
+        );
+        return;
+    }
+
+    let transpose = match rotation {
+        90 => "transpose=clock",
+        180 => "hflip,vflip",
+        _ => "transpose=cclock",
+    };
+    println!(
+        "Format {}: rotating source by {} degrees with {}",
+        format.id, rotation, transpose
+    );
+    format.vf = Some(match format.vf.take() {
+        Some(vf) if !vf.is_empty() => format!("{},{}", transpose, vf),
+        _ => transpose.to_string(),
+    });
+}
+
+/// Returns the source's video stream that the format encodes.
+///
+/// # Arguments
+/// * `source_probe` - The probe result of the source.
+/// * `format` - The desired output format, after `apply_source_video_stream`.
+///
+fn source_video_stream<'a>(
+    source_probe: &'a SourceProbe,
+    format: &VideoFormat,
+) -> Option<&'a ProbeStream> {
+    source_probe
+        .streams_of_type("video")
+        .get(format.source_video_stream.unwrap_or(0) as usize)
+        .copied()
+}
+
+/// Chooses which of the source's video streams a video format encodes, so sources that also
+/// carry e.g. a thumbnail stream are not encoded from the wrong one. The format's
+/// `video_stream_index` is used if set, and must exist in the source; otherwise the primary video
+/// stream, the one with the highest resolution and then bitrate. Sources with a single video
+/// stream are left to ffmpeg's automatic stream selection.
+///
+/// # Arguments
+/// * `source_probe` - The result of probing the source with ffprobe.
+/// * `format` - The desired output format.
+///
+fn apply_source_video_stream(
+    source_probe: &Result<SourceProbe, String>,
+    format: &mut VideoFormat,
+) -> Result<(), Status> {
+    let is_video = format
+        .vcodec
+        .as_deref()
+        .is_some_and(|vcodec| !vcodec.is_empty());
+    if !is_video || format.map.is_some() {
+        return Ok(());
+    }
+
+    let source_probe = match (format.video_stream_index, source_probe) {
+        (_, Ok(source_probe)) => source_probe,
+        (Some(_), Err(e)) => return Err(Status::new(Code::InvalidArgument, e.clone())),
+        (None, Err(_)) => return Ok(()),
+    };
+    let video_streams = source_probe.streams_of_type("video").len();
+
+    if let Some(index) = format.video_stream_index {
+        if index as usize >= video_streams {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!(
+                    "Video stream index {} does not exist; source has {} video stream(s)",
+                    index, video_streams
+                ),
+            ));
+        }
+        format.source_video_stream = Some(index);
+    } else if video_streams > 1 {
+        format.source_video_stream = source_probe
+            .primary_video_stream_index()
+            .map(|index| index as u32);
+        println!(
+            "Format {}: source has {} video streams, encoding primary stream 0:v:{}",
+            format.id,
+            video_streams,
+            format.source_video_stream.unwrap_or(0)
+        );
+    }
+
+    Ok(())
+}
+
+/// Checks that the audio track requested by the format exists in the source.
+///
+/// # Arguments
+/// * `source_probe` - The result of probing the source with ffprobe.
+/// * `format` - The desired output format.
+///
+fn validate_audio_stream_index(
+    source_probe: &Result<SourceProbe, String>,
+    format: &VideoFormat,
+) -> Result<(), Status> {
+    let index = match format.audio_stream_index {
+        Some(index) => index,
+        None => return Ok(()),
+    };
+
+    let source_probe = source_probe
+        .as_ref()
+        .map_err(|e| Status::new(Code::InvalidArgument, e.clone()))?;
+    let audio_streams = source_probe.streams_of_type("audio").len();
+
+    if index as usize >= audio_streams {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            format!(
+                "Audio stream index {} does not exist; source has {} audio stream(s)",
+                index, audio_streams
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Maps a container MIME type from the format's `type` property to the file extension players
+/// expect, e.g. `.m4a` for audio in an MP4 container.
+fn extension_for_mime_type(mime_type: &str) -> Option<&'static str> {
+    match mime_type {
+        "video/mp4" => Some("mp4"),
+        "audio/mp4" | "audio/aac" | "audio/x-m4a" => Some("m4a"),
+        "video/webm" | "audio/webm" => Some("webm"),
+        "video/x-matroska" | "video/matroska" => Some("mkv"),
+        "audio/x-matroska" | "audio/matroska" => Some("mka"),
+        "video/quicktime" => Some("mov"),
+        "video/mp2t" => Some("ts"),
+        "audio/ogg" => Some("ogg"),
+        "audio/opus" => Some("opus"),
+        "audio/mpeg" | "audio/mp3" => Some("mp3"),
+        "audio/flac" | "audio/x-flac" => Some("flac"),
+        "audio/wav" | "audio/x-wav" | "audio/wave" => Some("wav"),
+        _ => None,
+    }
+}
+
+/// Maps a codec to the conventional extension of the container it is most commonly carried in.
+fn extension_for_codec(codec: &str, is_video: bool) -> Option<&'static str> {
+    if is_video {
+        match codec {
+            "h264" | "libx264" | "h264_nvenc" | "h264_qsv" | "h264_vaapi" | "hevc" | "libx265"
+            | "hevc_nvenc" | "hevc_qsv" | "hevc_vaapi" | "av1" | "libaom-av1" | "libsvtav1"
+            | "av1_nvenc" | "av1_qsv" => Some("mp4"),
+            "vp8" | "vp9" | "libvpx" | "libvpx-vp9" => Some("webm"),
+            "prores" | "prores_ks" => Some("mov"),
+            _ => None,
+        }
+    } else {
+        match codec {
+            "aac" | "libfdk_aac" | "alac" => Some("m4a"),
+            "opus" | "libopus" => Some("opus"),
+            "vorbis" | "libvorbis" => Some("ogg"),
+            "flac" => Some("flac"),
+            "mp3" | "libmp3lame" => Some("mp3"),
+            "pcm_s16le" | "pcm_s24le" => Some("wav"),
+            _ => None,
+        }
+    }
+}
+
+/// Resolves the output extension for a format that omits `ext`, from its container `type` or,
+/// failing that, its video or audio codec.
+fn resolve_extension(format: &VideoFormat) -> Option<&'static str> {
+    if let Some(ext) = format.mime_type.as_deref().and_then(extension_for_mime_type) {
+        return Some(ext);
+    }
+
+    if let Some(vcodec) = format.vcodec.as_deref().filter(|vcodec| !vcodec.is_empty()) {
+        return extension_for_codec(vcodec, true);
+    }
+
+    format
+        .acodec
+        .as_deref()
+        .or(format.c_a.as_deref())
+        .and_then(|acodec| extension_for_codec(acodec, false))
+}
+
+/// How `resolve_name_collisions` handles formats whose outputs would get the same name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NamingCollisionStrategy {
+    // The later formats get a `_2`, `_3`, ... suffix
+    Suffix,
+    // The formats are rejected before anything is transcoded
+    Reject,
+}
+
+/// Reads `NAMING_COLLISION_STRATEGY`, which is "suffix" when not set or empty, or "reject".
+pub fn naming_collision_strategy() -> Result<NamingCollisionStrategy, String> {
+    match var("NAMING_COLLISION_STRATEGY").unwrap_or_default().trim() {
+        "" | "suffix" => Ok(NamingCollisionStrategy::Suffix),
+        "reject" => Ok(NamingCollisionStrategy::Reject),
+        strategy => Err(format!(
+            "Invalid NAMING_COLLISION_STRATEGY {}; expected suffix or reject",
+            strategy
+        )),
+    }
+}
+
+/// Appends `_{n}` to a filename before its extension.
+pub fn with_suffix(file_name: &str, n: usize) -> String {
+    match file_name.rsplit_once('.') {
+        Some((stem, ext)) => format!("{}_{}.{}", stem, n, ext),
+        None => format!("{}_{}", file_name, n),
+    }
+}
+
+/// Detects formats whose outputs would overwrite each other's, because their names are the same.
+/// With `NamingCollisionStrategy::Suffix` each collision is reported and the later name is given
+/// the lowest `_{n}` suffix that is not yet used.
+///
+/// # Arguments
+/// * `names` - The output name of each format, `None` for formats that have none.
+/// * `strategy` - How collisions are handled.
+///
+/// # Returns
+/// The names with collisions suffixed, or an error message if a collision is rejected.
+///
+pub fn resolve_name_collisions(
+    names: Vec<Option<String>>,
+    strategy: NamingCollisionStrategy,
+) -> Result<Vec<Option<String>>, String> {
+    let mut used_names = HashSet::new();
+    let mut resolved_names = Vec::new();
+    for (index, name) in names.into_iter().enumerate() {
+        let mut name = match name {
+            Some(name) => name,
+            None => {
+                resolved_names.push(None);
+                continue;
+            }
+        };
+
+        if used_names.contains(&name) {
+            if strategy == NamingCollisionStrategy::Reject {
+                return Err(format!(
+                    "format {}: output name {} collides with an earlier format",
+                    index, name
+                ));
+            }
+
+            let mut n = 2;
+            while used_names.contains(&with_suffix(&name, n)) {
+                n += 1;
+            }
+            let suffixed = with_suffix(&name, n);
+            eprintln!(
+                "format {}: output name {} collides with an earlier format, using {}",
+                index, name, suffixed
+            );
+            name = suffixed;
+        }
+
+        used_names.insert(name.clone());
+        resolved_names.push(Some(name));
+    }
+
+    Ok(resolved_names)
+}
+
+/// Returns the name a format's outputs are written under: the source's file name followed by the
+/// format's `id` and `name_suffix`, e.g. `{source}_1` for `{source}_1_ue.mp4`.
+///
+/// # Arguments
+/// * `source_name` - The file name of the source.
+/// * `format` - The output format.
+///
+fn output_file_name(source_name: &str, format: &VideoFormat) -> String {
+    format!(
+        "{}_{}{}",
+        source_name,
+        format.id,
+        format.name_suffix.as_deref().unwrap_or_default()
+    )
+}
+
+/// Default template for `friendly_file_name`.
+// Only the CLI names its outputs
+#[allow(dead_code)]
+pub const DEFAULT_FILENAME_TEMPLATE: &str = "{basename}_{resolution}_{codec}.{ext}";
+
+/// Maps an encoder name to the short codec name used in friendly filenames, e.g. "libx264" and
+/// "h264_nvenc" both become "h264".
+#[allow(dead_code)]
+fn friendly_codec_name(codec: &str) -> String {
+    match codec {
+        "libx264" | "h264_nvenc" | "h264_qsv" | "h264_vaapi" => "h264",
+        "hevc" | "libx265" | "hevc_nvenc" | "hevc_qsv" | "hevc_vaapi" => "h265",
+        "libaom-av1" | "libsvtav1" | "av1_nvenc" | "av1_qsv" => "av1",
+        "libvpx" => "vp8",
+        "libvpx-vp9" => "vp9",
+        "libfdk_aac" => "aac",
+        "libopus" => "opus",
+        "libvorbis" => "vorbis",
+        "libmp3lame" => "mp3",
+        _ => codec,
+    }
+    .to_string()
+}
+
+/// Builds a human-readable filename for a transcoded output from a template such as
+/// `DEFAULT_FILENAME_TEMPLATE`. The placeholders are `{basename}` (the source file name without its
+/// extension), `{id}`, `{resolution}` (e.g. "1080p" from the `scale` filter, or "audio" for
+/// audio-only formats), `{codec}` and `{ext}`. The result is sanitized so it is safe to use as a
+/// file name.
+///
+/// # Arguments
+/// * `template` - The filename template.
+/// * `basename` - The source file name without its extension.
+/// * `format` - The output format.
+///
+#[allow(dead_code)]
+pub fn friendly_file_name(template: &str, basename: &str, format: &VideoFormat) -> String {
+    let vcodec = format.vcodec.as_deref().filter(|vcodec| !vcodec.is_empty());
+
+    let resolution = match vcodec {
+        Some(_) => format
+            .vf
+            .as_deref()
+            .and_then(parse_scale)
+            .map(|(_, height)| format!("{}p", height))
+            .unwrap_or_else(|| format.id.to_string()),
+        None => "audio".to_string(),
+    };
+
+    let codec = vcodec
+        .or(format.acodec.as_deref())
+        .or(format.c_a.as_deref())
+        .map(friendly_codec_name)
+        .unwrap_or_default();
+
+    let file_name = template
+        .replace("{basename}", basename)
+        .replace("{id}", &format.id.to_string())
+        .replace("{resolution}", &resolution)
+        .replace("{codec}", &codec)
+        .replace("{ext}", &format.ext);
+
+    sanitize(file_name)
+}
+
+pub fn get_video_format_from_str(video_format: &str) -> Result<VideoFormat, Status> {
+    let mut format = serde_json::from_str::<VideoFormat>(video_format).map_err(|err| {
+        Status::new(
+            Code::InvalidArgument,
+            format!("Invalid video format: {}", err),
+        )
+    })?;
+
+    if format.ext.is_empty() {
+        format.ext = resolve_extension(&format)
+            .ok_or_else(|| {
+                Status::new(
+                    Code::InvalidArgument,
+                    format!(
+                        "Format {} has no ext and none could be derived from its type or codec",
+                        format.id
+                    ),
+                )
+            })?
+            .to_string();
+    }
+
+    // Part of file names, so it is never more than the suffix `resolve_name_collisions` adds
+    if let Some(name_suffix) = format.name_suffix.as_deref() {
+        let is_valid = name_suffix
+            .strip_prefix('_')
+            .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()));
+        if !is_valid {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!(
+                    "Invalid name_suffix {} of format {}; expected _ followed by digits",
+                    name_suffix, format.id
+                ),
+            ));
+        }
+    }
+
+    if let Some(color_range) = format.color_range.as_deref() {
+        if !COLOR_RANGES.contains(&color_range) {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!(
+                    "Invalid color_range {}; expected one of {}",
+                    color_range,
+                    COLOR_RANGES.join(", ")
+                ),
+            ));
+        }
+    }
+
+    if format.fragmented.unwrap_or(false) && !MP4_EXTENSIONS.contains(&format.ext.as_str()) {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            format!(
+                "Format {} is fragmented but its ext {} is not an MP4 container",
+                format.id, format.ext
+            ),
+        ));
+    }
+
+    for encoder in [&format.vcodec, &format.c_a, &format.acodec] {
+        if let Some(encoder) = encoder.as_deref() {
+            capabilities::check_encoder(encoder).map_err(|e| {
+                Status::new(
+                    Code::InvalidArgument,
+                    format!("Format {}: {}", format.id, e),
+                )
+            })?;
+        }
+    }
+
+    if is_streamed(&format) {
+        validate_stream_upload(&format)?;
+    }
+
+    let is_video = format
+        .vcodec
+        .as_deref()
+        .is_some_and(|vcodec| !vcodec.is_empty());
+    if format.also_extract_audio.is_some() && !is_video {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            format!(
+                "Format {} sets also_extract_audio but is not a video format",
+                format.id
+            ),
+        ));
+    }
+
+    if format.video_stream_index.is_some() {
+        if !is_video {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!(
+                    "Format {} sets video_stream_index but is not a video format",
+                    format.id
+                ),
+            ));
+        }
+        if format.map.is_some() {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!(
+                    "Format {} sets both map and video_stream_index; select the video stream in \
+                     map",
+                    format.id
+                ),
+            ));
+        }
+    }
+
+    if format.map.is_some() && format.audio_stream_index.is_some() {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            format!(
+                "Format {} sets both map and audio_stream_index; select the audio track in map",
+                format.id
+            ),
+        ));
+    }
+
+    if let Some(quality_mode) = format.quality_mode.as_deref() {
+        validate_quality_mode(quality_mode).map_err(|e| Status::new(Code::InvalidArgument, e))?;
+    }
+
+    if let Some(vcodec) = format.vcodec.as_deref() {
+        if format.preset.is_some() && ENCODERS_WITHOUT_PRESET.contains(&vcodec) {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!("Format {} sets preset, which {} does not have", format.id, vcodec),
+            ));
+        }
+    }
+
+    // The first pass only sets how a target bitrate is spent
+    if format.two_pass == Some(true) && format.b_v.is_none() && format.target_size_mb.is_none() {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            format!(
+                "Format {} sets two_pass, which needs a b_v or target_size_mb",
+                format.id
+            ),
+        ));
+    }
+
+    if let Some(target_size_mb) = format.target_size_mb {
+        if target_size_mb.is_nan() || target_size_mb <= 0.0 {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!("Format {} target_size_mb must be positive", format.id),
+            ));
+        }
+        if !is_video {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!(
+                    "Format {} sets target_size_mb but is not a video format",
+                    format.id
+                ),
+            ));
+        }
+        if format.b_v.is_some() || format.crf.is_some() {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!(
+                    "Format {} sets target_size_mb, which chooses the bitrate, along with b_v or crf",
+                    format.id
+                ),
+            ));
+        }
+    }
+
+    if format.min_keyint == Some(0) || format.max_keyint == Some(0) {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            format!("Format {} keyframe intervals must be at least 1", format.id),
+        ));
+    }
+
+    if let (Some(min_keyint), Some(max_keyint)) = (format.min_keyint, format.max_keyint) {
+        if min_keyint > max_keyint {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!(
+                    "Format {} min_keyint {} is greater than max_keyint {}",
+                    format.id, min_keyint, max_keyint
+                ),
+            ));
+        }
+    }
+
+    if let Some(min_vmaf) = format.min_vmaf {
+        if !(0.0..=100.0).contains(&min_vmaf) {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!("Format {} min_vmaf must be between 0 and 100", format.id),
+            ));
+        }
+        if !is_video || is_streamed(&format) {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!(
+                    "Format {} sets min_vmaf, which needs a video output written to disk",
+                    format.id
+                ),
+            ));
+        }
+        capabilities::check_feature("libvmaf").map_err(|e| {
+            Status::new(
+                Code::InvalidArgument,
+                format!("Format {} sets min_vmaf: {}", format.id, e),
+            )
+        })?;
+    }
+
+    if format.perceptual_hash.unwrap_or(false) && (!is_video || is_streamed(&format)) {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            format!(
+                "Format {} sets perceptual_hash, which needs a video output written to disk",
+                format.id
+            ),
+        ));
+    }
+
+    match &format.fps {
+        Some(FrameRate::Rate(rate)) if !rate.is_finite() || *rate <= 0.0 => {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!("Format {} fps must be above 0", format.id),
+            ));
+        }
+        Some(FrameRate::Named(name)) if name != "source" => {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!(
+                    "Invalid fps {} for format {}; expected a number or \"source\"",
+                    name, format.id
+                ),
+            ));
+        }
+        _ => {}
+    }
+    if let Some(max_fps) = format.max_fps {
+        if !max_fps.is_finite() || max_fps <= 0.0 {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!("Format {} max_fps must be above 0", format.id),
+            ));
+        }
+    }
+    if (format.fps.is_some() || format.max_fps.is_some()) && !is_video {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            format!(
+                "Format {} sets fps or max_fps, which needs a video output",
+                format.id
+            ),
+        ));
+    }
+
+    if let Some(chapters) = &format.chapters {
+        if chapters.is_empty() {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!("Format {} sets an empty list of chapters", format.id),
+            ));
+        }
+        let mut previous_start = None;
+        for chapter in chapters {
+            if chapter.start.is_nan()
+                || chapter.start < 0.0
+                || previous_start.is_some_and(|previous_start| chapter.start <= previous_start)
+            {
+                return Err(Status::new(
+                    Code::InvalidArgument,
+                    format!(
+                        "Format {} chapter starts must be increasing and not negative, but {} \
+                         starts at {}",
+                        format.id, chapter.title, chapter.start
+                    ),
+                ));
+            }
+            previous_start = Some(chapter.start);
+        }
+    }
+
+    if let Some(hls_encryption) = &format.hls_encryption {
+        validate_hls_packaging(&format, "hls_encryption", is_video)?;
+        validate_hls_encryption(&format, hls_encryption)?;
+    }
+    validate_ll_hls(&format, is_video)?;
+    if format.iframe_playlist.unwrap_or(false) {
+        validate_hls_packaging(&format, "iframe_playlist", is_video)?;
+        // Byte ranges into an AES-128 segment cannot be decrypted on their own
+        if format.hls_encryption.is_some() {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!(
+                    "Format {} iframe_playlist cannot be combined with hls_encryption",
+                    format.id
+                ),
+            ));
+        }
+    }
+
+    if let Some(scene_split) = &format.scene_split {
+        let threshold = scene_split.threshold.unwrap_or(DEFAULT_SCENE_THRESHOLD);
+        if threshold.is_nan() || threshold <= 0.0 || threshold > 1.0 {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!(
+                    "Format {} scene_split threshold must be above 0 and at most 1",
+                    format.id
+                ),
+            ));
+        }
+        if !is_video || is_streamed(&format) {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!(
+                    "Format {} sets scene_split, which needs a video output written to disk",
+                    format.id
+                ),
+            ));
+        }
+    }
+
+    apply_default_dest(&mut format)?;
+    cap_output_pixels(&mut format, *MAX_OUTPUT_PIXELS, *REJECT_OVERSIZED_OUTPUT)?;
+    apply_streaming_vbv_defaults(&mut format);
+    apply_quality_mode(&mut format);
+    apply_audio_mode(&mut format)?;
+    apply_text_watermark(&mut format)?;
+    apply_color_range(&mut format);
+    apply_preset_file(&mut format)?;
+    apply_audio_description(&mut format)?;
+
+    // Flags may be combined with '+', e.g. "lanczos+accurate_rnd"; only the algorithm is checked
+    if let Some(scale_flags) = format.scale_flags.as_deref() {
+        let has_algorithm = scale_flags
+            .split('+')
+            .any(|flag| SCALE_FLAGS.contains(&flag));
+        if !has_algorithm {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!(
+                    "Invalid scale_flags {}; expected one of {}",
+                    scale_flags,
+                    SCALE_FLAGS.join(", ")
+                ),
+            ));
+        }
+    }
+
+    Ok(format)
+}
+
+/// Returns the ffmpeg muxer of a container that can be written to a pipe, or `None` if the
+/// container needs to seek back into the file, as a regular MP4 does to write its moov.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+///
+fn streaming_muxer(format: &VideoFormat) -> Option<&'static str> {
+    let is_fragmented = format.fragmented.unwrap_or(false);
+    match format.ext.as_str() {
+        "mp4" | "m4v" if is_fragmented => Some("mp4"),
+        "m4a" if is_fragmented => Some("ipod"),
+        "mov" if is_fragmented => Some("mov"),
+        "webm" => Some("webm"),
+        "mkv" | "mka" => Some("matroska"),
+        "ts" => Some("mpegts"),
+        "ogg" | "opus" => Some("ogg"),
+        "mp3" => Some("mp3"),
+        "aac" => Some("adts"),
+        _ => None,
+    }
+}
+
+/// Returns whether a format's output is uploaded from ffmpeg's stdout as it is encoded.
+fn is_streamed(format: &VideoFormat) -> bool {
+    format.stream_upload.unwrap_or(false)
+}
+
+/// Checks that a format setting `stream_upload` can be streamed: its container must be streamable,
+/// it must be stored on IPFS, as S5 uploads are created with the hash of the whole file, and its
+/// output must not need further processing after encoding.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+///
+fn validate_stream_upload(format: &VideoFormat) -> Result<(), Status> {
+    if streaming_muxer(format).is_none() {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            format!(
+                "Format {} sets stream_upload but its ext {} is not a streamable container; use a \
+                 fragmented mp4, webm, mkv, ts, ogg, mp3 or aac",
+                format.id, format.ext
+            ),
+        ));
+    }
+    // A format without a dest is uploaded to `DEFAULT_DEST`
+    let dest = format.dest.as_deref().unwrap_or(DEFAULT_DEST.as_str());
+    if dest != "ipfs" {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            format!(
+                "Format {} sets stream_upload with dest {}, but streamed uploads are only \
+                 supported for dest ipfs; S5 uploads need the hash of the whole output before they \
+                 start",
+                format.id, dest
+            ),
+        ));
+    }
+    if format.encrypt.unwrap_or(false) || format.also_extract_audio.is_some() {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            format!(
+                "Format {} sets stream_upload, which cannot be combined with encrypt or \
+                 also_extract_audio",
+                format.id
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Computes the VMAF score of a transcoded output against its source with ffmpeg's libvmaf. The
+/// output is scaled to the source's resolution, as VMAF compares frames of the same size, and the
+/// source is clipped to the format's `start` and `end` the way the output was, so the frames line
+/// up: with input options on the source, which snap to the same keyframes, or with `frame_accurate`
+/// by trimming the source's decoded frames at the exact cut points.
+///
+/// # Arguments
+/// * `output_path` - The path to the transcoded output.
+/// * `source_path` - The path to the source video file.
+/// * `format` - The format the output was transcoded with.
+///
+/// # Returns
+/// The pooled VMAF score from 0 to 100, or an error message.
+///
+fn compute_vmaf(output_path: &str, source_path: &str, format: &VideoFormat) -> Result<f64, String> {
+    let mut cmd = Command::new(FFMPEG_PATH.as_str());
+    cmd.args(["-hide_banner", "-i", output_path]);
+    cmd.args(clip_args(format, true));
+    cmd.args(["-i", source_path]);
+
+    // Output options would clip the scored frames of both files, so the source is trimmed instead
+    let mut trim = Vec::new();
+    if format.frame_accurate.unwrap_or(false) {
+        if let Some(start) = format.start.as_deref().and_then(parse_time) {
+            trim.push(format!("start={}", start));
+        }
+        if let Some(end) = format.end.as_deref().and_then(parse_time) {
+            trim.push(format!("end={}", end));
+        }
+    }
+    let trim = if trim.is_empty() {
+        String::new()
+    } else {
+        format!("trim={},", trim.join(":"))
+    };
+    // Compares against the source video stream the output was encoded from
+    let filter = format!(
+        "[1:v:{}]{}setpts=PTS-STARTPTS[clipped];\
+         [0:v][clipped]scale2ref=flags=bicubic[scaled][source];\
+         [scaled]setpts=PTS-STARTPTS[distorted];[source]setpts=PTS-STARTPTS[reference];\
+         [distorted][reference]libvmaf",
+        format.source_video_stream.unwrap_or(0),
+        trim
+    );
+    cmd.args(["-lavfi", filter.as_str(), "-f", "null", "-"]);
+
+    let output = cmd
+        .output()
+        .map_err(|e| format!("Failed to execute ffmpeg for VMAF: {}", e))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        return Err(format!(
+            "VMAF computation failed: {}",
+            stderr.lines().last().unwrap_or_default()
+        ));
+    }
+
+    // libvmaf logs e.g. "[libvmaf @ 0x...] VMAF score: 93.412345"
+    stderr
+        .lines()
+        .filter_map(|line| line.split("VMAF score:").nth(1))
+        .filter_map(|score| score.trim().parse::<f64>().ok())
+        .next_back()
+        .ok_or_else(|| "VMAF score not found in ffmpeg output".to_string())
+}
+
+/// Computes the pHash of a grayscale image of `PHASH_IMAGE_SIZE` by `PHASH_IMAGE_SIZE` pixels:
+/// bit `i` of the hash is set if coefficient `i` of the lowest 8 by 8 frequencies of the image's
+/// DCT, row by row, is above the median of the 64. Images that look alike have hashes that differ
+/// in few bits, even after scaling or re-encoding.
+///
+/// # Arguments
+/// * `pixels` - The image's pixels, row by row.
+///
+fn phash(pixels: &[u8]) -> u64 {
+    let n = PHASH_IMAGE_SIZE;
+    let cosines: Vec<Vec<f64>> = (0..8)
+        .map(|u| {
+            (0..n)
+                .map(|x| {
+                    ((2 * x + 1) as f64 * u as f64 * std::f64::consts::PI / (2 * n) as f64).cos()
+                })
+                .collect()
+        })
+        .collect();
+
+    // The DCT is separable, so the rows are transformed first and then their columns
+    let rows: Vec<[f64; 8]> = pixels
+        .chunks(n)
+        .map(|row| {
+            let mut coefficients = [0.0; 8];
+            for (u, coefficient) in coefficients.iter_mut().enumerate() {
+                *coefficient = row
+                    .iter()
+                    .zip(&cosines[u])
+                    .map(|(pixel, cosine)| *pixel as f64 * cosine)
+                    .sum();
+            }
+            coefficients
+        })
+        .collect();
+
+    let rows = &rows;
+    let coefficients: Vec<f64> = cosines
+        .iter()
+        .flat_map(|column_cosines| {
+            (0..8).map(move |u| {
+                rows.iter()
+                    .zip(column_cosines)
+                    .map(|(row, cosine)| row[u] * cosine)
+                    .sum::<f64>()
+            })
+        })
+        .collect();
+
+    let mut sorted = coefficients.clone();
+    sorted.sort_by(f64::total_cmp);
+    let median = (sorted[31] + sorted[32]) / 2.0;
+
+    coefficients
+        .iter()
+        .enumerate()
+        .filter(|(_, coefficient)| **coefficient > median)
+        .fold(0, |hash, (i, _)| hash | (1u64 << i))
+}
+
+/// Returns the times `perceptual_hash` samples a rendition at: the middles of
+/// `PERCEPTUAL_HASH_FRAMES` equal parts of its duration. They depend only on the duration, so two
+/// encodes of the same source are sampled at the same points, wherever their keyframes are.
+///
+/// # Arguments
+/// * `duration` - The duration of the rendition in seconds.
+///
+fn perceptual_hash_times(duration: f64) -> Vec<f64> {
+    (0..PERCEPTUAL_HASH_FRAMES)
+        .map(|i| duration * (i as f64 + 0.5) / PERCEPTUAL_HASH_FRAMES as f64)
+        .collect()
+}
+
+/// Decodes the frame of a transcoded output shown at `timestamp`, scaled to a grayscale image of
+/// `PHASH_IMAGE_SIZE` pixels square.
+///
+/// # Arguments
+/// * `output_path` - The path to the transcoded output.
+/// * `timestamp` - The time of the frame in seconds.
+///
+fn decode_hash_frame(output_path: &str, timestamp: f64) -> Result<Vec<u8>, String> {
+    let scale = format!(
+        "scale={}:{}:flags=area,format=gray",
+        PHASH_IMAGE_SIZE, PHASH_IMAGE_SIZE
+    );
+    let timestamp = format!("{:.3}", timestamp);
+    let output = Command::new(FFMPEG_PATH.as_str())
+        .args(["-hide_banner", "-v", "error"])
+        .args(["-ss", timestamp.as_str(), "-i", output_path])
+        .args(["-map", "0:v:0", "-frames:v", "1", "-vf", scale.as_str()])
+        .args(["-f", "rawvideo", "-"])
+        .output()
+        .map_err(|e| format!("Failed to execute ffmpeg for perceptual hashes: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Perceptual hash computation failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+                .lines()
+                .last()
+                .unwrap_or_default()
+        ));
+    }
+
+    let mut frame = output.stdout;
+    if frame.len() < PHASH_IMAGE_SIZE * PHASH_IMAGE_SIZE {
+        return Err(format!(
+            "Perceptual hash computation found no frame at {}s",
+            timestamp
+        ));
+    }
+    frame.truncate(PHASH_IMAGE_SIZE * PHASH_IMAGE_SIZE);
+    Ok(frame)
+}
+
+/// Computes the pHashes of a transcoded output for `perceptual_hash`: the frames shown at
+/// `perceptual_hash_times` are each scaled to a grayscale image of `PHASH_IMAGE_SIZE` pixels
+/// square and hashed with `phash`.
+///
+/// # Arguments
+/// * `output_path` - The path to the transcoded output.
+///
+/// # Returns
+/// The hashes as 16 hex digits each, in output order, or an error message.
+///
+fn compute_perceptual_hashes(output_path: &str) -> Result<Vec<String>, String> {
+    let duration = get_video_duration(output_path)?;
+    if duration.is_nan() || duration <= 0.0 {
+        return Err("Perceptual hash computation could not read the duration".to_string());
+    }
+
+    perceptual_hash_times(duration)
+        .into_iter()
+        .map(|timestamp| {
+            decode_hash_frame(output_path, timestamp).map(|frame| format!("{:016x}", phash(&frame)))
+        })
+        .collect()
+}
+
+/// Parses the times of scene changes from the stderr of ffmpeg running the `showinfo` filter
+/// after a scene `select`, which logs a line such as
+/// "[Parsed_showinfo_1 @ 0x...] n:   0 pts: 122880 pts_time:4.8 duration: ..." per selected
+/// frame.
+///
+/// # Arguments
+/// * `stderr` - The stderr of the ffmpeg command.
+///
+/// # Returns
+/// The times in seconds, in increasing order and without duplicates.
+///
+pub fn parse_scene_times(stderr: &str) -> Vec<f64> {
+    let mut scene_times: Vec<f64> = stderr
+        .lines()
+        .filter(|line| line.contains("Parsed_showinfo"))
+        .filter_map(|line| line.split("pts_time:").nth(1))
+        .filter_map(|pts_time| pts_time.split_whitespace().next())
+        .filter_map(|pts_time| pts_time.parse::<f64>().ok())
+        .filter(|pts_time| pts_time.is_finite() && *pts_time > 0.0)
+        .collect();
+    scene_times.sort_by(f64::total_cmp);
+    scene_times.dedup();
+    scene_times
+}
+
+/// Detects the scene changes of a transcoded output with ffmpeg's scene score.
+///
+/// # Arguments
+/// * `output_path` - The path to the transcoded output.
+/// * `threshold` - The minimum scene change score from 0 to 1.
+///
+/// # Returns
+/// The times of the scene changes in seconds, or an error message.
+///
+fn detect_scene_times(output_path: &str, threshold: f64) -> Result<Vec<f64>, String> {
+    let output = Command::new(FFMPEG_PATH.as_str())
+        .args(["-hide_banner", "-i", output_path, "-map", "0:v:0", "-vf"])
+        .arg(format!("select='gt(scene,{})',showinfo", threshold))
+        .args(["-f", "null", "-"])
+        .output()
+        .map_err(|e| format!("Failed to execute ffmpeg for scene detection: {}", e))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        return Err(format!(
+            "Scene detection failed: {}",
+            stderr.lines().last().unwrap_or_default()
+        ));
+    }
+
+    Ok(parse_scene_times(&stderr))
+}
+
+/// Splits a transcoded output at the given times with ffmpeg's segment muxer, copying its streams.
+/// Each cut lands on the first keyframe at or after its time, so the segment times are read back
+/// from the muxer's segment list.
+///
+/// # Arguments
+/// * `output_path` - The path to the transcoded output.
+/// * `segment_prefix` - The path prefix of the segment files.
+/// * `ext` - The extension of the output and its segments.
+/// * `scene_times` - The times to cut at in seconds.
+///
+/// # Returns
+/// The path, start and end of each segment, or an error message.
+///
+fn split_at_scenes(
+    output_path: &str,
+    segment_prefix: &str,
+    ext: &str,
+    scene_times: &[f64],
+) -> Result<Vec<(String, f64, f64)>, String> {
+    let list_path = format!("{}list.csv", segment_prefix);
+    let segment_times: Vec<String> = scene_times.iter().map(|time| time.to_string()).collect();
+
+    let output = Command::new(FFMPEG_PATH.as_str())
+        .args(["-hide_banner", "-v", "error", "-i", output_path])
+        .args(["-map", "0", "-c", "copy", "-f", "segment", "-segment_times"])
+        .arg(segment_times.join(","))
+        .args(["-reset_timestamps", "1", "-segment_list_type", "csv"])
+        .args(["-segment_list", list_path.as_str(), "-y"])
+        .arg(format!("{}%03d.{}", segment_prefix, ext))
+        .output()
+        .map_err(|e| format!("Failed to execute ffmpeg to split scenes: {}", e))?;
+    if !output.status.success() {
+        let _ = std::fs::remove_file(&list_path);
+        return Err(format!(
+            "Splitting scenes failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    // Each line of the list reads "<segment file name>,<start>,<end>"
+    let list = std::fs::read_to_string(&list_path)
+        .map_err(|e| format!("Failed to read scene segment list: {}", e));
+    let _ = std::fs::remove_file(&list_path);
+    let segment_dir = Path::new(segment_prefix)
+        .parent()
+        .map(|dir| dir.to_path_buf())
+        .unwrap_or_default();
+
+    Ok(list?
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.rsplitn(3, ',');
+            let end = fields.next()?.trim().parse::<f64>().ok()?;
+            let start = fields.next()?.trim().parse::<f64>().ok()?;
+            let segment_path = segment_dir.join(fields.next()?.trim_matches('"'));
+            Some((segment_path.to_string_lossy().to_string(), start, end))
+        })
+        .collect())
+}
+
+/// Splits a transcoded output at its scene changes and uploads each segment. An output with no
+/// scene changes is a single segment, the rendition itself, so it is not uploaded again.
+///
+/// # Arguments
+/// * `task_id` - The task whose disk quota the segments count against.
+/// * `output_path` - The path to the transcoded output.
+/// * `file_name` - The name of the output file without its extension.
+/// * `output_dir` - The directory the output was written to.
+/// * `format` - The format the output was transcoded with.
+/// * `cid` - The CID of the uploaded rendition.
+///
+/// # Returns
+/// The uploaded segments with their times, or an error message.
+///
+async fn upload_scene_segments(
+    task_id: &str,
+    output_path: &str,
+    file_name: &str,
+    output_dir: &str,
+    format: &VideoFormat,
+    cid: &str,
+) -> Result<Vec<SceneSegment>, String> {
+    let threshold = format
+        .scene_split
+        .as_ref()
+        .and_then(|scene_split| scene_split.threshold)
+        .unwrap_or(DEFAULT_SCENE_THRESHOLD);
+    let scene_times = detect_scene_times(output_path, threshold)?;
+    println!(
+        "Format {} has {} scene changes above {}",
+        format.id,
+        scene_times.len(),
+        threshold
+    );
+
+    if scene_times.is_empty() {
+        return Ok(vec![SceneSegment {
+            cid: cid.to_string(),
+            start: 0.0,
+            end: get_video_duration(output_path).unwrap_or_default(),
+        }]);
+    }
+
+    let segment_prefix = format!("{}{}_scene", output_dir, file_name);
+    let segments = split_at_scenes(output_path, &segment_prefix, &format.ext, &scene_times)?;
+    for (segment_path, _, _) in &segments {
+        shared::record_task_file(task_id, segment_path);
+    }
+
+    let mut scenes = Vec::new();
+    let mut upload_error = check_disk_quota(task_id).err();
+    for (segment_path, start, end) in &segments {
+        if upload_error.is_none() {
+            match upload_video(segment_path.as_str(), format.dest.clone()).await {
+                Ok(cid) => scenes.push(SceneSegment {
+                    cid,
+                    start: *start,
+                    end: *end,
+                }),
+                Err(e) => {
+                    upload_error = Some(format!(
+                        "Failed to upload scene segment {}: {}",
+                        segment_path, e
+                    ))
+                }
+            }
+        }
+        let _ = std::fs::remove_file(segment_path);
+    }
+
+    match upload_error {
+        Some(e) => Err(e),
+        None => Ok(scenes),
+    }
+}
+
+/// Returns whether a format's output is re-packaged as HLS segments by `package_hls` before it is
+/// uploaded.
+fn is_hls_packaged(format: &VideoFormat) -> bool {
+    format.hls_encryption.is_some() || format.iframe_playlist.unwrap_or(false) || is_ll_hls(format)
+}
+
+/// Returns whether a format's `packaging` is "ll-hls", low-latency HLS with partial segments.
+fn is_ll_hls(format: &VideoFormat) -> bool {
+    format
+        .packaging
+        .as_deref()
+        .is_some_and(|packaging| packaging.eq_ignore_ascii_case("ll-hls"))
+}
+
+/// Returns the target segment and partial segment durations in seconds of an "ll-hls" format.
+fn ll_hls_durations(format: &VideoFormat) -> (u32, f64) {
+    let ll_hls = format.ll_hls.as_ref();
+    (
+        ll_hls
+            .and_then(|ll_hls| ll_hls.segment_secs)
+            .unwrap_or(DEFAULT_HLS_SEGMENT_SECS),
+        ll_hls
+            .and_then(|ll_hls| ll_hls.part_secs)
+            .unwrap_or(DEFAULT_LL_HLS_PART_SECS),
+    )
+}
+
+/// Checks a format with "ll-hls" packaging or `ll_hls` durations: it must be a video format with
+/// "ll-hls" packaging written to disk, without `scene_split`, and its part duration must divide
+/// its segment duration evenly, so every segment is made of whole parts.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+/// * `is_video` - Whether the format has a video codec.
+///
+fn validate_ll_hls(format: &VideoFormat, is_video: bool) -> Result<(), Status> {
+    let invalid = |message: String| {
+        Status::new(
+            Code::InvalidArgument,
+            format!("Format {} {}", format.id, message),
+        )
+    };
+
+    if !is_ll_hls(format) {
+        if format.ll_hls.is_some() {
+            return Err(invalid("sets ll_hls without packaging \"ll-hls\"".to_string()));
+        }
+        return Ok(());
+    }
+    if !is_video || is_streamed(format) {
+        return Err(invalid(
+            "packaging ll-hls needs a video format written to disk".to_string(),
+        ));
+    }
+    // Scene segments are cut from the output and uploaded as they are
+    if format.scene_split.is_some() {
+        return Err(invalid(
+            "packaging ll-hls cannot be combined with scene_split".to_string(),
+        ));
+    }
+
+    let (segment_secs, part_secs) = ll_hls_durations(format);
+    if segment_secs == 0 || segment_secs > MAX_HLS_SEGMENT_SECS {
+        return Err(invalid(format!(
+            "ll_hls segment_secs must be between 1 and {}",
+            MAX_HLS_SEGMENT_SECS
+        )));
+    }
+    if !part_secs.is_finite() || part_secs < MIN_LL_HLS_PART_SECS || part_secs > segment_secs as f64
+    {
+        return Err(invalid(format!(
+            "ll_hls part_secs must be between {} and segment_secs {}",
+            MIN_LL_HLS_PART_SECS, segment_secs
+        )));
+    }
+    let parts_per_segment = segment_secs as f64 / part_secs;
+    if (parts_per_segment - parts_per_segment.round()).abs() > 1e-6 {
+        return Err(invalid(format!(
+            "ll_hls part_secs {} does not divide segment_secs {} evenly",
+            part_secs, segment_secs
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that a format whose `option` re-packages its output as HLS can be: it must be a video
+/// format with `packaging` "hls" written to disk, without `scene_split`.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+/// * `option` - The name of the option that re-packages the output.
+/// * `is_video` - Whether the format has a video codec.
+///
+fn validate_hls_packaging(
+    format: &VideoFormat,
+    option: &str,
+    is_video: bool,
+) -> Result<(), Status> {
+    let invalid = |message: &str| {
+        Status::new(
+            Code::InvalidArgument,
+            format!("Format {} {} {}", format.id, option, message),
+        )
+    };
+
+    let is_hls = format
+        .packaging
+        .as_deref()
+        .is_some_and(|packaging| packaging.eq_ignore_ascii_case("hls"));
+    if !is_hls || !is_video || is_streamed(format) {
+        return Err(invalid(
+            "needs a video format with packaging \"hls\" written to disk",
+        ));
+    }
+    // Scene segments are cut from the output and uploaded as they are
+    if format.scene_split.is_some() {
+        return Err(invalid("cannot be combined with scene_split"));
+    }
+    Ok(())
+}
+
+/// Checks a format's `hls_encryption`. SAMPLE-AES, which `sample_aes::encrypt_segment` applies to
+/// the segments after ffmpeg writes them, needs H.264 video.
+///
+/// # Arguments
+/// * `format` - The desired output format.
+/// * `hls_encryption` - The format's `hls_encryption`.
+///
+fn validate_hls_encryption(
+    format: &VideoFormat,
+    hls_encryption: &HlsEncryption,
+) -> Result<(), Status> {
+    let invalid = |message: String| {
+        Status::new(
+            Code::InvalidArgument,
+            format!("Format {} hls_encryption {}", format.id, message),
+        )
+    };
+
+    let method = hls_encryption.method.as_deref().unwrap_or("aes-128");
+    if !HLS_ENCRYPTION_METHODS.contains(&method) {
+        return Err(invalid(format!(
+            "method {} is not one of {}",
+            method,
+            HLS_ENCRYPTION_METHODS.join(", ")
+        )));
+    }
+    let is_h264 = format
+        .vcodec
+        .as_deref()
+        .is_some_and(|vcodec| vcodec == "libx264" || vcodec.starts_with("h264"));
+    if method == "sample-aes" && !is_h264 {
+        return Err(invalid(
+            "method sample-aes needs H.264 video, such as vcodec libx264 or h264_nvenc".to_string(),
+        ));
+    }
+
+    let key_delivery = hls_encryption.key_delivery.as_deref().unwrap_or("upload");
+    if !HLS_KEY_DELIVERIES.contains(&key_delivery) {
+        return Err(invalid(format!(
+            "key_delivery {} is not one of {}",
+            key_delivery,
+            HLS_KEY_DELIVERIES.join(", ")
+        )));
+    }
+    match (key_delivery, hls_encryption.key_uri.as_deref()) {
+        ("key_uri", Some(key_uri))
+            if key_uri.starts_with("https://") || key_uri.starts_with("http://") => {}
+        ("key_uri", _) => {
+            return Err(invalid(
+                "needs an http or https key_uri for key_uri delivery".to_string(),
+            ))
+        }
+        (_, Some(_)) => {
+            return Err(invalid(
+                "sets key_uri, which is only used for key_uri delivery".to_string(),
+            ))
+        }
+        _ => {}
+    }
+
+    let segment_secs = hls_encryption
+        .segment_secs
+        .unwrap_or(DEFAULT_HLS_SEGMENT_SECS);
+    if segment_secs == 0 || segment_secs > MAX_HLS_SEGMENT_SECS {
+        return Err(invalid(format!(
+            "segment_secs must be between 1 and {}",
+            MAX_HLS_SEGMENT_SECS
+        )));
+    }
+
+    Ok(())
+}
+
+/// Returns the URL players fetch an uploaded HLS segment or key from: `HLS_CONTENT_URL` with
+/// `{cid}` replaced by its CID, or the CID prefixed with its storage network.
+///
+/// # Arguments
+/// * `cid` - The CID of the uploaded file.
+/// * `dest` - The storage network the file was uploaded to.
+///
+pub fn hls_content_url(cid: &str, dest: Option<&str>) -> String {
+    match HLS_CONTENT_URL.as_deref() {
+        Some(template) => template.replace("{cid}", cid),
+        None if dest == Some("ipfs") => format!("ipfs://{}", cid),
+        None => format!("s5://{}", cid),
+    }
+}
+
+/// Returns the segment files an HLS media playlist lists, in order: every line that is neither
+/// blank nor a tag or comment starting with `#`.
+///
+/// # Arguments
+/// * `playlist` - The contents of the playlist.
+///
+pub fn playlist_segments(playlist: &str) -> Vec<&str> {
+    playlist
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect()
+}
+
+/// Returns an HLS media playlist with its segment lines, those `playlist_segments` returns,
+/// replaced in order by `urls`. Tags and comments are left as they are, even where they contain a
+/// segment's file name.
+///
+/// # Arguments
+/// * `playlist` - The contents of the playlist.
+/// * `urls` - The URL of each segment, in playlist order.
+///
+pub fn replace_playlist_segments(playlist: &str, urls: &[String]) -> String {
+    let mut urls = urls.iter();
+    let mut replaced = String::with_capacity(playlist.len());
+    for line in playlist.lines() {
+        let is_segment = !line.trim().is_empty() && !line.trim().starts_with('#');
+        match if is_segment { urls.next() } else { None } {
+            Some(url) => replaced.push_str(url),
+            None => replaced.push_str(line),
+        }
+        replaced.push('\n');
+    }
+    replaced
+}
+
+/// Adds a SAMPLE-AES `#EXT-X-KEY` tag before the first segment of an HLS media playlist, which
+/// ffmpeg writes without one as it does not encrypt the segments itself. SAMPLE-AES needs version
+/// 5 of the protocol, so an `#EXT-X-VERSION` below it is raised.
+///
+/// # Arguments
+/// * `playlist` - The contents of the playlist.
+/// * `key_uri` - The URI players fetch the key from.
+/// * `iv` - The IV the segments were encrypted with.
+///
+pub fn add_sample_aes_key(playlist: &str, key_uri: &str, iv: &[u8; 16]) -> String {
+    let key_line = format!(
+        "#EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"{}\",IV=0x{},KEYFORMAT=\"identity\",\
+         KEYFORMATVERSIONS=\"1\"",
+        key_uri,
+        hex::encode(iv)
+    );
+    let mut with_key = String::with_capacity(playlist.len() + key_line.len() + 1);
+    let mut added = false;
+    for line in playlist.lines() {
+        let trimmed = line.trim();
+        if let Some(version) = trimmed.strip_prefix("#EXT-X-VERSION:") {
+            if version.trim().parse::<u32>().is_ok_and(|version| version < 5) {
+                with_key.push_str("#EXT-X-VERSION:5\n");
+                continue;
+            }
+        }
+        let is_segment = !trimmed.is_empty() && !trimmed.starts_with('#');
+        if !added && (trimmed.starts_with("#EXTINF") || is_segment) {
+            with_key.push_str(&key_line);
+            with_key.push('\n');
+            added = true;
+        }
+        with_key.push_str(line);
+        with_key.push('\n');
+    }
+    with_key
+}
+
+/// Checks that an HLS playlist encrypts its segments with `method`: it must have an `#EXT-X-KEY`
+/// tag before its first segment with `METHOD=AES-128` or `METHOD=SAMPLE-AES`, a quoted `URI` and
+/// an `IV`.
+///
+/// # Arguments
+/// * `playlist` - The contents of the playlist.
+/// * `method` - One of `HLS_ENCRYPTION_METHODS`.
+///
+pub fn check_playlist_key(playlist: &str, method: &str) -> Result<(), String> {
+    let key_line = playlist
+        .lines()
+        .map(str::trim)
+        .take_while(|line| line.is_empty() || line.starts_with('#'))
+        .find(|line| line.starts_with("#EXT-X-KEY:"))
+        .ok_or("the playlist has no EXT-X-KEY tag before its first segment")?;
+
+    let attributes = &key_line["#EXT-X-KEY:".len()..];
+    let expected_method = format!("METHOD={}", method.to_uppercase());
+    let has_method = attributes.split(',').any(|a| a == expected_method);
+    let has_uri = attributes.contains("URI=\"");
+    let has_iv = attributes.split(',').any(|a| a.starts_with("IV=0x"));
+    if !has_method || !has_uri || !has_iv {
+        return Err(format!(
+            "the playlist's EXT-X-KEY tag is not an {} key with a URI and IV: {}",
+            method.to_uppercase(),
+            key_line
+        ));
+    }
+    Ok(())
+}
+
+// Size of an MPEG-TS packet
+const TS_PACKET_SIZE: usize = 188;
+
+// PIDs of the MPEG-TS program association table and service description table
+const TS_PAT_PID: u16 = 0x0000;
+const TS_SDT_PID: u16 = 0x0011;
+
+/// Returns the PID and payload of an MPEG-TS packet, or `None` if it is not one.
+fn ts_packet_payload(packet: &[u8]) -> Option<(u16, &[u8])> {
+    if packet.len() != TS_PACKET_SIZE || packet[0] != 0x47 {
+        return None;
+    }
+    let pid = (u16::from(packet[1] & 0x1f) << 8) | u16::from(packet[2]);
+    let payload_start = match (packet[3] >> 4) & 0x3 {
+        0x1 => 4,
+        0x3 => 5 + usize::from(packet[4]),
+        _ => TS_PACKET_SIZE,
+    };
+    Some((pid, packet.get(payload_start..).unwrap_or_default()))
+}
+
+/// Returns the PIDs of an MPEG-TS segment's tables: the PAT, the PMT of every program listed in
+/// the segment's first PAT, and the SDT.
+///
+/// # Arguments
+/// * `segment` - The contents of the segment.
+///
+fn ts_table_pids(segment: &[u8]) -> Vec<u16> {
+    let mut pids = vec![TS_PAT_PID, TS_SDT_PID];
+    let pat = segment
+        .chunks(TS_PACKET_SIZE)
+        .filter_map(ts_packet_payload)
+        .find(|(pid, payload)| *pid == TS_PAT_PID && !payload.is_empty())
+        .map(|(_, payload)| payload);
+
+    // The pointer field, then the section: 3 bytes up to its length, 5 more to the programs, and
+    // a CRC of 4 bytes after them
+    if let Some(section) = pat.and_then(|pat| pat.get(1 + usize::from(pat[0])..)) {
+        if section.len() >= 3 {
+            let section_length = (usize::from(section[1] & 0x0f) << 8) | usize::from(section[2]);
+            let programs_end = (3 + section_length).saturating_sub(4).min(section.len());
+            for program in section.get(8..programs_end).unwrap_or_default().chunks_exact(4) {
+                let program_number = u16::from_be_bytes([program[0], program[1]]);
+                // Program 0 gives the network PID, not a PMT
+                if program_number != 0 {
+                    pids.push((u16::from(program[2] & 0x1f) << 8) | u16::from(program[3]));
+                }
+            }
+        }
+    }
+    pids
+}
+
+/// Returns the times and byte ranges of the keyframes of an MPEG-TS segment, from its video
+/// packets as `(pts_time, pos, is_keyframe)` in file order. Each keyframe's range runs to the next
+/// video packet, or to the end of the segment for the last one, so it also covers any audio
+/// interleaved with the keyframe. It starts at the PAT and PMT written right before the keyframe,
+/// as ffmpeg does for every keyframe, so a player can decode the range on its own.
+///
+/// # Arguments
+/// * `packets` - The segment's video packets.
+/// * `segment` - The contents of the segment.
+///
+pub fn keyframe_ranges(packets: &[(f64, u64, bool)], segment: &[u8]) -> Vec<Keyframe> {
+    let segment_size = segment.len() as u64;
+    let table_pids = ts_table_pids(segment);
+    let is_table_packet = |offset: u64| {
+        let offset = offset as usize;
+        segment
+            .get(offset..offset + TS_PACKET_SIZE)
+            .and_then(ts_packet_payload)
+            .is_some_and(|(pid, _)| table_pids.contains(&pid))
+    };
+
+    packets
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, _, is_keyframe))| *is_keyframe)
+        .map(|(i, (time, offset, _))| {
+            let end = packets
+                .get(i + 1)
+                .map_or(segment_size, |(_, next_offset, _)| *next_offset);
+            let mut start = *offset;
+            while start >= TS_PACKET_SIZE as u64 && is_table_packet(start - TS_PACKET_SIZE as u64) {
+                start -= TS_PACKET_SIZE as u64;
+            }
+            Keyframe {
+                time: *time,
+                offset: start,
+                length: end.saturating_sub(start),
+            }
+        })
+        .collect()
+}
+
+/// Probes the video packets of an MPEG-TS segment with ffprobe and returns its keyframes.
+///
+/// # Arguments
+/// * `segment_path` - The path to the segment.
+///
+fn probe_keyframes(segment_path: &str) -> Result<Vec<Keyframe>, String> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-select_streams", "v:0"])
+        .args(["-show_entries", "packet=pts_time,pos,flags", "-of", "json"])
+        .arg(segment_path)
+        .output()
+        .map_err(|e| format!("Failed to execute ffprobe: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Failed to probe keyframes of {}: {}",
+            segment_path,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let probe: serde_json::Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| format!("Failed to parse keyframes of {}: {}", segment_path, e))?;
+    let field = |packet: &serde_json::Value, name: &str| {
+        packet
+            .get(name)
+            .and_then(|value| value.as_str())
+            .map(str::to_string)
+    };
+    let packets: Vec<(f64, u64, bool)> = probe["packets"]
+        .as_array()
+        .map(|packets| {
+            packets
+                .iter()
+                .filter_map(|packet| {
+                    let time = field(packet, "pts_time")?.parse::<f64>().ok()?;
+                    let offset = field(packet, "pos")?.parse::<u64>().ok()?;
+                    let is_keyframe = field(packet, "flags")?.contains('K');
+                    Some((time, offset, is_keyframe))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let segment = std::fs::read(segment_path)
+        .map_err(|e| format!("Failed to read HLS segment {}: {}", segment_path, e))?;
+    Ok(keyframe_ranges(&packets, &segment))
+}
+
+/// Returns the total duration in seconds of the segments of an HLS media playlist, the sum of its
+/// `#EXTINF` durations.
+///
+/// # Arguments
+/// * `playlist` - The contents of the playlist.
+///
+pub fn playlist_duration(playlist: &str) -> f64 {
+    playlist_segment_durations(playlist).iter().sum()
+}
+
+/// Returns the duration in seconds of each segment of an HLS media playlist, from its `#EXTINF`
+/// tags, in playlist order.
+///
+/// # Arguments
+/// * `playlist` - The contents of the playlist.
+///
+pub fn playlist_segment_durations(playlist: &str) -> Vec<f64> {
+    playlist
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("#EXTINF:"))
+        .filter_map(|extinf| extinf.split(',').next()?.trim().parse::<f64>().ok())
+        .collect()
+}
+
+/// Builds a low-latency HLS media playlist. Every segment is preceded by an `#EXT-X-PART` entry
+/// for each of its partial segments, given as a byte range of the segment, as the segments are
+/// the parts joined in order. Every part starts with a keyframe, so all are marked independent.
+///
+/// # Arguments
+/// * `segments` - The URL of each segment with the duration and size in bytes of its parts, in
+///   playlist order.
+/// * `part_secs` - The target partial segment duration in seconds.
+///
+pub fn build_ll_hls_playlist(segments: &[(String, Vec<(f64, u64)>)], part_secs: f64) -> String {
+    // No part may last longer than the part target, which is rounded up to whole milliseconds
+    let part_target = segments
+        .iter()
+        .flat_map(|(_, parts)| parts.iter().map(|(duration, _)| *duration))
+        .fold(part_secs, f64::max);
+    let part_target = (part_target * 1000.0).ceil() / 1000.0;
+    let target_duration = segments
+        .iter()
+        .map(|(_, parts)| parts.iter().map(|(duration, _)| duration).sum::<f64>())
+        .fold(1.0, f64::max)
+        .ceil() as u64;
+
+    let mut playlist = format!(
+        "#EXTM3U\n#EXT-X-VERSION:6\n#EXT-X-TARGETDURATION:{}\n\
+         #EXT-X-PART-INF:PART-TARGET={:.3}\n#EXT-X-MEDIA-SEQUENCE:0\n\
+         #EXT-X-PLAYLIST-TYPE:VOD\n#EXT-X-INDEPENDENT-SEGMENTS\n",
+        target_duration, part_target
+    );
+    for (url, parts) in segments {
+        let mut offset = 0;
+        for (duration, length) in parts {
+            playlist.push_str(&format!(
+                "#EXT-X-PART:DURATION={:.6},URI=\"{}\",BYTERANGE=\"{}@{}\",INDEPENDENT=YES\n",
+                duration, url, length, offset
+            ));
+            offset += length;
+        }
+        let duration: f64 = parts.iter().map(|(duration, _)| duration).sum();
+        playlist.push_str(&format!("#EXTINF:{:.6},\n{}\n", duration, url));
+    }
+    playlist.push_str("#EXT-X-ENDLIST\n");
+    playlist
+}
+
+/// Joins the partial segments ffmpeg wrote for an "ll-hls" format into segments of its
+/// `segment_secs` and uploads each segment. Each part is a whole MPEG-TS file, so a segment made
+/// of them in order is one too.
+///
+/// # Arguments
+/// * `task_id` - The task whose disk quota the segments count against.
+/// * `prefix` - The path prefix of the HLS files.
+/// * `playlist` - The playlist ffmpeg wrote, listing every part as a segment.
+/// * `part_paths` - The paths of the parts, in playlist order.
+/// * `format` - The format the output was transcoded with.
+///
+/// # Returns
+/// The LL-HLS playlist fetching the uploaded segments from `HLS_CONTENT_URL`, or an error message.
+///
+async fn upload_ll_hls_segments(
+    task_id: &str,
+    prefix: &str,
+    playlist: &str,
+    part_paths: &[String],
+    format: &VideoFormat,
+) -> Result<String, String> {
+    let (segment_secs, part_secs) = ll_hls_durations(format);
+    let parts_per_segment = ((segment_secs as f64 / part_secs).round() as usize).max(1);
+    let part_durations = playlist_segment_durations(playlist);
+    if part_durations.len() != part_paths.len() {
+        return Err(format!(
+            "Packaging LL-HLS failed: the playlist has {} durations for {} parts",
+            part_durations.len(),
+            part_paths.len()
+        ));
+    }
+
+    let mut segments = Vec::new();
+    for (index, (paths, durations)) in part_paths
+        .chunks(parts_per_segment)
+        .zip(part_durations.chunks(parts_per_segment))
+        .enumerate()
+    {
+        let segment_path = format!("{}_segment_{:05}.ts", prefix, index);
+        shared::record_task_file(task_id, &segment_path);
+        let result = match join_parts(paths, &segment_path).and_then(|lengths| {
+            check_disk_quota(task_id)?;
+            Ok(lengths)
+        }) {
+            Ok(lengths) => upload_video(segment_path.as_str(), format.dest.clone())
+                .await
+                .map(|cid| (hls_content_url(&cid, format.dest.as_deref()), lengths))
+                .map_err(|e| format!("Failed to upload HLS segment {}: {}", segment_path, e)),
+            Err(e) => Err(e),
+        };
+        let _ = std::fs::remove_file(&segment_path);
+
+        let (url, lengths) = result?;
+        let parts = durations.iter().copied().zip(lengths).collect();
+        segments.push((url, parts));
+    }
+
+    Ok(build_ll_hls_playlist(&segments, part_secs))
+}
+
+/// Appends the files at `part_paths` to a new file at `segment_path`, in order.
+///
+/// # Returns
+/// The size in bytes of each part, or an error message.
+///
+fn join_parts(part_paths: &[String], segment_path: &str) -> Result<Vec<u64>, String> {
+    let mut segment = std::fs::File::create(segment_path)
+        .map_err(|e| format!("Failed to create HLS segment {}: {}", segment_path, e))?;
+    part_paths
+        .iter()
+        .map(|part_path| {
+            let part = std::fs::read(part_path)
+                .map_err(|e| format!("Failed to read HLS part {}: {}", part_path, e))?;
+            segment
+                .write_all(&part)
+                .map_err(|e| format!("Failed to write HLS segment {}: {}", segment_path, e))?;
+            Ok(part.len() as u64)
+        })
+        .collect()
+}
+
+/// Builds an `#EXT-X-I-FRAMES-ONLY` playlist, which players use for fast scrubbing, with an
+/// `#EXT-X-BYTERANGE` entry for every keyframe of the segments. Each keyframe lasts until the
+/// next, and the last until `end_time`.
+///
+/// # Arguments
+/// * `segments` - The URL of each segment with its keyframes, in playlist order.
+/// * `end_time` - The time the last segment ends, on the same timeline as the keyframes.
+///
+pub fn build_iframe_playlist(segments: &[(String, Vec<Keyframe>)], end_time: f64) -> String {
+    let keyframes: Vec<(&str, &Keyframe)> = segments
+        .iter()
+        .flat_map(|(url, keyframes)| keyframes.iter().map(move |k| (url.as_str(), k)))
+        .collect();
+    let durations: Vec<f64> = keyframes
+        .iter()
+        .enumerate()
+        .map(|(i, (_, keyframe))| {
+            let next_time = keyframes.get(i + 1).map_or(end_time, |(_, next)| next.time);
+            (next_time - keyframe.time).max(0.0)
+        })
+        .collect();
+    let target_duration = durations.iter().cloned().fold(1.0, f64::max).ceil() as u64;
+
+    let mut playlist = format!(
+        "#EXTM3U\n#EXT-X-VERSION:4\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:0\n\
+         #EXT-X-PLAYLIST-TYPE:VOD\n#EXT-X-I-FRAMES-ONLY\n",
+        target_duration
+    );
+    for ((url, keyframe), duration) in keyframes.iter().zip(&durations) {
+        playlist.push_str(&format!(
+            "#EXTINF:{:.6},\n#EXT-X-BYTERANGE:{}@{}\n{}\n",
+            duration, keyframe.length, keyframe.offset, url
+        ));
+    }
+    playlist.push_str("#EXT-X-ENDLIST\n");
+    playlist
+}
+
+/// Encrypts an MPEG-TS HLS segment in place with SAMPLE-AES.
+fn encrypt_sample_aes_segment(
+    segment_path: &str,
+    key: &[u8; 16],
+    iv: &[u8; 16],
+) -> Result<(), String> {
+    let segment = std::fs::read(segment_path)
+        .map_err(|e| format!("Failed to read HLS segment {}: {}", segment_path, e))?;
+    let encrypted = sample_aes::encrypt_segment(&segment, key, iv)
+        .map_err(|e| format!("Failed to encrypt HLS segment {}: {}", segment_path, e))?;
+    std::fs::write(segment_path, encrypted)
+        .map_err(|e| format!("Failed to write HLS segment {}: {}", segment_path, e))
+}
+
+/// Uploads an HLS segment, first probing its keyframes if they are needed for an I-frame
+/// playlist.
+///
+/// # Returns
+/// The URL of the uploaded segment and its keyframes, or an error message.
+///
+async fn upload_hls_segment(
+    segment_path: &str,
+    with_iframes: bool,
+    dest: Option<String>,
+) -> Result<(String, Vec<Keyframe>), String> {
+    let keyframes = if with_iframes {
+        probe_keyframes(segment_path)?
+    } else {
+        Vec::new()
+    };
+    let cid = upload_video(segment_path, dest.clone())
+        .await
+        .map_err(|e| format!("Failed to upload HLS segment {}: {}", segment_path, e))?;
+    Ok((hls_content_url(&cid, dest.as_deref()), keyframes))
+}
+
+/// Re-packages a transcoded output as HLS, for formats with `hls_encryption`, `iframe_playlist` or
+/// "ll-hls" packaging. With "ll-hls" ffmpeg cuts the output into its partial segments, which are
+/// joined into segments by `upload_ll_hls_segments`. With `hls_encryption` the segments are
+/// encrypted with AES-128 by ffmpeg, or with SAMPLE-AES once ffmpeg has written them, using a newly
+/// generated key and IV. With "upload" key delivery the key is uploaded first and the playlist's
+/// `#EXT-X-KEY` points at it; with "key_uri" delivery it points at the format's `key_uri` and the
+/// key is returned so the client can serve it. Each segment is uploaded, the playlist is rewritten to fetch the
+/// segments from `HLS_CONTENT_URL` and uploaded last, preceded by the I-frame playlist with
+/// `iframe_playlist`. The transcoded output itself is never uploaded.
+///
+/// # Arguments
+/// * `task_id` - The task whose disk quota the segments count against.
+/// * `output_path` - The path to the transcoded output.
+/// * `file_name` - The name of the output file without its extension.
+/// * `output_dir` - The directory the output was written to.
+/// * `format` - The format the output was transcoded with.
+///
+/// # Returns
+/// The CIDs of the playlists and the key's CID or the key itself, or an error message.
+///
+async fn package_hls(
+    task_id: &str,
+    output_path: &str,
+    file_name: &str,
+    output_dir: &str,
+    format: &VideoFormat,
+) -> Result<PackagedHls, String> {
+    let prefix = format!("{}{}_hls", output_dir, file_name);
+    let key_path = format!("{}.key", prefix);
+    let key_info_path = format!("{}.keyinfo", prefix);
+    let playlist_path = format!("{}.m3u8", prefix);
+    let iframe_playlist_path = format!("{}_iframes.m3u8", prefix);
+    for path in [&key_path, &key_info_path, &playlist_path, &iframe_playlist_path] {
+        shared::record_task_file(task_id, path);
+    }
+    let result = segment_and_upload_hls(task_id, output_path, &prefix, format).await;
+
+    for path in [
+        &key_path,
+        &key_info_path,
+        &playlist_path,
+        &iframe_playlist_path,
+    ] {
+        let _ = std::fs::remove_file(path);
+    }
+    result
+}
+
+/// Does the work of `package_hls`, leaving its key, key info and playlist files under `prefix` for
+/// it to remove.
+async fn segment_and_upload_hls(
+    task_id: &str,
+    output_path: &str,
+    prefix: &str,
+    format: &VideoFormat,
+) -> Result<PackagedHls, String> {
+    let dest = format.dest.clone();
+    let mut packaged_hls = PackagedHls::default();
+    // Key, IV and key URI of segments encrypted with SAMPLE-AES once ffmpeg has written them
+    let mut sample_aes = None;
+    let mut ffmpeg = Command::new(FFMPEG_PATH.as_str());
+    ffmpeg
+        .args(["-hide_banner", "-v", "error", "-i", output_path])
+        .args(["-map", "0", "-c", "copy", "-f", "hls", "-hls_time"]);
+
+    if let Some(hls_encryption) = &format.hls_encryption {
+        ffmpeg.arg(
+            hls_encryption
+                .segment_secs
+                .unwrap_or(DEFAULT_HLS_SEGMENT_SECS)
+                .to_string(),
+        );
+
+        let key: [u8; 16] = rand::random();
+        let iv: [u8; 16] = rand::random();
+        let key_path = format!("{}.key", prefix);
+        std::fs::write(&key_path, key).map_err(|e| format!("Failed to write HLS key: {}", e))?;
+
+        let key_uri = match hls_encryption.key_uri.as_deref() {
+            Some(key_uri) => {
+                packaged_hls.key = hex::encode(key);
+                key_uri.to_string()
+            }
+            None => {
+                packaged_hls.key_cid = upload_video(key_path.as_str(), dest.clone())
+                    .await
+                    .map_err(|e| format!("Failed to upload HLS key: {}", e))?;
+                hls_content_url(&packaged_hls.key_cid, dest.as_deref())
+            }
+        };
+
+        if hls_encryption.method.as_deref() == Some("sample-aes") {
+            sample_aes = Some((key, iv, key_uri));
+        } else {
+            // ffmpeg reads the key URI, key file and IV from the key info file, one per line
+            let key_info_path = format!("{}.keyinfo", prefix);
+            std::fs::write(
+                &key_info_path,
+                format!("{}\n{}\n{}\n", key_uri, key_path, hex::encode(iv)),
+            )
+            .map_err(|e| format!("Failed to write HLS key info: {}", e))?;
+            ffmpeg.arg("-hls_key_info_file").arg(&key_info_path);
+        }
+    } else if is_ll_hls(format) {
+        // Each part is written as a segment of its own, then joined by `upload_ll_hls_segments`
+        let (_, part_secs) = ll_hls_durations(format);
+        ffmpeg.arg(part_secs.to_string());
+    } else {
+        ffmpeg.arg(DEFAULT_HLS_SEGMENT_SECS.to_string());
+    }
+
+    let playlist_path = format!("{}.m3u8", prefix);
+    let output = ffmpeg
+        .args(["-hls_playlist_type", "vod", "-hls_segment_filename"])
+        .arg(format!("{}_%05d.ts", prefix))
+        .args(["-y", playlist_path.as_str()])
+        .output()
+        .map_err(|e| format!("Failed to execute ffmpeg to package HLS: {}", e))?;
+
+    let mut playlist = std::fs::read_to_string(&playlist_path).unwrap_or_default();
+    if let Some((_, iv, key_uri)) = &sample_aes {
+        playlist = add_sample_aes_key(&playlist, key_uri, iv);
+    }
+    let segment_dir = Path::new(prefix)
+        .parent()
+        .map(|dir| dir.to_path_buf())
+        .unwrap_or_default();
+    let segment_paths: Vec<String> = playlist_segments(&playlist)
+        .iter()
+        .map(|segment| segment_dir.join(segment).to_string_lossy().to_string())
+        .collect();
+    for segment_path in &segment_paths {
+        shared::record_task_file(task_id, segment_path);
+    }
+
+    let mut upload_error = if !output.status.success() {
+        Some(format!(
+            "Packaging HLS failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    } else if let Err(e) = check_disk_quota(task_id) {
+        Some(e)
+    } else if let Some(hls_encryption) = &format.hls_encryption {
+        check_playlist_key(&playlist, hls_encryption.method.as_deref().unwrap_or("aes-128")).err()
+    } else {
+        None
+    };
+    if is_ll_hls(format) {
+        let result = match upload_error {
+            Some(e) => Err(e),
+            None => {
+                upload_ll_hls_segments(task_id, prefix, &playlist, &segment_paths, format).await
+            }
+        };
+        for segment_path in &segment_paths {
+            let _ = std::fs::remove_file(segment_path);
+        }
+        std::fs::write(&playlist_path, result?)
+            .map_err(|e| format!("Failed to write HLS playlist: {}", e))?;
+        packaged_hls.playlist_cid = upload_video(playlist_path.as_str(), dest)
+            .await
+            .map_err(|e| format!("Failed to upload HLS playlist: {}", e))?;
+        return Ok(packaged_hls);
+    }
+
+    let with_iframes = format.iframe_playlist.unwrap_or(false);
+    let mut iframe_segments = Vec::new();
+    for segment_path in &segment_paths {
+        if upload_error.is_none() {
+            if let Some((key, iv, _)) = &sample_aes {
+                upload_error = encrypt_sample_aes_segment(segment_path, key, iv).err();
+            }
+        }
+        if upload_error.is_none() {
+            match upload_hls_segment(segment_path, with_iframes, dest.clone()).await {
+                Ok((url, keyframes)) => iframe_segments.push((url, keyframes)),
+                Err(e) => upload_error = Some(e),
+            }
+        }
+        let _ = std::fs::remove_file(segment_path);
+    }
+    if let Some(e) = upload_error {
+        return Err(e);
+    }
+    let urls: Vec<String> = iframe_segments.iter().map(|(url, _)| url.clone()).collect();
+    let rewritten = replace_playlist_segments(&playlist, &urls);
+
+    if with_iframes {
+        let start_time = iframe_segments
+            .iter()
+            .find_map(|(_, keyframes)| keyframes.first())
+            .map_or(0.0, |keyframe| keyframe.time);
+        let iframe_playlist =
+            build_iframe_playlist(&iframe_segments, start_time + playlist_duration(&playlist));
+        let iframe_playlist_path = format!("{}_iframes.m3u8", prefix);
+        std::fs::write(&iframe_playlist_path, iframe_playlist)
+            .map_err(|e| format!("Failed to write HLS I-frame playlist: {}", e))?;
+        packaged_hls.iframe_playlist_cid =
+            upload_video(iframe_playlist_path.as_str(), dest.clone())
+                .await
+                .map_err(|e| format!("Failed to upload HLS I-frame playlist: {}", e))?;
+    }
+
+    std::fs::write(&playlist_path, rewritten)
+        .map_err(|e| format!("Failed to write HLS playlist: {}", e))?;
+    packaged_hls.playlist_cid = upload_video(playlist_path.as_str(), dest)
+        .await
+        .map_err(|e| format!("Failed to upload HLS playlist: {}", e))?;
+    Ok(packaged_hls)
+}
+
+/// Gets video duration in seconds using `ffprobe`.
+///
+/// # Arguments
+/// * `file_path`: Path to the video file.
+///
+/// # Returns:
+/// `Result<f64, String>` - Duration in seconds or error message.
+///
+fn get_video_duration(file_path: &str) -> Result<f64, String> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-show_entries",
+            "format=duration",
+            "-of",
+            "default=noprint_wrappers=1:nokey=1",
+            file_path,
+        ])
+        .output()
+        .expect("failed to execute ffprobe");
+
+    if output.status.success() {
+        let duration_str = String::from_utf8(output.stdout).unwrap();
+        duration_str
+            .trim()
+            .parse::<f64>()
+            .map_err(|e| e.to_string())
+    } else {
+        Err(String::from("Failed to retrieve video duration"))
+    }
+}
+
+/// Parses ffmpeg progress output to calculate and return the transcoding progress as a percentage.
+/// This function searches for time stamps in the ffmpeg output and calculates the progress based
+/// on the total duration of the video. If the total duration is not positive, it returns 0 to
+/// prevent division by zero errors.
+///
+/// # Arguments
+/// * `line` - A string slice containing a line of ffmpeg output.
+/// * `total_duration` - The total duration of the video in seconds.
+///
+/// # Returns
+/// An `Option<i32>` representing the transcoding progress percentage, or `None` if the progress
+/// cannot be determined from the given line.
+///
+fn parse_progress(line: &str, total_duration: f64) -> Option<i32> {
+    if total_duration <= 0.0 {
+        return Some(0); // Prevent division by zero
+    }
+
+    let re = Regex::new(r"time=(\d+):(\d+):(\d+\.\d+)").unwrap();
+    if let Some(caps) = re.captures(line) {
+        let hours = caps.get(1).unwrap().as_str().parse::<f64>().unwrap_or(0.0);
+        let minutes = caps.get(2).unwrap().as_str().parse::<f64>().unwrap_or(0.0);
+        let seconds = caps.get(3).unwrap().as_str().parse::<f64>().unwrap_or(0.0);
+        let current_time_seconds = hours * 3600.0 + minutes * 60.0 + seconds;
+        let progress = ((current_time_seconds / total_duration) * 100.0).round() as i32;
+        return Some(progress);
+    }
+
+    None
+}
+
+/// Counts the ffmpeg processes running on one kind of hardware and makes further encodes wait while
+/// `limit` are running.
+struct EncodeSessions {
+    limit: u32,
+    active: Mutex<u32>,
+    released: Condvar,
+}
+
+/// An encode session, released when dropped.
+struct EncodeSession<'a> {
+    sessions: &'a EncodeSessions,
+}
+
+impl EncodeSessions {
+    fn new(limit: u32) -> Self {
+        EncodeSessions {
+            limit,
+            active: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Waits for a free session and takes it. Gives up if the task is cancelled while waiting.
+    ///
+    /// # Arguments
+    /// * `task_id` - The task the encode belongs to.
+    ///
+    fn acquire(&self, task_id: &str) -> Result<EncodeSession<'_>, Status> {
+        let mut active = self.active.lock().unwrap();
+        while self.limit > 0 && *active >= self.limit {
+            if shared::is_task_cancelled(task_id) {
+                return Err(Status::cancelled(format!("Task {} was cancelled", task_id)));
+            }
+            active = self
+                .released
+                .wait_timeout(active, std::time::Duration::from_secs(1))
+                .unwrap()
+                .0;
+        }
+        *active += 1;
+
+        Ok(EncodeSession { sessions: self })
+    }
+}
+
+impl Drop for EncodeSession<'_> {
+    fn drop(&mut self) {
+        *self.sessions.active.lock().unwrap() -= 1;
+        self.sessions.released.notify_one();
+    }
+}
+
+/// Takes an encode session on the GPU or CPU, waiting while `MAX_GPU_SESSIONS` or
+/// `MAX_CPU_SESSIONS` encodes of that kind are running, so GPU encodes do not fail for lack of
+/// NVENC sessions and CPU encodes do not oversubscribe the cores.
+///
+/// # Arguments
+/// * `task_id` - The task the encode belongs to.
+/// * `is_gpu` - Whether the encode runs on the GPU.
+///
+fn acquire_encode_session(task_id: &str, is_gpu: bool) -> Result<EncodeSession<'static>, Status> {
+    let sessions: &'static EncodeSessions = if is_gpu { &GPU_SESSIONS } else { &CPU_SESSIONS };
+    sessions.acquire(task_id)
+}
+
+/// Spawns the given ffmpeg command. A missing binary is reported as an actionable error naming the
+/// configured path rather than a generic I/O error.
+///
+/// # Arguments
+/// * `cmd` - The fully built ffmpeg command.
+///
+/// # Returns
+/// The spawned child process, or a `Status` describing why it could not be started.
+///
+fn spawn_ffmpeg(cmd: &mut Command) -> Result<Child, Status> {
+    cmd.spawn().map_err(|e| {
+        let message = if e.kind() == std::io::ErrorKind::NotFound {
+            format!(
+                "ffmpeg binary not found at {}; set FFMPEG_PATH",
+                cmd.get_program().to_string_lossy()
+            )
+        } else {
+            format!("Failed to start ffmpeg command: {}", e)
+        };
+        eprintln!("{}", message);
+        Status::new(Code::FailedPrecondition, message)
+    })
+}
+
+/// Marker in the names of outputs that are still being written. Outputs are renamed to their final
+/// name only once complete, so a crash never leaves a partial file under the final name.
+const PARTIAL_OUTPUT_MARKER: &str = ".tmp.";
+
+/// Returns the path ffmpeg writes an output to before it is renamed to `{file_name}_ue.{ext}`. The
+/// extension is kept last so ffmpeg can still infer the container from it.
+fn partial_output_path(output_dir: &str, file_name: &str, ext: &str) -> String {
+    format!(
+        "{}{}_ue{}{}",
+        output_dir, file_name, PARTIAL_OUTPUT_MARKER, ext
+    )
+}
+
+/// Returns the ffmpeg arguments for a format's main output: its partial file, or stdout when the
+/// output is streamed to storage.
+///
+/// # Arguments
+/// * `output_dir` - The directory the transcoded file is written to.
+/// * `file_name` - The name of the transcoded file, without extension.
+/// * `format` - The desired output format.
+///
+fn output_args(output_dir: &str, file_name: &str, format: &VideoFormat) -> Vec<String> {
+    if is_streamed(format) {
+        // There is no file name for ffmpeg to pick the muxer from
+        let muxer = streaming_muxer(format).unwrap_or(format.ext.as_str());
+        vec![
+            "-f".to_string(),
+            muxer.to_string(),
+            "-y".to_string(),
+            "pipe:1".to_string(),
+        ]
+    } else {
+        vec![
+            "-y".to_string(),
+            partial_output_path(output_dir, file_name, &format.ext),
+        ]
+    }
+}
+
+/// Reads a streamed ffmpeg output for its upload, computing the output's blake3 hash and size on
+/// the way. The end of the stream is held back until ffmpeg has exited, so the upload only
+/// completes if ffmpeg succeeded; otherwise the read fails and the upload is aborted.
+struct StreamedOutputReader<R> {
+    inner: R,
+    hasher: Arc<Mutex<blake3::Hasher>>,
+    size: Arc<AtomicU64>,
+    ffmpeg_succeeded: mpsc::Receiver<bool>,
+    finished: Option<bool>,
+}
+
+impl<R: Read> Read for StreamedOutputReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        if count > 0 {
+            self.hasher.lock().unwrap().update(&buf[..count]);
+            self.size.fetch_add(count as u64, Ordering::Relaxed);
+            return Ok(count);
+        }
+
+        // A killed or failed ffmpeg closes its stdout too, which must not complete the upload
+        let ffmpeg_succeeded = *self
+            .finished
+            .get_or_insert_with(|| self.ffmpeg_succeeded.recv().unwrap_or(false));
+        if ffmpeg_succeeded {
+            Ok(0)
+        } else {
+            Err(io::Error::other(
+                "ffmpeg failed, aborting the streamed upload",
+            ))
+        }
+    }
+}
+
+/// The result of a format whose output was uploaded while it was encoded.
+struct StreamedOutput {
+    cid: String,
+    blake3: String,
+    size: u64,
+}
+
+/// Returns the file name, without the `_ue` suffix and extension, of the audio-only output a video
+/// format produces with `also_extract_audio`.
+fn audio_extract_file_name(file_name: &str) -> String {
+    format!("{}_audio", file_name)
+}
+
+/// Returns the extension of an `also_extract_audio` output: its `ext`, or the usual container for
+/// its codec.
+fn audio_extract_ext(audio_extract: &AudioExtract) -> String {
+    if let Some(ext) = audio_extract.ext.as_deref() {
+        return ext.to_string();
+    }
+
+    match audio_extract.codec.as_str() {
+        "libopus" | "opus" => "opus",
+        "libvorbis" | "vorbis" => "ogg",
+        "libmp3lame" | "mp3" => "mp3",
+        "flac" => "flac",
+        _ => "m4a",
+    }
+    .to_string()
+}
+
+/// Adds a second, audio-only output to a video command for a format's `also_extract_audio`, so the
+/// audio is extracted in the same ffmpeg run as the video. Must be called after the video output.
+///
+/// # Arguments
+/// * `cmd` - The ffmpeg command being built.
+/// * `file_name` - The name of the video output, without extension.
+/// * `output_dir` - The directory outputs are written to.
+/// * `format` - The desired output format.
+///
+fn add_audio_extract_output(
+    cmd: &mut Command,
+    file_name: &str,
+    output_dir: &str,
+    format: &VideoFormat,
+) {
+    let audio_extract = match &format.also_extract_audio {
+        Some(audio_extract) => audio_extract,
+        None => return,
+    };
+
+    let index = format.audio_stream_index.unwrap_or(0);
+    add_arg(cmd, "-map", Some(&format!("0:a:{}?", index)));
+    cmd.arg("-vn");
+    add_arg(cmd, "-c:a", Some(&audio_extract.codec));
+    add_arg(cmd, "-b:a", audio_extract.bitrate.as_deref());
+    cmd.args(clip_args(format, false));
+    cmd.args(timestamp_args(format));
+    add_arg(
+        cmd,
+        "-y",
+        Some(&partial_output_path(
+            output_dir,
+            &audio_extract_file_name(file_name),
+            &audio_extract_ext(audio_extract),
+        )),
+    );
+}
+
+/// Renames a completed output from its partial name to its final name.
+fn finalize_output(partial_path: &str, final_path: &str) -> Result<(), Status> {
+    std::fs::rename(partial_path, final_path).map_err(|e| {
+        Status::new(
+            Code::Internal,
+            format!("Failed to rename {} to {}: {}", partial_path, final_path, e),
+        )
+    })
+}
+
+/// Removes outputs left partially written by a transcode that was interrupted, e.g. by a crash,
+/// from `PATH_TO_TRANSCODED_FILE` and, when enabled, `TMPFS_PATH`, including all of their
+/// subdirectories. Called at startup, before any transcodes run.
+///
+pub fn remove_partial_outputs() {
+    let mut dirs = vec![PATH_TO_TRANSCODED_FILE.to_string()];
+    if *USE_TMPFS {
+        dirs.push(TMPFS_PATH.to_string());
+    }
+
+    for dir in dirs {
+        let entries = match list_files_recursive(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                eprintln!("Failed to read directory {}: {}", dir, e);
+                continue;
+            }
+        };
+
+        for entry in entries {
+            let path = entry.path();
+            let is_partial = path.file_name().is_some_and(|name| {
+                name.to_string_lossy().contains(PARTIAL_OUTPUT_MARKER)
+            });
+
+            if is_partial && path.is_file() {
+                match std::fs::remove_file(&path) {
+                    Ok(()) => println!("Removed partial output {}", path.display()),
+                    Err(e) => {
+                        eprintln!("Failed to remove partial output {}: {}", path.display(), e)
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Builds the ffmpeg command to transcode a video file based on the specified parameters.
+/// This function supports GPU acceleration and handles various video formats.
+///
+/// # Arguments
+/// * `file_path` - The path to the input video file to be transcoded.
+/// * `file_name` - The name of the input video file.
+/// * `output_dir` - The directory the transcoded file is written to.
+/// * `is_gpu` - A boolean flag indicating whether to use GPU acceleration for transcoding.
+/// * `format` - The desired output video format.
+///
+/// # Returns
+/// A `Result<Command, Status>` with the command ready to be spawned, or an error if the format
+/// does not specify a codec.
+///
+fn build_ffmpeg_command(
+    file_path: &str,
+    file_name: &str,
+    output_dir: &str,
+    is_gpu: bool,
+    format: &VideoFormat,
+    pass: Option<u8>,
+) -> Result<Command, Status> {
+    let mut cmd = Command::new(FFMPEG_PATH.as_str());
+    // Each line is prefixed with its level, e.g. "[warning]", so warnings can be picked out
+    cmd.arg("-loglevel")
+        .arg(format!("level+{}", FFMPEG_LOGLEVEL.as_str()));
+    cmd.arg("-progress").arg("pipe:2");
+    cmd.arg("-stats_period").arg("1");
+
+    if is_gpu {
+        println!("GPU transcoding is being executed with vcodec: {:?}", format.vcodec);
+
+        if let Some(file_path) = Some(file_path) {
+            add_input(&mut cmd, file_path, format);
+        }
+        if let Some(vcodec) = format.vcodec.as_deref() {
+            add_arg(&mut cmd, "-c:v", Some(vcodec));
+        }
+        if let Some(b_v) = format.b_v.as_deref() {
+            add_arg(&mut cmd, "-b:v", Some(b_v));
+        }
+        if let Some(c_a) = format.c_a.as_deref() {
+            add_arg(&mut cmd, "-c:a", Some(c_a));
+        }
+        if let Some(b_a) = format.b_a.as_deref() {
+            add_arg(&mut cmd, "-b:a", Some(b_a));
+        }
+        if let Some(ch) = format.ch {
+            add_arg(&mut cmd, "-ac", Some(&ch.to_string()));
+        }
+        if let Some(ar) = format.ar.as_deref() {
+            add_arg(&mut cmd, "-ar", Some(ar));
+        }
+        if let Some(vf) = format.vf.as_deref() {
+            add_arg(&mut cmd, "-vf", Some(vf));
+        }
+        if let Some(ref minrate) = format.minrate {
+            cmd.args(["-minrate", minrate]);
+        }
+        if let Some(ref maxrate) = format.maxrate {
+            cmd.args(["-maxrate", maxrate]);
+        }
+        if let Some(ref bufsize) = format.bufsize {
+            cmd.args(["-bufsize", bufsize]);
+        }
+        add_format_options(&mut cmd, format, true);
+        cmd.args(output_args(output_dir, file_name, format));
+        add_audio_extract_output(&mut cmd, file_name, output_dir, format);
+
+        let args: Vec<String> = cmd
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        println!("ffmpeg {}", args.join(" "));
+    } else {
+        if let Some(vcodec) = &format.vcodec {
+            if !vcodec.is_empty() {
+
+                println!("CPU transcoding is being executed with vcodec: {:?}", format.vcodec);
+
+                add_arg(&mut cmd, "-cpu-used", Some("4"));
+
+                if let Some(file_path) = Some(file_path) {
+                    add_input(&mut cmd, file_path, format);
+                }
+                if let Some(vcodec) = format.vcodec.as_deref() {
+                    add_arg(&mut cmd, "-c:v", Some(vcodec));
+                }
+                if let Some(b_v) = format.b_v.as_deref() {
+                    add_arg(&mut cmd, "-b:v", Some(b_v));
+                }
+                if let Some(c_a) = format.c_a.as_deref() {
+                    add_arg(&mut cmd, "-c:a", Some(c_a));
+                }
+                if let Some(b_a) = format.b_a.as_deref() {
+                    add_arg(&mut cmd, "-b:a", Some(b_a));
+                }
+                if let Some(ch) = format.ch {
+                    add_arg(&mut cmd, "-ac", Some(&ch.to_string()));
+                }
+                if let Some(ar) = format.ar.as_deref() {
+                    add_arg(&mut cmd, "-ar", Some(ar));
+                }
+                if let Some(vf) = format.vf.as_deref() {
+                    add_arg(&mut cmd, "-vf", Some(vf));
+                }
+                if let Some(ref minrate) = format.minrate {
+                    cmd.args(["-minrate", minrate]);
+                }
+                if let Some(ref maxrate) = format.maxrate {
+                    cmd.args(["-maxrate", maxrate]);
+                }
                 if let Some(ref bufsize) = format.bufsize {
                     cmd.args(["-bufsize", bufsize]);
                 }
-                cmd.args([
-                    "-y",
-                    format!(
-                        "{}{}_ue.{}",
-                        *PATH_TO_TRANSCODED_FILE, file_name, format.ext
-                    )
-                    .as_str(),
-                ]);
-            
-                // Convert to Vec<String> instead of Vec<Cow<'_, str>>
-                let args: Vec<String> = cmd.get_args()
+                add_format_options(&mut cmd, format, true);
+                if let Some(pass) = pass {
+                    cmd.args(["-pass", &pass.to_string()]);
+                    cmd.args(["-passlogfile", &pass_log_prefix(output_dir, file_name)]);
+                }
+                if pass == Some(1) {
+                    // The first pass only writes the rate-control log
+                    cmd.args(["-an", "-f", "null", "-y", "/dev/null"]);
+                } else {
+                    cmd.args(output_args(output_dir, file_name, format));
+                    add_audio_extract_output(&mut cmd, file_name, output_dir, format);
+                }
+
+                let args: Vec<String> = cmd
+                    .get_args()
                     .map(|arg| arg.to_string_lossy().into_owned())
                     .collect();
                 println!("ffmpeg {}", args.join(" "));
-                
-                // Now String implements AsRef<OsStr>
-                for arg in args {
-                    cmd.arg(arg);
-                }
             } else {
                 return Err(Status::new(
                     Code::InvalidArgument,
@@ -295,8 +4977,9 @@ fn run_ffmpeg(
             }
         } else if let Some(acodec) = &format.acodec {
             if !acodec.is_empty() {
-                add_arg(&mut cmd, "-i", Some(file_path));
+                add_input(&mut cmd, file_path, format);
                 add_arg(&mut cmd, "-acodec", format.acodec.as_deref());
+                add_arg(&mut cmd, "-b:a", format.b_a.as_deref());
                 if let Some(ch) = format.ch {
                     add_arg(&mut cmd, "-ac", Some(&ch.to_string()));
                 }
@@ -308,37 +4991,222 @@ fn run_ffmpeg(
                         Some(&compression_level.to_string()),
                     );
                 }
-                add_arg(
-                    &mut cmd,
-                    "-y",
-                    Some(&format!(
-                        "{}{}_ue.{}",
-                        *PATH_TO_TRANSCODED_FILE, file_name, format.ext
-                    )),
-                );
+                add_format_options(&mut cmd, format, false);
+                cmd.args(output_args(output_dir, file_name, format));
             } else {
                 return Err(Status::new(
                     Code::InvalidArgument,
                     "No audio codec specified",
                 ));
             }
-        } else {
-            return Err(Status::new(Code::InvalidArgument, "No codec specified"));
+        } else {
+            return Err(Status::new(Code::InvalidArgument, "No codec specified"));
+        }
+    }
+
+    Ok(cmd)
+}
+
+/// Extracts the message of a warning from a line of ffmpeg output logged with the `level` flag,
+/// such as "[mov,mp4 @ 0x55d0] [warning] Non-monotonic DTS; ...". The address of the logging
+/// context is dropped, so repeats of the same warning compare equal.
+///
+/// # Arguments
+/// * `line` - A line of ffmpeg's stderr.
+///
+/// # Returns
+/// The warning, e.g. "[mov,mp4] Non-monotonic DTS; ...", or `None` if the line is not a warning.
+///
+fn parse_ffmpeg_warning(line: &str) -> Option<String> {
+    let (context, message) = line.split_once("[warning] ")?;
+    let context = Regex::new(r" @ 0x[0-9a-fA-F]+")
+        .unwrap()
+        .replace_all(context, "");
+    let warning = format!("{}{}", context, message.trim());
+    Some(warning.trim().to_string())
+}
+
+/// The full stderr of a rendition's ffmpeg passes, saved for a format with `save_encode_log`. Once
+/// half of `MAX_ENCODE_LOG_BYTES` has been written, only the latest lines, up to the other half,
+/// are kept and written by `finish`, so the log keeps both how the encode started and how it
+/// ended.
+struct EncodeLog {
+    file: std::fs::File,
+    written: u64,
+    tail: std::collections::VecDeque<String>,
+    tail_bytes: u64,
+    omitted_lines: u64,
+}
+
+impl EncodeLog {
+    fn create(path: &str) -> io::Result<Self> {
+        Ok(EncodeLog {
+            file: std::fs::File::create(path)?,
+            written: 0,
+            tail: std::collections::VecDeque::new(),
+            tail_bytes: 0,
+            omitted_lines: 0,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) {
+        let half = *MAX_ENCODE_LOG_BYTES / 2;
+        let line_bytes = line.len() as u64 + 1;
+        if self.written + line_bytes <= half && self.tail.is_empty() {
+            if writeln!(self.file, "{}", line).is_ok() {
+                self.written += line_bytes;
+            }
+            return;
+        }
+
+        self.tail.push_back(line.to_string());
+        self.tail_bytes += line_bytes;
+        while self.tail_bytes > half {
+            match self.tail.pop_front() {
+                Some(dropped) => {
+                    self.tail_bytes -= dropped.len() as u64 + 1;
+                    self.omitted_lines += 1;
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        if self.omitted_lines > 0 {
+            let _ = writeln!(
+                self.file,
+                "[... {} lines omitted, MAX_ENCODE_LOG_BYTES is {} ...]",
+                self.omitted_lines, *MAX_ENCODE_LOG_BYTES
+            );
+        }
+        for line in self.tail.drain(..) {
+            let _ = writeln!(self.file, "{}", line);
         }
+        self.tail_bytes = 0;
+        self.omitted_lines = 0;
+        let _ = self.file.flush();
     }
+}
+
+/// Returns the path of the encode log of a rendition with `save_encode_log`.
+fn encode_log_path(output_dir: &str, file_name: &str) -> String {
+    format!("{}{}_encode.log", output_dir, file_name)
+}
+
+/// Uploads the encode log of a rendition with `save_encode_log` to the same storage network as
+/// the rendition, then removes it from disk.
+///
+/// # Arguments
+/// * `file_name` - The name of the output file without its extension.
+/// * `output_dir` - The directory the output was written to.
+/// * `format` - The format the output was transcoded with.
+///
+/// # Returns
+/// The log's CID prefixed with its storage network, or an error message.
+///
+async fn upload_encode_log(
+    file_name: &str,
+    output_dir: &str,
+    format: &VideoFormat,
+) -> Result<String, String> {
+    let log_path = encode_log_path(output_dir, file_name);
+    let result = upload_video(log_path.as_str(), format.dest.clone())
+        .await
+        .map_err(|e| format!("Failed to upload encode log {}: {}", log_path, e));
+    let _ = std::fs::remove_file(&log_path);
+
+    let cid = result?;
+    Ok(match format.dest.as_deref() {
+        Some("ipfs") => format!("ipfs://{}", cid),
+        _ => format!("s5://{}", cid),
+    })
+}
 
-    cmd.stderr(Stdio::piped()).stdout(Stdio::null());
+/// Returns the error of a rendition aborted because its task exceeded `MAX_TASK_DISK_BYTES`.
+fn disk_quota_exceeded_status(task_id: &str) -> Status {
+    TranscodeErrorCode::DiskQuotaExceeded.status(
+        Code::ResourceExhausted,
+        format!(
+            "Task {} exceeded its disk quota of {} bytes (MAX_TASK_DISK_BYTES)",
+            task_id,
+            *shared::MAX_TASK_DISK_BYTES
+        ),
+    )
+}
 
-    let mut child = cmd.spawn().expect("failed to start ffmpeg command");
+/// Checks the task's disk quota once files other than ffmpeg's monitored outputs have been
+/// written, such as HLS and scene segments or an encrypted copy.
+fn check_disk_quota(task_id: &str) -> Result<(), String> {
+    if shared::check_task_disk_quota(task_id) {
+        Ok(())
+    } else {
+        Err(disk_quota_exceeded_status(task_id).message().to_string())
+    }
+}
 
+/// Reads ffmpeg's progress from its stderr until it exits, reporting it as the format's progress
+/// scaled into `progress_start..progress_end`, and kills it if the task is cancelled or its files,
+/// including those ffmpeg is writing, take it over `MAX_TASK_DISK_BYTES`. Distinct warnings are added to `warnings`, up to
+/// `MAX_FFMPEG_WARNINGS`. Every line is also written to `encode_log` if there is one.
+///
+/// # Arguments
+/// * `task_id` - A unique identifier for the transcoding task.
+/// * `format_index` - The index of the format being transcoded.
+/// * `child` - The running ffmpeg process.
+/// * `total_duration` - The total duration of the video file in seconds.
+/// * `progress_start` - The progress reported when ffmpeg starts.
+/// * `progress_end` - The progress reported when ffmpeg finishes.
+/// * `warnings` - The warnings of the rendition so far.
+/// * `encode_log` - The rendition's encode log, for a format with `save_encode_log`.
+///
+/// # Returns
+/// The exit status of ffmpeg.
+///
+#[allow(clippy::too_many_arguments)]
+fn monitor_ffmpeg(
+    task_id: &str,
+    format_index: usize,
+    child: &mut Child,
+    total_duration: f64,
+    progress_start: i32,
+    progress_end: i32,
+    warnings: &mut Vec<String>,
+    encode_log: &mut Option<EncodeLog>,
+) -> ExitStatus {
     if let Some(stderr) = child.stderr.take() {
         let reader = BufReader::new(stderr);
-        let mut last_progress = 0;
+        let mut last_progress = progress_start;
         for line_result in reader.lines() {
+            // Progress is reported every second, so a cancelled task is noticed promptly
+            if shared::is_task_cancelled(task_id) {
+                if let Err(e) = child.kill() {
+                    eprintln!("Failed to kill ffmpeg of cancelled task {}: {}", task_id, e);
+                }
+                break;
+            }
+            if !shared::check_task_disk_quota(task_id) {
+                if let Err(e) = child.kill() {
+                    eprintln!(
+                        "Failed to kill ffmpeg of task {} over its disk quota: {}",
+                        task_id, e
+                    );
+                }
+                break;
+            }
             if let Ok(line) = line_result {
+                if let Some(encode_log) = encode_log {
+                    encode_log.write_line(&line);
+                }
                 if let Some(progress) = parse_progress(&line, total_duration) {
-                    last_progress = progress;
-                    shared::update_progress(&task_id, format_index, last_progress);
+                    last_progress =
+                        progress_start + progress * (progress_end - progress_start) / 100;
+                    shared::update_progress(task_id, format_index, last_progress);
+                }
+                if let Some(warning) = parse_ffmpeg_warning(&line) {
+                    if warnings.len() < MAX_FFMPEG_WARNINGS && !warnings.contains(&warning) {
+                        warnings.push(warning);
+                    }
                 }
                 println!("£££££ {} £££££", line);
                 println!("Progress: {}%", last_progress);
@@ -348,10 +5216,602 @@ fn run_ffmpeg(
 
     let output = child.wait().expect("Transcode process wasn't running");
     println!("Transcode finished with status: {}", output);
+    output
+}
 
-    Ok(())
+/// Returns the program and arguments of a command, e.g. for recording how a rendition was made.
+fn command_line(cmd: &Command) -> Vec<String> {
+    let mut command_line = vec![cmd.get_program().to_string_lossy().into_owned()];
+    command_line.extend(cmd.get_args().map(|arg| arg.to_string_lossy().into_owned()));
+    command_line
+}
+
+/// Executes the ffmpeg command to transcode a video file based on the specified parameters.
+///
+/// # Arguments
+/// * `task_id` - A unique identifier for the transcoding task.
+/// * `format_index` - The index specifying the target video format from a predefined list.
+/// * `file_path` - The path to the input video file to be transcoded.
+/// * `file_name` - The name of the input video file.
+/// * `output_dir` - The directory the transcoded file is written to.
+/// * `is_gpu` - A boolean flag indicating whether to use GPU acceleration for transcoding.
+/// * `format` - The desired output video format.
+/// * `total_duration` - The total duration of the video file in seconds.
+/// * `warnings` - Receives the distinct warnings ffmpeg logged.
+///
+/// # Returns
+/// The CID, hash and size of the output if the format sets `stream_upload` and it was uploaded
+/// while encoding, or a `Status` error if the transcoding operation failed.
+///
+#[allow(clippy::too_many_arguments)]
+fn run_ffmpeg(
+    task_id: String,
+    format_index: usize,
+    file_path: &str,
+    file_name: &str,
+    output_dir: &str,
+    is_gpu: bool,
+    format: &VideoFormat,
+    total_duration: f64,
+    warnings: &mut Vec<String>,
+) -> Result<Option<StreamedOutput>, Status> {
+    // Held until the last pass has finished
+    let _session = acquire_encode_session(&task_id, is_gpu)?;
+
+    let mut encode_log = None;
+    if format.save_encode_log.unwrap_or(false) {
+        let log_path = encode_log_path(output_dir, file_name);
+        shared::record_task_file(&task_id, &log_path);
+        match EncodeLog::create(&log_path) {
+            Ok(log) => encode_log = Some(log),
+            Err(e) => eprintln!("Failed to create encode log {}: {}", log_path, e),
+        }
+    }
+
+    // The first of two passes analyses the source into the rate-control log the second encodes with
+    let two_pass = is_two_pass(format, is_gpu);
+    if two_pass {
+        let pass_log_prefix = pass_log_prefix(output_dir, file_name);
+        for suffix in PASS_LOG_SUFFIXES {
+            shared::record_task_file(&task_id, &format!("{}{}", pass_log_prefix, suffix));
+        }
+        let mut cmd =
+            build_ffmpeg_command(file_path, file_name, output_dir, is_gpu, format, Some(1))?;
+        cmd.stderr(Stdio::piped()).stdout(Stdio::null());
+        if let Some(encode_log) = &mut encode_log {
+            encode_log.write_line(&format!("$ {}", command_line(&cmd).join(" ")));
+        }
+
+        let mut child = spawn_ffmpeg(&mut cmd)?;
+        let output = monitor_ffmpeg(
+            &task_id,
+            format_index,
+            &mut child,
+            total_duration,
+            0,
+            50,
+            warnings,
+            &mut encode_log,
+        );
+        if let Some(encode_log) = &mut encode_log {
+            encode_log.write_line(&format!("# first pass exited with {}", output));
+            encode_log.finish();
+        }
+
+        if shared::is_task_cancelled(&task_id) || !output.success() {
+            remove_pass_logs(output_dir, file_name);
+            if shared::is_task_cancelled(&task_id) {
+                return Err(Status::cancelled(format!("Task {} was cancelled", task_id)));
+            }
+            return Err(Status::new(
+                Code::Internal,
+                format!("ffmpeg first pass exited with {}", output),
+            ));
+        }
+    }
+
+    let pass = if two_pass { Some(2) } else { None };
+    let mut cmd = build_ffmpeg_command(file_path, file_name, output_dir, is_gpu, format, pass)?;
+    if let Some(encode_log) = &mut encode_log {
+        encode_log.write_line(&format!("$ {}", command_line(&cmd).join(" ")));
+    }
+
+    let streamed = is_streamed(format);
+    cmd.stderr(Stdio::piped()).stdout(if streamed {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    });
+
+    let mut child = spawn_ffmpeg(&mut cmd)?;
+
+    // A streamed output is uploaded from ffmpeg's stdout on another thread while it encodes
+    let hasher = Arc::new(Mutex::new(blake3::Hasher::new()));
+    let size = Arc::new(AtomicU64::new(0));
+    let (ffmpeg_result_sender, ffmpeg_result_receiver) = mpsc::channel();
+    let upload = match child.stdout.take() {
+        Some(stdout) if streamed => {
+            let reader = StreamedOutputReader {
+                inner: stdout,
+                hasher: Arc::clone(&hasher),
+                size: Arc::clone(&size),
+                ffmpeg_succeeded: ffmpeg_result_receiver,
+                finished: None,
+            };
+            let upload_name = format!("{}_ue.{}", file_name, format.ext);
+            Some(std::thread::spawn(move || {
+                upload_stream_ipfs(reader, &upload_name).map_err(|e| e.to_string())
+            }))
+        }
+        _ => None,
+    };
+
+    // (partial path, final path) of each output of the command
+    let mut outputs = vec![(
+        partial_output_path(output_dir, file_name, &format.ext),
+        format!("{}{}_ue.{}", output_dir, file_name, format.ext),
+    )];
+    if let Some(audio_extract) = &format.also_extract_audio {
+        let audio_file_name = audio_extract_file_name(file_name);
+        let audio_ext = audio_extract_ext(audio_extract);
+        outputs.push((
+            partial_output_path(output_dir, &audio_file_name, &audio_ext),
+            format!("{}{}_ue.{}", output_dir, audio_file_name, audio_ext),
+        ));
+    }
+    for (partial_path, final_path) in &outputs {
+        shared::record_task_file(&task_id, partial_path);
+        shared::record_task_file(&task_id, final_path);
+    }
+
+    let progress_start = if two_pass { 50 } else { 0 };
+    let output = monitor_ffmpeg(
+        &task_id,
+        format_index,
+        &mut child,
+        total_duration,
+        progress_start,
+        100,
+        warnings,
+        &mut encode_log,
+    );
+    if let Some(encode_log) = &mut encode_log {
+        encode_log.write_line(&format!("# ffmpeg exited with {}", output));
+        encode_log.finish();
+    }
+    if two_pass {
+        remove_pass_logs(output_dir, file_name);
+    }
+
+    let _ = ffmpeg_result_sender.send(output.success() && !shared::is_task_cancelled(&task_id));
+
+    if shared::is_task_cancelled(&task_id) || !output.success() {
+        for (partial_path, _) in &outputs {
+            let _ = std::fs::remove_file(partial_path);
+        }
+        if shared::is_disk_quota_exceeded(&task_id) {
+            return Err(disk_quota_exceeded_status(&task_id));
+        }
+        if shared::is_task_cancelled(&task_id) {
+            return Err(Status::cancelled(format!("Task {} was cancelled", task_id)));
+        }
+        return Err(Status::new(
+            Code::Internal,
+            format!("ffmpeg exited with {}", output),
+        ));
+    }
+
+    if let Some(upload) = upload {
+        let cid = upload
+            .join()
+            .map_err(|_| {
+                TranscodeErrorCode::UploadFailed
+                    .status(Code::Internal, "Streamed upload thread panicked")
+            })?
+            .map_err(|e| {
+                TranscodeErrorCode::UploadFailed
+                    .status(Code::Unavailable, format!("Streamed upload failed: {}", e))
+            })?;
+
+        return Ok(Some(StreamedOutput {
+            cid,
+            blake3: hasher.lock().unwrap().finalize().to_hex().to_string(),
+            size: size.load(Ordering::Relaxed),
+        }));
+    }
+
+    for (partial_path, final_path) in &outputs {
+        finalize_output(partial_path, final_path)?;
+    }
+
+    Ok(None)
+}
+
+/// Validates a format against the source and applies what it takes from the source: the video
+/// stream to encode, its rotation and frame rate, and the bitrate that hits `target_size_mb` for
+/// the source's duration. Also resolves the files the format references, its audio description
+/// track and preset file. The source is probed once for all of them.
+///
+/// # Arguments
+/// * `file_path` - The path to the source video file.
+/// * `format` - The desired output format.
+///
+/// # Returns
+/// The duration of the source in seconds, or 0 if it could not be read.
+///
+fn prepare_format(file_path: &str, format: &mut VideoFormat) -> Result<f64, Status> {
+    let source_probe = probe_source(file_path);
+
+    validate_audio_stream_index(&source_probe, format)?;
+    validate_stream_map(&source_probe, format)?;
+    resolve_audio_description(format)?;
+    resolve_preset_file(format)?;
+    apply_source_video_stream(&source_probe, format)?;
+    apply_source_rotation(&source_probe, format);
+    apply_source_frame_rate(&source_probe, format);
+    check_output_pixels(&source_probe, format, *MAX_OUTPUT_PIXELS)?;
+
+    let total_duration = source_probe
+        .as_ref()
+        .ok()
+        .and_then(|source_probe| source_probe.format.duration_secs())
+        .unwrap_or(0.0);
+    apply_target_size(format, total_duration)?;
+    Ok(total_duration)
+}
+
+/// Validates a format against the source and resolves the ffmpeg arguments `transcode_video` would
+/// run for it, without running ffmpeg. Used by verify mode to surface configuration problems.
+///
+/// # Arguments
+/// * `file_path` - The path to the input video file.
+/// * `video_format` - The desired output video format.
+/// * `is_gpu` - A boolean flag indicating whether to use GPU acceleration for transcoding.
+///
+/// # Returns
+/// The ffmpeg argument vector, or a `Status` error if the format is invalid for the source.
+///
+pub fn resolve_ffmpeg_args(
+    file_path: &str,
+    video_format: &str,
+    is_gpu: bool,
+) -> Result<Vec<String>, Status> {
+    let file_name = Path::new(file_path)
+        .file_name()
+        .ok_or_else(|| Status::new(Code::InvalidArgument, "Invalid file path"))?
+        .to_string_lossy()
+        .to_string();
+
+    let mut format = get_video_format_from_str(video_format)?;
+    let file_name = output_file_name(&file_name, &format);
+
+    let total_duration = prepare_format(file_path, &mut format)?;
+    // The file is kept so the resolved command can be run, and is garbage collected with the other
+    // files in `PATH_TO_TRANSCODED_FILE`
+    write_chapters_file(
+        &mut format,
+        &file_name,
+        &PATH_TO_TRANSCODED_FILE,
+        total_duration,
+    )?;
+
+    // The command of the final pass, which writes the output
+    let gpu_flag = format.gpu.unwrap_or(is_gpu);
+    let pass = if is_two_pass(&format, gpu_flag) {
+        Some(2)
+    } else {
+        None
+    };
+    let cmd = build_ffmpeg_command(
+        file_path,
+        &file_name,
+        &PATH_TO_TRANSCODED_FILE,
+        gpu_flag,
+        &format,
+        pass,
+    )?;
+
+    Ok(cmd
+        .get_args()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect())
+}
+
+/// Returns the name of the directory a rendition is written to with `UNIQUE_OUTPUT_DIRS`: the first
+/// 16 hex digits of the blake3 hash of its source, its format settings and whether it is
+/// encrypted. Renditions of the same source with identical settings share a directory, and
+/// renditions with different settings never write to the same path, even when they reuse a format
+/// id. `serde_json` serializes object keys in sorted
+/// order, so the order of the settings in the request does not matter.
+///
+/// # Arguments
+/// * `source_name` - The file name of the source, its CID.
+/// * `video_format` - The format settings as JSON.
+/// * `encrypt_flag` - Whether the rendition is encrypted.
+///
+pub fn rendition_dir_name(source_name: &str, video_format: &str, encrypt_flag: bool) -> String {
+    let settings = serde_json::from_str::<serde_json::Value>(video_format)
+        .map(|settings| settings.to_string())
+        .unwrap_or_else(|_| video_format.to_string());
+
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(source_name.as_bytes());
+    hasher.update(&[0]);
+    hasher.update(settings.as_bytes());
+    hasher.update(&[encrypt_flag as u8]);
+    hasher.finalize().to_hex()[..16].to_string()
+}
+
+/// Marks a `UNIQUE_OUTPUT_DIRS` directory as in use by a rendition until dropped, so that neither
+/// `remove_empty_output_dirs` nor garbage collection removes it from under the rendition.
+pub struct OutputDirGuard {
+    dir_name: String,
+}
+
+impl OutputDirGuard {
+    /// Creates the directory `{parent}{dir_name}/` and marks it in use. Both happen under the lock
+    /// `remove_empty_output_dirs` holds, so the directory cannot be removed between the two.
+    ///
+    /// # Arguments
+    /// * `parent` - The directory the output directory is created in, ending in `/`.
+    /// * `dir_name` - The name of the output directory.
+    ///
+    fn create(parent: &str, dir_name: &str) -> std::io::Result<Self> {
+        let mut active_dirs = ACTIVE_OUTPUT_DIRS.lock().unwrap();
+        std::fs::create_dir_all(format!("{}{}/", parent, dir_name))?;
+        *active_dirs.entry(dir_name.to_string()).or_insert(0) += 1;
+        Ok(OutputDirGuard {
+            dir_name: dir_name.to_string(),
+        })
+    }
+}
+
+impl Drop for OutputDirGuard {
+    fn drop(&mut self) {
+        let mut active_dirs = ACTIVE_OUTPUT_DIRS.lock().unwrap();
+        if let Some(count) = active_dirs.get_mut(&self.dir_name) {
+            *count -= 1;
+            if *count == 0 {
+                active_dirs.remove(&self.dir_name);
+            }
+        }
+    }
+}
+
+/// Returns whether a file is in a `UNIQUE_OUTPUT_DIRS` directory that a rendition is writing to.
+///
+/// # Arguments
+/// * `path` - The path to the file.
+///
+pub fn is_in_active_output_dir(path: &Path) -> bool {
+    let dir_name = match path.parent().and_then(|parent| parent.file_name()) {
+        Some(dir_name) => dir_name.to_string_lossy(),
+        None => return false,
+    };
+    ACTIVE_OUTPUT_DIRS.lock().unwrap().contains_key(dir_name.as_ref())
+}
+
+/// Removes the immediate subdirectories of a directory that are empty and not in use by a
+/// rendition, such as those of `UNIQUE_OUTPUT_DIRS` whose renditions have all been deleted.
+///
+/// # Arguments
+/// * `directory` - The directory whose subdirectories are removed.
+///
+pub fn remove_empty_output_dirs(directory: &str) {
+    let active_dirs = ACTIVE_OUTPUT_DIRS.lock().unwrap();
+    if let Ok(entries) = std::fs::read_dir(directory) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let in_use = active_dirs.contains_key(entry.file_name().to_string_lossy().as_ref());
+            // Fails, and is left alone, unless the directory is empty
+            if path.is_dir() && !in_use && std::fs::remove_dir(&path).is_ok() {
+                println!("Removed empty directory {}", path.display());
+            }
+        }
+    }
+}
+
+/// Returns the path a rendition's output is found at with `UNIQUE_OUTPUT_DIRS`, if a previous
+/// transcode has left it there. The directory is named after the source and settings, so an
+/// output there was encoded from the same source with the same settings and can be reused.
+///
+/// # Arguments
+/// * `file_path` - The path to the source video file.
+/// * `video_format` - The format settings as JSON.
+/// * `encrypt_flag` - Whether the rendition is encrypted.
+///
+pub fn existing_output_path(
+    file_path: &str,
+    video_format: &str,
+    encrypt_flag: bool,
+) -> Option<String> {
+    if !*UNIQUE_OUTPUT_DIRS {
+        return None;
+    }
+    let format = get_video_format_from_str(video_format).ok()?;
+    let source_name = Path::new(file_path).file_name()?.to_string_lossy().to_string();
+    let output_path = format!(
+        "{}{}/{}_ue.{}",
+        scratch_dir(file_path),
+        rendition_dir_name(&source_name, video_format, encrypt_flag),
+        output_file_name(&source_name, &format),
+        format.ext
+    );
+    Path::new(&output_path).is_file().then_some(output_path)
+}
+
+/// Returns the directory a task's transcoded files are written to. When `USE_TMPFS` is set and the
+/// source is no larger than `TMPFS_MAX_SOURCE_SIZE`, this is `TMPFS_PATH` so that intermediates
+/// stay in memory; larger sources, or a tmpfs that cannot be created, fall back to
+/// `PATH_TO_TRANSCODED_FILE` on disk.
+///
+/// # Arguments
+/// * `file_path` - The path to the source video file.
+///
+fn scratch_dir(file_path: &str) -> String {
+    if !*USE_TMPFS {
+        return PATH_TO_TRANSCODED_FILE.to_string();
+    }
+
+    let source_size = match metadata(file_path) {
+        Ok(metadata) => metadata.len(),
+        Err(_) => return PATH_TO_TRANSCODED_FILE.to_string(),
+    };
+    if source_size > *TMPFS_MAX_SOURCE_SIZE {
+        return PATH_TO_TRANSCODED_FILE.to_string();
+    }
+
+    let mut tmpfs_path = TMPFS_PATH.to_string();
+    if !tmpfs_path.ends_with('/') {
+        tmpfs_path.push('/');
+    }
+    if let Err(e) = std::fs::create_dir_all(&tmpfs_path) {
+        eprintln!("Failed to create tmpfs directory {}: {}", tmpfs_path, e);
+        return PATH_TO_TRANSCODED_FILE.to_string();
+    }
+
+    tmpfs_path
+}
+
+/// Transcodes a video to the given format with ffmpeg and leaves the output on local disk in
+/// `PATH_TO_TRANSCODED_FILE`, without encrypting or uploading it. Used by `transcode-cli`.
+///
+/// # Arguments
+/// * `task_id` - A unique identifier for the transcoding task.
+/// * `format_index` - The index specifying the target video format from a predefined list.
+/// * `file_path` - The path to the input video file to be transcoded.
+/// * `video_format` - The desired output video format.
+/// * `is_gpu` - A boolean flag indicating whether to use GPU acceleration for transcoding.
+///
+/// # Returns
+/// The path to the transcoded file, or a `Status` error on failure.
+///
+// Only the CLI transcodes locally
+#[allow(dead_code)]
+pub fn transcode_video_local(
+    task_id: String,
+    format_index: usize,
+    file_path: &str,
+    video_format: &str,
+    is_gpu: bool,
+) -> Result<String, Status> {
+    let file_name = Path::new(file_path)
+        .file_name()
+        .ok_or_else(|| Status::new(Code::InvalidArgument, "Invalid file path"))?
+        .to_string_lossy()
+        .to_string();
+
+    let mut format = get_video_format_from_str(video_format)?;
+    let file_name = output_file_name(&file_name, &format);
+
+    let gpu_flag = format.gpu.unwrap_or(is_gpu);
+
+    let total_duration = prepare_format(file_path, &mut format)?;
+    write_chapters_file(
+        &mut format,
+        &file_name,
+        &PATH_TO_TRANSCODED_FILE,
+        total_duration,
+    )?;
+
+    // The output stays on local disk, so there is nothing to stream it to
+    if is_streamed(&format) {
+        println!(
+            "Format {} sets stream_upload, ignored for local transcodes",
+            format.id
+        );
+        format.stream_upload = None;
+    }
+    // Nor is there anywhere to upload HLS segments to
+    if is_hls_packaged(&format) {
+        println!(
+            "Format {} sets hls_encryption, iframe_playlist or packaging ll-hls, ignored for local \
+             transcodes",
+            format.id
+        );
+        format.hls_encryption = None;
+        format.iframe_playlist = None;
+        if is_ll_hls(&format) {
+            format.packaging = None;
+            format.ll_hls = None;
+        }
+    }
+
+    let mut warnings = Vec::new();
+    let ffmpeg_result = run_ffmpeg(
+        task_id,
+        format_index,
+        file_path,
+        &file_name,
+        &PATH_TO_TRANSCODED_FILE,
+        gpu_flag,
+        &format,
+        total_duration,
+        &mut warnings,
+    );
+    remove_chapters_file(&format);
+    ffmpeg_result?;
+    for warning in &warnings {
+        eprintln!("Format {} warning: {}", format.id, warning);
+    }
+
+    Ok(format!(
+        "{}{}_ue.{}",
+        *PATH_TO_TRANSCODED_FILE, file_name, format.ext
+    ))
 }
 
+/// Writes a sidecar JSON recording how a rendition was produced — the exact ffmpeg command, the
+/// blake3 hashes of the input and of the unencrypted output, the source duration and a timestamp —
+/// and uploads it to the same storage network as the rendition.
+///
+/// # Arguments
+/// * `file_path` - The path to the source video file.
+/// * `file_name` - The name of the transcoded file, without extension.
+/// * `output_dir` - The directory the transcoded file was written to.
+/// * `format` - The output format.
+/// * `ffmpeg_command` - The ffmpeg program and arguments the rendition was transcoded with.
+/// * `output_hash` - The blake3 hash of the unencrypted output.
+/// * `total_duration` - The duration of the source in seconds.
+///
+/// # Returns
+/// The CID of the uploaded sidecar, or an error message.
+///
+async fn upload_sidecar(
+    file_path: &str,
+    file_name: &str,
+    output_dir: &str,
+    format: &VideoFormat,
+    ffmpeg_command: &[String],
+    output_hash: &str,
+    total_duration: f64,
+) -> Result<String, String> {
+    let input_hash = hash_blake3_file(file_path.to_string())
+        .map(|hash| hash.to_hex().to_string())
+        .map_err(|e| format!("Error computing blake3 hash of input: {}", e))?;
+
+    let sidecar = serde_json::json!({
+        "format_id": format.id,
+        "ffmpeg_command": ffmpeg_command,
+        "input_hash": input_hash,
+        "output_hash": output_hash,
+        "duration": total_duration,
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+    });
+
+    let sidecar_path = format!("{}{}_sidecar.json", output_dir, file_name);
+    std::fs::write(&sidecar_path, sidecar.to_string())
+        .map_err(|e| format!("Failed to write sidecar {}: {}", sidecar_path, e))?;
+
+    let cid = upload_video(sidecar_path.as_str(), format.dest.clone())
+        .await
+        .map_err(|e| format!("Failed to upload sidecar {}: {}", sidecar_path, e))?;
+
+    Ok(match format.dest.as_deref() {
+        Some("ipfs") => format!("ipfs://{}", cid),
+        _ => format!("s5://{}", cid),
+    })
+}
 
 /// Asynchronously transcodes a video from a given format to another using ffmpeg,
 /// based on the specified transcoder settings. This function supports optional
@@ -364,6 +5824,8 @@ fn run_ffmpeg(
 /// * `video_format` - The desired output video format.
 /// * `is_encrypted` - A boolean flag indicating whether the output video should be encrypted.
 /// * `is_gpu` - A boolean flag indicating whether to use GPU acceleration for transcoding.
+/// * `reuse_output` - Whether an output left by an earlier transcode with the same settings, found
+///   by `existing_output_path`, is uploaded instead of encoding again.
 ///
 /// # Returns
 /// A `Result` wrapping a `Response` with the `TranscodeVideoResponse` on success,
@@ -376,6 +5838,7 @@ pub async fn transcode_video(
     video_format: &str,
     is_encrypted: bool,
     is_gpu: bool,
+    reuse_output: bool,
 ) -> Result<Response<TranscodeVideoResponse>, Status> {
     println!("transcode_video: Processing video at: {}", file_path);
     println!("transcode_video: video_format: {}", video_format);
@@ -388,46 +5851,315 @@ pub async fn transcode_video(
         .to_string_lossy()
         .to_string();
 
-    let format = get_video_format_from_str(video_format)?;
-    
-    let file_name = format!("{}_{}", file_name, format.id.to_string());
-    
+    let mut format = get_video_format_from_str(video_format)?;
+
+    let file_name = output_file_name(&file_name, &format);
+
     println!("Transcoding video: {}", &file_path);
     println!("is_gpu = {}", &is_gpu);
-    
-    let total_duration = get_video_duration(file_path).unwrap_or_else(|_| 0.0);
-    println!("Total video duration: {} seconds", total_duration);
-    
-    let mut encryption_key1: Vec<u8> = Vec::new();
-    
-    let response: TranscodeVideoResponse;
-    
+
+    let encryption_key1: Vec<u8>;
+
+    let mut response: TranscodeVideoResponse;
+
     // Use format.gpu if it has a value, otherwise use is_gpu
     let gpu_flag = format.gpu.unwrap_or(is_gpu);
     println!("transcode_video: gpu_flag: {}", gpu_flag);
 
     let encrypt_flag = format.encrypt.unwrap_or(is_encrypted);
     println!("transcode_video: encrypt_flag: {}", encrypt_flag);
-    
-    run_ffmpeg(
-        task_id,
-        format_index,
-        file_path,
-        &file_name,
-        gpu_flag,
-        &format,
-        total_duration,
-    )?;
 
-    if encrypt_flag {
-        match encrypt_file_xchacha20(
+    let scratch_dir = scratch_dir(file_path);
+    // Held until the outputs have been uploaded
+    let mut output_dir_guard = None;
+    let output_dir = if *UNIQUE_OUTPUT_DIRS {
+        let source_name = Path::new(file_path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let dir_name = rendition_dir_name(&source_name, video_format, encrypt_flag);
+        let dir = format!("{}{}/", scratch_dir, dir_name);
+        output_dir_guard = Some(OutputDirGuard::create(&scratch_dir, &dir_name).map_err(|e| {
+            Status::new(
+                Code::Internal,
+                format!("Failed to create output directory {}: {}", dir, e),
+            )
+        })?);
+        dir
+    } else {
+        scratch_dir.clone()
+    };
+
+    let total_duration = prepare_format(file_path, &mut format)?;
+    println!("Total video duration: {} seconds", total_duration);
+
+    // Only the video rendition goes through the encryption pipeline, so an extracted audio output
+    // would be uploaded in the clear
+    if format.also_extract_audio.is_some() && encrypt_flag {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            format!(
+                "Format {} sets also_extract_audio, which is not supported for encrypted outputs",
+                format.id
+            ),
+        ));
+    }
+
+    // HLS segments are uploaded as they are cut from the output, encrypted only by
+    // hls_encryption's own AES-128 key
+    if is_hls_packaged(&format) && encrypt_flag {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            format!(
+                "Format {} sets hls_encryption, iframe_playlist or packaging ll-hls, which are \
+                 not supported for encrypted outputs",
+                format.id
+            ),
+        ));
+    }
+
+    // Scene segments are uploaded as they are cut from the unencrypted output
+    if format.scene_split.is_some() && encrypt_flag {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            format!(
+                "Format {} sets scene_split, which is not supported for encrypted outputs",
+                format.id
+            ),
+        ));
+    }
+
+    // A streamed output is uploaded as it is encoded, before it could be encrypted
+    if is_streamed(&format) && encrypt_flag {
+        return Err(Status::new(
+            Code::InvalidArgument,
             format!(
+                "Format {} sets stream_upload, which is not supported for encrypted outputs",
+                format.id
+            ),
+        ));
+    }
+
+    write_chapters_file(&mut format, &file_name, &output_dir, total_duration)?;
+
+    // A streamed output was uploaded as it was encoded, and an encode log is of this encode, so
+    // neither can be reused
+    let reused_output = reuse_output
+        && !is_streamed(&format)
+        && !format.save_encode_log.unwrap_or(false)
+        && existing_output_path(file_path, video_format, encrypt_flag).is_some()
+        && format.also_extract_audio.as_ref().is_none_or(|audio_extract| {
+            Path::new(&format!(
                 "{}{}_ue.{}",
-                *PATH_TO_TRANSCODED_FILE, file_name, format.ext
+                output_dir,
+                audio_extract_file_name(&file_name),
+                audio_extract_ext(audio_extract)
+            ))
+            .is_file()
+        });
+
+    let encode_start = std::time::Instant::now();
+    let mut warnings = Vec::new();
+    let ffmpeg_result = if reused_output {
+        println!(
+            "Format {} reuses the output in {} of an earlier transcode",
+            format.id, output_dir
+        );
+        Ok(None)
+    } else {
+        run_ffmpeg(
+            task_id.clone(),
+            format_index,
+            file_path,
+            &file_name,
+            &output_dir,
+            gpu_flag,
+            &format,
+            total_duration,
+            &mut warnings,
+        )
+    };
+    remove_chapters_file(&format);
+    let streamed_output = match ffmpeg_result {
+        Ok(streamed_output) => streamed_output,
+        // The log is most useful for a failed encode, so it is referenced from the error
+        Err(status) if format.save_encode_log.unwrap_or(false) => {
+            return Err(
+                match upload_encode_log(&file_name, &output_dir, &format).await {
+                    Ok(encode_log_cid) => Status::new(
+                        status.code(),
+                        format!("{} (encode log {})", status.message(), encode_log_cid),
+                    ),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        status
+                    }
+                },
+            );
+        }
+        Err(status) => return Err(status),
+    };
+    let encode_secs = encode_start.elapsed().as_secs_f64();
+    let encode_speed = if encode_secs > 0.0 {
+        output_duration(&format, total_duration) / encode_secs
+    } else {
+        0.0
+    };
+    println!(
+        "Format {} encoded in {:.2}s ({:.2}x realtime)",
+        format.id, encode_secs, encode_speed
+    );
+
+    let mut vmaf_score = None;
+    let mut low_quality = false;
+    if let Some(min_vmaf) = format.min_vmaf {
+        let output_path = format!("{}{}_ue.{}", output_dir, file_name, format.ext);
+        match compute_vmaf(&output_path, file_path, &format) {
+            Ok(score) => {
+                println!("Format {} VMAF score: {:.2}", format.id, score);
+                vmaf_score = Some(score);
+                low_quality = score < min_vmaf;
+            }
+            // A strict format is never delivered without a score
+            Err(e) if format.vmaf_strict.unwrap_or(false) => {
+                let _ = std::fs::remove_file(&output_path);
+                let _ = std::fs::remove_file(encode_log_path(&output_dir, &file_name));
+                return Err(TranscodeErrorCode::QualityBelowTarget.status(
+                    Code::FailedPrecondition,
+                    format!(
+                        "Format {} sets vmaf_strict but its VMAF score could not be computed: {}",
+                        format.id, e
+                    ),
+                ));
+            }
+            Err(e) => eprintln!("Failed to compute VMAF of format {}: {}", format.id, e),
+        }
+
+        if low_quality && format.vmaf_strict.unwrap_or(false) {
+            let _ = std::fs::remove_file(&output_path);
+            let _ = std::fs::remove_file(encode_log_path(&output_dir, &file_name));
+            return Err(TranscodeErrorCode::QualityBelowTarget.status(
+                Code::FailedPrecondition,
+                format!(
+                    "Format {} VMAF score {:.2} is below min_vmaf {}",
+                    format.id,
+                    vmaf_score.unwrap_or_default(),
+                    min_vmaf
+                ),
+            ));
+        }
+    }
+
+    let mut perceptual_hashes = Vec::new();
+    if format.perceptual_hash.unwrap_or(false) {
+        let output_path = format!("{}{}_ue.{}", output_dir, file_name, format.ext);
+        match compute_perceptual_hashes(&output_path) {
+            Ok(hashes) => perceptual_hashes = hashes,
+            Err(e) => eprintln!(
+                "Failed to compute perceptual hashes of format {}: {}",
+                format.id, e
             ),
-            format!("{}{}.{}", *PATH_TO_TRANSCODED_FILE, file_name, format.ext),
+        }
+    }
+
+    let (output_hash, output_size) = match &streamed_output {
+        Some(streamed_output) => (streamed_output.blake3.clone(), streamed_output.size),
+        None => (
+            hash_blake3_file(format!("{}{}_ue.{}", output_dir, file_name, format.ext))
+                .map(|hash| hash.to_hex().to_string())
+                .map_err(|e| eprintln!("Error computing blake3 hash of output: {}", e))
+                .unwrap_or_default(),
+            metadata(format!("{}{}_ue.{}", output_dir, file_name, format.ext))
+                .map(|metadata| metadata.len())
+                .unwrap_or_default(),
+        ),
+    };
+
+    // The command of the final pass, which writes the output
+    let pass = if is_two_pass(&format, gpu_flag) {
+        Some(2)
+    } else {
+        None
+    };
+    let ffmpeg_command =
+        build_ffmpeg_command(file_path, &file_name, &output_dir, gpu_flag, &format, pass)
+            .map(|cmd| command_line(&cmd))
+            .unwrap_or_default();
+
+    let mut sidecar_cid = String::new();
+    if format.emit_sidecar.unwrap_or(false) {
+        shared::record_task_file(&task_id, &format!("{}{}_sidecar.json", output_dir, file_name));
+        match upload_sidecar(
+            file_path,
+            &file_name,
+            &output_dir,
+            &format,
+            &ffmpeg_command,
+            &output_hash,
+            total_duration,
+        )
+        .await
+        {
+            Ok(cid) => sidecar_cid = cid,
+            // A rendition that asked for a sidecar is never delivered without one
+            Err(e) => {
+                for path in [
+                    format!("{}{}_ue.{}", output_dir, file_name, format.ext),
+                    format!("{}{}_sidecar.json", output_dir, file_name),
+                    encode_log_path(&output_dir, &file_name),
+                ] {
+                    let _ = std::fs::remove_file(path);
+                }
+                return Err(TranscodeErrorCode::UploadFailed.status(Code::Unavailable, e));
+            }
+        }
+    }
+
+    let mut encode_log_cid = String::new();
+    if format.save_encode_log.unwrap_or(false) {
+        match upload_encode_log(&file_name, &output_dir, &format).await {
+            Ok(cid) => encode_log_cid = cid,
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+
+    let mut audio_cid = String::new();
+    if let Some(audio_extract) = &format.also_extract_audio {
+        let audio_path = format!(
+            "{}{}_ue.{}",
+            output_dir,
+            audio_extract_file_name(&file_name),
+            audio_extract_ext(audio_extract)
+        );
+        match upload_video(audio_path.as_str(), format.dest.clone()).await {
+            Ok(cid) => audio_cid = cid,
+            Err(e) => eprintln!("Failed to upload extracted audio {}: {}", audio_path, e),
+        }
+    }
+
+    if encrypt_flag {
+        let encrypted_partial_path = format!(
+            "{}{}{}{}",
+            output_dir, file_name, PARTIAL_OUTPUT_MARKER, format.ext
+        );
+        let encrypted_path = format!("{}{}.{}", output_dir, file_name, format.ext);
+        shared::record_task_file(&task_id, &encrypted_partial_path);
+        shared::record_task_file(&task_id, &encrypted_path);
+        let encryption_result = encrypt_file_xchacha20(
+            format!("{}{}_ue.{}", output_dir, file_name, format.ext),
+            encrypted_partial_path.clone(),
             0,
-        ) {
+        )
+        .and_then(|key| {
+            std::fs::rename(&encrypted_partial_path, &encrypted_path)?;
+            Ok(key)
+        });
+        if !shared::check_task_disk_quota(&task_id) {
+            let _ = std::fs::remove_file(&encrypted_path);
+            return Err(disk_quota_exceeded_status(&task_id));
+        }
+
+        match encryption_result {
             Ok(bytes) => {
                 // Encryption succeeded, and `bytes` contains the encrypted data
                 // Add your success handling code here
@@ -435,26 +6167,23 @@ pub async fn transcode_video(
                 println!("Encryption succeeded");
             }
             Err(error) => {
-                // Encryption failed
-                // Handle the error here
                 eprintln!("Encryption error: {:?}", error);
-                // Optionally, you can return an error or perform error-specific handling
+                return Err(TranscodeErrorCode::EncryptFailed.status(
+                    Code::Internal,
+                    format!("Encryption error: {}", error),
+                ));
             }
         }
 
-        let file_path = format!(
-            "{}{}_ue.{}",
-            *PATH_TO_TRANSCODED_FILE, file_name, format.ext
-        );
-        let file_path_encrypted =
-            format!("{}{}.{}", *PATH_TO_TRANSCODED_FILE, file_name, format.ext);
+        let file_path = format!("{}{}_ue.{}", output_dir, file_name, format.ext);
+        let file_path_encrypted = format!("{}{}.{}", output_dir, file_name, format.ext);
 
         let hash_result = hash_blake3_file(file_path.clone());
         let hash_result_encrypted = hash_blake3_file(file_path_encrypted.to_owned());
 
         let cid_type_encrypted: u8 = 0xae; // replace with your actual cid type encrypted
         let encryption_algorithm: u8 = 0xa6; // replace with your actual encryption algorithm
-        let chunk_size_as_power_of_2: u8 = 18; // replace with your actual chunk size as power of 2
+        let chunk_size_as_power_of_2: u8 = CHUNK_SIZE_AS_POWER_OF_2;
         let padding: u32 = 0; // replace with your actual padding
 
         // Upload the transcoded videos to storage
@@ -465,12 +6194,8 @@ pub async fn transcode_video(
                     &cid_encrypted
                 );
 
-                let mut hash = Vec::new();
-                match hash_result {
-                    Ok(hash1) => {
-                        hash = hash1.as_bytes().to_vec();
-                        // Now you can use bytes as needed.
-                    }
+                let hash = match hash_result {
+                    Ok(hash1) => hash1.as_bytes().to_vec(),
                     Err(err) => {
                         eprintln!("Error computing blake3 hash: {}", err);
 
@@ -479,14 +6204,10 @@ pub async fn transcode_video(
                             format!("Error computing blake3 hash: {}", err),
                         ));
                     }
-                }
+                };
 
-                let mut hash_encrypted = Vec::new();
-                match hash_result_encrypted {
-                    Ok(hash1) => {
-                        hash_encrypted = hash1.as_bytes().to_vec();
-                        // Now you can use bytes as needed.
-                    }
+                let hash_encrypted = match hash_result_encrypted {
+                    Ok(hash1) => hash1.as_bytes().to_vec(),
                     Err(err) => {
                         eprintln!("Error computing blake3 hash: {}", err);
 
@@ -495,7 +6216,7 @@ pub async fn transcode_video(
                             format!("Error computing blake3 hash: {}", err),
                         ));
                     }
-                }
+                };
 
                 let mut encrypted_blob_hash = vec![0x1f];
                 encrypted_blob_hash.extend(hash_encrypted);
@@ -557,6 +6278,7 @@ pub async fn transcode_video(
                     status_code: 200,
                     message: String::from("Transcoding successful"),
                     cid: encrypted_cid,
+                    ..Default::default()
                 };
             }
             Err(e) => {
@@ -567,20 +6289,75 @@ pub async fn transcode_video(
                     status_code: 500,
                     message: format!("Transcoding task failed with error {}", e),
                     cid: "".to_string(),
+                    ..Default::default()
                 };
             }
         };
+    } else if let Some(streamed_output) = streamed_output {
+        println!("cid: {:?}", streamed_output.cid);
+
+        response = TranscodeVideoResponse {
+            status_code: 200,
+            message: String::from("Transcoding successful"),
+            cid: streamed_output.cid,
+            ..Default::default()
+        };
+    } else if is_hls_packaged(&format) {
+        let file_path = format!("{}{}_ue.{}", output_dir, file_name, format.ext);
+
+        match package_hls(&task_id, &file_path, &file_name, &output_dir, &format).await {
+            Ok(packaged_hls) => {
+                println!("cid: {:?}", packaged_hls.playlist_cid);
+
+                response = TranscodeVideoResponse {
+                    status_code: 200,
+                    message: String::from("Transcoding successful"),
+                    cid: packaged_hls.playlist_cid,
+                    iframe_playlist_cid: packaged_hls.iframe_playlist_cid,
+                    hls_key_cid: packaged_hls.key_cid,
+                    hls_key: packaged_hls.key,
+                    ..Default::default()
+                };
+            }
+            Err(e) => {
+                eprintln!("Error: {}", e);
+
+                response = TranscodeVideoResponse {
+                    status_code: 500,
+                    message: format!("Transcoding task failed with error {}", e),
+                    cid: "".to_string(),
+                    ..Default::default()
+                };
+            }
+        }
     } else {
-        let file_path = format!(
-            "{}{}_ue.{}",
-            *PATH_TO_TRANSCODED_FILE, file_name, format.ext
-        );
+        let file_path = format!("{}{}_ue.{}", output_dir, file_name, format.ext);
 
         // Upload the transcoded videos to storage
         match upload_video(file_path.as_str(), format.dest.clone()).await {
             Ok(cid) => {
                 println!("cid: {:?}", cid);
 
+                let mut scenes = Vec::new();
+                if format.scene_split.is_some() {
+                    scenes = upload_scene_segments(
+                        &task_id,
+                        &file_path,
+                        &file_name,
+                        &output_dir,
+                        &format,
+                        &cid,
+                    )
+                    .await
+                    .map_err(|e| {
+                        if shared::is_disk_quota_exceeded(&task_id) {
+                            disk_quota_exceeded_status(&task_id)
+                        } else {
+                            TranscodeErrorCode::UploadFailed.status(Code::Unavailable, e)
+                        }
+                    })?;
+                }
+
                 println!("Transcoding task finished");
 
                 // Return the TranscodeVideoResponse with the job ID
@@ -588,6 +6365,8 @@ pub async fn transcode_video(
                     status_code: 200,
                     message: String::from("Transcoding successful"),
                     cid,
+                    scenes,
+                    ..Default::default()
                 };
             }
             Err(e) => {
@@ -598,10 +6377,596 @@ pub async fn transcode_video(
                     status_code: 500,
                     message: format!("Transcoding task failed with error {}", e),
                     cid: "".to_string(),
+                    ..Default::default()
                 };
             }
         };
     }
 
+    response.blake3 = output_hash;
+    response.output_size = output_size;
+    response.sidecar_cid = sidecar_cid;
+    response.encode_log_cid = encode_log_cid;
+    response.ffmpeg_command = ffmpeg_command;
+    response.audio_cid = audio_cid;
+    response.encode_secs = encode_secs;
+    response.encode_speed = encode_speed;
+    response.vmaf_score = vmaf_score;
+    response.low_quality = low_quality;
+    response.perceptual_hashes = perceptual_hashes;
+    response.warnings = warnings;
+
+    // Free the ramdisk as soon as the outputs have been uploaded
+    if scratch_dir != *PATH_TO_TRANSCODED_FILE {
+        for path in [
+            format!("{}{}_ue.{}", output_dir, file_name, format.ext),
+            format!("{}{}.{}", output_dir, file_name, format.ext),
+            format!("{}{}_sidecar.json", output_dir, file_name),
+        ] {
+            let _ = std::fs::remove_file(path);
+        }
+        if let Some(audio_extract) = &format.also_extract_audio {
+            let _ = std::fs::remove_file(format!(
+                "{}{}_ue.{}",
+                output_dir,
+                audio_extract_file_name(&file_name),
+                audio_extract_ext(audio_extract)
+            ));
+        }
+        if output_dir != scratch_dir {
+            // Left alone while another rendition with the same settings is still using it
+            drop(output_dir_guard.take());
+            remove_empty_output_dirs(&scratch_dir);
+        }
+    }
+
     Ok(Response::new(response))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drawtext_filter_escapes_the_text_and_places_it() {
+        let watermark: TextWatermark =
+            serde_json::from_str(r#"{"text": "50% off: it's [new], x;y\n"}"#).unwrap();
+        assert_eq!(
+            drawtext_filter(&watermark, None),
+            concat!(
+                r"drawtext=expansion=none:text=50% off\\: it\\\'s \[new\]\, x\;y",
+                ":fontsize=24:fontcolor=white:x=w-tw-10:y=h-th-10"
+            )
+        );
+
+        let watermark: TextWatermark = serde_json::from_str(
+            r##"{"text": "© Example", "fontsize": 32, "position": "top", "color": "#ffcc00@0.5"}"##,
+        )
+        .unwrap();
+        assert_eq!(
+            drawtext_filter(&watermark, Some("/fonts/Sans:Bold.ttf")),
+            concat!(
+                r"drawtext=fontfile=/fonts/Sans\\:Bold.ttf:expansion=none:text=© Example",
+                ":fontsize=32:fontcolor=#ffcc00@0.5:x=(w-tw)/2:y=10"
+            )
+        );
+    }
+
+    fn audio_description(json: &str) -> AudioDescription {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn audio_description_filter_ducks_main_audio_under_narration() {
+        let description = audio_description(r#"{"cid": "s5://track", "level": 1.5}"#);
+        let filter = audio_description_filter(&description, 1, 2, None);
+
+        assert_eq!(
+            filter,
+            "[0:a:1]volume=2[ad_main];\
+             [2:a:0]volume=1.5,apad,asplit=2[ad_sidechain][ad_split];\
+             [ad_split]volume=2[ad_narration];\
+             [ad_main][ad_sidechain]sidechaincompress=threshold=0.05:attack=20:release=400:ratio=4[ad_ducked];\
+             [ad_ducked][ad_narration]amix=inputs=2:duration=first:dropout_transition=0[ad_out]"
+        );
+    }
+
+    #[test]
+    fn audio_description_filter_without_ducking_applies_af_to_the_mix() {
+        let description = audio_description(r#"{"cid": "s5://track", "duck_ratio": 1}"#);
+        let filter = audio_description_filter(&description, 0, 1, Some("loudnorm"));
+
+        assert_eq!(
+            filter,
+            "[0:a:0]volume=2[ad_main];[1:a:0]volume=1,apad,volume=2[ad_narration];\
+             [ad_main][ad_narration]amix=inputs=2:duration=first:dropout_transition=0,loudnorm[ad_out]"
+        );
+        assert!(!filter.contains("normalize"));
+    }
+
+    #[test]
+    fn rendition_dir_name_depends_on_settings_but_not_their_order() {
+        let dir = rendition_dir_name("source", r#"{"id": 1, "ext": "mp4", "crf": 23}"#, false);
+
+        assert_eq!(dir.len(), 16);
+        assert_eq!(
+            dir,
+            rendition_dir_name("source", r#"{"crf": 23, "ext": "mp4", "id": 1}"#, false)
+        );
+        assert_ne!(
+            dir,
+            rendition_dir_name("source", r#"{"id": 1, "ext": "mp4", "crf": 24}"#, false)
+        );
+        assert_ne!(
+            dir,
+            rendition_dir_name("other", r#"{"id": 1, "ext": "mp4", "crf": 23}"#, false)
+        );
+        assert_ne!(
+            dir,
+            rendition_dir_name("source", r#"{"id": 1, "ext": "mp4", "crf": 23}"#, true)
+        );
+    }
+
+    #[test]
+    fn parse_scene_times_sorts_and_dedups_finite_times() {
+        let stderr = "\
+[Parsed_showinfo_1 @ 0x1] n:   1 pts: 250 pts_time:10.4 duration: 1
+frame=  2 fps=0.0 q=-0.0 size=N/A time=00:00:10.40
+[Parsed_showinfo_1 @ 0x1] n:   0 pts: 120 pts_time:4.8 duration: 1
+[Parsed_showinfo_1 @ 0x1] n:   2 pts: 250 pts_time:10.4 duration: 1
+[Parsed_showinfo_1 @ 0x1] n:   3 pts: 0 pts_time:0 duration: 1
+[Parsed_showinfo_1 @ 0x1] n:   4 pts: N/A pts_time:nan duration: 1
+[Parsed_showinfo_1 @ 0x1] n:   5 pts: N/A pts_time:inf duration: 1
+";
+
+        assert_eq!(parse_scene_times(stderr), vec![4.8, 10.4]);
+    }
+
+    #[test]
+    fn resolve_output_fps_never_raises_the_source_frame_rate() {
+        let source = FrameRate::Named("source".to_string());
+
+        assert_eq!(resolve_output_fps(Some(&source), None, Some(29.97)), Some(29.97));
+        let rate = FrameRate::Rate(60.0);
+        assert_eq!(resolve_output_fps(Some(&rate), Some(30.0), Some(24.0)), Some(24.0));
+        assert_eq!(resolve_output_fps(Some(&rate), Some(30.0), None), Some(30.0));
+        assert_eq!(resolve_output_fps(None, Some(30.0), Some(60.0)), Some(30.0));
+        assert_eq!(resolve_output_fps(None, Some(30.0), Some(24.0)), None);
+        assert_eq!(resolve_output_fps(None, None, Some(24.0)), None);
+    }
+
+    #[test]
+    fn phash_sets_bit_0_from_the_dc_coefficient() {
+        // A horizontal gradient: the DC coefficient and those of horizontal frequencies are large
+        let gradient: Vec<u8> = (0..PHASH_IMAGE_SIZE * PHASH_IMAGE_SIZE)
+            .map(|i| (i % PHASH_IMAGE_SIZE * 8) as u8)
+            .collect();
+        let hash = phash(&gradient);
+
+        assert_eq!(hash & 1, 1);
+        assert_eq!(hash, phash(&gradient));
+        let inverted: Vec<u8> = gradient.iter().map(|pixel| 255 - pixel).collect();
+        assert_ne!(hash, phash(&inverted));
+    }
+
+    #[test]
+    fn perceptual_hash_times_are_spread_evenly_over_the_duration() {
+        let times = perceptual_hash_times(32.0);
+
+        assert_eq!(times.len(), PERCEPTUAL_HASH_FRAMES);
+        assert_eq!(times[0], 1.0);
+        assert_eq!(times[1], 3.0);
+        assert_eq!(times[PERCEPTUAL_HASH_FRAMES - 1], 31.0);
+    }
+
+    #[test]
+    fn resolve_name_collisions_applies_the_strategy() {
+        let names = vec![
+            Some("clip_1080p_h264.mp4".to_string()),
+            None,
+            Some("clip_1080p_h264.mp4".to_string()),
+            Some("clip_1080p_h264_2.mp4".to_string()),
+            Some("1".to_string()),
+            Some("1".to_string()),
+        ];
+
+        let resolved = resolve_name_collisions(names.clone(), NamingCollisionStrategy::Suffix);
+        assert_eq!(
+            resolved.unwrap(),
+            vec![
+                Some("clip_1080p_h264.mp4".to_string()),
+                None,
+                Some("clip_1080p_h264_2.mp4".to_string()),
+                Some("clip_1080p_h264_2_2.mp4".to_string()),
+                Some("1".to_string()),
+                Some("1_2".to_string()),
+            ]
+        );
+
+        let rejected = resolve_name_collisions(names, NamingCollisionStrategy::Reject);
+        assert_eq!(
+            rejected.unwrap_err(),
+            "format 2: output name clip_1080p_h264.mp4 collides with an earlier format"
+        );
+    }
+
+    #[test]
+    fn extra_inputs_are_numbered_after_the_source() {
+        let mut format: VideoFormat =
+            serde_json::from_str(r#"{"id": 1, "ext": "mkv", "vcodec": "libx264"}"#).unwrap();
+        let is_chapters = |input: &ExtraInput| matches!(input, ExtraInput::Chapters(_));
+        let is_description = |input: &ExtraInput| matches!(input, ExtraInput::AudioDescription(_));
+
+        format.audio_description_path = Some("track.wav".to_string());
+        assert_eq!(extra_input_index(&format, is_chapters), None);
+        assert_eq!(extra_input_index(&format, is_description), Some(1));
+
+        format.chapters_file = Some("chapters.txt".to_string());
+        assert_eq!(extra_input_index(&format, is_chapters), Some(1));
+        assert_eq!(extra_input_index(&format, is_description), Some(2));
+    }
+
+    #[test]
+    fn build_ffmpeg_command_emits_each_output_once() {
+        let format: VideoFormat = serde_json::from_str(
+            r#"{"id": 1, "ext": "mkv", "vcodec": "libx264", "stream_upload": true, "dest": "ipfs"}"#,
+        )
+        .unwrap();
+
+        for is_gpu in [false, true] {
+            let cmd = build_ffmpeg_command("source.mp4", "source_1", "/tmp/", is_gpu, &format, None)
+                .unwrap();
+            let args: Vec<String> = cmd
+                .get_args()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect();
+
+            assert_eq!(args.iter().filter(|arg| *arg == "-i").count(), 1);
+            assert_eq!(args.iter().filter(|arg| *arg == "pipe:1").count(), 1);
+            assert!(args.ends_with(&["-f", "matroska", "-y", "pipe:1"].map(String::from)));
+        }
+    }
+
+    #[test]
+    fn color_range_is_converted_as_well_as_tagged() {
+        let format = |json: &str| -> VideoFormat {
+            let mut format: VideoFormat = serde_json::from_str(json).unwrap();
+            apply_color_range(&mut format);
+            format
+        };
+
+        let defaults = format(r#"{"id": 1, "ext": "mp4", "vcodec": "libx264"}"#);
+        assert_eq!(defaults.vf.as_deref(), Some("scale=out_range=tv"));
+        assert_eq!(
+            color_args(&defaults),
+            ["-sws_flags", "bicubic", "-color_range", "tv"].map(String::from)
+        );
+
+        let full_range = format(
+            r#"{"id": 1, "ext": "mp4", "vcodec": "libx264", "vf": "scale=1280:720",
+                "color_range": "pc", "scale_flags": "lanczos"}"#,
+        );
+        assert_eq!(
+            full_range.vf.as_deref(),
+            Some("scale=1280:720,scale=out_range=pc")
+        );
+        assert_eq!(
+            color_args(&full_range),
+            ["-sws_flags", "lanczos", "-color_range", "pc"].map(String::from)
+        );
+
+        let explicit = format(
+            r#"{"id": 1, "ext": "mp4", "vcodec": "libx264", "vf": "scale=out_range=pc"}"#,
+        );
+        assert_eq!(explicit.vf.as_deref(), Some("scale=out_range=pc"));
+
+        let gpu = format(
+            r#"{"id": 1, "ext": "mp4", "vcodec": "h264_nvenc", "vf": "scale_cuda=1280:720"}"#,
+        );
+        assert_eq!(gpu.vf.as_deref(), Some("scale_cuda=1280:720"));
+        let downloaded = format(
+            r#"{"id": 1, "ext": "mp4", "vcodec": "h264_nvenc",
+                "vf": "scale_cuda=1280:720,hwdownload,format=nv12"}"#,
+        );
+        assert_eq!(
+            downloaded.vf.as_deref(),
+            Some("scale_cuda=1280:720,hwdownload,format=nv12,scale=out_range=tv")
+        );
+
+        let audio = format(r#"{"id": 1, "ext": "m4a", "c_a": "aac"}"#);
+        assert_eq!(audio.vf, None);
+    }
+
+    #[test]
+    fn output_size_follows_scale_expressions() {
+        let hd = Some((1920, 1080));
+        assert_eq!(output_size("scale=1280:720", None), Some((1280, 720)));
+        assert_eq!(output_size("scale=1280x720", None), Some((1280, 720)));
+        assert_eq!(output_size("scale=w=1280:h=720:flags=lanczos", None), Some((1280, 720)));
+        assert_eq!(output_size("scale=-2:720", None), None);
+        assert_eq!(output_size("scale=-2:720", hd), Some((1280, 720)));
+        assert_eq!(output_size("scale=7680:-1", hd), Some((7680, 4320)));
+        assert_eq!(output_size("scale=iw*4:ih*4", hd), Some((7680, 4320)));
+        assert_eq!(output_size("scale='min(3840,iw)':-2", Some((7680, 4320))), Some((3840, 2160)));
+        assert_eq!(output_size("transpose=clock,scale=iw/2:-2", hd), Some((540, 960)));
+        assert_eq!(output_size("yadif,crop=iw/2:ih,pad=1280:720", hd), Some((1280, 720)));
+        assert_eq!(output_size("scale=out_range=tv", hd), Some((1920, 1080)));
+        assert_eq!(output_size("scale=iw*2:-2,zoompan", hd), None);
+        assert_eq!(output_size("scale=sin(iw):-2", hd), None);
+    }
+
+    #[test]
+    fn an_8k_format_is_clamped_or_rejected_under_a_4k_cap() {
+        let max_pixels = 3840 * 2160;
+        let format = |vf: &str| -> VideoFormat {
+            serde_json::from_str(&format!(
+                r#"{{"id": 1, "ext": "mp4", "vcodec": "libx264", "vf": "{}"}}"#,
+                vf
+            ))
+            .unwrap()
+        };
+
+        let mut clamped = format("scale=7680:-2");
+        cap_output_pixels(&mut clamped, max_pixels, false).unwrap();
+        assert_eq!(
+            clamped.vf,
+            Some(format!("scale=7680:-2,{}", max_pixels_filter(max_pixels)))
+        );
+        assert!(check_output_pixels(&Err(String::new()), &mut clamped, max_pixels).is_ok());
+
+        assert!(cap_output_pixels(&mut format("scale=7680:4320"), max_pixels, true).is_err());
+        let mut rejected = format("scale=7680:-2");
+        cap_output_pixels(&mut rejected, max_pixels, true).unwrap();
+        let source_probe: SourceProbe = serde_json::from_str(
+            r#"{"streams": [{"index": 0, "codec_type": "video", "width": 1920, "height": 1080}],
+                "format": {}}"#,
+        )
+        .unwrap();
+        assert!(check_output_pixels(&Ok(source_probe), &mut rejected, max_pixels).is_err());
+
+        // A size that cannot be worked out is capped by ffmpeg instead
+        let mut unknown = format("scale=7680:-2");
+        check_output_pixels(&Err(String::new()), &mut unknown, max_pixels).unwrap();
+        assert_eq!(
+            unknown.vf,
+            Some(format!("scale=7680:-2,{}", max_pixels_filter(max_pixels)))
+        );
+
+        let mut gpu = format("scale_cuda=7680:4320");
+        assert!(cap_output_pixels(&mut gpu, max_pixels, false).is_err());
+    }
+
+    #[test]
+    fn validate_preset_counts_options_and_rejects_other_lines() {
+        assert_eq!(
+            validate_preset("# x264 tuning\n\ncoder=1\nflags=+loop\n  me_method=hex  \n"),
+            Ok(3)
+        );
+        assert_eq!(validate_preset("b:v=2M\nx264-params=keyint=60"), Ok(2));
+
+        assert!(validate_preset("").is_err());
+        assert!(validate_preset("# only a comment\n").is_err());
+        assert_eq!(
+            validate_preset("coder=1\n-y\n"),
+            Err("line 2 is not of the form option=value".to_string())
+        );
+        assert!(validate_preset("=1").is_err());
+        assert!(validate_preset("coder=").is_err());
+        assert!(validate_preset("co der=1").is_err());
+    }
+
+    #[test]
+    fn quality_mode_fills_in_preset_crf_and_two_pass() {
+        let format = |json: &str| -> VideoFormat {
+            let mut format: VideoFormat = serde_json::from_str(json).unwrap();
+            apply_quality_mode(&mut format);
+            format
+        };
+
+        let defaults = [
+            ("speed", "veryfast", 28),
+            ("balanced", "medium", 23),
+            ("quality", "slow", 20),
+        ];
+        for (mode, preset, crf) in defaults {
+            let crf_format = format(&format!(
+                r#"{{"id": 1, "ext": "mp4", "vcodec": "libx264", "quality_mode": "{}"}}"#,
+                mode
+            ));
+            assert_eq!(crf_format.preset.as_deref(), Some(preset));
+            assert_eq!(crf_format.crf, Some(crf));
+            assert!(!is_two_pass(&crf_format, false));
+
+            let bitrate_format = format(&format!(
+                r#"{{"id": 1, "ext": "mp4", "vcodec": "libx264", "b_v": "2M",
+                    "quality_mode": "{}"}}"#,
+                mode
+            ));
+            assert_eq!(bitrate_format.crf, None);
+            assert_eq!(is_two_pass(&bitrate_format, false), mode == "quality");
+            assert!(!is_two_pass(&bitrate_format, true));
+        }
+
+        let explicit = format(
+            r#"{"id": 1, "ext": "mp4", "vcodec": "libx264", "b_v": "2M", "preset": "fast",
+                "two_pass": false, "quality_mode": "quality"}"#,
+        );
+        assert_eq!(explicit.preset.as_deref(), Some("fast"));
+        assert!(!is_two_pass(&explicit, false));
+
+        let vp9 =
+            format(r#"{"id": 1, "ext": "webm", "vcodec": "libvpx-vp9", "quality_mode": "speed"}"#);
+        assert_eq!(vp9.preset, None);
+        assert_eq!(vp9.crf, Some(37));
+    }
+
+    #[test]
+    fn ll_hls_forces_a_keyframe_at_every_part() {
+        let format: VideoFormat = serde_json::from_str(
+            r#"{"id": 1, "ext": "ts", "vcodec": "libx264", "packaging": "ll-hls",
+                "ll_hls": {"segment_secs": 4, "part_secs": 0.5}}"#,
+        )
+        .unwrap();
+        assert!(validate_ll_hls(&format, true).is_ok());
+        assert!(is_hls_packaged(&format));
+
+        let args = keyint_args(&format);
+        assert_eq!(
+            args,
+            ["-force_key_frames", "expr:gte(t,n_forced*0.5)"].map(String::from)
+        );
+    }
+
+    #[test]
+    fn ll_hls_part_secs_must_divide_segment_secs() {
+        let format = |ll_hls: &str| -> VideoFormat {
+            serde_json::from_str(&format!(
+                r#"{{"id": 1, "ext": "ts", "vcodec": "libx264", "packaging": "ll-hls",
+                    "ll_hls": {}}}"#,
+                ll_hls
+            ))
+            .unwrap()
+        };
+
+        assert!(validate_ll_hls(&format(r#"{}"#), true).is_ok());
+        assert!(validate_ll_hls(&format(r#"{"segment_secs": 6, "part_secs": 0.25}"#), true).is_ok());
+        assert!(validate_ll_hls(&format(r#"{"segment_secs": 6, "part_secs": 0.4}"#), true).is_ok());
+        assert!(validate_ll_hls(&format(r#"{"segment_secs": 6, "part_secs": 0.7}"#), true).is_err());
+        assert!(validate_ll_hls(&format(r#"{"segment_secs": 2, "part_secs": 3}"#), true).is_err());
+        assert!(validate_ll_hls(&format(r#"{"part_secs": 0.1}"#), true).is_err());
+        assert!(validate_ll_hls(&format(r#"{}"#), false).is_err());
+
+        let without_packaging: VideoFormat =
+            serde_json::from_str(r#"{"id": 1, "ext": "ts", "ll_hls": {"part_secs": 1}}"#).unwrap();
+        assert!(validate_ll_hls(&without_packaging, true).is_err());
+    }
+
+    #[test]
+    fn build_ll_hls_playlist_lists_parts_as_byte_ranges_of_their_segment() {
+        let segments = vec![
+            ("https://cdn/0".to_string(), vec![(1.0, 100), (1.0005, 150)]),
+            ("https://cdn/1".to_string(), vec![(0.5, 80)]),
+        ];
+        let playlist = build_ll_hls_playlist(&segments, 1.0);
+
+        assert_eq!(
+            playlist,
+            "#EXTM3U\n#EXT-X-VERSION:6\n#EXT-X-TARGETDURATION:3\n\
+             #EXT-X-PART-INF:PART-TARGET=1.001\n#EXT-X-MEDIA-SEQUENCE:0\n\
+             #EXT-X-PLAYLIST-TYPE:VOD\n#EXT-X-INDEPENDENT-SEGMENTS\n\
+             #EXT-X-PART:DURATION=1.000000,URI=\"https://cdn/0\",BYTERANGE=\"100@0\",INDEPENDENT=YES\n\
+             #EXT-X-PART:DURATION=1.000500,URI=\"https://cdn/0\",BYTERANGE=\"150@100\",INDEPENDENT=YES\n\
+             #EXTINF:2.000500,\nhttps://cdn/0\n\
+             #EXT-X-PART:DURATION=0.500000,URI=\"https://cdn/1\",BYTERANGE=\"80@0\",INDEPENDENT=YES\n\
+             #EXTINF:0.500000,\nhttps://cdn/1\n\
+             #EXT-X-ENDLIST\n"
+        );
+    }
+
+    #[test]
+    fn check_playlist_key_needs_the_method_uri_and_iv_before_the_first_segment() {
+        let playlist = "#EXTM3U\n#EXT-X-VERSION:3\n\
+                        #EXT-X-KEY:METHOD=AES-128,URI=\"https://keys.example.com/1\",IV=0x0a0b\n\
+                        #EXTINF:6.0,\nsegment_00000.ts\n#EXT-X-ENDLIST\n";
+        assert!(check_playlist_key(playlist, "aes-128").is_ok());
+        assert!(check_playlist_key(playlist, "sample-aes").is_err());
+
+        let late_key = "#EXTM3U\n#EXTINF:6.0,\nsegment_00000.ts\n\
+                        #EXT-X-KEY:METHOD=AES-128,URI=\"key\",IV=0x0a0b\n";
+        assert!(check_playlist_key(late_key, "aes-128").is_err());
+        let no_iv = "#EXTM3U\n#EXT-X-KEY:METHOD=AES-128,URI=\"key\"\n#EXTINF:6.0,\na.ts\n";
+        assert!(check_playlist_key(no_iv, "aes-128").is_err());
+    }
+
+    #[test]
+    fn add_sample_aes_key_precedes_the_first_segment() {
+        let playlist = "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:6\n\
+                        #EXTINF:6.0,\nsegment_00000.ts\n#EXTINF:6.0,\nsegment_00001.ts\n";
+        let with_key = add_sample_aes_key(playlist, "https://keys.example.com/1", &[0xab; 16]);
+
+        assert!(check_playlist_key(&with_key, "sample-aes").is_ok());
+        assert!(with_key.contains("#EXT-X-VERSION:5\n"));
+        assert!(with_key.contains(&format!("IV=0x{}", "ab".repeat(16))));
+        assert_eq!(with_key.matches("#EXT-X-KEY").count(), 1);
+        assert_eq!(playlist_segments(&with_key), playlist_segments(playlist));
+    }
+
+    #[test]
+    fn replace_playlist_segments_replaces_whole_segment_lines() {
+        // The first segment's name is also part of the second's, and of a tag
+        let playlist = "#EXTM3U\n#EXT-X-MAP:URI=\"a.ts\"\n#EXTINF:6.0,\na.ts\n#EXTINF:6.0,\nfa.ts\n";
+        let urls = vec!["https://cdn/1".to_string(), "https://cdn/2".to_string()];
+
+        assert_eq!(
+            replace_playlist_segments(playlist, &urls),
+            "#EXTM3U\n#EXT-X-MAP:URI=\"a.ts\"\n#EXTINF:6.0,\nhttps://cdn/1\n#EXTINF:6.0,\n\
+             https://cdn/2\n"
+        );
+    }
+
+    fn ts_packet(pid: u16, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x47, 0x40 | (pid >> 8) as u8, pid as u8, 0x10];
+        packet.extend_from_slice(payload);
+        packet.resize(TS_PACKET_SIZE, 0xff);
+        packet
+    }
+
+    #[test]
+    fn keyframe_ranges_start_at_the_tables_before_each_keyframe() {
+        // Program 1 with its PMT on PID 0x1000
+        let pat = [0, 0, 0xb0, 0x0d, 0, 1, 0xc1, 0, 0, 0, 1, 0xf0, 0x00, 0, 0, 0, 0];
+        let pmt = ts_packet(0x1000, &[0, 2]);
+        let video = ts_packet(0x100, &[]);
+        let audio = ts_packet(0x101, &[]);
+        let segment: Vec<u8> = [
+            ts_packet(TS_PAT_PID, &pat),
+            pmt.clone(),
+            video.clone(),
+            audio.clone(),
+            video.clone(),
+            ts_packet(TS_PAT_PID, &pat),
+            pmt,
+            video.clone(),
+            audio.clone(),
+            video,
+            audio,
+        ]
+        .concat();
+        let packet = |index: u64| index * TS_PACKET_SIZE as u64;
+        let packets = [
+            (0.0, packet(2), true),
+            (0.5, packet(4), false),
+            (1.0, packet(7), true),
+            // Not preceded by the tables, as the packet before it is audio
+            (1.5, packet(9), true),
+        ];
+
+        assert_eq!(
+            keyframe_ranges(&packets, &segment),
+            vec![
+                Keyframe { time: 0.0, offset: 0, length: packet(4) },
+                Keyframe { time: 1.0, offset: packet(5), length: packet(4) },
+                Keyframe { time: 1.5, offset: packet(9), length: packet(2) },
+            ]
+        );
+    }
+
+    #[test]
+    fn build_iframe_playlist_lists_a_byte_range_per_keyframe() {
+        let keyframe = |time: f64, offset: u64, length: u64| Keyframe { time, offset, length };
+        let segments = vec![
+            ("https://cdn/1".to_string(), vec![keyframe(10.0, 0, 1000), keyframe(12.5, 5000, 800)]),
+            ("https://cdn/2".to_string(), vec![keyframe(16.0, 0, 900)]),
+        ];
+
+        assert_eq!(
+            build_iframe_playlist(&segments, 18.0),
+            "#EXTM3U\n#EXT-X-VERSION:4\n#EXT-X-TARGETDURATION:4\n#EXT-X-MEDIA-SEQUENCE:0\n\
+             #EXT-X-PLAYLIST-TYPE:VOD\n#EXT-X-I-FRAMES-ONLY\n\
+             #EXTINF:2.500000,\n#EXT-X-BYTERANGE:1000@0\nhttps://cdn/1\n\
+             #EXTINF:3.500000,\n#EXT-X-BYTERANGE:800@5000\nhttps://cdn/1\n\
+             #EXTINF:2.000000,\n#EXT-X-BYTERANGE:900@0\nhttps://cdn/2\n\
+             #EXT-X-ENDLIST\n"
+        );
+    }
+}
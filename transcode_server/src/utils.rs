@@ -1,23 +1,26 @@
-use dotenv::{dotenv, var};
+use crate::config::var;
 
-use base64::{engine::general_purpose, DecodeError, Engine as _};
+use base64::{engine::general_purpose, Engine as _};
 
-use tonic::{transport::Server, Code, Request, Response, Status};
+use tonic::{Code, Status};
 
 use serde::{Deserialize, Serialize};
-use serde_json;
+use serde_json::Value;
 
 use std::error::Error;
 use std::fs::metadata;
-use std::fs::{File, OpenOptions};
+use std::fs::OpenOptions;
 use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use tokio::fs;
 use tokio::io::AsyncReadExt;
 
 use sanitize_filename::sanitize;
 
-use crate::s5::download_file;
+use crate::error_code::is_transient_code;
+use crate::s5::{check_connected_address, checked_request, download_file, DownloadError};
 
 pub fn bytes_to_base64url(bytes: &[u8]) -> String {
     let engine = general_purpose::STANDARD_NO_PAD;
@@ -77,27 +80,298 @@ pub fn hash_bytes_to_cid(hash: Vec<u8>, file_size: u64) -> Vec<u8> {
 pub async fn download_video(url: &str, file_path: &str) -> Result<(), Status> {
     println!(" {}", url);
 
-    match download_file(url, file_path) {
+    match download_blocking(url, file_path, DOWNLOAD_STALL_TIMEOUT).await {
         Ok(()) => println!("File downloaded successfully"),
         Err(e) => {
-            eprintln!("Error downloading file: {}", e);
-            return Err(Status::new(
-                Code::Internal,
-                format!("Error downloading file: {}", e),
-            ));
+            eprintln!("Error downloading file: {}", e.message());
+            return Err(e);
         }
     }
 
     Ok(())
 }
 
+// How long a whole-file download waits for more data before failing, reqwest's default timeout
+const DOWNLOAD_STALL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+// Set when dropped, so a blocking download the task stopped waiting for, such as when the task
+// was cancelled, stops at its next read and removes its partial file
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Downloads `url` to `file_path` on a blocking thread, as the download blocks and sleeps while
+/// throttled. The download fails with `Code::DeadlineExceeded` if no data arrives for
+/// `stall_timeout`, and is stopped if the returned future is dropped before it finishes.
+///
+/// # Arguments
+///
+/// * `url` - The URL to download.
+/// * `file_path` - Where to save it.
+/// * `stall_timeout` - How long to wait for more data.
+///
+async fn download_blocking(
+    url: &str,
+    file_path: &str,
+    stall_timeout: std::time::Duration,
+) -> Result<(), Status> {
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let _cancel_on_drop = CancelOnDrop(cancelled.clone());
+
+    let url = url.to_string();
+    let path = file_path.to_string();
+    let download =
+        tokio::task::spawn_blocking(move || download_file(&url, &path, stall_timeout, &cancelled));
+
+    match download.await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(download_error_status(&e)),
+        Err(e) => Err(Status::new(
+            Code::Internal,
+            format!("Download task failed: {}", e),
+        )),
+    }
+}
+
+/// Converts a failed download to a status whose code tells whether it is worth retrying:
+/// `DeadlineExceeded` for a stalled download, `Unavailable` for other network failures and server
+/// errors, `PermissionDenied` for a rejected URL, `NotFound` for a missing file and
+/// `FailedPrecondition` for any other client error.
+fn download_error_status(error: &DownloadError) -> Status {
+    let code = match error {
+        DownloadError::Stalled(_) => Code::DeadlineExceeded,
+        _ if error.is_transient() => Code::Unavailable,
+        DownloadError::Rejected(_) => Code::PermissionDenied,
+        DownloadError::Status(status, _) if *status == reqwest::StatusCode::NOT_FOUND => {
+            Code::NotFound
+        }
+        _ => Code::FailedPrecondition,
+    };
+    Status::new(code, format!("Error downloading file: {}", error))
+}
+
+// Base delay before the first retry of a failed part download or POST; doubled on each further
+// retry
+const RETRY_BASE_DELAY: std::time::Duration = std::time::Duration::from_millis(500);
+const RETRY_MAX_DELAY: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Computes the delay before retry `attempt` (0 for the first retry) as exponential backoff with
+/// jitter: a random delay between half and all of `base_delay * 2^attempt`, capped at
+/// `max_delay`. The jitter spreads out retries from concurrent tasks so they do not hit a
+/// recovering gateway in lockstep. Every retry loop backs off with it.
+///
+/// # Arguments
+///
+/// * `base_delay` - The delay before the first retry, before jitter.
+/// * `max_delay` - The longest delay, before jitter.
+/// * `attempt` - The zero-based retry number.
+/// * `random` - A random number in `[0, 1)` selecting the point within the jitter window.
+///
+pub fn jittered_backoff(
+    base_delay: std::time::Duration,
+    max_delay: std::time::Duration,
+    attempt: u32,
+    random: f64,
+) -> std::time::Duration {
+    let delay = base_delay
+        .saturating_mul(2_u32.saturating_pow(attempt))
+        .min(max_delay);
+
+    delay / 2 + delay.mul_f64(random.clamp(0.0, 1.0) / 2.0)
+}
+
+/// Downloads a part, retrying up to `PART_DOWNLOAD_RETRIES` times (default 3) with jittered
+/// exponential backoff between attempts if it fails transiently. An attempt fails once no data has
+/// arrived for `PART_DOWNLOAD_TIMEOUT_SECS` (default 300), so a stalled connection is retried
+/// rather than blocking the task while a slow but steady one is left to finish. Attempts write to
+/// their own temporary file, which is removed if the attempt fails and renamed to `file_path` on
+/// success. Any left by an earlier process that stopped mid-download are removed first.
+///
+/// # Arguments
+///
+/// * `url` - The URL of the part.
+/// * `file_path` - Where to save the part.
+///
+async fn download_part_with_retry(url: &str, file_path: &str) -> Result<(), Status> {
+    let retries = var("PART_DOWNLOAD_RETRIES")
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(3);
+
+    let stall_timeout = std::time::Duration::from_secs(
+        var("PART_DOWNLOAD_TIMEOUT_SECS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(300),
+    );
+
+    for attempt in 0..=retries {
+        let _ = std::fs::remove_file(format!("{}.attempt{}", file_path, attempt));
+    }
+
+    let mut attempt = 0;
+    loop {
+        let attempt_path = format!("{}.attempt{}", file_path, attempt);
+        let result = download_blocking(url, &attempt_path, stall_timeout)
+            .await
+            .and_then(|()| {
+                std::fs::rename(&attempt_path, file_path).map_err(|e| {
+                    Status::new(
+                        Code::Internal,
+                        format!("Error moving downloaded part {}: {}", attempt_path, e),
+                    )
+                })
+            });
+
+        match result {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < retries && is_transient_code(e.code()) => {
+                let delay = jittered_backoff(
+                    RETRY_BASE_DELAY,
+                    RETRY_MAX_DELAY,
+                    attempt,
+                    rand::random::<f64>(),
+                );
+                eprintln!(
+                    "Download of part {} failed ({}), retrying in {:?}",
+                    url,
+                    e.message(),
+                    delay
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The parts appended so far to a file being assembled by `download_and_concat_files`, recorded
+/// next to it so a restart resumes with the next part.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ConcatProgress {
+    appended_parts: Vec<String>,
+    // Size of the file once the appended parts were written
+    size: u64,
+}
+
+fn concat_progress_path(file_path: &str) -> String {
+    format!("{}.progress.json", file_path)
+}
+
+/// Loads the recorded progress of assembling `file_path` from `parts` and truncates the file to
+/// the size it had after the last recorded part, discarding a part that was only partly appended.
+/// Without usable progress, e.g. when the file is shorter than recorded or the recorded parts do
+/// not start the list of `parts`, the file is truncated to start again from the first part.
+///
+/// # Arguments
+///
+/// * `file_path` - The file being assembled.
+/// * `parts` - The URLs of the parts in the order they are appended.
+///
+fn load_concat_progress(file_path: &str, parts: &[&String]) -> std::io::Result<ConcatProgress> {
+    let progress = std::fs::read_to_string(concat_progress_path(file_path))
+        .ok()
+        .and_then(|contents| serde_json::from_str::<ConcatProgress>(&contents).ok())
+        .filter(|progress| {
+            progress.appended_parts.len() <= parts.len()
+                && progress
+                    .appended_parts
+                    .iter()
+                    .zip(parts)
+                    .all(|(appended, part)| appended == *part)
+        })
+        .filter(|progress| metadata(file_path).is_ok_and(|m| m.len() >= progress.size))
+        .unwrap_or_default();
+
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(file_path)?
+        .set_len(progress.size)?;
+
+    if !progress.appended_parts.is_empty() {
+        println!(
+            "Resuming {} after {} appended parts",
+            file_path,
+            progress.appended_parts.len()
+        );
+    }
+    Ok(progress)
+}
+
+/// Records the progress of assembling `file_path`. Written to a temporary file first so a crash
+/// never leaves a truncated record.
+fn save_concat_progress(file_path: &str, progress: &ConcatProgress) -> std::io::Result<()> {
+    let progress_path = concat_progress_path(file_path);
+    let tmp_progress_path = format!("{}.tmp", progress_path);
+    std::fs::write(&tmp_progress_path, serde_json::to_string(progress)?)?;
+    std::fs::rename(&tmp_progress_path, &progress_path)
+}
+
+/// Checks that an assembled file is `expected_size` bytes, removing it if it is not so a retry
+/// assembles it again.
+///
+/// # Arguments
+///
+/// * `file_path` - The assembled file.
+/// * `expected_size` - The size recorded for the file.
+///
+fn check_assembled_size(file_path: &str, expected_size: u64) -> Result<(), String> {
+    let file_size = std::fs::metadata(file_path)
+        .map_err(|e| format!("Failed to read the size of {}: {}", file_path, e))?
+        .len();
+    if file_size != expected_size {
+        let _ = std::fs::remove_file(file_path);
+        return Err(format!(
+            "Concatenated file {} is {} bytes but its recorded size is {} bytes",
+            file_path, file_size, expected_size
+        ));
+    }
+    Ok(())
+}
+
+/// Downloads the parts listed in an encrypted source's locations metadata and appends them to
+/// `file_path` in order. Progress is recorded after each part, so if the process restarts the
+/// next call for the same `file_path` resumes with the next part. The record is removed once the
+/// file is complete. If the size of the file is known, the assembled file is checked against it
+/// and removed on a mismatch, so a retry assembles it again.
+///
+/// # Arguments
+///
+/// * `data` - The locations metadata JSON.
+/// * `file_path` - Where to assemble the file.
+/// * `expected_size` - The size of the assembled file, as recorded in the source's CID, if known.
+///
 pub async fn download_and_concat_files(
     data: String,
     file_path: String,
+    expected_size: Option<u64>,
 ) -> Result<(), Box<dyn Error>> {
     // Parse the JSON data
     let json_data: JsonData = serde_json::from_str(&data)?;
 
+    // Every part except the last part of the last location
+    let mut parts: Vec<&String> = json_data
+        .locations
+        .iter()
+        .flat_map(|location| location.parts.iter())
+        .collect();
+    if json_data
+        .locations
+        .last()
+        .is_some_and(|location| !location.parts.is_empty())
+    {
+        parts.pop();
+    }
+
+    let mut progress = load_concat_progress(&file_path, &parts)?;
+
     // Open the final file
     let mut final_file = OpenOptions::new()
         .create(true)
@@ -105,46 +379,278 @@ pub async fn download_and_concat_files(
         .open(&file_path)
         .expect("Failed to open final_file");
 
-    for (location_index, location) in json_data.locations.iter().enumerate() {
-        let last_part_index = location.parts.len() - 1;
-        for (part_index, part) in location.parts.iter().enumerate() {
-            if location_index == json_data.locations.len() - 1 && part_index == last_part_index {
-                continue;
-            }
+    for part in parts.iter().skip(progress.appended_parts.len()) {
+        println!("download_and_concat_files part: {}", part);
 
-            println!("download_and_concat_files part: {}", part);
+        let path_to_file = var("PATH_TO_FILE").unwrap();
+        let tmp_file_path = path_to_file.to_owned() + &sanitize(part.as_str());
 
-            let path_to_file = var("PATH_TO_FILE").unwrap();
-            let tmp_file_path = String::from(path_to_file.to_owned() + &sanitize(part.as_str()));
+        download_part_with_retry(part, tmp_file_path.as_str()).await?;
 
-            download_video(&part, tmp_file_path.as_str()).await?;
+        // Skipping the part would assemble a file with a gap in it
+        let mut downloaded_file = fs::File::open(&tmp_file_path)
+            .await
+            .map_err(|e| format!("Failed to open downloaded part {}: {}", &tmp_file_path, e))?;
+        let mut buffer = Vec::new();
+        downloaded_file.read_to_end(&mut buffer).await?;
 
-            let mut downloaded_file = match fs::File::open(&tmp_file_path).await {
-                Ok(file) => file,
-                Err(e) => {
-                    eprintln!("Failed to open downloaded file {}: {}", &tmp_file_path, e);
-                    continue;
-                }
+        println!("Size of buffer: {}", buffer.len());
+
+        // Append the content to the final file
+        final_file.write_all(&buffer)?;
+
+        let file_size = metadata(&file_path)?.len();
+        println!("Size of final file: {} bytes", file_size);
+
+        // Delete the downloaded file
+        std::fs::remove_file(tmp_file_path)?;
+
+        progress.appended_parts.push(part.to_string());
+        progress.size = file_size;
+        save_concat_progress(&file_path, &progress)?;
+    }
+
+    let _ = std::fs::remove_file(concat_progress_path(&file_path));
+
+    if let Some(expected_size) = expected_size {
+        check_assembled_size(&file_path, expected_size)?;
+    }
+
+    Ok(())
+}
+
+/// POSTs a JSON body to `url`, retrying up to `retries` times with jittered exponential backoff
+/// when the request fails or the response has a non-success status. Each attempt runs on a
+/// blocking thread.
+///
+/// # Arguments
+///
+/// * `url` - The URL to POST to.
+/// * `body` - The JSON body.
+/// * `headers` - Extra headers to send, as (name, value) pairs; a `Content-Type` among them
+///   replaces the JSON one.
+/// * `retries` - How many times to retry a failed request.
+///
+pub async fn post_json_with_retry(
+    url: &str,
+    body: &Value,
+    headers: &[(String, String)],
+    retries: u32,
+) -> Result<(), String> {
+    let body = serde_json::to_vec(body).map_err(|e| format!("Error serializing body: {}", e))?;
+    post_serialized_json_with_retry(url, body, headers, retries, false).await
+}
+
+/// POSTs an already serialized JSON body like `post_json_with_retry`, so the caller can sign the
+/// exact bytes sent.
+///
+/// # Arguments
+///
+/// * `url` - The URL to POST to.
+/// * `body` - The serialized JSON body.
+/// * `headers` - Extra headers to send, as for `post_json_with_retry`.
+/// * `retries` - How many times to retry a failed request.
+/// * `checked` - Whether `url` is a client's, to be checked and connected to with
+///   `s5::checked_request` like a download URL. Redirects of a checked URL are not followed and
+///   fail the request.
+///
+pub async fn post_serialized_json_with_retry(
+    url: &str,
+    body: Vec<u8>,
+    headers: &[(String, String)],
+    retries: u32,
+    checked: bool,
+) -> Result<(), String> {
+    let mut attempt = 0;
+    loop {
+        let request_url = url.to_string();
+        let request_body = body.clone();
+        let request_headers = headers.to_vec();
+        let result = tokio::task::spawn_blocking(move || {
+            let mut header_map = reqwest::header::HeaderMap::new();
+            for (name, value) in &request_headers {
+                let name = reqwest::header::HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|e| format!("Invalid header name {}: {}", name, e))?;
+                let value = reqwest::header::HeaderValue::from_str(value)
+                    .map_err(|e| format!("Invalid value for header {}: {}", name, e))?;
+                header_map.append(name, value);
+            }
+
+            let (request, checked_address, parsed_url) = if checked {
+                let client = reqwest::Client::builder()
+                    .redirect(reqwest::RedirectPolicy::none())
+                    .build()
+                    .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
+                let parsed_url = reqwest::Url::parse(&request_url)
+                    .map_err(|e| format!("Invalid URL {}: {}", request_url, e))?;
+                let (request, checked_address) =
+                    checked_request(&client, reqwest::Method::POST, &parsed_url)?;
+                (request, checked_address, Some(parsed_url))
+            } else {
+                (reqwest::Client::new().post(&request_url), None, None)
             };
-            let mut buffer = Vec::new();
-            downloaded_file.read_to_end(&mut buffer).await?;
 
-            println!("Size of buffer: {}", buffer.len());
+            // Replaces the JSON content type if the headers set their own
+            let response = request
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(request_body)
+                .headers(header_map)
+                .send()
+                .map_err(|e| format!("POST to {} failed: {}", request_url, e))?;
+            if let Some(parsed_url) = &parsed_url {
+                check_connected_address(&response, parsed_url, checked_address)?;
+            }
+            if response.status().is_success() {
+                Ok(())
+            } else {
+                Err(format!(
+                    "POST to {} returned {}",
+                    request_url,
+                    response.status()
+                ))
+            }
+        })
+        .await
+        .unwrap_or_else(|e| Err(format!("POST task failed: {}", e)));
+
+        match result {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < retries => {
+                let delay = jittered_backoff(
+                    RETRY_BASE_DELAY,
+                    RETRY_MAX_DELAY,
+                    attempt,
+                    rand::random::<f64>(),
+                );
+                eprintln!("{}, retrying in {:?}", e, delay);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
 
-            // Append the content to the final file
-            final_file.write_all(&buffer)?;
+/// Checks that an HTTP endpoint is reachable within `timeout`. Any HTTP response, including an
+/// error status, counts as reachable; only connection failures and timeouts are reported.
+///
+/// # Arguments
+///
+/// * `url` - The URL to request.
+/// * `timeout` - How long to wait for a response.
+///
+pub fn check_endpoint_reachable(url: &str, timeout: std::time::Duration) -> Result<(), String> {
+    let client = reqwest::Client::builder()
+        .timeout(timeout)
+        .build()
+        .map_err(|e| format!("Failed to build HTTP client: {}", e))?;
+
+    client
+        .get(url)
+        .send()
+        .map(|response| println!("{} responded with status {}", url, response.status()))
+        .map_err(|e| format!("{} is unreachable: {}", url, e))
+}
 
-            let file_size = metadata(&file_path)?.len();
-            println!("Size of final file: {} bytes", file_size);
+/// Checks that each of `paths` is on a filesystem with at least `min_free_bytes` available.
+///
+/// # Arguments
+///
+/// * `paths` - Directories whose filesystems are checked.
+/// * `min_free_bytes` - The minimum free space required on each filesystem.
+///
+pub fn check_free_disk_space(paths: &[&str], min_free_bytes: u64) -> Result<(), String> {
+    check_free_disk_space_with(paths, min_free_bytes, |path| fs2::available_space(path))
+}
 
-            // Delete the downloaded file
-            std::fs::remove_file(tmp_file_path)?;
+/// Like `check_free_disk_space`, but queries free space with `available_space` so the disk query
+/// can be substituted.
+///
+pub fn check_free_disk_space_with<F>(
+    paths: &[&str],
+    min_free_bytes: u64,
+    available_space: F,
+) -> Result<(), String>
+where
+    F: Fn(&str) -> std::io::Result<u64>,
+{
+    for path in paths {
+        let available = available_space(path)
+            .map_err(|e| format!("Failed to query free disk space of {}: {}", path, e))?;
+
+        if available < min_free_bytes {
+            return Err(format!(
+                "Insufficient disk space on {}: {} bytes free, {} required",
+                path, available, min_free_bytes
+            ));
         }
     }
 
     Ok(())
 }
 
+/// Returns whether a boolean setting is on: `true`, `1`, `yes` or `on`, in any case. Unset, empty
+/// and any other value are off.
+///
+/// # Arguments
+/// * `key` - The name of the setting.
+///
+pub fn env_flag(key: &str) -> bool {
+    var(key).is_ok_and(|v| {
+        matches!(
+            v.trim().to_ascii_lowercase().as_str(),
+            "true" | "1" | "yes" | "on"
+        )
+    })
+}
+
+/// Returns the files in a directory and in all of its subdirectories, however deeply nested.
+/// Subdirectories that cannot be read are logged and skipped.
+///
+/// # Arguments
+/// * `directory` - The directory to list.
+///
+pub fn list_files_recursive(directory: &str) -> std::io::Result<Vec<std::fs::DirEntry>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![std::fs::read_dir(directory)?];
+    while let Some(entries) = dirs.pop() {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                match std::fs::read_dir(&path) {
+                    Ok(entries) => dirs.push(entries),
+                    Err(e) => eprintln!("Failed to read directory {}: {}", path.display(), e),
+                }
+            } else if path.is_file() {
+                files.push(entry);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Returns the files in a directory and in its immediate subdirectories, which hold the
+/// renditions written with `UNIQUE_OUTPUT_DIRS`.
+///
+/// # Arguments
+/// * `directory` - The directory to list.
+///
+pub fn list_output_files(directory: &str) -> std::io::Result<Vec<std::fs::DirEntry>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(directory)?.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            match std::fs::read_dir(&path) {
+                Ok(entries) => files.extend(entries.flatten().filter(|e| e.path().is_file())),
+                Err(e) => eprintln!("Failed to read directory {}: {}", path.display(), e),
+            }
+        } else if path.is_file() {
+            files.push(entry);
+        }
+    }
+    Ok(files)
+}
+
 #[derive(Debug, Deserialize)]
 struct Location {
     parts: Vec<String>,
@@ -154,3 +660,86 @@ struct Location {
 struct JsonData {
     locations: Vec<Location>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn jittered_backoff_falls_within_half_to_all_of_the_exponential_delay() {
+        let base = std::time::Duration::from_millis(500);
+        let max = std::time::Duration::from_secs(30);
+        for attempt in 0..8 {
+            let delay = base * 2_u32.pow(attempt);
+            let delay = delay.min(max);
+            for random in [0.0, 0.25, 0.5, 0.999] {
+                let backoff = jittered_backoff(base, max, attempt, random);
+                assert!(backoff >= delay / 2 && backoff <= delay, "{:?}", backoff);
+            }
+            assert_eq!(jittered_backoff(base, max, attempt, 0.0), delay / 2);
+        }
+        assert_eq!(
+            jittered_backoff(base, max, 3, 0.5),
+            std::time::Duration::from_millis(3000)
+        );
+        assert_eq!(jittered_backoff(base, max, 100, 1.0), max);
+    }
+
+    #[test]
+    fn an_assembled_file_of_the_wrong_size_is_removed() {
+        let file_path = std::env::temp_dir()
+            .join(format!("assembled_{}", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        std::fs::write(&file_path, b"0123456789").unwrap();
+
+        assert!(check_assembled_size(&file_path, 10).is_ok());
+        assert!(std::path::Path::new(&file_path).exists());
+
+        let e = check_assembled_size(&file_path, 12).unwrap_err();
+        assert!(e.contains("is 10 bytes but its recorded size is 12 bytes"), "{}", e);
+        assert!(!std::path::Path::new(&file_path).exists());
+    }
+
+    #[tokio::test]
+    async fn a_stalled_part_download_is_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::env::set_var("PORTAL_URL", format!("http://127.0.0.1:{}", port));
+        std::env::set_var("PART_DOWNLOAD_TIMEOUT_SECS", "1");
+        std::env::set_var("PART_DOWNLOAD_RETRIES", "1");
+
+        // The first request gets no response at all, the second gets the part
+        std::thread::spawn(move || {
+            for (index, stream) in listener.incoming().take(2).enumerate() {
+                let mut stream = stream.unwrap();
+                std::thread::spawn(move || {
+                    let mut request = [0; 1024];
+                    let _ = stream.read(&mut request);
+                    if index == 0 {
+                        std::thread::sleep(std::time::Duration::from_secs(5));
+                    } else {
+                        let _ = stream.write_all(
+                            b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\nConnection: close\r\n\r\npart",
+                        );
+                    }
+                });
+            }
+        });
+
+        let file_path = std::env::temp_dir()
+            .join(format!("stalled_part_{}", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let url = format!("http://127.0.0.1:{}/part", port);
+        let started = std::time::Instant::now();
+        download_part_with_retry(&url, &file_path).await.unwrap();
+
+        assert_eq!(std::fs::read(&file_path).unwrap(), b"part");
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+        assert!(!std::path::Path::new(&format!("{}.attempt0", file_path)).exists());
+        let _ = std::fs::remove_file(&file_path);
+    }
+}
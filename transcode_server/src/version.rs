@@ -0,0 +1,79 @@
+use crate::capabilities::{run_ffmpeg_query, FFMPEG_CAPS};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+// Encoders the media formats commonly use, reported by `/version` when ffmpeg supports them
+const KNOWN_ENCODERS: [&str; 14] = [
+    "libx264",
+    "libx265",
+    "h264_nvenc",
+    "hevc_nvenc",
+    "av1_nvenc",
+    "libsvtav1",
+    "libaom-av1",
+    "libvpx-vp9",
+    "prores_ks",
+    "aac",
+    "libfdk_aac",
+    "libopus",
+    "libmp3lame",
+    "flac",
+];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    pub version: String,
+    pub git_commit: String,
+    pub ffmpeg_version: String,
+    // Features ffmpeg was configured with, e.g. "libx264"
+    pub ffmpeg_enabled: Vec<String>,
+    pub encoders: Vec<String>,
+    pub hwaccels: Vec<String>,
+}
+
+/// Version and build information, detected from ffmpeg on first use. `main` forces it at startup so
+/// `/version` reports what was available when the server started.
+pub static BUILD_INFO: Lazy<BuildInfo> = Lazy::new(|| BuildInfo {
+    version: env!("CARGO_PKG_VERSION").to_string(),
+    git_commit: env!("GIT_COMMIT").to_string(),
+    ffmpeg_version: FFMPEG_CAPS
+        .as_ref()
+        .map(|caps| caps.version_line.clone())
+        .unwrap_or_else(|| "unavailable".to_string()),
+    ffmpeg_enabled: FFMPEG_CAPS
+        .as_ref()
+        .map(|caps| caps.enabled.clone())
+        .unwrap_or_default(),
+    encoders: ffmpeg_encoders(),
+    hwaccels: ffmpeg_hwaccels(),
+});
+
+/// Returns the encoders in `KNOWN_ENCODERS` that ffmpeg was built with.
+fn ffmpeg_encoders() -> Vec<String> {
+    let available = match FFMPEG_CAPS.as_ref() {
+        Some(caps) => &caps.encoders,
+        None => return Vec::new(),
+    };
+
+    KNOWN_ENCODERS
+        .iter()
+        .filter(|encoder| available.iter().any(|name| name == *encoder))
+        .map(|encoder| encoder.to_string())
+        .collect()
+}
+
+/// Returns the hardware acceleration methods listed by `ffmpeg -hwaccels`, e.g. "cuda".
+fn ffmpeg_hwaccels() -> Vec<String> {
+    let output = match run_ffmpeg_query(&["-hide_banner", "-hwaccels"]) {
+        Some(output) => output,
+        None => return Vec::new(),
+    };
+
+    output
+        .lines()
+        .skip(1) // "Hardware acceleration methods:"
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_string())
+        .collect()
+}